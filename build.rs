@@ -0,0 +1,10 @@
+// compiles the protos of the gRPC services with a vendored protoc, so no system install
+// is needed
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    println!("cargo:rerun-if-changed=proto");
+    tonic_build::configure()
+        .build_client(false)
+        .compile_protos(&["proto/rbac.proto", "proto/ext_authz.proto"], &["proto"])?;
+    Ok(())
+}
@@ -0,0 +1,38 @@
+// A downstream service guarded by rbac_auth. Run rbac_server, log in, then:
+//   cargo run -p rbac_auth --example actix_service -- http://127.0.0.1:8080
+//   curl -H "Authorization: Bearer <token>" localhost:9090/me
+//   curl -X DELETE -H "Authorization: Bearer <token>" localhost:9090/reports/1
+
+use std::time::Duration;
+
+use actix_web::{web, App, HttpServer};
+use rbac_auth::{actix::Authenticated, actix::RequirePermission, RbacAuth};
+
+async fn me(user: Authenticated) -> String {
+    format!("user {} with roles {:?}", user.0.claims.sub, user.0.claims.role)
+}
+
+async fn delete_report(user: Authenticated, id: web::Path<i32>) -> String {
+    format!("report {} deleted by user {}", id, user.0.claims.sub)
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let server = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "http://127.0.0.1:8080".to_string());
+    let auth = web::Data::new(RbacAuth::shared_key(b"rbac_secret").with_check_api(&server, Duration::from_secs(30)));
+    HttpServer::new(move || {
+        App::new()
+            .app_data(auth.clone())
+            .route("/me", web::get().to(me))
+            .service(
+                web::resource("/reports/{id}")
+                    .wrap(RequirePermission::new("Delete"))
+                    .route(web::delete().to(delete_report)),
+            )
+    })
+    .bind(("127.0.0.1", 9090))?
+    .run()
+    .await
+}
@@ -0,0 +1,139 @@
+use std::{
+    future::{ready, Ready},
+    rc::Rc,
+};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, StatusCode},
+    web, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError,
+};
+use futures_util::future::LocalBoxFuture;
+use serde_json::json;
+
+use crate::{AuthError, Identity, RbacAuth};
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::from_u16(AuthError::status_code(self)).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut res = HttpResponse::build(ResponseError::status_code(self));
+        if AuthError::status_code(self) == 401 {
+            res.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        res.json(json!({"code": self.code(), "message": self.to_string()}))
+    }
+}
+
+fn auth_header(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+}
+
+fn rbac_auth(req: &HttpRequest) -> Result<web::Data<RbacAuth>, AuthError> {
+    req.app_data::<web::Data<RbacAuth>>()
+        .cloned()
+        .ok_or_else(|| AuthError::Unavailable("RbacAuth is not registered as app data".to_string()))
+}
+
+// handler argument that only lets verified callers in:
+// `async fn me(user: Authenticated) -> String { user.0.claims.sub }`
+// Needs `web::Data<RbacAuth>` in the app data. Behind `RequirePermission` it reuses the
+// identity the middleware already verified.
+pub struct Authenticated(pub Identity);
+
+impl FromRequest for Authenticated {
+    type Error = AuthError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        if let Some(identity) = req.extensions().get::<Identity>() {
+            let identity = identity.clone();
+            return Box::pin(async move { Ok(Authenticated(identity)) });
+        }
+        let auth = rbac_auth(req);
+        let header = auth_header(req);
+        Box::pin(async move { Ok(Authenticated(auth?.authenticate(header.as_deref()).await?)) })
+    }
+}
+
+// `.wrap(RequirePermission::new("Update"))` on an app, scope or resource; rejects callers
+// without a valid token (401) or without the permission (403)
+pub struct RequirePermission {
+    permission: Option<Rc<str>>,
+}
+
+impl RequirePermission {
+    pub fn new(permission: &str) -> Self {
+        RequirePermission {
+            permission: Some(permission.into()),
+        }
+    }
+
+    // only a valid token, no particular permission
+    pub fn authenticated() -> Self {
+        RequirePermission { permission: None }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequirePermission
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequirePermissionMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequirePermissionMiddleware {
+            service: Rc::new(service),
+            permission: self.permission.clone(),
+        }))
+    }
+}
+
+pub struct RequirePermissionMiddleware<S> {
+    service: Rc<S>,
+    permission: Option<Rc<str>>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequirePermissionMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let permission = self.permission.clone();
+        Box::pin(async move {
+            let res = match rbac_auth(req.request()) {
+                Ok(auth) => {
+                    auth.require(auth_header(req.request()).as_deref(), permission.as_deref())
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(identity) => {
+                    req.extensions_mut().insert(identity);
+                    Ok(service.call(req).await?.map_into_left_body())
+                }
+                Err(e) => Ok(req.error_response(e).map_into_right_body()),
+            }
+        })
+    }
+}
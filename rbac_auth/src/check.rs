@@ -0,0 +1,182 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::AuthError;
+
+pub const DEFAULT_CHECK_TTL: Duration = Duration::from_secs(30);
+const MAX_CACHED_CHECKS: usize = 10_000;
+
+#[derive(Serialize)]
+struct CheckRequest<'a> {
+    action: &'a str,
+}
+
+#[derive(Deserialize)]
+struct CheckResponse {
+    allowed: bool,
+}
+
+// asks rbac_server's `POST /v1/check` whether a token holds a permission, remembering each
+// answer for `ttl` so hot paths don't make a round trip per request
+pub struct CheckClient {
+    url: String,
+    ttl: Duration,
+    client: reqwest::Client,
+    cache: Mutex<HashMap<(String, String), (bool, Instant)>>,
+}
+
+impl CheckClient {
+    // `base_url` is the server root, e.g. http://rbac:8080
+    pub fn new(base_url: &str, ttl: Duration) -> Self {
+        CheckClient {
+            url: format!("{}/v1/check", base_url.trim_end_matches('/')),
+            ttl,
+            client: reqwest::Client::new(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn allows(&self, token: &str, permission: &str) -> Result<bool, AuthError> {
+        let key = (token.to_string(), permission.to_string());
+        if let Some((allowed, at)) = self.cache.lock().unwrap().get(&key) {
+            if at.elapsed() < self.ttl {
+                return Ok(*allowed);
+            }
+        }
+        let res = self
+            .client
+            .post(&self.url)
+            .bearer_auth(token)
+            .json(&CheckRequest { action: permission })
+            .send()
+            .await
+            .map_err(|e| AuthError::Unavailable(format!("check API: {}", e)))?;
+        if res.status() == reqwest::StatusCode::UNAUTHORIZED {
+            return Err(AuthError::InvalidToken("Rejected by the check API".to_string()));
+        }
+        let allowed = res
+            .error_for_status()
+            .map_err(|e| AuthError::Unavailable(format!("check API: {}", e)))?
+            .json::<CheckResponse>()
+            .await
+            .map_err(|e| AuthError::Unavailable(format!("check API: {}", e)))?
+            .allowed;
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHED_CHECKS {
+            let ttl = self.ttl;
+            cache.retain(|_, (_, at)| at.elapsed() < ttl);
+            if cache.len() >= MAX_CACHED_CHECKS {
+                cache.clear();
+            }
+        }
+        cache.insert(key, (allowed, Instant::now()));
+        Ok(allowed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    // a stand-in for `POST /v1/check`: token "reader" holds Read only, token "nobody" is rejected
+    // and any other token holds nothing. Returns the base url and a count of requests served
+    async fn check_server() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let served = Arc::new(AtomicUsize::new(0));
+        let counter = served.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                // headers and the small JSON body arrive together; read until the body is complete
+                loop {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request);
+                    if n == 0 || text.contains("\r\n\r\n{") && text.ends_with('}') {
+                        break;
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).to_lowercase();
+                let response = if request.contains("bearer nobody") {
+                    "HTTP/1.1 401 Unauthorized\r\ncontent-length: 0\r\nconnection: close\r\n\r\n".to_string()
+                } else {
+                    let allowed = request.contains("bearer reader") && request.contains(r#""action":"read""#);
+                    let body = format!(r#"{{"allowed":{}}}"#, allowed);
+                    format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                };
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, served)
+    }
+
+    #[tokio::test]
+    async fn answers_are_cached_within_the_ttl() {
+        let (url, served) = check_server().await;
+        let client = CheckClient::new(&url, Duration::from_secs(60));
+        assert!(client.allows("reader", "Read").await.unwrap());
+        assert!(client.allows("reader", "Read").await.unwrap());
+        assert!(!client.allows("reader", "Delete").await.unwrap());
+        assert!(!client.allows("reader", "Delete").await.unwrap());
+        // denials are cached as well as grants
+        assert_eq!(served.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn answers_expire_after_the_ttl() {
+        let (url, served) = check_server().await;
+        let client = CheckClient::new(&url, Duration::from_millis(50));
+        assert!(client.allows("reader", "Read").await.unwrap());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(client.allows("reader", "Read").await.unwrap());
+        assert_eq!(served.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn answers_are_kept_per_token_and_permission() {
+        let (url, served) = check_server().await;
+        let client = CheckClient::new(&url, Duration::from_secs(60));
+        assert!(client.allows("reader", "Read").await.unwrap());
+        // neither another token nor another permission reuses the cached grant
+        assert!(!client.allows("writer", "Read").await.unwrap());
+        assert!(!client.allows("reader", "Update").await.unwrap());
+        assert_eq!(served.load(Ordering::SeqCst), 3);
+        assert!(client.allows("reader", "Read").await.unwrap());
+        assert!(!client.allows("writer", "Read").await.unwrap());
+        assert_eq!(served.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn rejected_tokens_are_not_cached() {
+        let (url, served) = check_server().await;
+        let client = CheckClient::new(&url, Duration::from_secs(60));
+        for _ in 0..2 {
+            assert!(matches!(client.allows("nobody", "Read").await, Err(AuthError::InvalidToken(_))));
+        }
+        assert_eq!(served.load(Ordering::SeqCst), 2);
+    }
+}
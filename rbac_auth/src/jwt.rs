@@ -0,0 +1,44 @@
+use jsonwebtoken::{decode, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+
+use crate::{jwks::JwksCache, AuthError};
+
+// claims of the tokens rbac_server issues at login
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub role: Vec<String>,
+    pub exp: usize,
+}
+
+// the token of an `Authorization: Bearer <token>` value, wherever the header came from
+pub fn parse_bearer(value: &str) -> Option<String> {
+    if value.starts_with("Bearer") {
+        return Some(value.trim_start_matches("Bearer ").to_string());
+    }
+    None
+}
+
+// checks the signature and expiry of an HS256 token signed with a shared secret
+pub fn decode_shared(token: &str, secret: &[u8]) -> Result<Claims, AuthError> {
+    let validation = Validation::new(jsonwebtoken::Algorithm::HS256);
+    match decode::<Claims>(token, &DecodingKey::from_secret(secret), &validation) {
+        Ok(token_data) => Ok(token_data.claims),
+        Err(e) => Err(AuthError::InvalidToken(e.to_string())),
+    }
+}
+
+// where the key that signed a token comes from
+pub enum TokenVerifier {
+    SharedKey(Vec<u8>),
+    Jwks(JwksCache),
+}
+
+impl TokenVerifier {
+    pub async fn verify(&self, token: &str) -> Result<Claims, AuthError> {
+        match self {
+            TokenVerifier::SharedKey(secret) => decode_shared(token, secret),
+            TokenVerifier::Jwks(jwks) => jwks.verify(token).await,
+        }
+    }
+}
@@ -0,0 +1,129 @@
+// Token verification and permission checks for services that sit behind rbac_server, so
+// teams stop copying its jwt.rs. Build one `RbacAuth` at startup and share it with the actix
+// extractor and middleware (feature `actix`) or the tower layer (feature `tower`).
+
+use std::{collections::HashMap, time::Duration};
+
+use thiserror::Error;
+
+pub mod check;
+pub mod jwks;
+pub mod jwt;
+
+#[cfg(feature = "actix")]
+pub mod actix;
+#[cfg(feature = "tower")]
+pub mod tower;
+
+pub use check::CheckClient;
+pub use jwks::JwksCache;
+pub use jwt::{decode_shared, parse_bearer, Claims, TokenVerifier};
+
+#[derive(Debug, Clone, Error)]
+pub enum AuthError {
+    #[error("Token is required.")]
+    MissingToken,
+    #[error("Invalid Authorization: {0}")]
+    InvalidToken(String),
+    #[error("Missing permission {0}")]
+    Forbidden(String),
+    // the JWKS endpoint or the check API could not be reached
+    #[error("{0}")]
+    Unavailable(String),
+}
+
+impl AuthError {
+    // same values rbac_server puts in `extensions.code` / the REST `code` field
+    pub fn code(&self) -> &'static str {
+        match self {
+            AuthError::MissingToken | AuthError::InvalidToken(_) => "UNAUTHENTICATED",
+            AuthError::Forbidden(_) => "FORBIDDEN",
+            AuthError::Unavailable(_) => "UNAVAILABLE",
+        }
+    }
+
+    pub fn status_code(&self) -> u16 {
+        match self {
+            AuthError::MissingToken | AuthError::InvalidToken(_) => 401,
+            AuthError::Forbidden(_) => 403,
+            AuthError::Unavailable(_) => 503,
+        }
+    }
+}
+
+// a verified caller
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub token: String,
+    pub claims: Claims,
+}
+
+pub struct RbacAuth {
+    verifier: TokenVerifier,
+    checker: Option<CheckClient>,
+    // role -> actions, for services that resolve permissions without calling the server
+    role_permissions: HashMap<String, Vec<String>>,
+}
+
+impl RbacAuth {
+    // tokens signed with the server's HS256 secret
+    pub fn shared_key(secret: &[u8]) -> Self {
+        Self::new(TokenVerifier::SharedKey(secret.to_vec()))
+    }
+
+    // tokens signed with an asymmetric key published at `url`
+    pub fn jwks(url: &str) -> Self {
+        Self::new(TokenVerifier::Jwks(JwksCache::new(url, jwks::DEFAULT_JWKS_TTL)))
+    }
+
+    pub fn new(verifier: TokenVerifier) -> Self {
+        RbacAuth {
+            verifier,
+            checker: None,
+            role_permissions: HashMap::new(),
+        }
+    }
+
+    // resolve permissions through the server's check API, caching each answer for `ttl`
+    pub fn with_check_api(mut self, base_url: &str, ttl: Duration) -> Self {
+        self.checker = Some(CheckClient::new(base_url, ttl));
+        self
+    }
+
+    // resolve permissions locally from a fixed role -> actions map
+    pub fn with_role_permissions(mut self, role_permissions: HashMap<String, Vec<String>>) -> Self {
+        self.role_permissions = role_permissions;
+        self
+    }
+
+    // `header` is the raw Authorization header value
+    pub async fn authenticate(&self, header: Option<&str>) -> Result<Identity, AuthError> {
+        let token = header.and_then(parse_bearer).ok_or(AuthError::MissingToken)?;
+        let claims = self.verifier.verify(&token).await?;
+        Ok(Identity { token, claims })
+    }
+
+    // the check API wins when configured; otherwise the local map decides, and without either
+    // nothing is allowed
+    pub async fn allows(&self, identity: &Identity, permission: &str) -> Result<bool, AuthError> {
+        if let Some(checker) = &self.checker {
+            return checker.allows(&identity.token, permission).await;
+        }
+        Ok(identity.claims.role.iter().any(|role| {
+            self.role_permissions
+                .get(role)
+                .map(|actions| actions.iter().any(|a| a == permission))
+                .unwrap_or(false)
+        }))
+    }
+
+    pub async fn require(&self, header: Option<&str>, permission: Option<&str>) -> Result<Identity, AuthError> {
+        let identity = self.authenticate(header).await?;
+        if let Some(permission) = permission {
+            if !self.allows(&identity, permission).await? {
+                return Err(AuthError::Forbidden(permission.to_string()));
+            }
+        }
+        Ok(identity)
+    }
+}
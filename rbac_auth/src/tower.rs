@@ -0,0 +1,97 @@
+use std::{
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures_util::future::BoxFuture;
+use http::{header, Request, Response, StatusCode};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::RbacAuth;
+
+// tower/axum counterpart of `RequirePermission`:
+// `Router::new().route(..).layer(RbacLayer::new(auth).require("Update"))`
+// Verified callers get an `Identity` in the request extensions (`Extension<Identity>` in axum).
+// Rejections are empty 401/403/503 responses.
+#[derive(Clone)]
+pub struct RbacLayer {
+    auth: Arc<RbacAuth>,
+    permission: Option<Arc<str>>,
+}
+
+impl RbacLayer {
+    pub fn new(auth: Arc<RbacAuth>) -> Self {
+        RbacLayer { auth, permission: None }
+    }
+
+    pub fn require(mut self, permission: &str) -> Self {
+        self.permission = Some(permission.into());
+        self
+    }
+}
+
+impl<S> Layer<S> for RbacLayer {
+    type Service = RbacService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RbacService {
+            inner,
+            auth: self.auth.clone(),
+            permission: self.permission.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RbacService<S> {
+    inner: S,
+    auth: Arc<RbacAuth>,
+    permission: Option<Arc<str>>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for RbacService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    ReqBody: Send + 'static,
+    ResBody: Default + Send,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        // the service that was polled ready is the one that has to handle the request
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let auth = self.auth.clone();
+        let permission = self.permission.clone();
+        Box::pin(async move {
+            let header = req
+                .headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string());
+            match auth.require(header.as_deref(), permission.as_deref()).await {
+                Ok(identity) => {
+                    req.extensions_mut().insert(identity);
+                    inner.call(req).await
+                }
+                Err(e) => {
+                    let mut res = Response::new(ResBody::default());
+                    *res.status_mut() = StatusCode::from_u16(e.status_code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                    if e.status_code() == 401 {
+                        res.headers_mut()
+                            .insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+                    }
+                    Ok(res)
+                }
+            }
+        })
+    }
+}
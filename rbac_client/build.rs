@@ -0,0 +1,437 @@
+// Generates the client from schema.graphql: a struct or enum per schema type, and a document and
+// a method per Query/Mutation field. Refresh the schema with
+//   cargo run -p rbac_server -- print-schema > rbac_client/schema.graphql
+// so a renamed or removed field breaks the build of every caller instead of failing at runtime.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    env,
+    fmt::Write,
+    fs,
+    path::Path,
+};
+
+use async_graphql_parser::{
+    parse_schema,
+    types::{BaseType, ConstDirective, FieldDefinition, InputValueDefinition, Type, TypeDefinition, TypeKind, TypeSystemDefinition},
+    Positioned,
+};
+use async_graphql_value::ConstValue;
+
+const SCHEMA: &str = "schema.graphql";
+
+// hand-written `Client` methods the generated ones must not shadow
+const RESERVED_METHODS: &[&str] = &[
+    "new", "with_token", "with_refresh_token", "with_credentials", "with_retries", "token", "refresh_token", "authenticate",
+    "authenticate_mfa", "raw", "execute", "can_refresh", "authorized", "send", "refresh", "keep_session",
+];
+
+const KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern", "false", "fn",
+    "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "self", "Self",
+    "static", "struct", "super", "trait", "true", "try", "type", "unsafe", "use", "where", "while", "yield",
+];
+
+struct Schema {
+    types: BTreeMap<String, TypeDefinition>,
+    query: String,
+    mutation: Option<String>,
+    subscription: Option<String>,
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", SCHEMA);
+    let sdl = match fs::read_to_string(SCHEMA) {
+        Ok(v) => v,
+        Err(e) => panic!("Error on reading {} = {}", SCHEMA, e),
+    };
+    let document = match parse_schema(&sdl) {
+        Ok(v) => v,
+        Err(e) => panic!("Error on parsing {} = {}", SCHEMA, e),
+    };
+
+    let mut schema = Schema {
+        types: BTreeMap::new(),
+        query: "Query".to_string(),
+        mutation: None,
+        subscription: None,
+    };
+    for definition in document.definitions {
+        match definition {
+            TypeSystemDefinition::Type(ty) => {
+                schema.types.insert(ty.node.name.node.to_string(), ty.node);
+            }
+            TypeSystemDefinition::Schema(def) => {
+                if let Some(query) = def.node.query {
+                    schema.query = query.node.to_string();
+                }
+                schema.mutation = def.node.mutation.map(|v| v.node.to_string());
+                schema.subscription = def.node.subscription.map(|v| v.node.to_string());
+            }
+            TypeSystemDefinition::Directive(_) => (),
+        }
+    }
+
+    let out = env::var("OUT_DIR").unwrap();
+    let (documents, operations) = operations_rs(&schema);
+    fs::write(Path::new(&out).join("types.rs"), types_rs(&schema)).unwrap();
+    fs::write(Path::new(&out).join("documents.rs"), documents).unwrap();
+    fs::write(Path::new(&out).join("operations.rs"), operations).unwrap();
+}
+
+fn scalar_type(name: &str) -> Option<&'static str> {
+    match name {
+        "Int" => Some("i64"),
+        "Float" => Some("f64"),
+        "String" | "ID" => Some("String"),
+        "Boolean" => Some("bool"),
+        "DateTime" => Some("chrono::DateTime<chrono::Utc>"),
+        "JSON" => Some("serde_json::Value"),
+        _ => None,
+    }
+}
+
+fn named_type(schema: &Schema, name: &str) -> String {
+    if let Some(ty) = scalar_type(name) {
+        return ty.to_string();
+    }
+    match schema.types.get(name).map(|t| &t.kind) {
+        Some(TypeKind::Scalar) => panic!("no Rust type for scalar {}, add it to scalar_type in build.rs", name),
+        Some(_) => name.to_string(),
+        None => panic!("{} references the unknown type {}", SCHEMA, name),
+    }
+}
+
+fn rust_type(schema: &Schema, ty: &Type) -> String {
+    let inner = match &ty.base {
+        BaseType::Named(name) => named_type(schema, name),
+        BaseType::List(item) => format!("Vec<{}>", rust_type(schema, item)),
+    };
+    if ty.nullable {
+        format!("Option<{}>", inner)
+    } else {
+        inner
+    }
+}
+
+// strings are borrowed and lists are slices; everything that has a server-side default or may be
+// null becomes an Option that is left out of the variables when None
+fn param_type(schema: &Schema, arg: &InputValueDefinition) -> String {
+    let ty = &arg.ty.node;
+    let inner = match &ty.base {
+        BaseType::Named(name) if matches!(name.as_str(), "String" | "ID") => "&str".to_string(),
+        BaseType::Named(name) => named_type(schema, name),
+        BaseType::List(item) => format!("&[{}]", rust_type(schema, item)),
+    };
+    if ty.nullable || arg.default_value.is_some() {
+        format!("Option<{}>", inner)
+    } else {
+        inner
+    }
+}
+
+fn base_name(ty: &Type) -> &str {
+    match &ty.base {
+        BaseType::Named(name) => name.as_str(),
+        BaseType::List(item) => base_name(item),
+    }
+}
+
+fn object_fields<'a>(schema: &'a Schema, name: &str) -> Option<&'a [Positioned<FieldDefinition>]> {
+    match schema.types.get(name).map(|t| &t.kind) {
+        Some(TypeKind::Object(object)) => Some(&object.fields),
+        _ => None,
+    }
+}
+
+// objects with an `id` are entities; their relations to other entities (User.roles,
+// Roles.members, ...) are left out of the default selection so one call can't fan out
+fn is_entity(schema: &Schema, name: &str) -> bool {
+    object_fields(schema, name)
+        .map(|fields| fields.iter().any(|f| f.node.name.node.as_str() == "id"))
+        .unwrap_or(false)
+}
+
+fn in_default_selection(schema: &Schema, parent: &str, field: &FieldDefinition) -> bool {
+    let target = base_name(&field.ty.node);
+    field.arguments.is_empty() && !(is_entity(schema, parent) && is_entity(schema, target))
+}
+
+fn selection(schema: &Schema, name: &str, path: &mut Vec<String>) -> String {
+    let Some(fields) = object_fields(schema, name) else {
+        return String::new();
+    };
+    if path.iter().any(|p| p == name) {
+        panic!("the default selection of {} loops back to itself through {:?}", name, path);
+    }
+    path.push(name.to_string());
+    let mut out = String::from(" {");
+    for field in fields.iter().filter(|f| in_default_selection(schema, name, &f.node)) {
+        write!(out, " {}", field.node.name.node).unwrap();
+        out.push_str(&selection(schema, base_name(&field.node.ty.node), path));
+    }
+    out.push_str(" }");
+    path.pop();
+    out
+}
+
+fn snake_case(name: &str) -> String {
+    let mut out = String::new();
+    let mut prev_lower = false;
+    for c in name.chars() {
+        if c.is_ascii_uppercase() && prev_lower {
+            out.push('_');
+        }
+        prev_lower = c.is_ascii_lowercase() || c.is_ascii_digit();
+        out.push(c.to_ascii_lowercase());
+    }
+    out
+}
+
+fn pascal_case(name: &str) -> String {
+    name.split('_')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let lower = if part.chars().all(|c| !c.is_ascii_lowercase()) {
+                part.to_ascii_lowercase()
+            } else {
+                part.to_string()
+            };
+            let mut chars = lower.chars();
+            match chars.next() {
+                Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+fn ident(name: &str) -> String {
+    let name = snake_case(name);
+    if KEYWORDS.contains(&name.as_str()) {
+        format!("{}_", name)
+    } else {
+        name
+    }
+}
+
+fn doc(out: &mut String, indent: &str, description: Option<&Positioned<String>>) {
+    if let Some(description) = description {
+        for line in description.node.trim().lines() {
+            writeln!(out, "{}/// {}", indent, line.trim()).unwrap();
+        }
+    }
+}
+
+fn deprecation(directives: &[Positioned<ConstDirective>]) -> Option<String> {
+    let directive = directives.iter().find(|d| d.node.name.node.as_str() == "deprecated")?;
+    Some(match directive.node.get_argument("reason") {
+        Some(reason) => match &reason.node {
+            ConstValue::String(v) => v.clone(),
+            other => other.to_string(),
+        },
+        None => "No longer supported".to_string(),
+    })
+}
+
+fn types_rs(schema: &Schema) -> String {
+    let roots: HashSet<&str> = [Some(&schema.query), schema.mutation.as_ref(), schema.subscription.as_ref()]
+        .into_iter()
+        .flatten()
+        .map(|v| v.as_str())
+        .collect();
+    let mut out = String::new();
+    for (name, def) in &schema.types {
+        if roots.contains(name.as_str()) || name.starts_with("__") {
+            continue;
+        }
+        match &def.kind {
+            TypeKind::Scalar => {
+                // fails the build for scalars without a mapping
+                named_type(schema, name);
+            }
+            TypeKind::Object(object) => {
+                doc(&mut out, "", def.description.as_ref());
+                out.push_str("#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]\n");
+                writeln!(out, "pub struct {} {{", name).unwrap();
+                for field in &object.fields {
+                    let field = &field.node;
+                    doc(&mut out, "    ", field.description.as_ref());
+                    let ty = rust_type(schema, &field.ty.node);
+                    if in_default_selection(schema, name, field) {
+                        writeln!(out, "    #[serde(rename = \"{}\")]", field.name.node).unwrap();
+                        writeln!(out, "    pub {}: {},", ident(&field.name.node), ty).unwrap();
+                    } else {
+                        // only filled when a custom document selects it, see Client::raw
+                        let ty = if field.ty.node.nullable { ty } else { format!("Option<{}>", ty) };
+                        writeln!(
+                            out,
+                            "    #[serde(rename = \"{}\", default, skip_serializing_if = \"Option::is_none\")]",
+                            field.name.node
+                        )
+                        .unwrap();
+                        writeln!(out, "    pub {}: {},", ident(&field.name.node), ty).unwrap();
+                    }
+                }
+                out.push_str("}\n\n");
+            }
+            TypeKind::Enum(e) => {
+                doc(&mut out, "", def.description.as_ref());
+                out.push_str("#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]\n");
+                writeln!(out, "pub enum {} {{", name).unwrap();
+                for value in &e.values {
+                    doc(&mut out, "    ", value.node.description.as_ref());
+                    writeln!(out, "    #[serde(rename = \"{}\")]", value.node.value.node).unwrap();
+                    writeln!(out, "    {},", pascal_case(&value.node.value.node)).unwrap();
+                }
+                out.push_str("}\n\n");
+            }
+            TypeKind::InputObject(input) => {
+                let all_optional = input
+                    .fields
+                    .iter()
+                    .all(|f| f.node.ty.node.nullable || f.node.default_value.is_some());
+                doc(&mut out, "", def.description.as_ref());
+                if all_optional {
+                    out.push_str("#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]\n");
+                } else {
+                    out.push_str("#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]\n");
+                }
+                writeln!(out, "pub struct {} {{", name).unwrap();
+                for field in &input.fields {
+                    let field = &field.node;
+                    doc(&mut out, "    ", field.description.as_ref());
+                    let ty = rust_type(schema, &field.ty.node);
+                    if field.ty.node.nullable || field.default_value.is_some() {
+                        let ty = if field.ty.node.nullable { ty } else { format!("Option<{}>", ty) };
+                        writeln!(
+                            out,
+                            "    #[serde(rename = \"{}\", skip_serializing_if = \"Option::is_none\")]",
+                            field.name.node
+                        )
+                        .unwrap();
+                        writeln!(out, "    pub {}: {},", ident(&field.name.node), ty).unwrap();
+                    } else {
+                        writeln!(out, "    #[serde(rename = \"{}\")]", field.name.node).unwrap();
+                        writeln!(out, "    pub {}: {},", ident(&field.name.node), ty).unwrap();
+                    }
+                }
+                out.push_str("}\n\n");
+            }
+            TypeKind::Interface(_) | TypeKind::Union(_) => {
+                panic!("{} is an interface or union, which the generator doesn't support yet", name)
+            }
+        }
+    }
+    out
+}
+
+fn operations_rs(schema: &Schema) -> (String, String) {
+    let mut documents = String::new();
+    let mut operations = String::from("impl crate::Client {\n");
+    let mut seen = HashSet::new();
+    let roots = [("query", "Query", Some(&schema.query)), ("mutation", "Mutation", schema.mutation.as_ref())];
+    for (keyword, operation, root) in roots {
+        let Some(root) = root else { continue };
+        let Some(fields) = object_fields(schema, root) else {
+            panic!("the {} root type {} is not an object", keyword, root)
+        };
+        for field in fields {
+            let field = &field.node;
+            let method = ident(&field.name.node);
+            if RESERVED_METHODS.contains(&method.as_str()) {
+                panic!("{}.{} would shadow Client::{}", root, field.name.node, method);
+            }
+            if !seen.insert(method.clone()) {
+                panic!("{}.{} clashes with another Query or Mutation field named {}", root, field.name.node, method);
+            }
+            let constant = method.trim_end_matches('_').to_uppercase();
+
+            // the document
+            let mut vars = Vec::new();
+            let mut args = Vec::new();
+            for arg in &field.arguments {
+                let arg = &arg.node;
+                let mut var = format!("${}: {}", arg.name.node, arg.ty.node);
+                if let Some(default) = &arg.default_value {
+                    write!(var, " = {}", default.node).unwrap();
+                }
+                vars.push(var);
+                args.push(format!("{}: ${}", arg.name.node, arg.name.node));
+            }
+            let mut text = format!("{} {}", keyword, pascal_case(&field.name.node));
+            if !vars.is_empty() {
+                write!(text, "({})", vars.join(", ")).unwrap();
+            }
+            write!(text, " {{ {}", field.name.node).unwrap();
+            if !args.is_empty() {
+                write!(text, "({})", args.join(", ")).unwrap();
+            }
+            text.push_str(&selection(schema, base_name(&field.ty.node), &mut Vec::new()));
+            text.push_str(" }");
+            writeln!(documents, "/// `{}.{}` with its default selection", root, field.name.node).unwrap();
+            writeln!(documents, "pub const {}: &str = {:?};\n", constant, text).unwrap();
+
+            // the method
+            match field.description.as_ref() {
+                Some(_) => doc(&mut operations, "    ", field.description.as_ref()),
+                None => writeln!(operations, "    /// Runs `{}.{}`.", root, field.name.node).unwrap(),
+            }
+            if let Some(reason) = deprecation(&field.directives) {
+                writeln!(operations, "    #[deprecated(note = {:?})]", reason).unwrap();
+            }
+            if field.arguments.len() > 7 {
+                operations.push_str("    #[allow(clippy::too_many_arguments)]\n");
+            }
+            let params: Vec<String> = field
+                .arguments
+                .iter()
+                .map(|arg| format!("{}: {}", ident(&arg.node.name.node), param_type(schema, &arg.node)))
+                .collect();
+            let mut signature = String::from("&self");
+            for param in &params {
+                write!(signature, ", {}", param).unwrap();
+            }
+            writeln!(
+                operations,
+                "    pub async fn {}({}) -> Result<{}, crate::ClientError> {{",
+                method,
+                signature,
+                rust_type(schema, &field.ty.node)
+            )
+            .unwrap();
+            if field.arguments.is_empty() {
+                operations.push_str("        let variables = serde_json::Map::new();\n");
+            } else {
+                operations.push_str("        let mut variables = serde_json::Map::new();\n");
+            }
+            for arg in &field.arguments {
+                let arg = &arg.node;
+                let name = ident(&arg.name.node);
+                if arg.ty.node.nullable || arg.default_value.is_some() {
+                    writeln!(
+                        operations,
+                        "        if let Some(v) = {} {{\n            variables.insert(\"{}\".to_string(), serde_json::json!(v));\n        }}",
+                        name, arg.name.node
+                    )
+                    .unwrap();
+                } else {
+                    writeln!(
+                        operations,
+                        "        variables.insert(\"{}\".to_string(), serde_json::json!({}));",
+                        arg.name.node, name
+                    )
+                    .unwrap();
+                }
+            }
+            writeln!(
+                operations,
+                "        self.execute(crate::Operation::{}, crate::documents::{}, variables, \"{}\").await\n    }}\n",
+                operation, constant, field.name.node
+            )
+            .unwrap();
+        }
+    }
+    operations.push_str("}\n");
+    (documents, operations)
+}
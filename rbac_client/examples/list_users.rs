@@ -0,0 +1,34 @@
+// Pages through all users with the generated client. Run rbac_server, then:
+//   cargo run -p rbac_client --example list_users -- http://127.0.0.1:8080/ admin@test.com Admin
+
+use rbac_client::{types::UserFilter, Client, ClientError};
+
+#[tokio::main]
+async fn main() -> Result<(), ClientError> {
+    let mut args = std::env::args().skip(1);
+    let url = args.next().unwrap_or_else(|| "http://127.0.0.1:8080/".to_string());
+    let email = args.next().unwrap_or_else(|| "admin@test.com".to_string());
+    let password = args.next().unwrap_or_else(|| "Admin".to_string());
+    let client = Client::new(&url).with_credentials(&email, &password);
+
+    let mut after: Option<String> = None;
+    loop {
+        let page = client
+            .users(Some(2), after.as_deref(), Some(UserFilter::default()), None)
+            .await?;
+        for edge in &page.edges {
+            println!("{}\t{}\t{}", edge.node.id, edge.node.name, edge.node.email);
+        }
+        if !page.page_info.has_next_page {
+            break;
+        }
+        after = page.page_info.end_cursor;
+    }
+
+    match client.fetch_user("999999").await {
+        Ok(user) => println!("found {}", user.name),
+        Err(ClientError::NotFound(message)) => println!("not found: {}", message),
+        Err(e) => return Err(e),
+    }
+    Ok(())
+}
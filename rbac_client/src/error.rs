@@ -0,0 +1,125 @@
+use serde::Deserialize;
+use thiserror::Error;
+
+// one variant per `extensions.code` the server sends, plus what can go wrong on the way
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    Unauthenticated(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{message}")]
+    Validation { field: Option<String>, message: String },
+    #[error("{0}")]
+    Internal(String),
+    #[error("{0}")]
+    QueryTooDeep(String),
+    #[error("{0}")]
+    QueryTooComplex(String),
+    // seconds until the server accepts the next request
+    #[error("{message}")]
+    RateLimited { retry_after: Option<u64>, message: String },
+    // too many wrong passwords for the account or from this address
+    #[error("{message}")]
+    LoginLocked { retry_after: Option<u64>, message: String },
+    // the password was right, finish with `Client::authenticate_mfa`; when `enrolled` is false
+    // the account must set up an authenticator with the token first (enroll_totp, confirm_totp)
+    #[error("{message}")]
+    MfaRequired { mfa_token: String, enrolled: bool, message: String },
+    // the account waits for `verify_email`; `send_verification_email` mails a new link
+    #[error("{0}")]
+    EmailNotVerified(String),
+    // a code this version of the client doesn't know yet
+    #[error("{message}")]
+    Other { code: Option<String>, message: String },
+    #[error("HTTP {status}: {body}")]
+    Http { status: u16, body: String },
+    #[error("{0}")]
+    Transport(#[from] reqwest::Error),
+    #[error("unexpected response: {0}")]
+    Decode(String),
+}
+
+impl ClientError {
+    pub fn code(&self) -> Option<&str> {
+        match self {
+            ClientError::NotFound(_) => Some("NOT_FOUND"),
+            ClientError::Conflict(_) => Some("CONFLICT"),
+            ClientError::Unauthenticated(_) => Some("UNAUTHENTICATED"),
+            ClientError::Forbidden(_) => Some("FORBIDDEN"),
+            ClientError::Validation { .. } => Some("VALIDATION_FAILED"),
+            ClientError::Internal(_) => Some("INTERNAL_SERVER_ERROR"),
+            ClientError::QueryTooDeep(_) => Some("QUERY_TOO_DEEP"),
+            ClientError::QueryTooComplex(_) => Some("QUERY_TOO_COMPLEX"),
+            ClientError::RateLimited { .. } => Some("RATE_LIMITED"),
+            ClientError::LoginLocked { .. } => Some("LOGIN_LOCKED"),
+            ClientError::MfaRequired { .. } => Some("MFA_REQUIRED"),
+            ClientError::EmailNotVerified(_) => Some("EMAIL_NOT_VERIFIED"),
+            ClientError::Other { code, .. } => code.as_deref(),
+            ClientError::Http { .. } | ClientError::Transport(_) | ClientError::Decode(_) => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct GraphQLError {
+    message: String,
+    #[serde(default)]
+    extensions: Option<GraphQLErrorExtensions>,
+}
+
+#[derive(Deserialize)]
+struct GraphQLErrorExtensions {
+    code: Option<String>,
+    field: Option<String>,
+    #[serde(rename = "retryAfter")]
+    retry_after: Option<u64>,
+    #[serde(rename = "mfaToken")]
+    mfa_token: Option<String>,
+    #[serde(rename = "mfaEnrolled")]
+    mfa_enrolled: Option<bool>,
+}
+
+impl From<GraphQLError> for ClientError {
+    fn from(e: GraphQLError) -> Self {
+        let (code, field, retry_after, mfa_token, mfa_enrolled) = match e.extensions {
+            Some(v) => (v.code, v.field, v.retry_after, v.mfa_token, v.mfa_enrolled),
+            None => (None, None, None, None, None),
+        };
+        match code.as_deref() {
+            Some("NOT_FOUND") => ClientError::NotFound(e.message),
+            Some("CONFLICT") => ClientError::Conflict(e.message),
+            Some("UNAUTHENTICATED") => ClientError::Unauthenticated(e.message),
+            Some("FORBIDDEN") => ClientError::Forbidden(e.message),
+            Some("VALIDATION_FAILED") => ClientError::Validation {
+                field,
+                message: e.message,
+            },
+            Some("INTERNAL_SERVER_ERROR") => ClientError::Internal(e.message),
+            Some("QUERY_TOO_DEEP") => ClientError::QueryTooDeep(e.message),
+            Some("QUERY_TOO_COMPLEX") => ClientError::QueryTooComplex(e.message),
+            Some("RATE_LIMITED") => ClientError::RateLimited {
+                retry_after,
+                message: e.message,
+            },
+            Some("LOGIN_LOCKED") => ClientError::LoginLocked {
+                retry_after,
+                message: e.message,
+            },
+            Some("MFA_REQUIRED") => ClientError::MfaRequired {
+                mfa_token: mfa_token.unwrap_or_default(),
+                enrolled: mfa_enrolled.unwrap_or(true),
+                message: e.message,
+            },
+            Some("EMAIL_NOT_VERIFIED") => ClientError::EmailNotVerified(e.message),
+            _ => ClientError::Other {
+                code,
+                message: e.message,
+            },
+        }
+    }
+}
@@ -0,0 +1,275 @@
+// Typed client for rbac_server's GraphQL API. The types, documents and one method per Query and
+// Mutation field are generated by build.rs from schema.graphql, so a renamed field on the server
+// shows up as a compile error here after the schema is refreshed, not as a runtime surprise.
+//
+//   let client = Client::new("http://127.0.0.1:8080/").with_credentials("admin@test.com", "Admin");
+//   let users = client.users(Some(20), None, None, None).await?;
+
+use std::{sync::Mutex, time::Duration};
+
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Map, Value};
+
+mod error;
+
+pub use error::ClientError;
+use error::GraphQLError;
+
+// structs and enums for every object, input and enum type of the schema. Fields that are not in
+// the default selection (relations between entities such as `User.roles`) are Options that stay
+// None unless a document passed to `Client::raw` selects them
+pub mod types {
+    include!(concat!(env!("OUT_DIR"), "/types.rs"));
+}
+
+// the query documents behind the generated methods
+pub mod documents {
+    include!(concat!(env!("OUT_DIR"), "/documents.rs"));
+}
+
+mod operations {
+    use crate::types::*;
+
+    include!(concat!(env!("OUT_DIR"), "/operations.rs"));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Operation {
+    Query,
+    Mutation,
+}
+
+#[derive(Deserialize)]
+struct GraphQLResponse {
+    data: Option<Value>,
+    #[serde(default)]
+    errors: Vec<GraphQLError>,
+}
+
+pub struct Client {
+    url: String,
+    http: reqwest::Client,
+    token: Mutex<Option<String>>,
+    // from the last login or refreshSession; spent to renew the token without the password
+    refresh_token: Mutex<Option<String>>,
+    // email and password used to log in again when the token is missing or rejected
+    credentials: Option<(String, String)>,
+    refresh_lock: tokio::sync::Mutex<()>,
+    max_retries: u32,
+    retry_backoff: Duration,
+}
+
+impl Client {
+    // `url` is the GraphQL endpoint, e.g. http://rbac:8080/
+    pub fn new(url: &str) -> Self {
+        Client {
+            url: url.to_string(),
+            http: reqwest::Client::new(),
+            token: Mutex::new(None),
+            refresh_token: Mutex::new(None),
+            credentials: None,
+            refresh_lock: tokio::sync::Mutex::new(()),
+            max_retries: 3,
+            retry_backoff: Duration::from_millis(200),
+        }
+    }
+
+    // a token from an earlier `login`; without credentials it is used until it expires
+    pub fn with_token(self, token: &str) -> Self {
+        *self.token.lock().unwrap() = Some(token.to_string());
+        self
+    }
+
+    // the refresh token of an earlier `login`, spent when the access token is rejected
+    pub fn with_refresh_token(self, refresh_token: &str) -> Self {
+        *self.refresh_token.lock().unwrap() = Some(refresh_token.to_string());
+        self
+    }
+
+    // log in on the first call, and again whenever the server answers UNAUTHENTICATED and the
+    // refresh token can't renew the session
+    pub fn with_credentials(mut self, email: &str, password: &str) -> Self {
+        self.credentials = Some((email.to_string(), password.to_string()));
+        self
+    }
+
+    // transient failures are retried with exponential backoff starting at `backoff`. Queries are
+    // retried on any transport error or 502/503/504; mutations only when the connection was
+    // never made, so nothing runs twice. Rate limited calls of either kind wait for Retry-After
+    pub fn with_retries(mut self, max_retries: u32, backoff: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.retry_backoff = backoff;
+        self
+    }
+
+    pub fn token(&self) -> Option<String> {
+        self.token.lock().unwrap().clone()
+    }
+
+    // the refresh token the next renewal will spend, to keep the session across restarts
+    pub fn refresh_token(&self) -> Option<String> {
+        self.refresh_token.lock().unwrap().clone()
+    }
+
+    // log in with the configured credentials now instead of on the first call
+    pub async fn authenticate(&self) -> Result<(), ClientError> {
+        let stale = self.token();
+        self.refresh(stale.as_deref()).await.map(|_| ())
+    }
+
+    // second login step after `authenticate` (or any call) failed with MfaRequired: a code from
+    // the authenticator or a recovery code. The session is kept like one from a plain login
+    pub async fn authenticate_mfa(&self, mfa_token: &str, code: &str) -> Result<(), ClientError> {
+        let variables = json!({ "mfaToken": mfa_token, "code": code });
+        let data = self.send(Operation::Mutation, documents::VERIFY_MFA, &variables, None).await?;
+        let _guard = self.refresh_lock.lock().await;
+        self.keep_session(take_field(data, "verifyMfa")?);
+        Ok(())
+    }
+
+    // runs a hand-written document and returns its whole `data` object, for selections the
+    // generated methods don't make
+    pub async fn raw<T: DeserializeOwned>(&self, document: &str, variables: Value) -> Result<T, ClientError> {
+        let operation = if document.trim_start().starts_with("mutation") {
+            Operation::Mutation
+        } else {
+            Operation::Query
+        };
+        let data = self.authorized(operation, document, &variables).await?;
+        serde_json::from_value(data).map_err(|e| ClientError::Decode(e.to_string()))
+    }
+
+    pub(crate) async fn execute<T: DeserializeOwned>(
+        &self,
+        operation: Operation,
+        document: &str,
+        variables: Map<String, Value>,
+        field: &str,
+    ) -> Result<T, ClientError> {
+        let data = self.authorized(operation, document, &Value::Object(variables)).await?;
+        take_field(data, field)
+    }
+
+    fn can_refresh(&self) -> bool {
+        self.credentials.is_some() || self.refresh_token.lock().unwrap().is_some()
+    }
+
+    async fn authorized(&self, operation: Operation, document: &str, variables: &Value) -> Result<Value, ClientError> {
+        let mut token = self.token();
+        if token.is_none() && self.can_refresh() {
+            token = match self.refresh(None).await {
+                Ok(v) => Some(v),
+                // the call may be one of the MFA or email verification steps, which carry their
+                // own token instead; any other call ends up back here through UNAUTHENTICATED and
+                // returns the same error
+                Err(ClientError::MfaRequired { .. } | ClientError::EmailNotVerified(_)) => None,
+                Err(e) => return Err(e),
+            };
+        }
+        match self.send(operation, document, variables, token.as_deref()).await {
+            // the token expired, renew it and repeat the call once; the rejected attempt never
+            // ran, so this is safe for mutations too
+            Err(ClientError::Unauthenticated(_)) if self.can_refresh() => {
+                let token = self.refresh(token.as_deref()).await?;
+                self.send(operation, document, variables, Some(&token)).await
+            }
+            res => res,
+        }
+    }
+
+    // renews the session unless another task already replaced the `stale` token: with the
+    // refresh token when there is one, else by logging in with the credentials
+    async fn refresh(&self, stale: Option<&str>) -> Result<String, ClientError> {
+        let _guard = self.refresh_lock.lock().await;
+        if let Some(current) = self.token() {
+            if Some(current.as_str()) != stale {
+                return Ok(current);
+            }
+        }
+        let refresh_token = self.refresh_token();
+        if let Some(refresh_token) = refresh_token {
+            let variables = json!({ "refreshToken": refresh_token });
+            match self.send(Operation::Mutation, documents::REFRESH_SESSION, &variables, None).await {
+                Ok(data) => return Ok(self.keep_session(take_field(data, "refreshSession")?)),
+                // expired or revoked, fall back to the credentials
+                Err(ClientError::Unauthenticated(_)) => *self.refresh_token.lock().unwrap() = None,
+                Err(e) => return Err(e),
+            }
+        }
+        let Some((email, password)) = &self.credentials else {
+            return Err(ClientError::Unauthenticated("No credentials to log in with".to_string()));
+        };
+        let variables = json!({ "email": email, "password": password });
+        let data = self.send(Operation::Mutation, documents::LOGIN, &variables, None).await?;
+        Ok(self.keep_session(take_field(data, "login")?))
+    }
+
+    fn keep_session(&self, session: types::AuthPayload) -> String {
+        *self.refresh_token.lock().unwrap() = Some(session.refresh_token);
+        *self.token.lock().unwrap() = Some(session.access_token.clone());
+        session.access_token
+    }
+
+    async fn send(
+        &self,
+        operation: Operation,
+        document: &str,
+        variables: &Value,
+        token: Option<&str>,
+    ) -> Result<Value, ClientError> {
+        let body = json!({ "query": document, "variables": variables });
+        let mut attempt = 0;
+        loop {
+            let mut req = self.http.post(&self.url).json(&body);
+            if let Some(token) = token {
+                req = req.bearer_auth(token);
+            }
+            let mut wait = self.retry_backoff * 2u32.pow(attempt);
+            let (retry, err) = match req.send().await {
+                // rejected before anything ran, so safe to repeat even for mutations
+                Ok(res) if res.status().as_u16() == 429 => match read_response(res).await {
+                    Err(ClientError::RateLimited { retry_after, message }) => {
+                        if let Some(secs) = retry_after {
+                            wait = wait.max(Duration::from_secs(secs));
+                        }
+                        (true, ClientError::RateLimited { retry_after, message })
+                    }
+                    res => return res,
+                },
+                Ok(res) if matches!(res.status().as_u16(), 502..=504) => {
+                    let status = res.status().as_u16();
+                    let body = res.text().await.unwrap_or_default();
+                    (operation == Operation::Query, ClientError::Http { status, body })
+                }
+                Ok(res) => return read_response(res).await,
+                Err(e) => (e.is_connect() || operation == Operation::Query, ClientError::Transport(e)),
+            };
+            if !retry || attempt >= self.max_retries {
+                return Err(err);
+            }
+            tokio::time::sleep(wait).await;
+            attempt += 1;
+        }
+    }
+}
+
+async fn read_response(res: reqwest::Response) -> Result<Value, ClientError> {
+    let status = res.status().as_u16();
+    let body = res.text().await?;
+    let parsed = match serde_json::from_str::<GraphQLResponse>(&body) {
+        Ok(v) => v,
+        Err(_) if status >= 300 => return Err(ClientError::Http { status, body }),
+        Err(e) => return Err(ClientError::Decode(e.to_string())),
+    };
+    if let Some(error) = parsed.errors.into_iter().next() {
+        return Err(error.into());
+    }
+    parsed
+        .data
+        .ok_or_else(|| ClientError::Decode("response has neither data nor errors".to_string()))
+}
+
+fn take_field<T: DeserializeOwned>(mut data: Value, field: &str) -> Result<T, ClientError> {
+    let value = data.get_mut(field).map(Value::take).unwrap_or(Value::Null);
+    serde_json::from_value(value).map_err(|e| ClientError::Decode(format!("{}: {}", field, e)))
+}
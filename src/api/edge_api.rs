@@ -0,0 +1,160 @@
+use std::sync::Arc;
+
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use sqlx::PgPool;
+use tonic::{Request, Response, Status};
+use uuid::Uuid;
+
+use crate::{
+    utilities::{
+        audit::{AuditWriter, RequestMeta},
+        edge_auth::{decide, EdgeDecision, RouteRules},
+        errors::RbacError,
+        jwt::{extract_jwt, parse_bearer},
+        rate_limit::RateLimiter,
+    },
+    AppState,
+};
+
+pub mod pb {
+    tonic::include_proto!("envoy.service.auth.v3");
+}
+
+use pb::{
+    authorization_server::Authorization, check_response::HttpResponse as EnvoyHttpResponse, BoolValue,
+    CheckRequest, CheckResponse, DeniedHttpResponse, HeaderValue, HeaderValueOption, HttpStatus,
+    OkHttpResponse, RpcStatus,
+};
+pub use pb::authorization_server::AuthorizationServer as ExtAuthzServer;
+
+// identity handed to the backend when a request is let through
+pub const USER_ID_HEADER: &str = "X-User-Id";
+pub const USER_ROLES_HEADER: &str = "X-User-Roles";
+
+fn original_header(req: &HttpRequest, names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .find_map(|name| req.headers().get(*name))
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+}
+
+// nginx `auth_request` / Traefik `forwardAuth` target. The proxy passes the original request
+// in X-Original-Method and X-Original-URI (nginx) or X-Forwarded-Method and X-Forwarded-Uri
+// (Traefik), together with the client's Authorization header.
+pub async fn auth_request(data: web::Data<AppState>, req: HttpRequest) -> HttpResponse {
+    let method = original_header(&req, &["X-Original-Method", "X-Forwarded-Method"])
+        .unwrap_or_else(|| req.method().to_string());
+    let path = match original_header(&req, &["X-Original-URI", "X-Forwarded-Uri"]) {
+        Some(v) => v,
+        None => return HttpResponse::BadRequest().body("Missing X-Original-URI"),
+    };
+    let meta = RequestMeta::from_request(&req);
+    let decision = decide(
+        &data.pool,
+        &data.audit,
+        &meta,
+        &data.rules,
+        extract_jwt(&req),
+        &method,
+        &path,
+    )
+    .await;
+    match decision {
+        EdgeDecision::Allow { sub, roles } => HttpResponse::Ok()
+            .insert_header((USER_ID_HEADER, sub))
+            .insert_header((USER_ROLES_HEADER, roles.join(",")))
+            .finish(),
+        EdgeDecision::Unauthenticated(message) => HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+            .body(message),
+        EdgeDecision::Forbidden(message) => HttpResponse::Forbidden().body(message),
+        EdgeDecision::Error(message) => HttpResponse::InternalServerError().body(message),
+    }
+}
+
+// Envoy `ext_authz` gRPC service with the same rules and answers as `auth_request`
+pub struct ExtAuthzService {
+    pub pool: PgPool,
+    pub audit: AuditWriter,
+    pub rules: Arc<RouteRules>,
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+fn header_option(key: &str, value: &str) -> HeaderValueOption {
+    HeaderValueOption {
+        header: Some(HeaderValue {
+            key: key.to_string(),
+            value: value.to_string(),
+        }),
+        append: Some(BoolValue { value: false }),
+    }
+}
+
+fn denied(rpc_code: i32, http_code: i32, message: String, headers: Vec<HeaderValueOption>) -> CheckResponse {
+    CheckResponse {
+        status: Some(RpcStatus {
+            code: rpc_code,
+            message: message.clone(),
+        }),
+        http_response: Some(EnvoyHttpResponse::DeniedResponse(DeniedHttpResponse {
+            status: Some(HttpStatus { code: http_code }),
+            headers,
+            body: message,
+        })),
+    }
+}
+
+#[tonic::async_trait]
+impl Authorization for ExtAuthzService {
+    async fn check(&self, req: Request<CheckRequest>) -> Result<Response<CheckResponse>, Status> {
+        let attributes = req.into_inner().attributes.unwrap_or_default();
+        let http = attributes
+            .request
+            .and_then(|r| r.http)
+            .unwrap_or_default();
+        let meta = RequestMeta {
+            client_ip: attributes
+                .source
+                .and_then(|p| p.address)
+                .and_then(|a| a.socket_address)
+                .map(|a| a.address),
+            request_id: if http.id.is_empty() {
+                Uuid::new_v4().to_string()
+            } else {
+                http.id.clone()
+            },
+        };
+        let token = http.headers.get("authorization").and_then(|v| parse_bearer(v));
+        // google.rpc.Code: 0 OK, 7 PERMISSION_DENIED, 8 RESOURCE_EXHAUSTED, 16 UNAUTHENTICATED
+        if let Some(limiter) = &self.rate_limiter {
+            if let Err(retry_after) = limiter.check_client(token.as_deref(), meta.client_ip.as_deref()) {
+                let message = RbacError::RateLimited { retry_after }.to_string();
+                let headers = vec![header_option("Retry-After", &retry_after.to_string())];
+                return Ok(Response::new(denied(8, 429, message, headers)));
+            }
+        }
+        let decision = decide(&self.pool, &self.audit, &meta, &self.rules, token, &http.method, &http.path).await;
+        let res = match decision {
+            EdgeDecision::Allow { sub, roles } => CheckResponse {
+                status: Some(RpcStatus {
+                    code: 0,
+                    message: String::new(),
+                }),
+                http_response: Some(EnvoyHttpResponse::OkResponse(OkHttpResponse {
+                    headers: vec![
+                        header_option(USER_ID_HEADER, &sub),
+                        header_option(USER_ROLES_HEADER, &roles.join(",")),
+                    ],
+                })),
+            },
+            EdgeDecision::Unauthenticated(message) => {
+                denied(16, 401, message, vec![header_option("WWW-Authenticate", "Bearer")])
+            }
+            EdgeDecision::Forbidden(message) => denied(7, 403, message, Vec::new()),
+            // an error status makes Envoy apply its failure_mode_allow setting
+            EdgeDecision::Error(message) => return Err(Status::internal(message)),
+        };
+        Ok(Response::new(res))
+    }
+}
@@ -1,21 +1,49 @@
-use std::sync::{Arc, Mutex};
+use actix_web::{web, HttpRequest, HttpResponse};
+use async_graphql::Data;
+use async_graphql_actix_web::{GraphQLRequest, GraphQLResponse, GraphQLSubscription};
 
-use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, Responder, ResponseError};
-use async_graphql::Response;
-use async_graphql_actix_web::{GraphQLRequest, GraphQLResponse};
-
-use crate::{utilities::jwt::extract_jwt, AppState};
+use crate::{
+    graphql::queries::RequestAuth,
+    utilities::{audit::RequestMeta, jwt::extract_jwt},
+    AppState,
+};
 
 pub async fn graphql_handler(
     data: web::Data<AppState>,
     http_req: HttpRequest,
     req: GraphQLRequest,
-) -> GraphQLResponse{
-    let schema = data.schema.lock().await;
+) -> GraphQLResponse {
+    // Schema is an Arc internally, so every worker executes requests concurrently on a shared handle
+    let meta = RequestMeta::from_request(&http_req);
+    let token = extract_jwt(&http_req);
+    let ctx = req.into_inner().data(token).data(meta).data(RequestAuth::default());
+    data.schema.execute(ctx).await.into()
+}
+
+// graphql-ws / graphql-transport-ws endpoint for subscriptions. The token can come from the
+// Authorization header of the upgrade request or from the connection_init payload
+// ({"Authorization": "Bearer <token>"}), since browsers can't set headers on websockets.
+pub async fn graphql_ws_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    payload: web::Payload,
+) -> actix_web::Result<HttpResponse> {
+    let schema = data.schema.clone();
+    let meta = RequestMeta::from_request(&http_req);
+    let header_token = extract_jwt(&http_req);
 
-    // let header = http_req.headers().get("Authorization").unwrap().clone();
-    let http_req = Mutex::new(http_req);
-    let token = extract_jwt(http_req);
-    let ctx = req.into_inner().data(token);
-    schema.execute(ctx).await.into()
+    let mut conn_data = Data::default();
+    conn_data.insert(meta);
+    GraphQLSubscription::new(schema)
+        .with_data(conn_data)
+        .on_connection_init(move |value| async move {
+            let init_token = ["Authorization", "authorization"]
+                .iter()
+                .find_map(|key| value.get(*key).and_then(|v| v.as_str()))
+                .map(|v| v.trim_start_matches("Bearer ").to_string());
+            let mut data = Data::default();
+            data.insert(init_token.or(header_token));
+            Ok(data)
+        })
+        .start(&http_req, payload)
 }
@@ -0,0 +1,263 @@
+use std::{net::SocketAddr, pin::Pin, sync::Arc};
+
+use async_graphql::{Error, ErrorExtensions};
+use futures_util::{future::join_all, Stream};
+use sqlx::PgPool;
+use tokio::{net::TcpListener, sync::broadcast::error::RecvError};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{metadata::MetadataMap, transport::Server, Request, Response, Status};
+use uuid::Uuid;
+
+use crate::{
+    api::edge_api::{ExtAuthzServer, ExtAuthzService},
+    db::roles::fetch_user_role_grants,
+    utilities::{
+        audit::{AuditEvent, AuditWriter, RequestMeta},
+        auth::{authorize, AuthPerm},
+        change_feed::ChangeFeed,
+        errors::{extension, retry_after, RbacError},
+        jwt::parse_bearer,
+        rate_limit::RateLimiter,
+    },
+};
+
+pub mod pb {
+    tonic::include_proto!("rbac.v1");
+}
+
+use pb::{
+    authorization_server::{Authorization, AuthorizationServer},
+    BatchCheckRequest, BatchCheckResponse, CheckRequest, CheckResponse, ListUserPermissionsRequest,
+    ListUserPermissionsResponse, PolicyEvent, RoleGrant, WatchPolicyRequest,
+};
+
+pub const MAX_BATCH_CHECKS: usize = 1000;
+
+pub struct AuthorizationService {
+    pub pool: PgPool,
+    pub audit: AuditWriter,
+    pub feed: ChangeFeed,
+}
+
+// maps the code the GraphQL error carries onto the closest gRPC status
+fn status(e: Error) -> Status {
+    match extension(&e, "code").as_deref() {
+        Some("NOT_FOUND") => Status::not_found(e.message),
+        Some("CONFLICT") => Status::already_exists(e.message),
+        Some("UNAUTHENTICATED") => Status::unauthenticated(e.message),
+        Some("FORBIDDEN") => Status::permission_denied(e.message),
+        Some("VALIDATION_FAILED") => Status::invalid_argument(e.message),
+        Some("RATE_LIMITED") | Some("LOGIN_LOCKED") => {
+            let secs = retry_after(&e);
+            let mut status = Status::resource_exhausted(e.message);
+            if let Some(secs) = secs {
+                status.metadata_mut().insert("retry-after", secs.into());
+            }
+            status
+        }
+        _ => Status::internal(e.message),
+    }
+}
+
+fn request_meta<T>(req: &Request<T>) -> RequestMeta {
+    RequestMeta {
+        client_ip: req.remote_addr().map(|v| v.ip().to_string()),
+        request_id: req
+            .metadata()
+            .get("x-request-id")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| Uuid::new_v4().to_string()),
+    }
+}
+
+fn bearer(metadata: &MetadataMap) -> Option<String> {
+    metadata
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_bearer)
+}
+
+fn token(value: String) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+impl AuthorizationService {
+    // the caller's own token, from the `authorization` metadata, must carry Read
+    async fn authorize_caller<T>(&self, req: &Request<T>, action: &str) -> Result<AuthPerm, Status> {
+        let meta = request_meta(req);
+        let role_perm = match authorize(&self.pool, bearer(req.metadata())).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error {}:- {:?}", action, e);
+                return Err(status(self.audit.denied(&meta, &AuditEvent::new("anonymous", action), e).await));
+            }
+        };
+        if !role_perm.perm.contains(&"Read".to_string()) {
+            let e = RbacError::Forbidden("You are not authorized to read permissions".to_string()).extend();
+            return Err(status(self.audit.denied(&meta, &AuditEvent::new(&role_perm.sub, action), e).await));
+        }
+        Ok(role_perm)
+    }
+
+    async fn check_one(&self, check: CheckRequest) -> Result<CheckResponse, Error> {
+        let role_perm = authorize(&self.pool, token(check.token)).await?;
+        Ok(CheckResponse {
+            allowed: role_perm.perm.contains(&check.action),
+            subject: role_perm.sub,
+            roles: role_perm.role,
+            error_code: String::new(),
+        })
+    }
+}
+
+type PolicyEventStream = Pin<Box<dyn Stream<Item = Result<PolicyEvent, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Authorization for AuthorizationService {
+    async fn check(&self, req: Request<CheckRequest>) -> Result<Response<CheckResponse>, Status> {
+        match self.check_one(req.into_inner()).await {
+            Ok(v) => Ok(Response::new(v)),
+            Err(e) => Err(status(e)),
+        }
+    }
+
+    async fn batch_check(&self, req: Request<BatchCheckRequest>) -> Result<Response<BatchCheckResponse>, Status> {
+        let checks = req.into_inner().checks;
+        if checks.len() > MAX_BATCH_CHECKS {
+            return Err(Status::invalid_argument(format!(
+                "a batch can hold at most {} checks",
+                MAX_BATCH_CHECKS
+            )));
+        }
+        let results = join_all(checks.into_iter().map(|check| async move {
+            match self.check_one(check).await {
+                Ok(v) => v,
+                Err(e) => CheckResponse {
+                    allowed: false,
+                    subject: String::new(),
+                    roles: Vec::new(),
+                    error_code: extension(&e, "code").unwrap_or_else(|| "INTERNAL_SERVER_ERROR".to_string()),
+                },
+            }
+        }))
+        .await;
+        Ok(Response::new(BatchCheckResponse { results }))
+    }
+
+    async fn list_user_permissions(
+        &self,
+        req: Request<ListUserPermissionsRequest>,
+    ) -> Result<Response<ListUserPermissionsResponse>, Status> {
+        self.authorize_caller(&req, "grpc.list_user_permissions").await?;
+        let user_id = req.into_inner().user_id;
+        let grants = fetch_user_role_grants(&self.pool, user_id).await.map_err(status)?;
+        let mut permissions: Vec<String> = grants.iter().flat_map(|g| g.actions.clone()).collect();
+        permissions.sort();
+        permissions.dedup();
+        Ok(Response::new(ListUserPermissionsResponse {
+            user_id,
+            roles: grants
+                .into_iter()
+                .map(|g| RoleGrant {
+                    role: g.name,
+                    actions: g.actions,
+                })
+                .collect(),
+            permissions,
+        }))
+    }
+
+    type WatchPolicyStream = PolicyEventStream;
+
+    async fn watch_policy(&self, req: Request<WatchPolicyRequest>) -> Result<Response<PolicyEventStream>, Status> {
+        self.authorize_caller(&req, "grpc.watch_policy").await?;
+        let mut rx = self.feed.subscribe();
+        let stream = async_stream::stream! {
+            loop {
+                match rx.recv().await {
+                    // the change feed reconnected and may have missed notifications
+                    Ok(event) if event.is_resync() => yield Ok(PolicyEvent {
+                        resync: true,
+                        ..Default::default()
+                    }),
+                    Ok(event) => yield Ok(PolicyEvent {
+                        table: event.table,
+                        op: event.op,
+                        user_id: event.user_id,
+                        role_id: event.role_id,
+                        old_role_id: event.old_role_id,
+                        resync: false,
+                    }),
+                    // some notifications were missed, so the client can't trust what it derived from them
+                    Err(RecvError::Lagged(_)) => yield Ok(PolicyEvent {
+                        resync: true,
+                        ..Default::default()
+                    }),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        };
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+// the HTTP rate limit, keyed by the caller's token or address. Envoy's ext_authz calls come from
+// Envoy for all of its clients, so that service applies the limit itself per client
+// tonic's Interceptor fixes the error type to Status
+#[allow(clippy::result_large_err)]
+fn rate_limit(limiter: Option<Arc<RateLimiter>>) -> impl FnMut(Request<()>) -> Result<Request<()>, Status> + Clone {
+    move |req: Request<()>| {
+        if let Some(limiter) = &limiter {
+            let client_ip = req.remote_addr().map(|v| v.ip().to_string());
+            if let Err(retry_after) = limiter.check_client(bearer(req.metadata()).as_deref(), client_ip.as_deref()) {
+                return Err(status(RbacError::RateLimited { retry_after }.extend()));
+            }
+        }
+        Ok(req)
+    }
+}
+
+// binds before returning so a port that is taken fails startup, like the HTTP listener
+pub async fn start_grpc_server(
+    addr: SocketAddr,
+    service: AuthorizationService,
+    ext_authz: ExtAuthzService,
+    rate_limiter: Option<Arc<RateLimiter>>,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    tokio::spawn(async move {
+        let res = Server::builder()
+            .add_service(AuthorizationServer::with_interceptor(service, rate_limit(rate_limiter)))
+            .add_service(ExtAuthzServer::new(ext_authz))
+            .serve_with_incoming(TcpListenerStream::new(listener))
+            .await;
+        if let Err(e) = res {
+            println!("Error grpc server = {:?}", e);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Code;
+
+    use super::*;
+
+    #[test]
+    fn throttled_errors_are_resource_exhausted_with_retry_after() {
+        for e in [RbacError::RateLimited { retry_after: 5 }, RbacError::LoginLocked { retry_after: 5 }] {
+            let s = status(e.extend());
+            assert_eq!(s.code(), Code::ResourceExhausted);
+            assert_eq!(s.metadata().get("retry-after").unwrap(), "5");
+        }
+        let s = status(RbacError::Unauthenticated("x".to_string()).extend());
+        assert_eq!(s.code(), Code::Unauthenticated);
+        assert!(s.metadata().get("retry-after").is_none());
+    }
+}
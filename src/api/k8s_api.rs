@@ -0,0 +1,42 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use crate::{
+    utilities::{
+        jwt::extract_jwt,
+        k8s_review::{review_access, review_token, SubjectAccessReview, TokenReview},
+    },
+    AppState,
+};
+
+// when --K8S_WEBHOOK_TOKEN is set, the kube-apiserver must send it as a bearer token
+// (`token:` in the webhook kubeconfig)
+fn webhook_caller_allowed(data: &AppState, req: &HttpRequest) -> bool {
+    match &data.k8s_webhook_token {
+        Some(secret) => extract_jwt(req).as_ref() == Some(secret),
+        None => true,
+    }
+}
+
+// kube-apiserver --authentication-token-webhook-config-file target
+pub async fn k8s_authenticate(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    review: web::Json<TokenReview>,
+) -> HttpResponse {
+    if !webhook_caller_allowed(&data, &req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    HttpResponse::Ok().json(review_token(&data.pool, review.into_inner()).await)
+}
+
+// kube-apiserver --authorization-webhook-config-file target
+pub async fn k8s_authorize(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    review: web::Json<SubjectAccessReview>,
+) -> HttpResponse {
+    if !webhook_caller_allowed(&data, &req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    HttpResponse::Ok().json(review_access(&data.pool, &data.k8s_rules, review.into_inner()).await)
+}
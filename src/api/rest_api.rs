@@ -0,0 +1,1024 @@
+use std::fmt;
+
+use actix_web::{
+    error,
+    http::{header, StatusCode},
+    web, HttpRequest, HttpResponse, ResponseError, Scope,
+};
+use async_graphql::{connection::CursorType, connection::OpaqueCursor, Error, ErrorExtensions};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Postgres, Row, Transaction};
+use utoipa::{
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    IntoParams, Modify, OpenApi, ToSchema,
+};
+
+use crate::{
+    db::{
+        pagination::{Page, SortKey},
+        policy::record_policy_revision,
+        roles::{
+            count_role_permissions, fetch_role_id, fetch_role_name, fetch_role_permissions_by_id, fetch_roles_page,
+            insert_role_permissions, insert_roles, remove_role_permission,
+        },
+        users::{
+            count_user_roles, fetch_user_by_id, fetch_user_id_by_email, fetch_users_page, insert_role_user, insert_users,
+            remove_role_user, require_email_verification, UserQuery,
+        },
+        webhooks::commit_with_event,
+    },
+    graphql::queries::decode_after,
+    utilities::{
+        account_mail::send_email_verification,
+        audit::{AuditEvent, RequestMeta},
+        auth::{authorize, AuthPerm},
+        errors::{extension, parse_id, retry_after, RbacError},
+        jwt::extract_jwt,
+        permission_cache::permission_cache,
+    },
+    AppState,
+};
+
+// body of every non 2xx response; `code` is the same value GraphQL puts in `extensions.code`
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+}
+
+// carries the GraphQL error through actix and picks the HTTP status from its code
+#[derive(Debug)]
+pub struct ApiError(Error);
+
+impl From<Error> for ApiError {
+    fn from(e: Error) -> Self {
+        ApiError(e)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match extension(&self.0, "code").as_deref() {
+            Some("NOT_FOUND") => StatusCode::NOT_FOUND,
+            Some("CONFLICT") => StatusCode::CONFLICT,
+            Some("UNAUTHENTICATED") => StatusCode::UNAUTHORIZED,
+            Some("FORBIDDEN") => StatusCode::FORBIDDEN,
+            Some("VALIDATION_FAILED") => StatusCode::BAD_REQUEST,
+            Some("RATE_LIMITED") | Some("LOGIN_LOCKED") => StatusCode::TOO_MANY_REQUESTS,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut res = HttpResponse::build(self.status_code());
+        if let Some(secs) = retry_after(&self.0) {
+            res.insert_header((header::RETRY_AFTER, secs.to_string()));
+        }
+        res.json(ErrorBody {
+            code: extension(&self.0, "code")
+                .unwrap_or_else(|| "INTERNAL_SERVER_ERROR".to_string()),
+            message: self.0.message.clone(),
+            field: extension(&self.0, "field"),
+        })
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UserItem {
+    pub id: i32,
+    pub name: String,
+    pub email: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RoleItem {
+    pub id: i32,
+    pub name: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PermissionItem {
+    pub id: i32,
+    pub action: String,
+}
+
+/// One page of users. Pass `next_cursor` as `after` to get the next page.
+#[derive(Serialize, ToSchema)]
+pub struct UserList {
+    pub items: Vec<UserItem>,
+    pub total_count: i64,
+    pub next_cursor: Option<String>,
+}
+
+/// One page of roles. Pass `next_cursor` as `after` to get the next page.
+#[derive(Serialize, ToSchema)]
+pub struct RoleList {
+    pub items: Vec<RoleItem>,
+    pub total_count: i64,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UserListParams {
+    /// Page size, 1 to 500 (default 50)
+    pub first: Option<i64>,
+    /// `next_cursor` of the previous page
+    pub after: Option<String>,
+    pub name_contains: Option<String>,
+    pub email_contains: Option<String>,
+    /// Only users holding this role
+    pub has_role: Option<String>,
+    pub created_after: Option<DateTime<Utc>>,
+    /// `id`, `name`, `email` or `created_at` (default `id`)
+    pub sort: Option<String>,
+    /// `asc` or `desc` (default `asc`)
+    pub direction: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RoleListParams {
+    /// Page size, 1 to 500 (default 50)
+    pub first: Option<i64>,
+    /// `next_cursor` of the previous page
+    pub after: Option<String>,
+    pub name_contains: Option<String>,
+    /// `id` or `name` (default `id`)
+    pub sort: Option<String>,
+    /// `asc` or `desc` (default `asc`)
+    pub direction: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct NewUser {
+    pub name: String,
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct NewRole {
+    pub name: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RoleGrant {
+    /// Name of the role, e.g. `Editor`
+    pub role: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct PermissionGrant {
+    /// Permission to grant, e.g. `Update`
+    pub action: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CheckRequest {
+    /// Permission to test, e.g. `Update`
+    pub action: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CheckResponse {
+    pub allowed: bool,
+    pub subject: String,
+    pub roles: Vec<String>,
+}
+
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "bearer",
+                SecurityScheme::Http(
+                    HttpBuilder::new()
+                        .scheme(HttpAuthScheme::Bearer)
+                        .bearer_format("JWT")
+                        .build(),
+                ),
+            );
+        }
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "RBAC Server", description = "REST surface of the RBAC server. It shares the database and authorization with the GraphQL API."),
+    paths(
+        list_users,
+        create_user,
+        assign_role,
+        revoke_role,
+        list_roles,
+        create_role,
+        list_role_permissions,
+        grant_permission,
+        revoke_permission,
+        check
+    ),
+    components(schemas(
+        ErrorBody,
+        UserItem,
+        RoleItem,
+        PermissionItem,
+        UserList,
+        RoleList,
+        NewUser,
+        NewRole,
+        RoleGrant,
+        PermissionGrant,
+        CheckRequest,
+        CheckResponse
+    )),
+    modifiers(&BearerAuth),
+    security(("bearer" = []))
+)]
+pub struct ApiDoc;
+
+// same check as the GraphQL resolvers: a valid token, plus the Read permission when asked for
+async fn authorize_rest(
+    data: &AppState,
+    req: &HttpRequest,
+    action: &str,
+    read: bool,
+) -> Result<AuthPerm, ApiError> {
+    let meta = RequestMeta::from_request(req);
+    let role_perm = match authorize(&data.pool, extract_jwt(req)).await {
+        Ok(v) => v,
+        Err(e) => return Err(data.audit.denied(&meta, &AuditEvent::new("anonymous", action), e).await.into()),
+    };
+    if read && !role_perm.perm.contains(&"Read".to_string()) {
+        let e = RbacError::Forbidden("You are not authorized to read this resource".to_string()).extend();
+        return Err(data.audit.denied(&meta, &AuditEvent::new(&role_perm.sub, action), e).await.into());
+    }
+    Ok(role_perm)
+}
+
+// the writes audit under the same actions as the GraphQL mutations they mirror
+async fn begin(data: &AppState, meta: &RequestMeta, audit: &AuditEvent) -> Result<Transaction<'static, Postgres>, ApiError> {
+    match data.pool.begin().await {
+        Ok(v) => Ok(v),
+        Err(e) => {
+            println!("Error begin transaction = {:?}", e);
+            let e = RbacError::Internal("Failed to start the transaction".to_string()).extend();
+            Err(data.audit.failure(meta, audit, e).await.into())
+        }
+    }
+}
+
+async fn user_name(data: &AppState, meta: &RequestMeta, audit: &AuditEvent, id: i32) -> Result<String, ApiError> {
+    match fetch_user_by_id(&data.pool, id).await {
+        Ok(Some(user)) => Ok(user.name),
+        Ok(None) => {
+            let e = RbacError::NotFound("User with the following id not found".to_string()).extend();
+            Err(data.audit.failure(meta, audit, e).await.into())
+        }
+        Err(e) => Err(data.audit.failure(meta, audit, e).await.into()),
+    }
+}
+
+async fn role_name(data: &AppState, meta: &RequestMeta, audit: &AuditEvent, id: i32) -> Result<String, ApiError> {
+    match fetch_role_name(&data.pool, id).await {
+        Ok(Some(name)) => Ok(name),
+        Ok(None) => {
+            let e = RbacError::NotFound("Role with the following id not found".to_string()).extend();
+            Err(data.audit.failure(meta, audit, e).await.into())
+        }
+        Err(e) => Err(data.audit.failure(meta, audit, e).await.into()),
+    }
+}
+
+fn sort_key(
+    sort: Option<&str>,
+    direction: Option<&str>,
+    columns: &[(&'static str, &'static str)],
+) -> Result<SortKey, ApiError> {
+    let sort = sort.unwrap_or("id");
+    let (name, sql_type) = match columns.iter().find(|(name, _)| *name == sort) {
+        Some(v) => *v,
+        None => {
+            let names: Vec<&str> = columns.iter().map(|(name, _)| *name).collect();
+            return Err(RbacError::validation("sort", format!("sort must be one of {}", names.join(", "))).extend().into());
+        }
+    };
+    let descending = match direction.unwrap_or("asc") {
+        "asc" => false,
+        "desc" => true,
+        _ => return Err(RbacError::validation("direction", "direction must be asc or desc").extend().into()),
+    };
+    // the sort names match the GraphQL ones, so cursors work in both APIs
+    Ok(SortKey {
+        name,
+        column: name,
+        sql_type,
+        descending,
+    })
+}
+
+fn next_cursor(page: &Page) -> Option<String> {
+    if !page.has_next {
+        return None;
+    }
+    page.rows
+        .last()
+        .map(|(cursor, _)| OpaqueCursor(cursor.clone()).encode_cursor())
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/users",
+    params(UserListParams),
+    responses(
+        (status = 200, body = UserList),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+    )
+)]
+pub async fn list_users(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    params: web::Query<UserListParams>,
+) -> Result<web::Json<UserList>, ApiError> {
+    authorize_rest(&data, &req, "v1.users", true).await?;
+    let params = params.into_inner();
+    let key = sort_key(
+        params.sort.as_deref(),
+        params.direction.as_deref(),
+        &[("id", "integer"), ("name", "varchar"), ("email", "varchar"), ("created_at", "timestamptz")],
+    )?;
+    let qry = UserQuery {
+        name_contains: params.name_contains,
+        email_contains: params.email_contains,
+        has_role: params.has_role,
+        created_after: params.created_after,
+    };
+    let after = decode_after(params.after)?;
+    let page = fetch_users_page(&data.pool, &qry, &key, after, params.first.unwrap_or(50)).await?;
+    Ok(web::Json(UserList {
+        next_cursor: next_cursor(&page),
+        total_count: page.total,
+        items: page
+            .rows
+            .iter()
+            .map(|(_, row)| UserItem {
+                id: row.get("id"),
+                name: row.get("name"),
+                email: row.get("email"),
+                created_at: row.get("created_at"),
+            })
+            .collect(),
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/roles",
+    params(RoleListParams),
+    responses(
+        (status = 200, body = RoleList),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+    )
+)]
+pub async fn list_roles(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    params: web::Query<RoleListParams>,
+) -> Result<web::Json<RoleList>, ApiError> {
+    authorize_rest(&data, &req, "v1.roles", true).await?;
+    let params = params.into_inner();
+    let key = sort_key(
+        params.sort.as_deref(),
+        params.direction.as_deref(),
+        &[("id", "integer"), ("name", "varchar")],
+    )?;
+    let after = decode_after(params.after)?;
+    let page = fetch_roles_page(
+        &data.pool,
+        params.name_contains.as_deref(),
+        &key,
+        after,
+        params.first.unwrap_or(50),
+    )
+    .await?;
+    Ok(web::Json(RoleList {
+        next_cursor: next_cursor(&page),
+        total_count: page.total,
+        items: page
+            .rows
+            .iter()
+            .map(|(_, row)| RoleItem {
+                id: row.get("id"),
+                name: row.get("name"),
+            })
+            .collect(),
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/roles/{id}/permissions",
+    params(("id" = String, Path, description = "Role id")),
+    responses(
+        (status = 200, body = Vec<PermissionItem>),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn list_role_permissions(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> Result<web::Json<Vec<PermissionItem>>, ApiError> {
+    authorize_rest(&data, &req, "v1.role_permissions", true).await?;
+    let id = parse_id(&id, "id")?;
+    match fetch_role_permissions_by_id(&data.pool, id).await? {
+        Some(v) => Ok(web::Json(
+            v.into_iter()
+                .map(|(id, action)| PermissionItem { id, action })
+                .collect(),
+        )),
+        None => Err(RbacError::NotFound("Role with the following id not found".to_string()).extend().into()),
+    }
+}
+
+/// Tells whether the bearer of the token holds a permission.
+#[utoipa::path(
+    post,
+    path = "/v1/check",
+    request_body = CheckRequest,
+    responses(
+        (status = 200, body = CheckResponse),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+    )
+)]
+pub async fn check(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Json<CheckRequest>,
+) -> Result<web::Json<CheckResponse>, ApiError> {
+    let role_perm = authorize_rest(&data, &req, "v1.check", false).await?;
+    Ok(web::Json(CheckResponse {
+        allowed: role_perm.perm.contains(&body.action),
+        subject: role_perm.sub,
+        roles: role_perm.role,
+    }))
+}
+
+/// Creates a user holding the Viewer role. Admins only.
+#[utoipa::path(
+    post,
+    path = "/v1/users",
+    request_body = NewUser,
+    responses(
+        (status = 201, body = UserItem),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 409, body = ErrorBody),
+    )
+)]
+pub async fn create_user(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Json<NewUser>,
+) -> Result<HttpResponse, ApiError> {
+    let role_perm = authorize_rest(&data, &req, "add_user", false).await?;
+    let meta = RequestMeta::from_request(&req);
+    let NewUser { name, email, password } = body.into_inner();
+    let audit = AuditEvent::new(&role_perm.sub, "add_user").target(&name).after(&email);
+    if !role_perm.role.contains(&"Admin".to_string()) {
+        let e = RbacError::Forbidden("Not Authorized to add user".to_string()).extend();
+        return Err(data.audit.denied(&meta, &audit, e).await.into());
+    }
+    let mut tx = begin(&data, &meta, &audit).await?;
+    let res = async {
+        insert_users(&mut tx, name.clone(), email.clone(), password).await?;
+        insert_role_user(&mut tx, name.clone(), "Viewer".to_string()).await?;
+        if data.mail.require_verification {
+            require_email_verification(&mut tx, &name).await?;
+        }
+        commit_with_event(tx, "user.created", json!({"name": name, "email": email, "roles": ["Viewer"]})).await?;
+        let id = fetch_user_id_by_email(&data.pool, &email).await?;
+        match id {
+            Some(id) => fetch_user_by_id(&data.pool, id).await,
+            None => Ok(None),
+        }
+    }
+    .await;
+    let user = match res {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            let e = RbacError::Internal("Failed to fetch the new user".to_string()).extend();
+            return Err(data.audit.failure(&meta, &audit, e).await.into());
+        }
+        Err(e) => return Err(data.audit.failure(&meta, &audit, e).await.into()),
+    };
+    if data.mail.require_verification {
+        // the user exists either way; a lost email is fixed with sendVerificationEmail
+        if let Err(e) = send_email_verification(&data.pool, &data.mail, user.id, &user.email).await {
+            println!("Error send_email_verification = {:?}", e);
+        }
+    }
+    data.audit.success(&meta, &audit).await;
+    Ok(HttpResponse::Created().json(UserItem {
+        id: user.id,
+        name: user.name,
+        email: user.email,
+        created_at: user.created_at,
+    }))
+}
+
+/// Gives a user a role. Needs the Update permission; the Admin role can't be given.
+#[utoipa::path(
+    post,
+    path = "/v1/users/{id}/roles",
+    params(("id" = String, Path, description = "User id")),
+    request_body = RoleGrant,
+    responses(
+        (status = 204),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn assign_role(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: web::Json<RoleGrant>,
+) -> Result<HttpResponse, ApiError> {
+    let role_perm = authorize_rest(&data, &req, "assign_user_role", false).await?;
+    let meta = RequestMeta::from_request(&req);
+    let role = body.into_inner().role;
+    let id = parse_id(&id, "id")?;
+    let audit = AuditEvent::new(&role_perm.sub, "assign_user_role").target(id).after(&role);
+    if role == "Admin" {
+        let e = RbacError::Forbidden("Can't Assign Admin role".to_string()).extend();
+        return Err(data.audit.failure(&meta, &audit, e).await.into());
+    }
+    if !role_perm.perm.contains(&"Update".to_string()) {
+        let e = RbacError::Forbidden("You are not authorized to add role".to_string()).extend();
+        return Err(data.audit.denied(&meta, &audit, e).await.into());
+    }
+    let user = user_name(&data, &meta, &audit, id).await?;
+    let audit = audit.target(&user);
+    let mut tx = begin(&data, &meta, &audit).await?;
+    let res = async {
+        insert_role_user(&mut tx, user.clone(), role.clone()).await?;
+        commit_with_event(tx, "user.role_assigned", json!({"user": user, "role": role})).await
+    }
+    .await;
+    if let Err(e) = res {
+        return Err(data.audit.failure(&meta, &audit, e).await.into());
+    }
+    permission_cache().invalidate();
+    data.audit.success(&meta, &audit).await;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Takes a role away from a user, who must keep at least one. Needs the Delete permission.
+#[utoipa::path(
+    delete,
+    path = "/v1/users/{id}/roles/{role}",
+    params(
+        ("id" = String, Path, description = "User id"),
+        ("role" = String, Path, description = "Role name"),
+    ),
+    responses(
+        (status = 204),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn revoke_role(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, ApiError> {
+    let role_perm = authorize_rest(&data, &req, "delete_user_role", false).await?;
+    let meta = RequestMeta::from_request(&req);
+    let (id, role) = path.into_inner();
+    let id = parse_id(&id, "id")?;
+    let audit = AuditEvent::new(&role_perm.sub, "delete_user_role").target(id).before(&role);
+    if role == "Admin" {
+        let e = RbacError::Forbidden("Admin Role Can't be deleted".to_string()).extend();
+        return Err(data.audit.failure(&meta, &audit, e).await.into());
+    }
+    if !role_perm.perm.contains(&"Delete".to_string()) {
+        let e = RbacError::Forbidden("Not Authorized to delete roles".to_string()).extend();
+        return Err(data.audit.denied(&meta, &audit, e).await.into());
+    }
+    let user = user_name(&data, &meta, &audit, id).await?;
+    let audit = audit.target(&user);
+    match count_user_roles(&data.pool, &user).await {
+        Ok(1) => {
+            let e = RbacError::validation("role", "Minimum One role required.").extend();
+            return Err(data.audit.failure(&meta, &audit, e).await.into());
+        }
+        Ok(_) => (),
+        Err(e) => return Err(data.audit.failure(&meta, &audit, e).await.into()),
+    }
+    let mut tx = begin(&data, &meta, &audit).await?;
+    let res = async {
+        if !remove_role_user(&mut tx, &user, &role).await? {
+            return Err(RbacError::NotFound("The user does not hold this role".to_string()).extend());
+        }
+        commit_with_event(tx, "user.role_removed", json!({"user": user, "role": role})).await
+    }
+    .await;
+    if let Err(e) = res {
+        return Err(data.audit.failure(&meta, &audit, e).await.into());
+    }
+    permission_cache().invalidate();
+    data.audit.success(&meta, &audit).await;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Creates a role without permissions. Admins only.
+#[utoipa::path(
+    post,
+    path = "/v1/roles",
+    request_body = NewRole,
+    responses(
+        (status = 201, body = RoleItem),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 409, body = ErrorBody),
+    )
+)]
+pub async fn create_role(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Json<NewRole>,
+) -> Result<HttpResponse, ApiError> {
+    let role_perm = authorize_rest(&data, &req, "add_role", false).await?;
+    let meta = RequestMeta::from_request(&req);
+    let name = body.into_inner().name;
+    let audit = AuditEvent::new(&role_perm.sub, "add_role").target(&name).after(&name);
+    if !role_perm.role.contains(&"Admin".to_string()) {
+        let e = RbacError::Forbidden("Not Authorized to add Roles".to_string()).extend();
+        return Err(data.audit.denied(&meta, &audit, e).await.into());
+    }
+    let mut tx = begin(&data, &meta, &audit).await?;
+    let res = async {
+        let name = insert_roles(&mut tx, name).await?;
+        record_policy_revision(&mut tx, &role_perm.sub, &format!("Added role {:?}", name)).await?;
+        commit_with_event(tx, "role.created", json!({"name": name})).await?;
+        Ok::<_, Error>(fetch_role_id(&data.pool, &name).await?.map(|id| RoleItem { id, name }))
+    }
+    .await;
+    let role = match res {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            let e = RbacError::Internal("Failed to fetch the new role".to_string()).extend();
+            return Err(data.audit.failure(&meta, &audit, e).await.into());
+        }
+        Err(e) => return Err(data.audit.failure(&meta, &audit, e).await.into()),
+    };
+    data.audit.success(&meta, &audit).await;
+    Ok(HttpResponse::Created().json(role))
+}
+
+/// Grants a permission to a role. Needs the Create permission.
+#[utoipa::path(
+    post,
+    path = "/v1/roles/{id}/permissions",
+    params(("id" = String, Path, description = "Role id")),
+    request_body = PermissionGrant,
+    responses(
+        (status = 204),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn grant_permission(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: web::Json<PermissionGrant>,
+) -> Result<HttpResponse, ApiError> {
+    let role_perm = authorize_rest(&data, &req, "assign_role_permissions", false).await?;
+    let meta = RequestMeta::from_request(&req);
+    let action = body.into_inner().action;
+    let id = parse_id(&id, "id")?;
+    let audit = AuditEvent::new(&role_perm.sub, "assign_role_permissions").target(id).after(&action);
+    if !role_perm.perm.contains(&"Create".to_string()) {
+        let e = RbacError::Forbidden("You are not authorized to add role".to_string()).extend();
+        return Err(data.audit.denied(&meta, &audit, e).await.into());
+    }
+    let role = role_name(&data, &meta, &audit, id).await?;
+    let audit = audit.target(&role);
+    let mut tx = begin(&data, &meta, &audit).await?;
+    let res = async {
+        insert_role_permissions(&mut tx, role.clone(), action.clone()).await?;
+        record_policy_revision(&mut tx, &role_perm.sub, &format!("Granted {:?} to role {:?}", action, role)).await?;
+        commit_with_event(tx, "role.permission_granted", json!({"role": role, "permission": action})).await
+    }
+    .await;
+    if let Err(e) = res {
+        return Err(data.audit.failure(&meta, &audit, e).await.into());
+    }
+    permission_cache().invalidate();
+    data.audit.success(&meta, &audit).await;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Revokes a permission from a role, which must keep at least one. Admins only.
+#[utoipa::path(
+    delete,
+    path = "/v1/roles/{id}/permissions/{action}",
+    params(
+        ("id" = String, Path, description = "Role id"),
+        ("action" = String, Path, description = "Permission, e.g. `Update`"),
+    ),
+    responses(
+        (status = 204),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn revoke_permission(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, ApiError> {
+    let role_perm = authorize_rest(&data, &req, "delete_role_permission", false).await?;
+    let meta = RequestMeta::from_request(&req);
+    let (id, action) = path.into_inner();
+    let id = parse_id(&id, "id")?;
+    let audit = AuditEvent::new(&role_perm.sub, "delete_role_permission").target(id).before(&action);
+    if !role_perm.role.contains(&"Admin".to_string()) {
+        let e = RbacError::Forbidden("Not Authorized to delete Permissions".to_string()).extend();
+        return Err(data.audit.denied(&meta, &audit, e).await.into());
+    }
+    let role = role_name(&data, &meta, &audit, id).await?;
+    let audit = audit.target(&role);
+    if role == "Admin" {
+        let e = RbacError::Forbidden("Admin Role can't be updated".to_string()).extend();
+        return Err(data.audit.failure(&meta, &audit, e).await.into());
+    }
+    match count_role_permissions(&data.pool, &role).await {
+        Ok(1) => {
+            let e = RbacError::validation("action", "Minimum One permission required.").extend();
+            return Err(data.audit.failure(&meta, &audit, e).await.into());
+        }
+        Ok(_) => (),
+        Err(e) => return Err(data.audit.failure(&meta, &audit, e).await.into()),
+    }
+    let mut tx = begin(&data, &meta, &audit).await?;
+    let res = async {
+        if !remove_role_permission(&mut tx, &role, &action).await? {
+            return Err(RbacError::NotFound("The role does not grant this permission".to_string()).extend());
+        }
+        record_policy_revision(&mut tx, &role_perm.sub, &format!("Revoked {:?} from role {:?}", action, role)).await?;
+        commit_with_event(tx, "role.permission_revoked", json!({"role": role, "permission": action})).await
+    }
+    .await;
+    if let Err(e) = res {
+        return Err(data.audit.failure(&meta, &audit, e).await.into());
+    }
+    permission_cache().invalidate();
+    data.audit.success(&meta, &audit).await;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn openapi_json() -> HttpResponse {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+// malformed query strings and bodies get the same JSON error shape as everything else
+fn bad_request(field: &str, e: impl fmt::Display) -> actix_web::Error {
+    error::InternalError::from_response(
+        e.to_string(),
+        ApiError(RbacError::validation(field, e.to_string()).extend()).error_response(),
+    )
+    .into()
+}
+
+pub fn v1_scope() -> Scope {
+    web::scope("/v1")
+        .app_data(web::QueryConfig::default().error_handler(|e, _| bad_request("query", e)))
+        .app_data(web::JsonConfig::default().error_handler(|e, _| bad_request("body", e)))
+        .route("/users", web::get().to(list_users))
+        .route("/users", web::post().to(create_user))
+        .route("/users/{id}/roles", web::post().to(assign_role))
+        .route("/users/{id}/roles/{role}", web::delete().to(revoke_role))
+        .route("/roles", web::get().to(list_roles))
+        .route("/roles", web::post().to(create_role))
+        .route("/roles/{id}/permissions", web::get().to(list_role_permissions))
+        .route("/roles/{id}/permissions", web::post().to(grant_permission))
+        .route("/roles/{id}/permissions/{action}", web::delete().to(revoke_permission))
+        .route("/check", web::post().to(check))
+        .route("/openapi.json", web::get().to(openapi_json))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::{
+        body::MessageBody,
+        dev::ServiceResponse,
+        http::Method,
+        test::{call_service, init_service, read_body_json, TestRequest},
+        App,
+    };
+    use async_graphql::Schema;
+    use serde_json::Value;
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{
+        db::db_config::{test_pool, test_user},
+        graphql::{mutations::Mutation, queries::Query, subscriptions::Subscription},
+        utilities::{
+            audit::AuditWriter, edge_auth::RouteRules, jwt::create_jwt, k8s_review::K8sRules,
+            mailer::{mailer_from_url, MailSettings},
+        },
+    };
+
+    fn state(pool: PgPool) -> AppState {
+        AppState {
+            schema: Schema::build(Query, Mutation, Subscription).finish(),
+            audit: AuditWriter {
+                pool: pool.clone(),
+                key: None,
+                dispatcher: None,
+            },
+            pool,
+            mail: MailSettings {
+                mailer: mailer_from_url("log", "rbac@test.invalid").unwrap(),
+                link_base: "http://localhost".to_string(),
+                require_verification: false,
+            },
+            rules: Arc::new(RouteRules::default()),
+            k8s_rules: Arc::new(K8sRules::default()),
+            k8s_webhook_token: None,
+        }
+    }
+
+    async fn token(pool: &PgPool, role: &str) -> String {
+        let id = test_user(pool).await;
+        create_jwt(&id.to_string(), vec![role.to_string()]).await.unwrap().0
+    }
+
+    fn request(method: Method, uri: &str, token: Option<&str>, body: Option<Value>) -> TestRequest {
+        let mut req = TestRequest::default().method(method).uri(uri);
+        if let Some(token) = token {
+            req = req.insert_header((header::AUTHORIZATION, format!("Bearer {}", token)));
+        }
+        if let Some(body) = body {
+            req = req.set_json(body);
+        }
+        req
+    }
+
+    async fn answer(res: ServiceResponse<impl MessageBody>) -> (StatusCode, Value) {
+        let status = res.status();
+        if status == StatusCode::NO_CONTENT {
+            return (status, Value::Null);
+        }
+        (status, read_body_json(res).await)
+    }
+
+    #[test]
+    fn status_follows_the_error_code() {
+        let cases = [
+            (RbacError::NotFound("x".to_string()), StatusCode::NOT_FOUND),
+            (RbacError::Conflict("x".to_string()), StatusCode::CONFLICT),
+            (RbacError::Unauthenticated("x".to_string()), StatusCode::UNAUTHORIZED),
+            (RbacError::Forbidden("x".to_string()), StatusCode::FORBIDDEN),
+            (RbacError::validation("id", "x"), StatusCode::BAD_REQUEST),
+            (RbacError::Internal("x".to_string()), StatusCode::INTERNAL_SERVER_ERROR),
+            (RbacError::RateLimited { retry_after: 3 }, StatusCode::TOO_MANY_REQUESTS),
+            (RbacError::LoginLocked { retry_after: 3 }, StatusCode::TOO_MANY_REQUESTS),
+        ];
+        for (e, expected) in cases {
+            assert_eq!(ApiError(e.extend()).status_code(), expected, "{}", e.code());
+        }
+    }
+
+    #[test]
+    fn throttled_answers_carry_retry_after() {
+        for e in [RbacError::RateLimited { retry_after: 7 }, RbacError::LoginLocked { retry_after: 7 }] {
+            let res = ApiError(e.extend()).error_response();
+            assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "7");
+        }
+        let res = ApiError(RbacError::Forbidden("x".to_string()).extend()).error_response();
+        assert!(res.headers().get(header::RETRY_AFTER).is_none());
+    }
+
+    #[actix_web::test]
+    async fn error_body_carries_code_and_field() {
+        // the query is rejected before the handler touches the database
+        let pool = PgPool::connect_lazy("postgres://nobody@127.0.0.1:1/none").unwrap();
+        let app = init_service(App::new().app_data(web::Data::new(state(pool))).service(v1_scope())).await;
+        let (status, body) = answer(call_service(&app, request(Method::GET, "/v1/users?first=abc", None, None).to_request()).await).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "VALIDATION_FAILED");
+        assert_eq!(body["field"], "query");
+    }
+
+    #[actix_web::test]
+    async fn missing_or_bad_token_is_unauthenticated() {
+        let Some(pool) = test_pool().await else { return };
+        let app = init_service(App::new().app_data(web::Data::new(state(pool))).service(v1_scope())).await;
+        for token in [None, Some("not-a-token")] {
+            let (status, body) = answer(call_service(&app, request(Method::GET, "/v1/users", token, None).to_request()).await).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert_eq!(body["code"], "UNAUTHENTICATED");
+            let (status, _) = answer(call_service(&app, request(Method::POST, "/v1/roles", token, Some(json!({"name": "x"}))).to_request()).await).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[actix_web::test]
+    async fn viewers_can_read_but_not_write() {
+        let Some(pool) = test_pool().await else { return };
+        let viewer = token(&pool, "Viewer").await;
+        let user = test_user(&pool).await;
+        let app = init_service(App::new().app_data(web::Data::new(state(pool))).service(v1_scope())).await;
+        let (status, _) = answer(call_service(&app, request(Method::GET, "/v1/roles", Some(&viewer), None).to_request()).await).await;
+        assert_eq!(status, StatusCode::OK);
+        let writes = [
+            (Method::POST, "/v1/users".to_string(), Some(json!({"name": "x", "email": "x@test.invalid", "password": "password1"}))),
+            (Method::POST, "/v1/roles".to_string(), Some(json!({"name": "x"}))),
+            (Method::POST, format!("/v1/users/{}/roles", user), Some(json!({"role": "Editor"}))),
+            (Method::DELETE, format!("/v1/users/{}/roles/Viewer", user), None),
+            (Method::POST, "/v1/roles/1/permissions".to_string(), Some(json!({"action": "Read"}))),
+            (Method::DELETE, "/v1/roles/1/permissions/Read".to_string(), None),
+        ];
+        for (method, uri, body) in writes {
+            let (status, body) = answer(call_service(&app, request(method, &uri, Some(&viewer), body).to_request()).await).await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{}", uri);
+            assert_eq!(body["code"], "FORBIDDEN");
+        }
+    }
+
+    #[actix_web::test]
+    async fn admin_creates_a_role_and_grants_it() {
+        let Some(pool) = test_pool().await else { return };
+        let admin = token(&pool, "Admin").await;
+        let user = test_user(&pool).await;
+        let app = init_service(App::new().app_data(web::Data::new(state(pool))).service(v1_scope())).await;
+        let name = format!("test_{}", uuid::Uuid::new_v4().simple());
+
+        let (status, role) = answer(call_service(&app, request(Method::POST, "/v1/roles", Some(&admin), Some(json!({"name": name}))).to_request()).await).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(role["name"], name.as_str());
+        let (status, body) = answer(call_service(&app, request(Method::POST, "/v1/roles", Some(&admin), Some(json!({"name": name}))).to_request()).await).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["code"], "CONFLICT");
+
+        let permissions = format!("/v1/roles/{}/permissions", role["id"]);
+        for action in ["Read", "Update"] {
+            let (status, _) = answer(call_service(&app, request(Method::POST, &permissions, Some(&admin), Some(json!({"action": action}))).to_request()).await).await;
+            assert_eq!(status, StatusCode::NO_CONTENT);
+        }
+        let (_, granted) = answer(call_service(&app, request(Method::GET, &permissions, Some(&admin), None).to_request()).await).await;
+        assert_eq!(granted.as_array().unwrap().len(), 2);
+        let (status, _) = answer(call_service(&app, request(Method::DELETE, &format!("{}/Update", permissions), Some(&admin), None).to_request()).await).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        // a role keeps at least one permission
+        let (status, body) = answer(call_service(&app, request(Method::DELETE, &format!("{}/Read", permissions), Some(&admin), None).to_request()).await).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["field"], "action");
+
+        let roles = format!("/v1/users/{}/roles", user);
+        let (status, _) = answer(call_service(&app, request(Method::POST, &roles, Some(&admin), Some(json!({"role": name}))).to_request()).await).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = answer(call_service(&app, request(Method::POST, &roles, Some(&admin), Some(json!({"role": "Admin"}))).to_request()).await).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = answer(call_service(&app, request(Method::DELETE, &format!("/v1/users/{}/roles/{}", i32::MAX, name), Some(&admin), None).to_request()).await).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,99 @@
+use async_graphql::{Error, ErrorExtensions};
+use sqlx::{PgPool, Row};
+
+use crate::utilities::errors::RbacError;
+
+fn internal(details: &'static str) -> impl Fn(sqlx::Error) -> Error {
+    move |e| {
+        println!("Error account_tokens = {:?}", e);
+        RbacError::Internal(details.to_string()).extend()
+    }
+}
+
+// stores a new token for `purpose` and drops the user's earlier unused ones, so only the link in
+// the latest email works
+pub async fn insert_account_token(
+    pool: &PgPool,
+    user_id: i32,
+    purpose: &str,
+    token_hash: &str,
+    ttl_secs: i64,
+) -> async_graphql::Result<()> {
+    let err = internal("Failed to create the token");
+    let mut tx = pool.begin().await.map_err(&err)?;
+    sqlx::query("DELETE FROM account_tokens WHERE (user_id = $1 AND purpose = $2) OR expires_at < now();")
+        .bind(user_id)
+        .bind(purpose)
+        .execute(&mut *tx)
+        .await
+        .map_err(&err)?;
+    sqlx::query(
+        "INSERT INTO account_tokens (token_hash, user_id, purpose, expires_at)
+        VALUES ($1, $2, $3, now() + make_interval(secs => $4));",
+    )
+    .bind(token_hash)
+    .bind(user_id)
+    .bind(purpose)
+    .bind(ttl_secs as f64)
+    .execute(&mut *tx)
+    .await
+    .map_err(&err)?;
+    tx.commit().await.map_err(&err)
+}
+
+// spends a token: the user it was issued to, None when it is unknown, expired or used
+pub async fn use_account_token(pool: &PgPool, purpose: &str, token_hash: &str) -> async_graphql::Result<Option<i32>> {
+    let qry = "DELETE FROM account_tokens
+        WHERE token_hash = $1 AND purpose = $2 AND expires_at > now() RETURNING user_id;";
+    let row = sqlx::query(qry)
+        .bind(token_hash)
+        .bind(purpose)
+        .fetch_optional(pool)
+        .await
+        .map_err(internal("Failed to check the token"))?;
+    Ok(row.map(|v| v.get("user_id")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::db_config::{test_pool, test_user};
+
+    const PURPOSE: &str = "password_reset";
+
+    fn token_hash() -> String {
+        crate::utilities::session::hash_token(&crate::utilities::session::new_token())
+    }
+
+    #[tokio::test]
+    async fn token_works_once() {
+        let Some(pool) = test_pool().await else { return };
+        let user_id = test_user(&pool).await;
+        let hash = token_hash();
+        insert_account_token(&pool, user_id, PURPOSE, &hash, 60).await.unwrap();
+        // the wrong purpose doesn't spend it
+        assert_eq!(use_account_token(&pool, "email_verification", &hash).await.unwrap(), None);
+        assert_eq!(use_account_token(&pool, PURPOSE, &hash).await.unwrap(), Some(user_id));
+        assert_eq!(use_account_token(&pool, PURPOSE, &hash).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn expired_token_is_refused() {
+        let Some(pool) = test_pool().await else { return };
+        let user_id = test_user(&pool).await;
+        let hash = token_hash();
+        insert_account_token(&pool, user_id, PURPOSE, &hash, -1).await.unwrap();
+        assert_eq!(use_account_token(&pool, PURPOSE, &hash).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn new_token_replaces_the_unused_one() {
+        let Some(pool) = test_pool().await else { return };
+        let user_id = test_user(&pool).await;
+        let (first, second) = (token_hash(), token_hash());
+        insert_account_token(&pool, user_id, PURPOSE, &first, 60).await.unwrap();
+        insert_account_token(&pool, user_id, PURPOSE, &second, 60).await.unwrap();
+        assert_eq!(use_account_token(&pool, PURPOSE, &first).await.unwrap(), None);
+        assert_eq!(use_account_token(&pool, PURPOSE, &second).await.unwrap(), Some(user_id));
+    }
+}
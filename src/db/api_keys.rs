@@ -0,0 +1,125 @@
+use async_graphql::{Error, ErrorExtensions};
+use chrono::{DateTime, Utc};
+use sqlx::{postgres::PgRow, PgPool, Row};
+
+use crate::utilities::errors::RbacError;
+
+fn internal(details: &'static str) -> impl Fn(sqlx::Error) -> Error {
+    move |e| {
+        println!("Error api_keys = {:?}", e);
+        RbacError::Internal(details.to_string()).extend()
+    }
+}
+
+pub struct ApiKeyRow {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    pub prefix: String,
+    // None when the key carries every permission of its user
+    pub permissions: Option<Vec<String>>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+const COLUMNS: &str = "id, user_id, name, prefix, permissions, created_at, expires_at, last_used_at";
+
+fn api_key_row(row: PgRow) -> ApiKeyRow {
+    ApiKeyRow {
+        id: row.get("id"),
+        user_id: row.get("user_id"),
+        name: row.get("name"),
+        prefix: row.get("prefix"),
+        permissions: row.get("permissions"),
+        created_at: row.get("created_at"),
+        expires_at: row.get("expires_at"),
+        last_used_at: row.get("last_used_at"),
+    }
+}
+
+pub async fn insert_api_key(
+    pool: &PgPool,
+    user_id: i32,
+    name: &str,
+    prefix: &str,
+    key_hash: &str,
+    permissions: Option<&[String]>,
+    expires_at: Option<DateTime<Utc>>,
+) -> async_graphql::Result<ApiKeyRow> {
+    let qry = format!(
+        "INSERT INTO api_keys (user_id, name, prefix, key_hash, permissions, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6) RETURNING {};",
+        COLUMNS
+    );
+    let row = sqlx::query(&qry)
+        .bind(user_id)
+        .bind(name)
+        .bind(prefix)
+        .bind(key_hash)
+        .bind(permissions)
+        .bind(expires_at)
+        .fetch_one(pool)
+        .await
+        .map_err(internal("Failed to create the API key"))?;
+    Ok(api_key_row(row))
+}
+
+// expired keys are listed too, so their owner sees why a script stopped working
+pub async fn fetch_api_keys(pool: &PgPool, user_id: i32) -> async_graphql::Result<Vec<ApiKeyRow>> {
+    let qry = format!("SELECT {} FROM api_keys WHERE user_id = $1 ORDER BY id;", COLUMNS);
+    let rows = sqlx::query(&qry)
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+        .map_err(internal("Failed to fetch the API keys"))?;
+    Ok(rows.into_iter().map(api_key_row).collect())
+}
+
+pub async fn fetch_api_key(pool: &PgPool, id: i32) -> async_graphql::Result<Option<ApiKeyRow>> {
+    let qry = format!("SELECT {} FROM api_keys WHERE id = $1;", COLUMNS);
+    let row = sqlx::query(&qry)
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+        .map_err(internal("Failed to fetch the API key"))?;
+    Ok(row.map(api_key_row))
+}
+
+// the live key with this hash. last_used_at is written at most once a minute, so a busy CI job
+// doesn't turn every request into a write
+pub async fn use_api_key(pool: &PgPool, key_hash: &str) -> async_graphql::Result<Option<ApiKeyRow>> {
+    let qry = format!(
+        "SELECT {} FROM api_keys WHERE key_hash = $1 AND (expires_at IS NULL OR expires_at > now());",
+        COLUMNS
+    );
+    let row = sqlx::query(&qry)
+        .bind(key_hash)
+        .fetch_optional(pool)
+        .await
+        .map_err(internal("Failed to check the API key"))?;
+    let Some(key) = row.map(api_key_row) else {
+        return Ok(None);
+    };
+    if let Err(e) = sqlx::query(
+        "UPDATE api_keys SET last_used_at = now()
+        WHERE id = $1 AND (last_used_at IS NULL OR last_used_at < now() - INTERVAL '1 minute');",
+    )
+    .bind(key.id)
+    .execute(pool)
+    .await
+    {
+        println!("Error use_api_key = {:?}", e);
+    }
+    Ok(Some(key))
+}
+
+// false when the key was already gone
+pub async fn delete_api_key(pool: &PgPool, id: i32) -> async_graphql::Result<bool> {
+    let res = sqlx::query("DELETE FROM api_keys WHERE id = $1;")
+        .bind(id)
+        .execute(pool)
+        .await
+        .map_err(internal("Failed to revoke the API key"))?;
+    Ok(res.rows_affected() > 0)
+}
@@ -0,0 +1,432 @@
+use async_graphql::ErrorExtensions;
+use chrono::{DateTime, SecondsFormat, SubsecRound, Utc};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use sqlx::{postgres::PgRow, Pool, Postgres, QueryBuilder, Row};
+
+use crate::utilities::errors::RbacError;
+
+// every event stores the hash of the event before it; the first one links to this
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+// serializes writers so two events can never claim the same predecessor
+const AUDIT_CHAIN_LOCK: i64 = 0x7262_6163_6175_6474;
+
+// server key used to sign each link of the chain
+#[derive(Clone)]
+pub struct AuditKey(pub Vec<u8>);
+
+pub struct AuditRecord {
+    pub actor: String,
+    pub action: String,
+    pub target: Option<String>,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub outcome: String,
+    pub reason: Option<String>,
+    pub client_ip: Option<String>,
+    pub request_id: Option<String>,
+}
+
+pub struct AuditRow {
+    pub id: i64,
+    pub created_at: DateTime<Utc>,
+    pub record: AuditRecord,
+    pub prev_hash: Option<String>,
+    pub hash: Option<String>,
+    pub signature: Option<String>,
+}
+
+pub enum AuditVerification {
+    Intact { events: i64 },
+    Broken { id: i64, checked: i64, reason: String },
+}
+
+#[derive(Default)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub target: Option<String>,
+    pub outcome: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+pub fn hash_audit_event(
+    prev_hash: &str,
+    created_at: &DateTime<Utc>,
+    record: &AuditRecord,
+) -> String {
+    let content = serde_json::json!([
+        prev_hash,
+        created_at.to_rfc3339_opts(SecondsFormat::Micros, true),
+        record.actor,
+        record.action,
+        record.target,
+        record.before,
+        record.after,
+        record.outcome,
+        record.reason,
+        record.client_ip,
+        record.request_id,
+    ]);
+    hex::encode(Sha256::digest(content.to_string().as_bytes()))
+}
+
+pub fn sign_audit_hash(key: &AuditKey, hash: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key.0).expect("HMAC can take key of any size");
+    mac.update(hash.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+pub async fn insert_audit_event(
+    pool: &Pool<Postgres>,
+    record: AuditRecord,
+    key: Option<&AuditKey>,
+) -> Result<AuditRow, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("SELECT pg_advisory_xact_lock($1);")
+        .bind(AUDIT_CHAIN_LOCK)
+        .execute(&mut *tx)
+        .await?;
+    let prev_hash: String = match sqlx::query("SELECT hash FROM audit_events ORDER BY id DESC LIMIT 1;")
+        .fetch_optional(&mut *tx)
+        .await?
+    {
+        Some(v) => v
+            .get::<Option<String>, _>("hash")
+            .unwrap_or_else(|| GENESIS_HASH.to_string()),
+        None => GENESIS_HASH.to_string(),
+    };
+    // postgres keeps microseconds, so hash exactly what will be read back
+    let created_at = Utc::now().trunc_subsecs(6);
+    let hash = hash_audit_event(&prev_hash, &created_at, &record);
+    let signature = key.map(|k| sign_audit_hash(k, &hash));
+
+    let res = sqlx::query(
+        "INSERT INTO audit_events (created_at, actor, action, target, before_value, after_value, outcome, reason, client_ip, request_id, prev_hash, hash, signature)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13) RETURNING id;",
+    )
+    .bind(created_at)
+    .bind(&record.actor)
+    .bind(&record.action)
+    .bind(&record.target)
+    .bind(&record.before)
+    .bind(&record.after)
+    .bind(&record.outcome)
+    .bind(&record.reason)
+    .bind(&record.client_ip)
+    .bind(&record.request_id)
+    .bind(&prev_hash)
+    .bind(&hash)
+    .bind(&signature)
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(AuditRow {
+        id: res.get("id"),
+        created_at,
+        record,
+        prev_hash: Some(prev_hash),
+        hash: Some(hash),
+        signature,
+    })
+}
+
+// links events written before the table carried hashes onto the chain
+pub async fn seal_audit_events(pool: &Pool<Postgres>, key: Option<&AuditKey>) -> Result<i64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("SELECT pg_advisory_xact_lock($1);")
+        .bind(AUDIT_CHAIN_LOCK)
+        .execute(&mut *tx)
+        .await?;
+    let rows = sqlx::query(&format!("{} ORDER BY id;", AUDIT_COLUMNS))
+        .fetch_all(&mut *tx)
+        .await?;
+    let mut prev_hash = GENESIS_HASH.to_string();
+    let mut sealed = 0;
+    for row in rows.iter().map(audit_row) {
+        if let Some(hash) = row.hash {
+            prev_hash = hash;
+            continue;
+        }
+        let hash = hash_audit_event(&prev_hash, &row.created_at, &row.record);
+        sqlx::query("UPDATE audit_events SET prev_hash = $1, hash = $2, signature = $3 WHERE id = $4;")
+            .bind(&prev_hash)
+            .bind(&hash)
+            .bind(key.map(|k| sign_audit_hash(k, &hash)))
+            .bind(row.id)
+            .execute(&mut *tx)
+            .await?;
+        prev_hash = hash;
+        sealed += 1;
+    }
+    tx.commit().await?;
+    Ok(sealed)
+}
+
+// walks the chain from the first event and stops at the first link that does not hold
+pub async fn verify_audit_chain(
+    pool: &Pool<Postgres>,
+    key: Option<&AuditKey>,
+) -> Result<AuditVerification, sqlx::Error> {
+    let mut prev_hash = GENESIS_HASH.to_string();
+    let mut last_id: i64 = 0;
+    let mut checked: i64 = 0;
+    loop {
+        let rows = sqlx::query(&format!("{} WHERE id > $1 ORDER BY id LIMIT 1000;", AUDIT_COLUMNS))
+            .bind(last_id)
+            .fetch_all(pool)
+            .await?;
+        if rows.is_empty() {
+            return Ok(AuditVerification::Intact { events: checked });
+        }
+        for row in rows.iter().map(audit_row) {
+            let broken = |reason: &str| AuditVerification::Broken {
+                id: row.id,
+                checked,
+                reason: reason.to_string(),
+            };
+            let (Some(stored_prev), Some(stored_hash)) = (&row.prev_hash, &row.hash) else {
+                return Ok(broken("event is not part of the hash chain"));
+            };
+            if *stored_prev != prev_hash {
+                return Ok(broken("previous hash does not match the preceding event"));
+            }
+            if hash_audit_event(&prev_hash, &row.created_at, &row.record) != *stored_hash {
+                return Ok(broken("event contents do not match its hash"));
+            }
+            if let Some(key) = key {
+                if row.signature.as_deref() != Some(sign_audit_hash(key, stored_hash).as_str()) {
+                    return Ok(broken("signature does not match the server key"));
+                }
+            }
+            prev_hash = stored_hash.clone();
+            last_id = row.id;
+            checked += 1;
+        }
+    }
+}
+
+const AUDIT_COLUMNS: &str = "SELECT id, created_at, actor, action, target, before_value, after_value, outcome, reason, client_ip, request_id, prev_hash, hash, signature FROM audit_events";
+
+fn audit_row(row: &PgRow) -> AuditRow {
+    AuditRow {
+        id: row.get("id"),
+        created_at: row.get("created_at"),
+        record: AuditRecord {
+            actor: row.get("actor"),
+            action: row.get("action"),
+            target: row.get("target"),
+            before: row.get("before_value"),
+            after: row.get("after_value"),
+            outcome: row.get("outcome"),
+            reason: row.get("reason"),
+            client_ip: row.get("client_ip"),
+            request_id: row.get("request_id"),
+        },
+        prev_hash: row.get("prev_hash"),
+        hash: row.get("hash"),
+        signature: row.get("signature"),
+    }
+}
+
+fn push_filters(qry: &mut QueryBuilder<'_, Postgres>, filter: &AuditQuery) {
+    qry.push(" WHERE TRUE");
+    if let Some(actor) = &filter.actor {
+        qry.push(" AND actor = ").push_bind(actor.clone());
+    }
+    if let Some(action) = &filter.action {
+        qry.push(" AND action = ").push_bind(action.clone());
+    }
+    if let Some(target) = &filter.target {
+        qry.push(" AND target = ").push_bind(target.clone());
+    }
+    if let Some(outcome) = &filter.outcome {
+        qry.push(" AND outcome = ").push_bind(outcome.clone());
+    }
+    if let Some(since) = filter.since {
+        qry.push(" AND created_at >= ").push_bind(since);
+    }
+    if let Some(until) = filter.until {
+        qry.push(" AND created_at < ").push_bind(until);
+    }
+}
+
+// newest first, returns the requested page and the total number of matching events
+pub async fn fetch_audit_events(
+    pool: &Pool<Postgres>,
+    filter: &AuditQuery,
+    limit: i64,
+    offset: i64,
+) -> async_graphql::Result<(i64, Vec<AuditRow>)> {
+    let internal = |e: sqlx::Error| {
+        println!("Error fetch_audit_events = {:?}", e);
+        RbacError::Internal("Failed to fetch the audit events".to_string()).extend()
+    };
+
+    let mut count = QueryBuilder::new("SELECT count(1) FROM audit_events");
+    push_filters(&mut count, filter);
+    let total: i64 = count
+        .build()
+        .fetch_one(pool)
+        .await
+        .map_err(internal)?
+        .get("count");
+
+    let mut qry = QueryBuilder::new(AUDIT_COLUMNS);
+    push_filters(&mut qry, filter);
+    qry.push(" ORDER BY id DESC LIMIT ")
+        .push_bind(limit)
+        .push(" OFFSET ")
+        .push_bind(offset);
+    let data = qry.build().fetch_all(pool).await.map_err(internal)?;
+    let res = data.iter().map(audit_row).collect();
+    Ok((total, res))
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{postgres::PgPoolOptions, Executor, PgPool};
+
+    use super::*;
+    use crate::db::db_config::test_pool;
+
+    // a copy of audit_events in a schema of its own, so tampering with it breaks no other chain
+    async fn chain_pool() -> Option<(PgPool, String)> {
+        let pool = test_pool().await?;
+        let schema = format!("audit_test_{}", uuid::Uuid::new_v4().simple());
+        let create = format!(
+            "CREATE SCHEMA {0}; CREATE TABLE {0}.audit_events (LIKE public.audit_events INCLUDING ALL);",
+            schema
+        );
+        pool.execute(create.as_str()).await.unwrap();
+        let search_path = format!("SET search_path TO {}", schema);
+        let chain = PgPoolOptions::new()
+            .after_connect(move |conn, _| {
+                let search_path = search_path.clone();
+                Box::pin(async move {
+                    conn.execute(search_path.as_str()).await?;
+                    Ok(())
+                })
+            })
+            .connect(&std::env::var("DATABASE_URL").unwrap())
+            .await
+            .unwrap();
+        Some((chain, schema))
+    }
+
+    async fn drop_chain(chain: PgPool, schema: &str) {
+        chain.execute(format!("DROP SCHEMA {} CASCADE;", schema).as_str()).await.unwrap();
+    }
+
+    fn record(actor: &str) -> AuditRecord {
+        AuditRecord {
+            actor: actor.to_string(),
+            action: "add_user_role".to_string(),
+            target: Some("bob".to_string()),
+            before: None,
+            after: Some("Editor".to_string()),
+            outcome: "success".to_string(),
+            reason: None,
+            client_ip: Some("203.0.113.9".to_string()),
+            request_id: None,
+        }
+    }
+
+    async fn write_chain(pool: &PgPool, key: Option<&AuditKey>) -> Vec<i64> {
+        let mut ids = Vec::new();
+        for actor in ["1", "2", "3"] {
+            ids.push(insert_audit_event(pool, record(actor), key).await.unwrap().id);
+        }
+        ids
+    }
+
+    fn broken_at(verification: AuditVerification) -> (i64, i64, String) {
+        match verification {
+            AuditVerification::Broken { id, checked, reason } => (id, checked, reason),
+            AuditVerification::Intact { events } => panic!("chain of {} events reported intact", events),
+        }
+    }
+
+    #[tokio::test]
+    async fn untouched_chain_verifies() {
+        let Some((pool, schema)) = chain_pool().await else { return };
+        let key = AuditKey(b"test key".to_vec());
+        write_chain(&pool, Some(&key)).await;
+        assert!(matches!(verify_audit_chain(&pool, Some(&key)).await.unwrap(), AuditVerification::Intact { events: 3 }));
+        drop_chain(pool, &schema).await;
+    }
+
+    #[tokio::test]
+    async fn edited_row_breaks_the_chain() {
+        let Some((pool, schema)) = chain_pool().await else { return };
+        let ids = write_chain(&pool, None).await;
+        sqlx::query("UPDATE audit_events SET actor = 'someone else' WHERE id = $1;")
+            .bind(ids[1])
+            .execute(&pool)
+            .await
+            .unwrap();
+        let (id, checked, reason) = broken_at(verify_audit_chain(&pool, None).await.unwrap());
+        assert_eq!((id, checked), (ids[1], 1));
+        assert_eq!(reason, "event contents do not match its hash");
+        drop_chain(pool, &schema).await;
+    }
+
+    #[tokio::test]
+    async fn removed_row_breaks_the_chain() {
+        let Some((pool, schema)) = chain_pool().await else { return };
+        let ids = write_chain(&pool, None).await;
+        sqlx::query("DELETE FROM audit_events WHERE id = $1;")
+            .bind(ids[1])
+            .execute(&pool)
+            .await
+            .unwrap();
+        let (id, checked, reason) = broken_at(verify_audit_chain(&pool, None).await.unwrap());
+        assert_eq!((id, checked), (ids[2], 1));
+        assert_eq!(reason, "previous hash does not match the preceding event");
+        drop_chain(pool, &schema).await;
+    }
+
+    #[tokio::test]
+    async fn rehashed_row_needs_the_key() {
+        let Some((pool, schema)) = chain_pool().await else { return };
+        let key = AuditKey(b"test key".to_vec());
+        let ids = write_chain(&pool, Some(&key)).await;
+        // without the key, the last event can be rewritten and hashed again unnoticed
+        let last = sqlx::query(&format!("{} WHERE id = $1;", AUDIT_COLUMNS))
+            .bind(ids[2])
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        let mut row = audit_row(&last);
+        row.record.actor = "someone else".to_string();
+        let hash = hash_audit_event(row.prev_hash.as_deref().unwrap(), &row.created_at, &row.record);
+        sqlx::query("UPDATE audit_events SET actor = $1, hash = $2 WHERE id = $3;")
+            .bind(&row.record.actor)
+            .bind(&hash)
+            .bind(ids[2])
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(matches!(verify_audit_chain(&pool, None).await.unwrap(), AuditVerification::Intact { events: 3 }));
+        let (id, checked, reason) = broken_at(verify_audit_chain(&pool, Some(&key)).await.unwrap());
+        assert_eq!((id, checked), (ids[2], 2));
+        assert_eq!(reason, "signature does not match the server key");
+        drop_chain(pool, &schema).await;
+    }
+
+    #[tokio::test]
+    async fn sealing_links_older_rows() {
+        let Some((pool, schema)) = chain_pool().await else { return };
+        sqlx::query("INSERT INTO audit_events (actor, action, outcome) VALUES ('1', 'login', 'success'), ('2', 'login', 'failure');")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let (_, checked, reason) = broken_at(verify_audit_chain(&pool, None).await.unwrap());
+        assert_eq!((checked, reason.as_str()), (0, "event is not part of the hash chain"));
+        assert_eq!(seal_audit_events(&pool, None).await.unwrap(), 2);
+        insert_audit_event(&pool, record("3"), None).await.unwrap();
+        assert!(matches!(verify_audit_chain(&pool, None).await.unwrap(), AuditVerification::Intact { events: 3 }));
+        drop_chain(pool, &schema).await;
+    }
+}
@@ -2,6 +2,7 @@ use sqlx::{PgPool, Pool, Postgres};
 
 use crate::db::{
     permissions::init_permissions,
+    policy::ensure_policy_baseline,
     roles::{init_role_permissions, init_roles},
     users::ensure_admin_exists,
 };
@@ -60,6 +61,284 @@ pub async fn init_db(pool: &Pool<Postgres>) {
         .await
         .expect("Failed to create the role-permission table");
 
+    // existing users get the time of the upgrade as their creation time
+    sqlx::query("ALTER TABLE users ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT now();")
+        .execute(pool)
+        .await
+        .expect("Failed to add created_at to the users table");
+    sqlx::query("ALTER TABLE users ADD COLUMN IF NOT EXISTS last_login_at TIMESTAMPTZ;")
+        .execute(pool)
+        .await
+        .expect("Failed to add last_login_at to the users table");
+    // existing users count as unverified but are never required to verify
+    sqlx::query(
+        "ALTER TABLE users
+        ADD COLUMN IF NOT EXISTS email_verified_at TIMESTAMPTZ,
+        ADD COLUMN IF NOT EXISTS email_verification_required BOOLEAN NOT NULL DEFAULT FALSE;",
+    )
+    .execute(pool)
+    .await
+    .expect("Failed to add email verification to the users table");
+    for index in [
+        "CREATE INDEX IF NOT EXISTS users_name_id ON users (name, id);",
+        "CREATE INDEX IF NOT EXISTS users_created_at_id ON users (created_at, id);",
+    ] {
+        sqlx::query(index)
+            .execute(pool)
+            .await
+            .expect("Failed to create the users indexes");
+    }
+
+    let policy_revision_table = "CREATE TABLE IF NOT EXISTS policy_revisions (
+        id SERIAL PRIMARY KEY,
+        actor VARCHAR(255) NOT NULL,
+        summary TEXT NOT NULL,
+        snapshot JSONB NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        );
+        ";
+    sqlx::query(policy_revision_table)
+        .execute(pool)
+        .await
+        .expect("Failed to create the policy-revision table");
+
+    let audit_table = "CREATE TABLE IF NOT EXISTS audit_events (
+        id BIGSERIAL PRIMARY KEY,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        actor VARCHAR(255) NOT NULL,
+        action VARCHAR(255) NOT NULL,
+        target TEXT,
+        before_value TEXT,
+        after_value TEXT,
+        outcome VARCHAR(32) NOT NULL,
+        reason TEXT,
+        client_ip VARCHAR(64),
+        request_id VARCHAR(255)
+        );
+        ";
+    sqlx::query(audit_table)
+        .execute(pool)
+        .await
+        .expect("Failed to create the audit-events table");
+    sqlx::query(
+        "ALTER TABLE audit_events
+        ADD COLUMN IF NOT EXISTS prev_hash VARCHAR(64),
+        ADD COLUMN IF NOT EXISTS hash VARCHAR(64),
+        ADD COLUMN IF NOT EXISTS signature VARCHAR(64);",
+    )
+    .execute(pool)
+    .await
+    .expect("Failed to add the hash chain to the audit-events table");
+
+    let webhook_table = "CREATE TABLE IF NOT EXISTS webhook_subscriptions (
+        id SERIAL PRIMARY KEY,
+        url TEXT NOT NULL,
+        secret VARCHAR(255) NOT NULL,
+        events TEXT[] NOT NULL DEFAULT '{}',
+        active BOOLEAN NOT NULL DEFAULT TRUE,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        );
+        ";
+    sqlx::query(webhook_table)
+        .execute(pool)
+        .await
+        .expect("Failed to create the webhook-subscriptions table");
+
+    let outbox_table = "CREATE TABLE IF NOT EXISTS webhook_outbox (
+        id BIGSERIAL PRIMARY KEY,
+        subscription_id INTEGER NOT NULL REFERENCES webhook_subscriptions(id) ON DELETE CASCADE,
+        event VARCHAR(255) NOT NULL,
+        payload JSONB NOT NULL,
+        status VARCHAR(16) NOT NULL DEFAULT 'pending',
+        attempts INTEGER NOT NULL DEFAULT 0,
+        next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        last_error TEXT,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        delivered_at TIMESTAMPTZ
+        );
+        ";
+    sqlx::query(outbox_table)
+        .execute(pool)
+        .await
+        .expect("Failed to create the webhook-outbox table");
+    sqlx::query("CREATE INDEX IF NOT EXISTS webhook_outbox_due ON webhook_outbox (next_attempt_at) WHERE status = 'pending';")
+        .execute(pool)
+        .await
+        .expect("Failed to create the webhook-outbox index");
+
+    let dead_letter_view = "CREATE OR REPLACE VIEW webhook_dead_letters AS
+        SELECT o.id, o.subscription_id, s.url, s.secret, o.event, o.payload, o.attempts, o.last_error, o.created_at
+        FROM webhook_outbox o JOIN webhook_subscriptions s ON s.id = o.subscription_id
+        WHERE o.status = 'dead';
+        ";
+    sqlx::query(dead_letter_view)
+        .execute(pool)
+        .await
+        .expect("Failed to create the webhook dead-letter view");
+
+    // failed logins per account ("email:<email>") and per client ("ip:<address>"). Kept out of
+    // the users table so a wrong password doesn't fire its change notifications
+    let login_failures_table = "CREATE TABLE IF NOT EXISTS login_failures (
+        key VARCHAR(320) PRIMARY KEY,
+        failures INTEGER NOT NULL DEFAULT 0,
+        last_failure_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        locked_until TIMESTAMPTZ
+        );
+        ";
+    sqlx::query(login_failures_table)
+        .execute(pool)
+        .await
+        .expect("Failed to create the login-failures table");
+
+    // refresh tokens are stored as SHA-256 hashes; a used token stays behind revoked, so a
+    // replay of it can be told apart from a token that never existed
+    let refresh_tokens_table = "CREATE TABLE IF NOT EXISTS refresh_tokens (
+        id BIGSERIAL PRIMARY KEY,
+        user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        token_hash CHAR(64) UNIQUE NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        expires_at TIMESTAMPTZ NOT NULL,
+        revoked_at TIMESTAMPTZ
+        );
+        ";
+    sqlx::query(refresh_tokens_table)
+        .execute(pool)
+        .await
+        .expect("Failed to create the refresh-tokens table");
+    sqlx::query("CREATE INDEX IF NOT EXISTS refresh_tokens_user_id ON refresh_tokens (user_id);")
+        .execute(pool)
+        .await
+        .expect("Failed to create the refresh-tokens index");
+
+    // TOTP secrets have to be readable to check codes, so unlike passwords they can't be hashed.
+    // last_used_step is the time step of the last accepted code, which can't be used again
+    let totp_table = "CREATE TABLE IF NOT EXISTS user_totp (
+        user_id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+        secret VARCHAR(64) NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        confirmed_at TIMESTAMPTZ,
+        last_used_step BIGINT
+        );
+        ";
+    sqlx::query(totp_table)
+        .execute(pool)
+        .await
+        .expect("Failed to create the user-totp table");
+    let recovery_codes_table = "CREATE TABLE IF NOT EXISTS mfa_recovery_codes (
+        id SERIAL PRIMARY KEY,
+        user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        code_hash CHAR(64) NOT NULL,
+        used_at TIMESTAMPTZ
+        );
+        ";
+    sqlx::query(recovery_codes_table)
+        .execute(pool)
+        .await
+        .expect("Failed to create the recovery-codes table");
+    // second login step: the password was right, a code is still owed
+    let mfa_challenges_table = "CREATE TABLE IF NOT EXISTS mfa_challenges (
+        token_hash CHAR(64) PRIMARY KEY,
+        user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        expires_at TIMESTAMPTZ NOT NULL
+        );
+        ";
+    sqlx::query(mfa_challenges_table)
+        .execute(pool)
+        .await
+        .expect("Failed to create the mfa-challenges table");
+
+    // single-use tokens mailed for password resets and email verification, stored hashed
+    let account_tokens_table = "CREATE TABLE IF NOT EXISTS account_tokens (
+        token_hash CHAR(64) PRIMARY KEY,
+        user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        purpose VARCHAR(32) NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        expires_at TIMESTAMPTZ NOT NULL
+        );
+        ";
+    sqlx::query(account_tokens_table)
+        .execute(pool)
+        .await
+        .expect("Failed to create the account-tokens table");
+
+    // credentials for scripts and CI, owned by a user. Only the SHA-256 of the key is stored, and
+    // the prefix that identifies it in listings. permissions, when set, is the subset of the
+    // user's permissions the key carries
+    let api_keys_table = "CREATE TABLE IF NOT EXISTS api_keys (
+        id SERIAL PRIMARY KEY,
+        user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        name VARCHAR(255) NOT NULL,
+        prefix VARCHAR(32) NOT NULL,
+        key_hash CHAR(64) UNIQUE NOT NULL,
+        permissions TEXT[],
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        expires_at TIMESTAMPTZ,
+        last_used_at TIMESTAMPTZ
+        );
+        ";
+    sqlx::query(api_keys_table)
+        .execute(pool)
+        .await
+        .expect("Failed to create the api-keys table");
+    sqlx::query("CREATE INDEX IF NOT EXISTS api_keys_user_id ON api_keys (user_id);")
+        .execute(pool)
+        .await
+        .expect("Failed to create the api-keys index");
+
+    // row-level changes are published on the rbac_changes channel for GraphQL subscriptions
+
+    let notify_function = "CREATE OR REPLACE FUNCTION rbac_notify_change() RETURNS trigger AS $$
+        DECLARE
+            rec RECORD;
+            user_id INTEGER;
+            role_id INTEGER;
+            old_role_id INTEGER;
+        BEGIN
+            IF TG_OP = 'DELETE' THEN rec := OLD; ELSE rec := NEW; END IF;
+            IF TG_TABLE_NAME = 'users' THEN
+                user_id := rec.id;
+            ELSIF TG_TABLE_NAME = 'roles' THEN
+                role_id := rec.id;
+            ELSIF TG_TABLE_NAME = 'user_roles' THEN
+                user_id := rec.user_id;
+                role_id := rec.role_id;
+                IF TG_OP = 'UPDATE' THEN old_role_id := OLD.role_id; END IF;
+            ELSIF TG_TABLE_NAME = 'role_permissions' THEN
+                role_id := rec.role_id;
+            END IF;
+            PERFORM pg_notify('rbac_changes', json_build_object(
+                'table', TG_TABLE_NAME, 'op', TG_OP,
+                'user_id', user_id, 'role_id', role_id, 'old_role_id', old_role_id)::text);
+            RETURN NULL;
+        END;
+        $$ LANGUAGE plpgsql;
+        ";
+    sqlx::query(notify_function)
+        .execute(pool)
+        .await
+        .expect("Failed to create the change notification function");
+    for table in ["users", "roles", "user_roles", "role_permissions"] {
+        sqlx::query(&format!("DROP TRIGGER IF EXISTS {0}_notify_change ON {0};", table))
+            .execute(pool)
+            .await
+            .expect("Failed to drop the change notification trigger");
+        // bookkeeping columns such as users.last_login_at change on every login and are not
+        // worth a notification
+        let update = match table {
+            "users" => "UPDATE OF name, email, password_hash",
+            _ => "UPDATE",
+        };
+        sqlx::query(&format!(
+            "CREATE TRIGGER {0}_notify_change AFTER INSERT OR {1} OR DELETE ON {0}
+            FOR EACH ROW EXECUTE FUNCTION rbac_notify_change();",
+            table, update
+        ))
+        .execute(pool)
+        .await
+        .expect("Failed to create the change notification trigger");
+    }
+
     let check_user = sqlx::query(
         "
     SELECT id from users where lower(name) = 'admin';",
@@ -70,10 +349,85 @@ pub async fn init_db(pool: &Pool<Postgres>) {
 
     if check_user.is_some() {
         println!("database already configured");
+        ensure_policy_baseline(pool).await;
         return;
     }
     init_roles(pool).await;
     init_permissions(pool).await;
     init_role_permissions(pool).await;
     ensure_admin_exists(pool).await;
+    ensure_policy_baseline(pool).await;
+}
+
+// the database for tests that need one, from DATABASE_URL; those tests pass without checking
+// anything when it is unset
+#[cfg(test)]
+pub async fn test_pool() -> Option<PgPool> {
+    let url = std::env::var("DATABASE_URL").ok()?;
+    // tests run in parallel, and concurrent CREATE TABLE IF NOT EXISTS can still collide
+    let init = sqlx::postgres::PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&url)
+        .await
+        .expect("Failed to connect to DATABASE_URL");
+    sqlx::query("SELECT pg_advisory_lock(hashtext('rbac_test_init'));")
+        .execute(&init)
+        .await
+        .expect("Failed to lock the test database");
+    init_db(&init).await;
+    init.close().await;
+    Some(PgPool::connect(&url).await.expect("Failed to connect to DATABASE_URL"))
+}
+
+// a user of its own for each test, so tests don't see each other's rows
+#[cfg(test)]
+pub async fn test_user(pool: &PgPool) -> i32 {
+    use sqlx::Row;
+
+    let name = format!("test_{}", uuid::Uuid::new_v4().simple());
+    sqlx::query("INSERT INTO users (name, email, password_hash) VALUES ($1, $2, '') RETURNING id;")
+        .bind(&name)
+        .bind(format!("{}@test.invalid", name))
+        .fetch_one(pool)
+        .await
+        .expect("Failed to create the test user")
+        .get("id")
+}
+
+// empty copies of `tables` in a schema of their own, for tests that rewrite whole tables and
+// would otherwise pull rows out from under tests running next to them. Drop it with
+// drop_test_schema
+#[cfg(test)]
+pub async fn test_schema_pool(tables: &[&str]) -> Option<(PgPool, String)> {
+    use sqlx::Executor;
+
+    let pool = test_pool().await?;
+    let schema = format!("test_{}", uuid::Uuid::new_v4().simple());
+    let mut create = format!("CREATE SCHEMA {};", schema);
+    for table in tables {
+        create += &format!(" CREATE TABLE {0}.{1} (LIKE public.{1} INCLUDING ALL);", schema, table);
+    }
+    pool.execute(create.as_str()).await.expect("Failed to create the test schema");
+    let search_path = format!("SET search_path TO {}", schema);
+    let isolated = sqlx::postgres::PgPoolOptions::new()
+        .after_connect(move |conn, _| {
+            let search_path = search_path.clone();
+            Box::pin(async move {
+                conn.execute(search_path.as_str()).await?;
+                Ok(())
+            })
+        })
+        .connect(&std::env::var("DATABASE_URL").unwrap())
+        .await
+        .expect("Failed to connect to DATABASE_URL");
+    Some((isolated, schema))
+}
+
+#[cfg(test)]
+pub async fn drop_test_schema(pool: PgPool, schema: &str) {
+    use sqlx::Executor;
+
+    pool.execute(format!("DROP SCHEMA {} CASCADE;", schema).as_str())
+        .await
+        .expect("Failed to drop the test schema");
 }
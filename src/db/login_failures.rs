@@ -0,0 +1,57 @@
+use async_graphql::ErrorExtensions;
+use sqlx::{PgPool, Row};
+
+use crate::utilities::errors::RbacError;
+
+// seconds until every one of `keys` is unlocked, None when none of them is locked
+pub async fn login_lock_remaining(pool: &PgPool, keys: &[String]) -> async_graphql::Result<Option<u64>> {
+    let qry = "SELECT CEIL(EXTRACT(EPOCH FROM MAX(locked_until) - now()))::BIGINT AS secs
+        FROM login_failures WHERE key = ANY($1) AND locked_until > now();";
+    match sqlx::query(qry).bind(keys).fetch_one(pool).await {
+        Ok(row) => Ok(row.get::<Option<i64>, _>("secs").map(|v| v.max(1) as u64)),
+        Err(e) => {
+            println!("Error login_lock_remaining = {:?}", e);
+            Err(RbacError::Internal("Failed to check the login lockout".to_string()).extend())
+        }
+    }
+}
+
+// counts a failed login for `key` and returns the number of failures in a row; failures older
+// than `window_secs` are forgotten
+pub async fn record_login_failure(pool: &PgPool, key: &str, window_secs: i64) -> async_graphql::Result<i32> {
+    let qry = "INSERT INTO login_failures (key, failures, last_failure_at) VALUES ($1, 1, now())
+        ON CONFLICT (key) DO UPDATE SET
+            failures = CASE WHEN login_failures.last_failure_at < now() - make_interval(secs => $2)
+                THEN 1 ELSE login_failures.failures + 1 END,
+            last_failure_at = now()
+        RETURNING failures;";
+    match sqlx::query(qry).bind(key).bind(window_secs as f64).fetch_one(pool).await {
+        Ok(row) => Ok(row.get("failures")),
+        Err(e) => {
+            println!("Error record_login_failure = {:?}", e);
+            Err(RbacError::Internal("Failed to record the failed login".to_string()).extend())
+        }
+    }
+}
+
+pub async fn lock_login(pool: &PgPool, key: &str, secs: i64) -> async_graphql::Result<()> {
+    let qry = "UPDATE login_failures SET locked_until = now() + make_interval(secs => $2) WHERE key = $1;";
+    match sqlx::query(qry).bind(key).bind(secs as f64).execute(pool).await {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            println!("Error lock_login = {:?}", e);
+            Err(RbacError::Internal("Failed to lock the login".to_string()).extend())
+        }
+    }
+}
+
+// forgets the failures and any lock of `key`; false when there was nothing to clear
+pub async fn clear_login_failures(pool: &PgPool, key: &str) -> async_graphql::Result<bool> {
+    match sqlx::query("DELETE FROM login_failures WHERE key = $1;").bind(key).execute(pool).await {
+        Ok(v) => Ok(v.rows_affected() > 0),
+        Err(e) => {
+            println!("Error clear_login_failures = {:?}", e);
+            Err(RbacError::Internal("Failed to clear the failed logins".to_string()).extend())
+        }
+    }
+}
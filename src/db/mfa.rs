@@ -0,0 +1,196 @@
+use async_graphql::{Error, ErrorExtensions};
+use sqlx::{PgPool, Row};
+
+use crate::utilities::errors::RbacError;
+
+fn internal(details: &'static str) -> impl Fn(sqlx::Error) -> Error {
+    move |e| {
+        println!("Error mfa = {:?}", e);
+        RbacError::Internal(details.to_string()).extend()
+    }
+}
+
+pub struct TotpRow {
+    pub secret: String,
+    // false between enrollTotp and confirmTotp
+    pub confirmed: bool,
+}
+
+pub async fn fetch_totp(pool: &PgPool, user_id: i32) -> async_graphql::Result<Option<TotpRow>> {
+    let row = sqlx::query("SELECT secret, confirmed_at IS NOT NULL AS confirmed FROM user_totp WHERE user_id = $1;")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(internal("Failed to fetch the authenticator of the user"))?;
+    Ok(row.map(|v| TotpRow {
+        secret: v.get("secret"),
+        confirmed: v.get("confirmed"),
+    }))
+}
+
+// stores a new secret awaiting confirmation; false when a confirmed one is already in place
+pub async fn insert_pending_totp(pool: &PgPool, user_id: i32, secret: &str) -> async_graphql::Result<bool> {
+    let qry = "INSERT INTO user_totp (user_id, secret) VALUES ($1, $2)
+        ON CONFLICT (user_id) DO UPDATE SET secret = EXCLUDED.secret, created_at = now(), last_used_step = NULL
+        WHERE user_totp.confirmed_at IS NULL;";
+    let res = sqlx::query(qry)
+        .bind(user_id)
+        .bind(secret)
+        .execute(pool)
+        .await
+        .map_err(internal("Failed to store the authenticator"))?;
+    Ok(res.rows_affected() > 0)
+}
+
+// burns the time step of a code that was just verified. False when that step, or a later one,
+// was already used: the code is being replayed
+pub async fn use_totp_step(pool: &PgPool, user_id: i32, step: i64) -> async_graphql::Result<bool> {
+    let qry = "UPDATE user_totp SET last_used_step = $2
+        WHERE user_id = $1 AND (last_used_step IS NULL OR last_used_step < $2);";
+    let res = sqlx::query(qry)
+        .bind(user_id)
+        .bind(step)
+        .execute(pool)
+        .await
+        .map_err(internal("Failed to check the authenticator code"))?;
+    Ok(res.rows_affected() > 0)
+}
+
+// turns MFA on and replaces the recovery codes, in one transaction
+pub async fn confirm_totp(pool: &PgPool, user_id: i32, recovery_hashes: &[String]) -> async_graphql::Result<()> {
+    let err = internal("Failed to enable the authenticator");
+    let mut tx = pool.begin().await.map_err(&err)?;
+    sqlx::query("UPDATE user_totp SET confirmed_at = now() WHERE user_id = $1;")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(&err)?;
+    sqlx::query("DELETE FROM mfa_recovery_codes WHERE user_id = $1;")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(&err)?;
+    sqlx::query("INSERT INTO mfa_recovery_codes (user_id, code_hash) SELECT $1, UNNEST($2::TEXT[]);")
+        .bind(user_id)
+        .bind(recovery_hashes)
+        .execute(&mut *tx)
+        .await
+        .map_err(&err)?;
+    tx.commit().await.map_err(&err)
+}
+
+// false when the code is unknown or was used before
+pub async fn use_recovery_code(pool: &PgPool, user_id: i32, code_hash: &str) -> async_graphql::Result<bool> {
+    let qry = "UPDATE mfa_recovery_codes SET used_at = now()
+        WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL;";
+    let res = sqlx::query(qry)
+        .bind(user_id)
+        .bind(code_hash)
+        .execute(pool)
+        .await
+        .map_err(internal("Failed to check the recovery code"))?;
+    Ok(res.rows_affected() > 0)
+}
+
+// removes the authenticator and the recovery codes; false when the user had none
+pub async fn delete_totp(pool: &PgPool, user_id: i32) -> async_graphql::Result<bool> {
+    let err = internal("Failed to remove the authenticator");
+    let mut tx = pool.begin().await.map_err(&err)?;
+    sqlx::query("DELETE FROM mfa_recovery_codes WHERE user_id = $1;")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(&err)?;
+    let res = sqlx::query("DELETE FROM user_totp WHERE user_id = $1;")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(&err)?;
+    tx.commit().await.map_err(&err)?;
+    Ok(res.rows_affected() > 0)
+}
+
+pub async fn insert_mfa_challenge(pool: &PgPool, user_id: i32, token_hash: &str, ttl_secs: i64) -> async_graphql::Result<()> {
+    let err = internal("Failed to start the second login step");
+    sqlx::query("DELETE FROM mfa_challenges WHERE expires_at < now();")
+        .execute(pool)
+        .await
+        .map_err(&err)?;
+    sqlx::query("INSERT INTO mfa_challenges (token_hash, user_id, expires_at) VALUES ($1, $2, now() + make_interval(secs => $3));")
+        .bind(token_hash)
+        .bind(user_id)
+        .bind(ttl_secs as f64)
+        .execute(pool)
+        .await
+        .map_err(&err)?;
+    Ok(())
+}
+
+// the user a live challenge belongs to
+pub async fn fetch_mfa_challenge(pool: &PgPool, token_hash: &str) -> async_graphql::Result<Option<i32>> {
+    let row = sqlx::query("SELECT user_id FROM mfa_challenges WHERE token_hash = $1 AND expires_at > now();")
+        .bind(token_hash)
+        .fetch_optional(pool)
+        .await
+        .map_err(internal("Failed to check the second login step"))?;
+    Ok(row.map(|v| v.get("user_id")))
+}
+
+// counts a wrong code; the challenge is dropped once `max_attempts` is reached
+pub async fn record_mfa_failure(pool: &PgPool, token_hash: &str, max_attempts: i32) -> async_graphql::Result<()> {
+    let err = internal("Failed to record the wrong code");
+    sqlx::query("UPDATE mfa_challenges SET attempts = attempts + 1 WHERE token_hash = $1;")
+        .bind(token_hash)
+        .execute(pool)
+        .await
+        .map_err(&err)?;
+    sqlx::query("DELETE FROM mfa_challenges WHERE token_hash = $1 AND attempts >= $2;")
+        .bind(token_hash)
+        .bind(max_attempts)
+        .execute(pool)
+        .await
+        .map_err(&err)?;
+    Ok(())
+}
+
+// false when the challenge was already used, so two racing requests can't both log in
+pub async fn delete_mfa_challenge(pool: &PgPool, token_hash: &str) -> async_graphql::Result<bool> {
+    let res = sqlx::query("DELETE FROM mfa_challenges WHERE token_hash = $1;")
+        .bind(token_hash)
+        .execute(pool)
+        .await
+        .map_err(internal("Failed to finish the second login step"))?;
+    Ok(res.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::db_config::{test_pool, test_user};
+
+    #[tokio::test]
+    async fn a_step_is_used_once_and_never_after_a_later_one() {
+        let Some(pool) = test_pool().await else { return };
+        let user_id = test_user(&pool).await;
+        assert!(insert_pending_totp(&pool, user_id, "GEZDGNBVGY3TQOJQ").await.unwrap());
+        assert!(use_totp_step(&pool, user_id, 100).await.unwrap());
+        // the same code again
+        assert!(!use_totp_step(&pool, user_id, 100).await.unwrap());
+        // an older code still inside the drift window
+        assert!(!use_totp_step(&pool, user_id, 99).await.unwrap());
+        assert!(use_totp_step(&pool, user_id, 101).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn recovery_codes_work_once() {
+        let Some(pool) = test_pool().await else { return };
+        let user_id = test_user(&pool).await;
+        insert_pending_totp(&pool, user_id, "GEZDGNBVGY3TQOJQ").await.unwrap();
+        confirm_totp(&pool, user_id, &["a".repeat(64), "b".repeat(64)]).await.unwrap();
+        assert!(use_recovery_code(&pool, user_id, &"a".repeat(64)).await.unwrap());
+        assert!(!use_recovery_code(&pool, user_id, &"a".repeat(64)).await.unwrap());
+        assert!(!use_recovery_code(&pool, user_id, &"c".repeat(64)).await.unwrap());
+        // a confirmed authenticator isn't replaced by a new enrollment
+        assert!(!insert_pending_totp(&pool, user_id, "GEZDGNBVGY3TQOJR").await.unwrap());
+    }
+}
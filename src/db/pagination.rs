@@ -0,0 +1,206 @@
+use async_graphql::{Error, ErrorExtensions};
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgRow, Pool, Postgres, QueryBuilder, Row};
+
+use crate::utilities::errors::RbacError;
+
+pub const MAX_PAGE_SIZE: i64 = 500;
+
+// keyset position after a row: its sort column as text, with the id breaking ties
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PageCursor {
+    pub sort: String,
+    pub key: String,
+    pub id: i32,
+}
+
+// a column a list can be ordered by
+pub struct SortKey {
+    // stored in the cursor so it cannot be replayed against another ordering
+    pub name: &'static str,
+    pub column: &'static str,
+    pub sql_type: &'static str,
+    pub descending: bool,
+}
+
+pub struct Page {
+    pub total: i64,
+    pub rows: Vec<(PageCursor, PgRow)>,
+    pub has_next: bool,
+}
+
+fn internal(e: sqlx::Error) -> Error {
+    println!("Error fetch_page = {:?}", e);
+    RbacError::Internal("Failed to fetch the page".to_string()).extend()
+}
+
+// `select` and `count` must both end in an open WHERE clause with the same filters applied
+pub async fn fetch_page(
+    pool: &Pool<Postgres>,
+    mut select: QueryBuilder<'_, Postgres>,
+    mut count: QueryBuilder<'_, Postgres>,
+    sort: &SortKey,
+    after: Option<PageCursor>,
+    first: i64,
+) -> async_graphql::Result<Page> {
+    if !(1..=MAX_PAGE_SIZE).contains(&first) {
+        return Err(RbacError::validation("first", format!("first must be between 1 and {}", MAX_PAGE_SIZE)).extend());
+    }
+    if after.as_ref().is_some_and(|after| after.sort != sort.name) {
+        return Err(RbacError::validation("after", "The cursor belongs to a different sort order").extend());
+    }
+    let total: i64 = count
+        .build()
+        .fetch_one(pool)
+        .await
+        .map_err(internal)?
+        .get("count");
+
+    let (op, dir) = if sort.descending { ("<", "DESC") } else { (">", "ASC") };
+    if let Some(after) = after {
+        select
+            .push(format!(" AND ({}, id) {} (CAST(", sort.column, op))
+            .push_bind(after.key)
+            .push(format!(" AS {}), ", sort.sql_type))
+            .push_bind(after.id)
+            .push(")");
+    }
+    // one extra row tells whether there is a next page
+    if sort.column == "id" {
+        select.push(format!(" ORDER BY id {} LIMIT ", dir));
+    } else {
+        select.push(format!(" ORDER BY {} {}, id {} LIMIT ", sort.column, dir, dir));
+    }
+    select.push_bind(first + 1);
+    let mut data = select.build().fetch_all(pool).await.map_err(internal)?;
+    let has_next = data.len() as i64 > first;
+    data.truncate(first as usize);
+
+    let rows = data
+        .into_iter()
+        .map(|row| {
+            let cursor = PageCursor {
+                sort: sort.name.to_string(),
+                key: row.get("sort_key"),
+                id: row.get("id"),
+            };
+            (cursor, row)
+        })
+        .collect();
+    Ok(Page {
+        total,
+        rows,
+        has_next,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::connection::{CursorType, OpaqueCursor};
+
+    use super::*;
+    use crate::{
+        db::db_config::{drop_test_schema, test_schema_pool},
+        utilities::errors::extension,
+    };
+
+    fn by(name: &'static str, column: &'static str, sql_type: &'static str, descending: bool) -> SortKey {
+        SortKey {
+            name,
+            column,
+            sql_type,
+            descending,
+        }
+    }
+
+    fn cursor(sort: &str, key: &str, id: i32) -> PageCursor {
+        PageCursor {
+            sort: sort.to_string(),
+            key: key.to_string(),
+            id,
+        }
+    }
+
+    // any query reaching the database fails with INTERNAL_SERVER_ERROR
+    fn unreachable_pool() -> Pool<Postgres> {
+        sqlx::postgres::PgPoolOptions::new()
+            .acquire_timeout(std::time::Duration::from_millis(100))
+            .connect_lazy("postgres://nobody@127.0.0.1:1/none")
+            .unwrap()
+    }
+
+    async fn page(pool: &Pool<Postgres>, sort: &SortKey, after: Option<PageCursor>, first: i64) -> async_graphql::Result<Page> {
+        let select = QueryBuilder::new(format!("SELECT id, {}::text AS sort_key FROM permissions WHERE TRUE", sort.column));
+        let count = QueryBuilder::new("SELECT count(1) FROM permissions WHERE TRUE");
+        fetch_page(pool, select, count, sort, after, first).await
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let encoded = OpaqueCursor(cursor("name", "Reader, \"quoted\"", 42)).encode_cursor();
+        assert!(!encoded.contains("Reader"));
+        let decoded = OpaqueCursor::<PageCursor>::decode_cursor(&encoded).unwrap().0;
+        assert_eq!((decoded.sort.as_str(), decoded.key.as_str(), decoded.id), ("name", "Reader, \"quoted\"", 42));
+    }
+
+    #[test]
+    fn garbage_is_not_a_cursor() {
+        assert!(OpaqueCursor::<PageCursor>::decode_cursor("not a cursor").is_err());
+        // valid base64, but not a PageCursor
+        let other = OpaqueCursor(vec![1, 2, 3]).encode_cursor();
+        assert!(OpaqueCursor::<PageCursor>::decode_cursor(&other).is_err());
+    }
+
+    #[tokio::test]
+    async fn first_must_be_within_bounds() {
+        let pool = unreachable_pool();
+        let sort = by("id", "id", "integer", false);
+        for first in [0, -1, MAX_PAGE_SIZE + 1] {
+            let e = page(&pool, &sort, None, first).await.err().unwrap();
+            assert_eq!(extension(&e, "code").as_deref(), Some("VALIDATION_FAILED"), "first = {}", first);
+            assert_eq!(extension(&e, "field").as_deref(), Some("first"));
+        }
+        // in bounds gets past validation to the database
+        for first in [1, MAX_PAGE_SIZE] {
+            let e = page(&pool, &sort, None, first).await.err().unwrap();
+            assert_eq!(extension(&e, "code").as_deref(), Some("INTERNAL_SERVER_ERROR"));
+        }
+    }
+
+    #[tokio::test]
+    async fn cursor_must_match_the_sort_order() {
+        let pool = unreachable_pool();
+        let sort = by("action", "action", "text", false);
+        let e = page(&pool, &sort, Some(cursor("id", "7", 7)), 10).await.err().unwrap();
+        assert_eq!(extension(&e, "code").as_deref(), Some("VALIDATION_FAILED"));
+        assert_eq!(extension(&e, "field").as_deref(), Some("after"));
+    }
+
+    async fn walk(pool: &Pool<Postgres>, sort: &SortKey) -> Vec<i32> {
+        let (mut ids, mut after) = (vec![], None);
+        loop {
+            let page = page(pool, sort, after, 2).await.unwrap();
+            assert_eq!(page.total, 5);
+            after = page.rows.last().map(|(c, _)| c.clone());
+            ids.extend(page.rows.iter().map(|(c, _)| c.id));
+            if !page.has_next {
+                return ids;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn pages_cover_every_row_once() {
+        let Some((pool, schema)) = test_schema_pool(&["permissions"]).await else { return };
+        // ties on the sort column fall back to the id
+        sqlx::query("INSERT INTO permissions (id, action) VALUES (1, 'b'), (2, 'a'), (3, 'c'), (4, 'a'), (5, 'b');")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(walk(&pool, &by("id", "id", "integer", false)).await, vec![1, 2, 3, 4, 5]);
+        assert_eq!(walk(&pool, &by("id", "id", "integer", true)).await, vec![5, 4, 3, 2, 1]);
+        assert_eq!(walk(&pool, &by("action", "action", "text", false)).await, vec![2, 4, 1, 5, 3]);
+        assert_eq!(walk(&pool, &by("action", "action", "text", true)).await, vec![3, 5, 1, 4, 2]);
+        drop_test_schema(pool, &schema).await;
+    }
+}
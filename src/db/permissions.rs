@@ -1,5 +1,10 @@
-use async_graphql::{Error, ErrorExtensions};
-use sqlx::{Pool, Postgres};
+use async_graphql::ErrorExtensions;
+use sqlx::{Pool, Postgres, QueryBuilder};
+
+use crate::{
+    db::pagination::{fetch_page, Page, PageCursor, SortKey},
+    utilities::errors::RbacError,
+};
 
 pub async fn init_permissions(pool: &Pool<Postgres>) {
 
@@ -27,14 +32,12 @@ pub async fn insert_permissions(
         Ok(v) => v,
         Err(e) => {
             println!("Error insert_permissions :- {:?}", e);
-            return Err(Error::new("Permission Check Failed")
-                .extend_with(|_, e| e.set("details", "Failed to check the permission")));
+            return Err(RbacError::Internal("Failed to check the permission".to_string()).extend());
         }
     };
 
     if check_perm.is_some() {
-        return Err(Error::new("Failed to Insert")
-            .extend_with(|_, e| e.set("details", "Permission already present")));
+        return Err(RbacError::Conflict("Permission already present".to_string()).extend());
     }
 
     let qry = "INSERT INTO permissions(action) VALUES ($1)";
@@ -42,9 +45,32 @@ pub async fn insert_permissions(
         Ok(v) => v,
         Err(e) => {
             println!("Error:- Failed to insert the permission {:?}", &action);
-            return Err(Error::new("Failed to Insert")
-                .extend_with(|_, e| e.set("details", "Failed to insert Permission")));
+            return Err(RbacError::Internal("Failed to insert Permission".to_string()).extend());
         }
     };
     Ok(action)
 }
+
+fn push_permission_filters(qry: &mut QueryBuilder<'_, Postgres>, action_contains: Option<&str>) {
+    qry.push(" WHERE TRUE");
+    if let Some(action) = action_contains {
+        qry.push(" AND strpos(lower(action), lower(").push_bind(action.to_string()).push(")) > 0");
+    }
+}
+
+pub async fn fetch_permissions_page(
+    pool: &Pool<Postgres>,
+    action_contains: Option<&str>,
+    sort: &SortKey,
+    after: Option<PageCursor>,
+    first: i64,
+) -> async_graphql::Result<Page> {
+    let mut select = QueryBuilder::new(format!(
+        "SELECT id, action, {}::text AS sort_key FROM permissions",
+        sort.column
+    ));
+    push_permission_filters(&mut select, action_contains);
+    let mut count = QueryBuilder::new("SELECT count(1) FROM permissions");
+    push_permission_filters(&mut count, action_contains);
+    fetch_page(pool, select, count, sort, after, first).await
+}
@@ -0,0 +1,460 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use async_graphql::ErrorExtensions;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, PgConnection, Pool, Postgres, Row};
+
+use crate::{db::webhooks::commit_with_event, utilities::errors::RbacError};
+
+// one role and the actions granted to it at the time of the snapshot
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RoleGrant {
+    pub id: i32,
+    pub name: String,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct PolicySnapshot {
+    pub roles: Vec<RoleGrant>,
+}
+
+pub struct PolicyRevisionRow {
+    pub id: i32,
+    pub actor: String,
+    pub summary: String,
+    pub created_at: DateTime<Utc>,
+    pub snapshot: PolicySnapshot,
+}
+
+#[derive(Debug, Default)]
+pub struct PolicyChanges {
+    pub added_roles: Vec<String>,
+    pub removed_roles: Vec<String>,
+    pub renamed_roles: Vec<(i32, String, String)>,
+    pub granted: Vec<(String, String)>,
+    pub revoked: Vec<(String, String)>,
+}
+
+pub async fn snapshot_policy(conn: &mut PgConnection) -> async_graphql::Result<PolicySnapshot> {
+    let qry = "SELECT r.id, r.name, p.action FROM roles r
+        LEFT JOIN role_permissions rp ON rp.role_id = r.id
+        LEFT JOIN permissions p ON p.id = rp.permission_id
+        ORDER BY r.id, p.action;";
+    let data = match sqlx::query(qry).fetch_all(&mut *conn).await {
+        Ok(v) => v,
+        Err(e) => {
+            println!("Error snapshot_policy = {:?}", e);
+            return Err(RbacError::Internal("Failed to read the current policy".to_string()).extend());
+        }
+    };
+
+    let mut roles: Vec<RoleGrant> = Vec::new();
+    for row in data {
+        let id: i32 = row.get("id");
+        let action: Option<String> = row.get("action");
+        if roles.last().map(|r| r.id) != Some(id) {
+            roles.push(RoleGrant {
+                id,
+                name: row.get("name"),
+                permissions: vec![],
+            });
+        }
+        if let Some(action) = action {
+            roles.last_mut().unwrap().permissions.push(action);
+        }
+    }
+    Ok(PolicySnapshot { roles })
+}
+
+// stores the current policy as a new revision and returns its number. Call it in the transaction
+// of the change, before the commit: the change and its revision then land together, and the lock
+// queues concurrent changes, so each snapshot holds exactly the changes committed before it plus
+// its own
+pub async fn record_policy_revision(
+    conn: &mut PgConnection,
+    actor: &str,
+    summary: &str,
+) -> async_graphql::Result<i32> {
+    // released at commit or rollback
+    if let Err(e) = sqlx::query("SELECT pg_advisory_xact_lock(hashtext('policy_revisions'));")
+        .execute(&mut *conn)
+        .await
+    {
+        println!("Error record_policy_revision = {:?}", e);
+        return Err(RbacError::Internal("Failed to record the policy revision".to_string()).extend());
+    }
+    let snapshot = snapshot_policy(&mut *conn).await?;
+    match sqlx::query(
+        "INSERT INTO policy_revisions (actor, summary, snapshot) VALUES ($1, $2, $3) RETURNING id;",
+    )
+    .bind(actor)
+    .bind(summary)
+    .bind(Json(&snapshot))
+    .fetch_one(&mut *conn)
+    .await
+    {
+        Ok(v) => Ok(v.get("id")),
+        Err(e) => {
+            println!("Error record_policy_revision = {:?}", e);
+            Err(RbacError::Internal("Failed to record the policy revision".to_string()).extend())
+        }
+    }
+}
+
+pub async fn ensure_policy_baseline(pool: &Pool<Postgres>) {
+    let res = sqlx::query("SELECT EXISTS (SELECT * FROM policy_revisions);")
+        .fetch_one(pool)
+        .await
+        .expect("Error:- Failed to check the policy revisions");
+    let exists: bool = res.get("exists");
+    if !exists {
+        let mut conn = pool.acquire().await.expect("Failed to acquire a connection");
+        record_policy_revision(&mut conn, "system", "Initial policy")
+            .await
+            .expect("Failed to record the initial policy revision");
+    }
+}
+
+pub async fn fetch_policy_revisions(
+    pool: &Pool<Postgres>,
+) -> async_graphql::Result<Vec<PolicyRevisionRow>> {
+    let data = match sqlx::query(
+        "SELECT id, actor, summary, created_at, snapshot FROM policy_revisions ORDER BY id DESC;",
+    )
+    .fetch_all(pool)
+    .await
+    {
+        Ok(v) => v,
+        Err(e) => {
+            println!("Error fetch_policy_revisions = {:?}", e);
+            return Err(RbacError::Internal("Failed to fetch the policy revisions".to_string()).extend());
+        }
+    };
+    let mut res = Vec::new();
+    for row in data {
+        let snapshot: Json<PolicySnapshot> = row.get("snapshot");
+        res.push(PolicyRevisionRow {
+            id: row.get("id"),
+            actor: row.get("actor"),
+            summary: row.get("summary"),
+            created_at: row.get("created_at"),
+            snapshot: snapshot.0,
+        });
+    }
+    Ok(res)
+}
+
+pub async fn fetch_policy_snapshot(
+    pool: &Pool<Postgres>,
+    revision: i32,
+) -> async_graphql::Result<PolicySnapshot> {
+    match sqlx::query("SELECT snapshot FROM policy_revisions WHERE id = $1;")
+        .bind(revision)
+        .fetch_optional(pool)
+        .await
+    {
+        Ok(Some(v)) => {
+            let snapshot: Json<PolicySnapshot> = v.get("snapshot");
+            Ok(snapshot.0)
+        }
+        Ok(None) => Err(RbacError::NotFound(format!("No policy revision {}", revision)).extend()),
+        Err(e) => {
+            println!("Error fetch_policy_snapshot = {:?}", e);
+            Err(RbacError::Internal("Failed to fetch the policy revision".to_string()).extend())
+        }
+    }
+}
+
+pub fn diff_policy(from: &PolicySnapshot, to: &PolicySnapshot) -> PolicyChanges {
+    let mut changes = PolicyChanges::default();
+    let old: BTreeMap<i32, &RoleGrant> = from.roles.iter().map(|r| (r.id, r)).collect();
+    let new: BTreeMap<i32, &RoleGrant> = to.roles.iter().map(|r| (r.id, r)).collect();
+
+    for (id, role) in old.iter() {
+        if !new.contains_key(id) {
+            changes.removed_roles.push(role.name.clone());
+            for perm in role.permissions.iter() {
+                changes.revoked.push((role.name.clone(), perm.clone()));
+            }
+        }
+    }
+    for (id, role) in new.iter() {
+        let before: BTreeSet<&String> = match old.get(id) {
+            Some(prev) => {
+                if prev.name != role.name {
+                    changes
+                        .renamed_roles
+                        .push((*id, prev.name.clone(), role.name.clone()));
+                }
+                prev.permissions.iter().collect()
+            }
+            None => {
+                changes.added_roles.push(role.name.clone());
+                BTreeSet::new()
+            }
+        };
+        let after: BTreeSet<&String> = role.permissions.iter().collect();
+        for perm in after.difference(&before) {
+            changes.granted.push((role.name.clone(), (*perm).clone()));
+        }
+        for perm in before.difference(&after) {
+            changes.revoked.push((role.name.clone(), (*perm).clone()));
+        }
+    }
+    changes
+}
+
+// restores the role names and role-permission grants of an earlier revision.
+// Roles created after that revision are kept (they may still have members) but
+// lose all of their permissions.
+pub async fn rollback_policy(
+    pool: &Pool<Postgres>,
+    revision: i32,
+    actor: &str,
+) -> async_graphql::Result<i32> {
+    let target = fetch_policy_snapshot(pool, revision).await?;
+    let internal = |e: sqlx::Error| {
+        println!("Error rollback_policy = {:?}", e);
+        RbacError::Internal("Failed to roll back the policy".to_string()).extend()
+    };
+
+    let mut tx = pool.begin().await.map_err(internal)?;
+    let ids: Vec<i32> = target.roles.iter().map(|r| r.id).collect();
+    let names: Vec<String> = target.roles.iter().map(|r| r.name.clone()).collect();
+    // a role created since then may have taken a name the rollback restores; it is kept, so
+    // the caller has to decide what happens to it
+    let blocking = sqlx::query("SELECT id, name FROM roles WHERE name = ANY($1) AND id <> ALL($2) ORDER BY id LIMIT 1;")
+        .bind(&names)
+        .bind(&ids)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(internal)?;
+    if let Some(row) = blocking {
+        let (id, name): (i32, String) = (row.get("id"), row.get("name"));
+        return Err(RbacError::Conflict(format!(
+            "Role {:?} (id {}) was created after revision {} and holds a name the rollback restores, rename or delete it first",
+            name, id, revision
+        ))
+        .extend());
+    }
+    // names that move between restored roles, e.g. after "rename A to B, then rename C to A",
+    // are freed first so restoring them one by one doesn't trip over UNIQUE(name)
+    sqlx::query(
+        "UPDATE roles r SET name = '__rollback_' || r.id
+        FROM unnest($1::INTEGER[], $2::TEXT[]) AS t(id, name)
+        WHERE r.id = t.id AND r.name <> t.name AND r.name = ANY($2);",
+    )
+    .bind(&ids)
+    .bind(&names)
+    .execute(&mut *tx)
+    .await
+    .map_err(internal)?;
+    sqlx::query("DELETE FROM role_permissions WHERE role_id <> ALL($1);")
+        .bind(&ids)
+        .execute(&mut *tx)
+        .await
+        .map_err(internal)?;
+
+    for role in target.roles.iter() {
+        sqlx::query(
+            "INSERT INTO roles (id, name) VALUES ($1, $2)
+            ON CONFLICT (id) DO UPDATE SET name = EXCLUDED.name;",
+        )
+        .bind(role.id)
+        .bind(&role.name)
+        .execute(&mut *tx)
+        .await
+        .map_err(internal)?;
+        sqlx::query("DELETE FROM role_permissions WHERE role_id = $1;")
+            .bind(role.id)
+            .execute(&mut *tx)
+            .await
+            .map_err(internal)?;
+        for perm in role.permissions.iter() {
+            sqlx::query(
+                "INSERT INTO permissions (action) SELECT $1
+                WHERE NOT EXISTS (SELECT 1 FROM permissions WHERE action = $1);",
+            )
+            .bind(perm)
+            .execute(&mut *tx)
+            .await
+            .map_err(internal)?;
+            sqlx::query(
+                "INSERT INTO role_permissions (role_id, permission_id)
+                VALUES ($1, (SELECT id FROM permissions WHERE action = $2 ORDER BY id LIMIT 1));",
+            )
+            .bind(role.id)
+            .bind(perm)
+            .execute(&mut *tx)
+            .await
+            .map_err(internal)?;
+        }
+    }
+    // keep the sequence ahead of any role id restored from the snapshot
+    sqlx::query("SELECT setval(pg_get_serial_sequence('roles', 'id'), (SELECT MAX(id) FROM roles));")
+        .execute(&mut *tx)
+        .await
+        .map_err(internal)?;
+    let id = record_policy_revision(&mut tx, actor, &format!("Rollback to revision {}", revision)).await?;
+    commit_with_event(
+        tx,
+        "policy.rolled_back",
+        serde_json::json!({ "revision": revision, "roles": target.roles }),
+    )
+    .await?;
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::db_config::{drop_test_schema, test_schema_pool},
+        utilities::errors::extension,
+    };
+
+    fn role(id: i32, name: &str, permissions: &[&str]) -> RoleGrant {
+        RoleGrant {
+            id,
+            name: name.to_string(),
+            permissions: permissions.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    fn pairs(v: &[(&str, &str)]) -> Vec<(String, String)> {
+        v.iter().map(|(r, p)| (r.to_string(), p.to_string())).collect()
+    }
+
+    #[test]
+    fn diff_finds_added_and_removed_roles() {
+        let from = PolicySnapshot {
+            roles: vec![role(1, "Admin", &["Read"]), role(2, "Auditor", &["Read"])],
+        };
+        let to = PolicySnapshot {
+            roles: vec![role(1, "Admin", &["Read"]), role(3, "Support", &["Read", "Update"])],
+        };
+        let changes = diff_policy(&from, &to);
+        assert_eq!(changes.added_roles, vec!["Support".to_string()]);
+        assert_eq!(changes.removed_roles, vec!["Auditor".to_string()]);
+        // a removed role takes its grants along, an added one brings its own
+        assert_eq!(changes.revoked, pairs(&[("Auditor", "Read")]));
+        assert_eq!(changes.granted, pairs(&[("Support", "Read"), ("Support", "Update")]));
+        assert!(changes.renamed_roles.is_empty());
+    }
+
+    #[test]
+    fn diff_finds_changed_grants_and_renames() {
+        let from = PolicySnapshot {
+            roles: vec![role(1, "Editor", &["Read", "Update"])],
+        };
+        let to = PolicySnapshot {
+            roles: vec![role(1, "Writer", &["Delete", "Read"])],
+        };
+        let changes = diff_policy(&from, &to);
+        assert_eq!(changes.renamed_roles, vec![(1, "Editor".to_string(), "Writer".to_string())]);
+        assert_eq!(changes.granted, pairs(&[("Writer", "Delete")]));
+        assert_eq!(changes.revoked, pairs(&[("Writer", "Update")]));
+        assert!(changes.added_roles.is_empty() && changes.removed_roles.is_empty());
+    }
+
+    #[test]
+    fn diff_of_a_snapshot_with_itself_is_empty() {
+        let snapshot = PolicySnapshot {
+            roles: vec![role(1, "Admin", &["Read", "Update"])],
+        };
+        let changes = diff_policy(&snapshot, &snapshot);
+        assert!(changes.added_roles.is_empty() && changes.removed_roles.is_empty() && changes.renamed_roles.is_empty());
+        assert!(changes.granted.is_empty() && changes.revoked.is_empty());
+    }
+
+    const TABLES: &[&str] = &["roles", "permissions", "role_permissions", "policy_revisions", "webhook_subscriptions", "webhook_outbox"];
+
+    async fn run(pool: &Pool<Postgres>, qry: &str) {
+        sqlx::query(qry).execute(pool).await.unwrap();
+    }
+
+    async fn revision(pool: &Pool<Postgres>, summary: &str) -> i32 {
+        let mut conn = pool.acquire().await.unwrap();
+        record_policy_revision(&mut conn, "test", summary).await.unwrap()
+    }
+
+    async fn current(pool: &Pool<Postgres>) -> PolicySnapshot {
+        let mut conn = pool.acquire().await.unwrap();
+        snapshot_policy(&mut conn).await.unwrap()
+    }
+
+    // two roles with grants, saved as a revision
+    async fn seed(pool: &Pool<Postgres>) -> i32 {
+        run(pool, "INSERT INTO permissions (id, action) VALUES (1, 'Read'), (2, 'Update'), (3, 'Delete');").await;
+        run(pool, "INSERT INTO roles (id, name) VALUES (1, 'Reader'), (2, 'Writer');").await;
+        run(pool, "INSERT INTO role_permissions (role_id, permission_id) VALUES (1, 1), (2, 1), (2, 2);").await;
+        revision(pool, "seed").await
+    }
+
+    #[tokio::test]
+    async fn rollback_restores_names_and_grants() {
+        let Some((pool, schema)) = test_schema_pool(TABLES).await else { return };
+        let seeded = seed(&pool).await;
+        let before = fetch_policy_snapshot(&pool, seeded).await.unwrap();
+        assert_eq!(before, current(&pool).await);
+
+        run(&pool, "UPDATE roles SET name = 'Scribe' WHERE id = 2;").await;
+        run(&pool, "DELETE FROM role_permissions WHERE role_id = 2 AND permission_id = 2;").await;
+        run(&pool, "INSERT INTO role_permissions (role_id, permission_id) VALUES (1, 3);").await;
+        run(&pool, "INSERT INTO roles (id, name) VALUES (3, 'Later');").await;
+        run(&pool, "INSERT INTO role_permissions (role_id, permission_id) VALUES (3, 1);").await;
+        let changed = revision(&pool, "changes").await;
+
+        let rolled = rollback_policy(&pool, seeded, "test").await.unwrap();
+        assert!(rolled > changed);
+        // the later role stays, without permissions; everything else is as it was
+        let after = current(&pool).await;
+        let changes = diff_policy(&before, &after);
+        assert_eq!(changes.added_roles, vec!["Later".to_string()]);
+        assert!(changes.removed_roles.is_empty() && changes.renamed_roles.is_empty());
+        assert!(changes.granted.is_empty() && changes.revoked.is_empty());
+        // and the rollback is a revision of its own
+        assert_eq!(fetch_policy_snapshot(&pool, rolled).await.unwrap(), after);
+        drop_test_schema(pool, &schema).await;
+    }
+
+    #[tokio::test]
+    async fn rollback_refuses_names_taken_by_newer_roles() {
+        let Some((pool, schema)) = test_schema_pool(TABLES).await else { return };
+        let seeded = seed(&pool).await;
+        run(&pool, "UPDATE roles SET name = 'Scribe' WHERE id = 2;").await;
+        run(&pool, "INSERT INTO roles (id, name) VALUES (3, 'Writer');").await;
+
+        let e = rollback_policy(&pool, seeded, "test").await.unwrap_err();
+        assert_eq!(extension(&e, "code").as_deref(), Some("CONFLICT"));
+        // nothing was touched
+        let names: Vec<String> = current(&pool).await.roles.into_iter().map(|r| r.name).collect();
+        assert_eq!(names, vec!["Reader".to_string(), "Scribe".to_string(), "Writer".to_string()]);
+        drop_test_schema(pool, &schema).await;
+    }
+
+    #[tokio::test]
+    async fn rollback_swaps_names_between_roles() {
+        let Some((pool, schema)) = test_schema_pool(TABLES).await else { return };
+        let seeded = seed(&pool).await;
+        // Reader and Writer trade names through a temporary one
+        run(&pool, "UPDATE roles SET name = 'tmp' WHERE id = 1;").await;
+        run(&pool, "UPDATE roles SET name = 'Reader' WHERE id = 2;").await;
+        run(&pool, "UPDATE roles SET name = 'Writer' WHERE id = 1;").await;
+
+        rollback_policy(&pool, seeded, "test").await.unwrap();
+        let before = fetch_policy_snapshot(&pool, seeded).await.unwrap();
+        assert_eq!(before, current(&pool).await);
+        drop_test_schema(pool, &schema).await;
+    }
+
+    #[tokio::test]
+    async fn rollback_to_a_missing_revision_is_not_found() {
+        let Some((pool, schema)) = test_schema_pool(TABLES).await else { return };
+        let e = rollback_policy(&pool, 12345, "test").await.unwrap_err();
+        assert_eq!(extension(&e, "code").as_deref(), Some("NOT_FOUND"));
+        drop_test_schema(pool, &schema).await;
+    }
+}
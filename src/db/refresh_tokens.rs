@@ -0,0 +1,149 @@
+use async_graphql::ErrorExtensions;
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Row};
+
+use crate::utilities::errors::RbacError;
+
+pub async fn insert_refresh_token(
+    pool: &PgPool,
+    user_id: i32,
+    token_hash: &str,
+    ttl_secs: i64,
+) -> async_graphql::Result<DateTime<Utc>> {
+    // expired tokens can't be replayed any more, so there is nothing left to detect with them
+    if let Err(e) = sqlx::query("DELETE FROM refresh_tokens WHERE user_id = $1 AND expires_at < now();")
+        .bind(user_id)
+        .execute(pool)
+        .await
+    {
+        println!("Error insert_refresh_token cleanup = {:?}", e);
+    }
+    let qry = "INSERT INTO refresh_tokens (user_id, token_hash, expires_at)
+        VALUES ($1, $2, now() + make_interval(secs => $3)) RETURNING expires_at;";
+    match sqlx::query(qry).bind(user_id).bind(token_hash).bind(ttl_secs as f64).fetch_one(pool).await {
+        Ok(row) => Ok(row.get("expires_at")),
+        Err(e) => {
+            println!("Error insert_refresh_token = {:?}", e);
+            Err(RbacError::Internal("Failed to start the session".to_string()).extend())
+        }
+    }
+}
+
+pub enum RefreshOutcome {
+    // the token was live and is now used up; the owner gets a new one
+    Rotated(i32),
+    // the token was already used or revoked, so it has leaked. Every session of the owner is
+    // revoked, the thief's and the user's alike
+    Reused(i32),
+    Invalid,
+}
+
+// spends a refresh token: each one can be exchanged exactly once
+pub async fn use_refresh_token(pool: &PgPool, token_hash: &str) -> async_graphql::Result<RefreshOutcome> {
+    let internal = |e: sqlx::Error| {
+        println!("Error use_refresh_token = {:?}", e);
+        RbacError::Internal("Failed to refresh the session".to_string()).extend()
+    };
+    let qry = "UPDATE refresh_tokens SET revoked_at = now()
+        WHERE token_hash = $1 AND revoked_at IS NULL AND expires_at > now() RETURNING user_id;";
+    if let Some(row) = sqlx::query(qry).bind(token_hash).fetch_optional(pool).await.map_err(internal)? {
+        return Ok(RefreshOutcome::Rotated(row.get("user_id")));
+    }
+    let qry = "SELECT user_id FROM refresh_tokens WHERE token_hash = $1 AND revoked_at IS NOT NULL;";
+    match sqlx::query(qry).bind(token_hash).fetch_optional(pool).await.map_err(internal)? {
+        Some(row) => {
+            let user_id: i32 = row.get("user_id");
+            revoke_user_refresh_tokens(pool, user_id).await?;
+            Ok(RefreshOutcome::Reused(user_id))
+        }
+        None => Ok(RefreshOutcome::Invalid),
+    }
+}
+
+// false when the token was unknown or already revoked
+pub async fn revoke_refresh_token(pool: &PgPool, token_hash: &str) -> async_graphql::Result<bool> {
+    let qry = "UPDATE refresh_tokens SET revoked_at = now() WHERE token_hash = $1 AND revoked_at IS NULL;";
+    match sqlx::query(qry).bind(token_hash).execute(pool).await {
+        Ok(v) => Ok(v.rows_affected() > 0),
+        Err(e) => {
+            println!("Error revoke_refresh_token = {:?}", e);
+            Err(RbacError::Internal("Failed to end the session".to_string()).extend())
+        }
+    }
+}
+
+pub async fn revoke_user_refresh_tokens(pool: &PgPool, user_id: i32) -> async_graphql::Result<u64> {
+    let qry = "UPDATE refresh_tokens SET revoked_at = now() WHERE user_id = $1 AND revoked_at IS NULL;";
+    match sqlx::query(qry).bind(user_id).execute(pool).await {
+        Ok(v) => Ok(v.rows_affected()),
+        Err(e) => {
+            println!("Error revoke_user_refresh_tokens = {:?}", e);
+            Err(RbacError::Internal("Failed to end the sessions of the user".to_string()).extend())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::db_config::{test_pool, test_user},
+        utilities::session::{hash_token, new_token},
+    };
+
+    async fn issue(pool: &PgPool, user_id: i32, ttl_secs: i64) -> String {
+        let hash = hash_token(&new_token());
+        insert_refresh_token(pool, user_id, &hash, ttl_secs).await.unwrap();
+        hash
+    }
+
+    async fn spend(pool: &PgPool, hash: &str) -> RefreshOutcome {
+        use_refresh_token(pool, hash).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn rotated_token_cannot_be_used_again() {
+        let Some(pool) = test_pool().await else { return };
+        let user_id = test_user(&pool).await;
+        let hash = issue(&pool, user_id, 60).await;
+        assert!(matches!(spend(&pool, &hash).await, RefreshOutcome::Rotated(v) if v == user_id));
+        assert!(matches!(spend(&pool, &hash).await, RefreshOutcome::Reused(v) if v == user_id));
+    }
+
+    #[tokio::test]
+    async fn reusing_an_old_token_revokes_every_session_of_the_user() {
+        let Some(pool) = test_pool().await else { return };
+        let (user_id, other_id) = (test_user(&pool).await, test_user(&pool).await);
+        let old = issue(&pool, user_id, 60).await;
+        assert!(matches!(spend(&pool, &old).await, RefreshOutcome::Rotated(_)));
+        // the rotated token and a session on another device
+        let rotated = issue(&pool, user_id, 60).await;
+        let device = issue(&pool, user_id, 60).await;
+        let other = issue(&pool, other_id, 60).await;
+
+        assert!(matches!(spend(&pool, &old).await, RefreshOutcome::Reused(v) if v == user_id));
+        assert!(!matches!(spend(&pool, &rotated).await, RefreshOutcome::Rotated(_)));
+        assert!(!matches!(spend(&pool, &device).await, RefreshOutcome::Rotated(_)));
+        // other users keep their sessions
+        assert!(matches!(spend(&pool, &other).await, RefreshOutcome::Rotated(v) if v == other_id));
+    }
+
+    #[tokio::test]
+    async fn expired_or_unknown_token_is_rejected() {
+        let Some(pool) = test_pool().await else { return };
+        let user_id = test_user(&pool).await;
+        let expired = issue(&pool, user_id, -1).await;
+        assert!(matches!(spend(&pool, &expired).await, RefreshOutcome::Invalid));
+        assert!(matches!(spend(&pool, &hash_token(&new_token())).await, RefreshOutcome::Invalid));
+    }
+
+    #[tokio::test]
+    async fn revoked_token_counts_as_reused() {
+        let Some(pool) = test_pool().await else { return };
+        let user_id = test_user(&pool).await;
+        let hash = issue(&pool, user_id, 60).await;
+        assert!(revoke_refresh_token(&pool, &hash).await.unwrap());
+        assert!(!revoke_refresh_token(&pool, &hash).await.unwrap());
+        assert!(matches!(spend(&pool, &hash).await, RefreshOutcome::Reused(_)));
+    }
+}
@@ -1,5 +1,10 @@
 use async_graphql::{Error, ErrorExtensions};
-use sqlx::{Pool, Postgres, Row};
+use sqlx::{PgConnection, Pool, Postgres, QueryBuilder, Row};
+
+use crate::{
+    db::pagination::{fetch_page, Page, PageCursor, SortKey},
+    utilities::errors::RbacError,
+};
 
 pub async fn init_roles(pool: &Pool<Postgres>) {
     let qry = "INSERT INTO roles(name) VALUES ('Admin'),('Viewer'),('Editor')";
@@ -10,34 +15,32 @@ pub async fn init_roles(pool: &Pool<Postgres>) {
         .expect("Error = Not able to Insert Role");
 }
 
-pub async fn insert_roles(pool: &Pool<Postgres>, name: String) -> async_graphql::Result<String> {
+pub async fn insert_roles(conn: &mut PgConnection, name: String) -> async_graphql::Result<String> {
     let check_role = match sqlx::query(
         "
     select id from roles where name = $1",
     )
     .bind(&name)
-    .fetch_optional(pool)
+    .fetch_optional(&mut *conn)
     .await
     {
         Ok(v) => v,
         Err(e) => {
-            return Err(Error::new("Internal Server Error")
-                .extend_with(|_, e| e.set("details", "Failed to check the role")));
+            println!("Error insert_roles = {:?}", e);
+            return Err(RbacError::Internal("Failed to check the role".to_string()).extend());
         }
     };
 
     if check_role.is_some() {
-        return Err(Error::new("Role Already Present")
-            .extend_with(|_, e| e.set("details", "Role already Present")));
+        return Err(RbacError::Conflict("Role Already Present".to_string()).extend());
     }
     let qry = "INSERT INTO roles(name) VALUES ($1)";
 
-    let ins_role = match sqlx::query(&qry).bind(&name).execute(pool).await {
-        Ok(v) => v,
+    match sqlx::query(qry).bind(&name).execute(conn).await {
+        Ok(_) => (),
         Err(e) => {
             println!("Error insert_role:- {:?}", e);
-            return Err(Error::new("Failed to Insert Role")
-                .extend_with(|_, e| e.set("details", "Failed to insert user")));
+            return Err(RbacError::Internal("Failed to insert role".to_string()).extend());
         }
     };
     Ok(name)
@@ -91,7 +94,7 @@ pub async fn init_role_permissions(pool: &Pool<Postgres>) {
 }
 
 pub async fn insert_role_permissions(
-    pool: &Pool<Postgres>,
+    conn: &mut PgConnection,
     role_name: String,
     permission: String,
 ) -> async_graphql::Result<()> {
@@ -101,45 +104,187 @@ pub async fn insert_role_permissions(
     )
     .bind(role_name)
     .bind(permission)
-    .execute(pool)
+    .execute(conn)
     .await{
         Ok(v) => Ok(()),
         Err(e) => {
             println!("Error = {:?}",e);
-            return Err(Error::new("Failed to Assign permissions to role").extend_with(|_,e| e.set("details", "Failed to insert permission for role")));
+            return Err(RbacError::Internal("Failed to insert permission for role".to_string()).extend());
+        }
+    }
+}
+
+pub async fn count_role_permissions(pool: &Pool<Postgres>, role_name: &str) -> async_graphql::Result<i64> {
+    match sqlx::query("select count(1) from role_permissions where role_id in (SELECT ID from roles where name = $1);")
+        .bind(role_name)
+        .fetch_one(pool)
+        .await
+    {
+        Ok(v) => Ok(v.get("count")),
+        Err(e) => {
+            println!("Error getting count of rolePermission = {:?}", e);
+            Err(RbacError::Internal("Failed to count the permissions of the role".to_string()).extend())
+        }
+    }
+}
+
+// false when the role did not grant the permission
+pub async fn remove_role_permission(conn: &mut PgConnection, role_name: &str, action: &str) -> async_graphql::Result<bool> {
+    match sqlx::query("DELETE FROM role_permissions where role_id in (SELECT ID from roles where name = $1) and permission_id in (SELECT id FROM permissions WHERE action = $2);")
+        .bind(role_name)
+        .bind(action)
+        .execute(conn)
+        .await
+    {
+        Ok(v) => Ok(v.rows_affected() > 0),
+        Err(e) => {
+            println!("Error delete role permission = {:?}", e);
+            Err(RbacError::Internal("Unable to Delete Assigned Permission".to_string()).extend())
+        }
+    }
+}
+
+pub async fn fetch_role_name(pool: &Pool<Postgres>, role_id: i32) -> async_graphql::Result<Option<String>> {
+    match sqlx::query("SELECT name FROM roles WHERE id = $1;").bind(role_id).fetch_optional(pool).await {
+        Ok(v) => Ok(v.map(|row| row.get("name"))),
+        Err(e) => {
+            println!("Error fetch_role_name = {:?}", e);
+            Err(RbacError::Internal("Failed to fetch the role".to_string()).extend())
+        }
+    }
+}
+
+pub async fn fetch_role_id(pool: &Pool<Postgres>, name: &str) -> async_graphql::Result<Option<i32>> {
+    match sqlx::query("SELECT id FROM roles WHERE name = $1;").bind(name).fetch_optional(pool).await {
+        Ok(v) => Ok(v.map(|row| row.get("id"))),
+        Err(e) => {
+            println!("Error fetch_role_id = {:?}", e);
+            Err(RbacError::Internal("Failed to fetch the role".to_string()).extend())
         }
     }
 }
 
+// the distinct actions one role grants
 pub struct RolePermission {
-    name: String,
-    action: String,
+    pub name: String,
+    pub actions: Vec<String>,
 }
 
+// role names are matched exactly, so `%` or `_` in a name is not a wildcard
+const ROLE_GRANTS_QUERY: &str = "SELECT r.name, array_agg(DISTINCT p.action ORDER BY p.action) AS actions
+    FROM roles r
+    JOIN role_permissions rp ON rp.role_id = r.id
+    JOIN permissions p ON p.id = rp.permission_id";
+
+fn role_grants_error(e: sqlx::Error) -> Error {
+    println!("Error fetch_role_grants = {:?}", e);
+    RbacError::Internal("Failed to fetch the roles permission for the user".to_string()).extend()
+}
+
+fn role_grants(rows: Vec<sqlx::postgres::PgRow>) -> Vec<RolePermission> {
+    rows.into_iter()
+        .map(|row| RolePermission {
+            name: row.get("name"),
+            actions: row.get("actions"),
+        })
+        .collect()
+}
+
+// permissions of each of the given roles, in one query
+pub async fn fetch_role_grants(
+    pool: &Pool<Postgres>,
+    role_names: &[String],
+) -> async_graphql::Result<Vec<RolePermission>> {
+    let qry = format!("{} WHERE r.name = ANY($1) GROUP BY r.name ORDER BY r.name;", ROLE_GRANTS_QUERY);
+    let data = sqlx::query(&qry)
+        .bind(role_names)
+        .fetch_all(pool)
+        .await
+        .map_err(role_grants_error)?;
+    Ok(role_grants(data))
+}
+
+// permissions of every role the user holds, in one query
+pub async fn fetch_user_role_grants(
+    pool: &Pool<Postgres>,
+    user_id: i32,
+) -> async_graphql::Result<Vec<RolePermission>> {
+    let qry = format!(
+        "{} JOIN user_roles ur ON ur.role_id = r.id WHERE ur.user_id = $1 GROUP BY r.name ORDER BY r.name;",
+        ROLE_GRANTS_QUERY
+    );
+    let data = sqlx::query(&qry)
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+        .map_err(role_grants_error)?;
+    Ok(role_grants(data))
+}
+
+// union of the permissions of the given roles, without duplicates
 pub async fn fetch_role_permission(
     pool: &Pool<Postgres>,
     role_name: Vec<String>,
 ) -> async_graphql::Result<Vec<String>> {
-    let mut role_permissions: Vec<String> = vec![];
-    for i in role_name {
-        let qry = "select a.name,b.action from roles a, permissions b, role_permissions c where a.name like $1 and a.id= c.role_id and b.id = c.permission_id;";
-        match sqlx::query(qry).bind(&i).fetch_all(pool).await {
-            Ok(v) => {
-                // let mut role_per: Vec<RolePermission> = Vec::new();
-                for i in v {
-                    role_permissions.push(i.get("action"));
-                }
-            }
-            Err(e) => {
-                println!("Error fetch_role_permission = {:?}", e);
-                return Err(Error::new("Internal Server Error").extend_with(|_, e| {
-                    e.set(
-                        "details",
-                        "Failed to fetch the roles permission for the user",
-                    )
-                }));
-            }
-        }
-    }
+    let mut role_permissions: Vec<String> = fetch_role_grants(pool, &role_name)
+        .await?
+        .into_iter()
+        .flat_map(|r| r.actions)
+        .collect();
+    role_permissions.sort();
+    role_permissions.dedup();
     Ok(role_permissions)
 }
+
+// (id, action) of each permission granted to the role, or None when the role does not exist
+pub async fn fetch_role_permissions_by_id(
+    pool: &Pool<Postgres>,
+    role_id: i32,
+) -> async_graphql::Result<Option<Vec<(i32, String)>>> {
+    let data = sqlx::query(
+        "SELECT p.id, p.action FROM roles r
+        LEFT JOIN role_permissions rp ON rp.role_id = r.id
+        LEFT JOIN permissions p ON p.id = rp.permission_id
+        WHERE r.id = $1 ORDER BY p.action;",
+    )
+    .bind(role_id)
+    .fetch_all(pool)
+    .await
+    .map_err(role_grants_error)?;
+    if data.is_empty() {
+        return Ok(None);
+    }
+    // a role without grants comes back as a single row of NULLs
+    let permissions = data
+        .iter()
+        .filter_map(|row| {
+            let id: Option<i32> = row.get("id");
+            id.map(|id| (id, row.get("action")))
+        })
+        .collect();
+    Ok(Some(permissions))
+}
+
+fn push_role_filters(qry: &mut QueryBuilder<'_, Postgres>, name_contains: Option<&str>) {
+    qry.push(" WHERE TRUE");
+    if let Some(name) = name_contains {
+        qry.push(" AND strpos(lower(name), lower(").push_bind(name.to_string()).push(")) > 0");
+    }
+}
+
+pub async fn fetch_roles_page(
+    pool: &Pool<Postgres>,
+    name_contains: Option<&str>,
+    sort: &SortKey,
+    after: Option<PageCursor>,
+    first: i64,
+) -> async_graphql::Result<Page> {
+    let mut select = QueryBuilder::new(format!(
+        "SELECT id, name, {}::text AS sort_key FROM roles",
+        sort.column
+    ));
+    push_role_filters(&mut select, name_contains);
+    let mut count = QueryBuilder::new("SELECT count(1) FROM roles");
+    push_role_filters(&mut count, name_contains);
+    fetch_page(pool, select, count, sort, after, first).await
+}
@@ -1,10 +1,25 @@
 use async_graphql::{Error, ErrorExtensions};
 use hmac::{Hmac, Mac};
 use sha2::Sha256;
-use sqlx::{Pool, Postgres, Row};
+use chrono::{DateTime, Utc};
+use sqlx::{PgConnection, Pool, Postgres, QueryBuilder, Row};
+
+use crate::{
+    db::pagination::{fetch_page, Page, PageCursor, SortKey},
+    graphql::queries::User,
+    utilities::errors::RbacError,
+};
+
+#[derive(Default)]
+pub struct UserQuery {
+    pub name_contains: Option<String>,
+    pub email_contains: Option<String>,
+    pub has_role: Option<String>,
+    pub created_after: Option<DateTime<Utc>>,
+}
 
 pub async fn insert_users(
-    pool: &Pool<Postgres>,
+    conn: &mut PgConnection,
     name: String,
     email: String,
     passwd: String,
@@ -14,17 +29,18 @@ pub async fn insert_users(
     Select id from USERS where name = $1;",
     )
     .bind(&name)
-    .fetch_optional(pool)
+    .fetch_optional(&mut *conn)
     .await
     {
         Ok(v) => v,
         Err(e) => {
-            return Err(e.into());
+            println!("Error insert_users = {:?}", e);
+            return Err(RbacError::Internal("Failed to check the user".to_string()).extend());
         }
     };
 
     if check_res.is_some() {
-        return Err("User Already Presnet".into());
+        return Err(RbacError::Conflict("User Already Present".to_string()).extend());
     }
 
     match sqlx::query(
@@ -33,38 +49,44 @@ pub async fn insert_users(
     )
     .bind(&name)
     .bind(&email)
-    .bind(&passwd)
-    .execute(pool)
+    .bind(hash_password(&passwd))
+    .execute(conn)
     .await
     {
         Ok(_) => Ok(()),
         Err(e) => {
-            return Err(e.into());
+            println!("Error insert_users = {:?}", e);
+            Err(RbacError::Internal("Failed to insert user".to_string()).extend())
         }
     }
 }
 
-pub async fn ensure_admin_exists(pool: &Pool<Postgres>) {
+pub fn hash_password(passwd: &str) -> String {
     type HmacSha256 = Hmac<Sha256>;
     let mut mac = HmacSha256::new_from_slice(b"gaurav")
     .expect("HMAC can take key of any size");
-    mac.update(b"Admin");
-    let passwd = hex::encode(mac.finalize().into_bytes());
+    mac.update(passwd.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+pub async fn ensure_admin_exists(pool: &Pool<Postgres>) {
+    let mut conn = pool.acquire().await.expect("Failed to acquire a connection");
+    // insert_users hashes the password itself
     insert_users(
-        pool,
+        &mut conn,
         "Admin".to_string(),
         "admin@test.com".to_string(),
-        passwd,
+        "Admin".to_string(),
     )
     .await
     .expect("Failed to insert admin user");
-    insert_role_user(pool, "Admin".to_string(), "Admin".to_string())
+    insert_role_user(&mut conn, "Admin".to_string(), "Admin".to_string())
         .await
         .expect("Faild to assign admin a role");
 }
 
 pub async fn insert_role_user(
-    pool: &Pool<Postgres>,
+    conn: &mut PgConnection,
     username: String,
     role: String,
 ) -> async_graphql::Result<()> {
@@ -75,14 +97,43 @@ pub async fn insert_role_user(
     )
     .bind(username)
     .bind(role)
-    .execute(pool)
+    .execute(conn)
     .await
     {
         Ok(v) => Ok(()),
         Err(e) => {
             println!("Error = {:?}", e);
-            return Err(Error::new("Role not found")
-                .extend_with(|_, e| e.set("details", "Failed to insert role for user")));
+            return Err(RbacError::NotFound("Role not found".to_string()).extend());
+        }
+    }
+}
+
+pub async fn count_user_roles(pool: &Pool<Postgres>, username: &str) -> async_graphql::Result<i64> {
+    match sqlx::query("select count(1) from user_roles where user_id in (SELECT ID from USERS where name = $1);")
+        .bind(username)
+        .fetch_one(pool)
+        .await
+    {
+        Ok(v) => Ok(v.get("count")),
+        Err(e) => {
+            println!("Error getting count of userRole = {:?}", e);
+            Err(RbacError::Internal("Failed to count the roles of the user".to_string()).extend())
+        }
+    }
+}
+
+// false when the user did not hold the role
+pub async fn remove_role_user(conn: &mut PgConnection, username: &str, role: &str) -> async_graphql::Result<bool> {
+    match sqlx::query("DELETE FROM user_roles where user_id in (SELECT ID from USERS where name = $1) and role_id in (SELECT id FROM roles WHERE name = $2);")
+        .bind(username)
+        .bind(role)
+        .execute(conn)
+        .await
+    {
+        Ok(v) => Ok(v.rows_affected() > 0),
+        Err(e) => {
+            println!("Error delete user role = {:?}", e);
+            Err(RbacError::Internal("Unable to Delete Assigned Role".to_string()).extend())
         }
     }
 }
@@ -93,46 +144,38 @@ pub struct UserInfo {
     pub id: i32,
     pub email: String,
     pub role: Vec<String>,
+    // created while verification was required, and not verified yet
+    pub email_unverified: bool,
 }
 pub async fn check_user_info(
     pool: &Pool<Postgres>,
     email: String,
     passwd: String,
 ) -> async_graphql::Result<UserInfo> {
-    let qry = "SELECT EXISTS (SELECT * FROM USERS WHERE email = $1);";
-    let res = match sqlx::query(&qry).bind(&email).fetch_one(pool).await {
+    // unknown emails and wrong passwords get the same answer, so login can't be used to find
+    // out which accounts exist. The LEFT JOINs let a user without roles log in too
+    let qry = "SELECT a.id,a.password_hash,a.email_verification_required AND a.email_verified_at IS NULL AS unverified,c.name from users a LEFT JOIN user_roles b ON a.id = b.user_id LEFT JOIN roles c ON b.role_id = c.id where a.email=$1;";
+    let res = match sqlx::query(&qry).bind(&email).fetch_all(pool).await {
         Ok(v) => v,
         Err(e) => {
-            return Err(Error::new("User Does not exists")
-                .extend_with(|_, e| e.set("details", "User Not Found")));
+            println!("Error check_user_info = {:?}", e);
+            return Err(RbacError::Internal("Failed to check the user".to_string()).extend());
         }
     };
-    let user_exists: bool = res.get("exists");
-    if !user_exists {
-        return Err(Error::new("User Does not exists")
-            .extend_with(|_, e| e.set("details", "User Not Found")));
-    }
-
-    let qry = "SELECT a.id,a.password_hash,c.name from users a , user_roles b, roles c where a.id = b.user_id and b.role_id =c.id  and a.email=$1;";
-    let res = sqlx::query(&qry)
-        .bind(&email)
-        .fetch_all(pool)
-        .await
-        .expect("Error:- Failed to fetch the id and password hash");
-    let db_passwd: String = res.get(0).unwrap().get("password_hash");
-    type HmacSha256 = Hmac<Sha256>;
-    let mut mac = HmacSha256::new_from_slice(b"gaurav").expect("HMAC can take key of any size");
-    mac.update(passwd.as_bytes());
-    let passwd = hex::encode(mac.finalize().into_bytes());
-    if passwd != db_passwd {
-        return Err(
-            Error::new("Wrong Password").extend_with(|_, e| e.set("details", "Wrong Credentials"))
-        );
+    let invalid = || RbacError::Unauthenticated("Invalid email or password".to_string()).extend();
+    let Some(user) = res.first() else {
+        return Err(invalid());
+    };
+    let db_passwd: String = user.get("password_hash");
+    if hash_password(&passwd) != db_passwd {
+        return Err(invalid());
     }
-    let uid: i32 = res.get(0).unwrap().get("id");
+    let uid: i32 = user.get("id");
     let mut roles: Vec<String> = Vec::new();
     for row in res.iter() {
-        roles.push(row.get("name"));
+        if let Some(name) = row.get::<Option<String>, _>("name") {
+            roles.push(name);
+        }
     }
     return Ok(UserInfo {
         status: true,
@@ -140,5 +183,161 @@ pub async fn check_user_info(
         id: uid,
         email,
         role: roles,
+        email_unverified: user.get("unverified"),
     });
 }
+
+pub async fn fetch_user_roles(pool: &Pool<Postgres>, user_id: i32) -> async_graphql::Result<Vec<String>> {
+    match sqlx::query("SELECT b.name FROM user_roles a, roles b WHERE a.role_id = b.id AND a.user_id = $1 ORDER BY b.name;")
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+    {
+        Ok(v) => Ok(v.iter().map(|row| row.get("name")).collect()),
+        Err(e) => {
+            println!("Error fetch_user_roles = {:?}", e);
+            Err(RbacError::Internal("Failed to fetch the roles of the user".to_string()).extend())
+        }
+    }
+}
+
+pub async fn fetch_user_email(pool: &Pool<Postgres>, user_id: i32) -> async_graphql::Result<Option<String>> {
+    match sqlx::query("SELECT email FROM users WHERE id = $1;")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+    {
+        Ok(v) => Ok(v.map(|row| row.get("email"))),
+        Err(e) => {
+            println!("Error fetch_user_email = {:?}", e);
+            Err(RbacError::Internal("Failed to fetch the user".to_string()).extend())
+        }
+    }
+}
+
+// stamps last_login_at and returns the user as a session sees it
+pub async fn record_login(pool: &Pool<Postgres>, user_id: i32) -> async_graphql::Result<User> {
+    match sqlx::query_as::<_, User>(
+        "UPDATE users SET last_login_at = now() WHERE id = $1 RETURNING id, name, email, created_at, last_login_at, email_verified_at;",
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await
+    {
+        Ok(v) => Ok(v),
+        Err(e) => {
+            println!("Error record_login = {:?}", e);
+            Err(RbacError::Internal("Failed to record the login".to_string()).extend())
+        }
+    }
+}
+
+pub async fn fetch_user_by_id(pool: &Pool<Postgres>, user_id: i32) -> async_graphql::Result<Option<User>> {
+    match sqlx::query_as::<_, User>("SELECT id, name, email, created_at, last_login_at, email_verified_at FROM users WHERE id = $1;")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+    {
+        Ok(v) => Ok(v),
+        Err(e) => {
+            println!("Error fetch_user_by_id = {:?}", e);
+            Err(RbacError::Internal("Failed to fetch the user".to_string()).extend())
+        }
+    }
+}
+
+// stores the hash of `passwd`
+pub async fn set_password(pool: &Pool<Postgres>, user_id: i32, passwd: &str) -> async_graphql::Result<()> {
+    match sqlx::query("UPDATE users SET password_hash = $1 WHERE id = $2;")
+        .bind(hash_password(passwd))
+        .bind(user_id)
+        .execute(pool)
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            println!("Error set_password = {:?}", e);
+            Err(RbacError::Internal("Unable to change Password".to_string()).extend())
+        }
+    }
+}
+
+// false when the email was already verified
+pub async fn mark_email_verified(pool: &Pool<Postgres>, user_id: i32) -> async_graphql::Result<bool> {
+    match sqlx::query("UPDATE users SET email_verified_at = now() WHERE id = $1 AND email_verified_at IS NULL;")
+        .bind(user_id)
+        .execute(pool)
+        .await
+    {
+        Ok(v) => Ok(v.rows_affected() > 0),
+        Err(e) => {
+            println!("Error mark_email_verified = {:?}", e);
+            Err(RbacError::Internal("Failed to verify the email".to_string()).extend())
+        }
+    }
+}
+
+// keeps a new user from logging in until their email is verified
+pub async fn require_email_verification(conn: &mut PgConnection, name: &str) -> async_graphql::Result<()> {
+    match sqlx::query("UPDATE users SET email_verification_required = TRUE WHERE name = $1;")
+        .bind(name)
+        .execute(conn)
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            println!("Error require_email_verification = {:?}", e);
+            Err(RbacError::Internal("Failed to require email verification".to_string()).extend())
+        }
+    }
+}
+
+pub async fn fetch_user_id_by_email(pool: &Pool<Postgres>, email: &str) -> async_graphql::Result<Option<i32>> {
+    match sqlx::query("SELECT id FROM users WHERE email = $1;")
+        .bind(email)
+        .fetch_optional(pool)
+        .await
+    {
+        Ok(v) => Ok(v.map(|row| row.get("id"))),
+        Err(e) => {
+            println!("Error fetch_user_id_by_email = {:?}", e);
+            Err(RbacError::Internal("Failed to fetch the user".to_string()).extend())
+        }
+    }
+}
+
+// strpos keeps `%` and `_` in the search text literal
+fn push_user_filters(qry: &mut QueryBuilder<'_, Postgres>, filter: &UserQuery) {
+    qry.push(" WHERE TRUE");
+    if let Some(name) = &filter.name_contains {
+        qry.push(" AND strpos(lower(name), lower(").push_bind(name.clone()).push(")) > 0");
+    }
+    if let Some(email) = &filter.email_contains {
+        qry.push(" AND strpos(lower(email), lower(").push_bind(email.clone()).push(")) > 0");
+    }
+    if let Some(role) = &filter.has_role {
+        qry.push(" AND EXISTS (SELECT 1 FROM user_roles ur JOIN roles r ON r.id = ur.role_id WHERE ur.user_id = users.id AND r.name = ")
+            .push_bind(role.clone())
+            .push(")");
+    }
+    if let Some(after) = filter.created_after {
+        qry.push(" AND created_at > ").push_bind(after);
+    }
+}
+
+pub async fn fetch_users_page(
+    pool: &Pool<Postgres>,
+    filter: &UserQuery,
+    sort: &SortKey,
+    after: Option<PageCursor>,
+    first: i64,
+) -> async_graphql::Result<Page> {
+    let mut select = QueryBuilder::new(format!(
+        "SELECT id, name, email, created_at, last_login_at, email_verified_at, {}::text AS sort_key FROM users",
+        sort.column
+    ));
+    push_user_filters(&mut select, filter);
+    let mut count = QueryBuilder::new("SELECT count(1) FROM users");
+    push_user_filters(&mut count, filter);
+    fetch_page(pool, select, count, sort, after, first).await
+}
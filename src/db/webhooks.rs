@@ -0,0 +1,223 @@
+use async_graphql::{Error, ErrorExtensions};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde_json::Value;
+use sqlx::{types::Json, PgConnection, Pool, Postgres, Row, Transaction};
+
+use crate::utilities::{errors::RbacError, webhooks::retry_delay};
+
+// a delivery is given up on (and shows up in webhook_dead_letters) after this many attempts
+pub const MAX_DELIVERY_ATTEMPTS: i32 = 8;
+
+pub struct WebhookRow {
+    pub id: i32,
+    pub url: String,
+    pub events: Vec<String>,
+    pub active: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+pub struct DeliveryRow {
+    pub id: i64,
+    pub subscription_id: i32,
+    pub url: String,
+    pub secret: String,
+    pub event: String,
+    pub payload: Value,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+fn internal(details: &'static str) -> impl Fn(sqlx::Error) -> Error {
+    move |e| {
+        println!("Error webhooks = {:?}", e);
+        RbacError::Internal(details.to_string()).extend()
+    }
+}
+
+// queues `event` for every active subscription listening to it, inside the caller's transaction
+pub async fn enqueue_webhook_event(
+    conn: &mut PgConnection,
+    event: &str,
+    payload: Value,
+) -> async_graphql::Result<()> {
+    sqlx::query(
+        "INSERT INTO webhook_outbox (subscription_id, event, payload)
+        SELECT id, $1, $2 FROM webhook_subscriptions
+        WHERE active AND (cardinality(events) = 0 OR $1 = ANY(events));",
+    )
+    .bind(event)
+    .bind(Json(payload))
+    .execute(conn)
+    .await
+    .map_err(internal("Failed to queue the webhook notification"))?;
+    Ok(())
+}
+
+// queues the notification and commits it together with the change it describes
+pub async fn commit_with_event(
+    mut tx: Transaction<'_, Postgres>,
+    event: &str,
+    payload: Value,
+) -> async_graphql::Result<()> {
+    enqueue_webhook_event(&mut tx, event, payload).await?;
+    tx.commit()
+        .await
+        .map_err(internal("Failed to commit the change"))?;
+    Ok(())
+}
+
+pub async fn insert_webhook(
+    pool: &Pool<Postgres>,
+    url: &str,
+    events: &[String],
+) -> async_graphql::Result<(WebhookRow, String)> {
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    let secret = hex::encode(secret);
+    let row = sqlx::query(
+        "INSERT INTO webhook_subscriptions (url, secret, events) VALUES ($1, $2, $3)
+        RETURNING id, url, events, active, created_at;",
+    )
+    .bind(url)
+    .bind(&secret)
+    .bind(events)
+    .fetch_one(pool)
+    .await
+    .map_err(internal("Failed to add the webhook"))?;
+    Ok((
+        WebhookRow {
+            id: row.get("id"),
+            url: row.get("url"),
+            events: row.get("events"),
+            active: row.get("active"),
+            created_at: row.get("created_at"),
+        },
+        secret,
+    ))
+}
+
+pub async fn fetch_webhooks(pool: &Pool<Postgres>) -> async_graphql::Result<Vec<WebhookRow>> {
+    let data = sqlx::query(
+        "SELECT id, url, events, active, created_at FROM webhook_subscriptions ORDER BY id;",
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(internal("Failed to fetch the webhooks"))?;
+    Ok(data
+        .into_iter()
+        .map(|row| WebhookRow {
+            id: row.get("id"),
+            url: row.get("url"),
+            events: row.get("events"),
+            active: row.get("active"),
+            created_at: row.get("created_at"),
+        })
+        .collect())
+}
+
+pub async fn delete_webhook(pool: &Pool<Postgres>, id: i32) -> async_graphql::Result<bool> {
+    let res = sqlx::query("DELETE FROM webhook_subscriptions WHERE id = $1;")
+        .bind(id)
+        .execute(pool)
+        .await
+        .map_err(internal("Failed to remove the webhook"))?;
+    Ok(res.rows_affected() > 0)
+}
+
+pub async fn fetch_dead_letters(pool: &Pool<Postgres>) -> async_graphql::Result<Vec<DeliveryRow>> {
+    let data = sqlx::query(
+        "SELECT id, subscription_id, url, secret, event, payload, attempts, last_error, created_at
+        FROM webhook_dead_letters ORDER BY id DESC;",
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(internal("Failed to fetch the failed deliveries"))?;
+    Ok(data.iter().map(delivery_row).collect())
+}
+
+// puts a dead delivery back in the queue with a fresh attempt budget
+pub async fn retry_delivery(pool: &Pool<Postgres>, id: i64) -> async_graphql::Result<bool> {
+    let res = sqlx::query(
+        "UPDATE webhook_outbox SET status = 'pending', attempts = 0, next_attempt_at = now()
+        WHERE id = $1 AND status = 'dead';",
+    )
+    .bind(id)
+    .execute(pool)
+    .await
+    .map_err(internal("Failed to retry the delivery"))?;
+    Ok(res.rows_affected() > 0)
+}
+
+// locks due deliveries so concurrent workers (or server instances) never send the same one twice
+pub async fn claim_due_deliveries(
+    tx: &mut Transaction<'_, Postgres>,
+    limit: i64,
+) -> Result<Vec<DeliveryRow>, sqlx::Error> {
+    let data = sqlx::query(
+        "SELECT o.id, o.subscription_id, s.url, s.secret, o.event, o.payload, o.attempts, o.last_error, o.created_at
+        FROM webhook_outbox o JOIN webhook_subscriptions s ON s.id = o.subscription_id
+        WHERE o.status = 'pending' AND o.next_attempt_at <= now() AND s.active
+        ORDER BY o.id LIMIT $1
+        FOR UPDATE OF o SKIP LOCKED;",
+    )
+    .bind(limit)
+    .fetch_all(&mut **tx)
+    .await?;
+    Ok(data.iter().map(delivery_row).collect())
+}
+
+pub async fn mark_delivered(tx: &mut Transaction<'_, Postgres>, id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE webhook_outbox SET status = 'delivered', attempts = attempts + 1, delivered_at = now(), last_error = NULL
+        WHERE id = $1;",
+    )
+    .bind(id)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+// retried on the retry_delay schedule, then dead-lettered
+pub async fn mark_failed(
+    tx: &mut Transaction<'_, Postgres>,
+    delivery: &DeliveryRow,
+    error: &str,
+) -> Result<(), sqlx::Error> {
+    let attempts = delivery.attempts + 1;
+    let status = if attempts >= MAX_DELIVERY_ATTEMPTS {
+        "dead"
+    } else {
+        "pending"
+    };
+    let delay = retry_delay(attempts);
+    sqlx::query(
+        "UPDATE webhook_outbox SET status = $1, attempts = $2, last_error = $3,
+        next_attempt_at = now() + make_interval(secs => $4)
+        WHERE id = $5;",
+    )
+    .bind(status)
+    .bind(attempts)
+    .bind(error)
+    .bind(delay as f64)
+    .bind(delivery.id)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+fn delivery_row(row: &sqlx::postgres::PgRow) -> DeliveryRow {
+    let payload: Json<Value> = row.get("payload");
+    DeliveryRow {
+        id: row.get("id"),
+        subscription_id: row.get("subscription_id"),
+        url: row.get("url"),
+        secret: row.get("secret"),
+        event: row.get("event"),
+        payload: payload.0,
+        attempts: row.get("attempts"),
+        last_error: row.get("last_error"),
+        created_at: row.get("created_at"),
+    }
+}
@@ -0,0 +1,146 @@
+use std::sync::Arc;
+
+use async_graphql::{
+    async_trait,
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextValidation},
+    ServerError, ValidationResult,
+};
+
+use crate::utilities::errors::{request_error, RbacError};
+
+// assumed length of a nested list the client can't bound (User.roles, Roles.members, ...)
+pub const NESTED_LIST_COST: i64 = 10;
+// assumed length of a top-level list that returns every row (fetchAllUser, ...)
+pub const UNBOUNDED_LIST_COST: i64 = 100;
+
+// complexity of a list field: `len` copies of the selection below it. Saturates, because
+// async-graphql adds the costs up with `+` and an absurd `first` must not overflow the sum
+pub fn list_cost(len: i64, child_complexity: usize) -> usize {
+    (len.max(0) as usize)
+        .saturating_mul(child_complexity)
+        .min(u32::MAX as usize)
+}
+
+// Rejects documents whose depth or complexity is above the configured limits, 0 meaning no limit.
+// Schema::limit_depth / limit_complexity would do the same check, but their errors carry no
+// `extensions.code`, so clients couldn't tell them apart from other failures.
+pub struct QueryLimits {
+    pub max_depth: usize,
+    pub max_complexity: usize,
+}
+
+impl ExtensionFactory for QueryLimits {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(QueryLimitsExtension {
+            max_depth: self.max_depth,
+            max_complexity: self.max_complexity,
+        })
+    }
+}
+
+struct QueryLimitsExtension {
+    max_depth: usize,
+    max_complexity: usize,
+}
+
+#[async_trait::async_trait]
+impl Extension for QueryLimitsExtension {
+    async fn validation(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        next: NextValidation<'_>,
+    ) -> Result<ValidationResult, Vec<ServerError>> {
+        let result = next.run(ctx).await?;
+        if self.max_depth > 0 && result.depth > self.max_depth {
+            return Err(vec![request_error(RbacError::QueryTooDeep {
+                depth: result.depth,
+                limit: self.max_depth,
+            })]);
+        }
+        if self.max_complexity > 0 && result.complexity > self.max_complexity {
+            return Err(vec![request_error(RbacError::QueryTooComplex {
+                complexity: result.complexity,
+                limit: self.max_complexity,
+            })]);
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::{EmptyMutation, EmptySubscription, Object, Response, Schema, Value};
+
+    use super::*;
+    use crate::graphql::{mutations::Mutation, queries::Query, subscriptions::Subscription};
+
+    // validation rejects before any resolver runs, so the real schema needs no database here
+    async fn execute(max_depth: usize, max_complexity: usize, query: &str) -> Response {
+        Schema::build(Query, Mutation, Subscription)
+            .extension(QueryLimits { max_depth, max_complexity })
+            .finish()
+            .execute(query)
+            .await
+    }
+
+    fn extension(res: &Response, name: &str) -> Value {
+        assert_eq!(res.errors.len(), 1, "{:?}", res.errors);
+        res.errors[0].extensions.as_ref().unwrap().get(name).unwrap().clone()
+    }
+
+    struct Node;
+
+    #[Object]
+    impl Node {
+        async fn value(&self) -> i32 {
+            1
+        }
+
+        async fn child(&self) -> Node {
+            Node
+        }
+    }
+
+    #[tokio::test]
+    async fn over_deep_query_is_rejected() {
+        let res = execute(3, 0, "{ users { edges { node { roles { name } } } } }").await;
+        assert_eq!(res.data, Value::Null);
+        assert_eq!(extension(&res, "code"), Value::from("QUERY_TOO_DEEP"));
+        assert_eq!(extension(&res, "limit"), Value::from(3));
+    }
+
+    #[tokio::test]
+    async fn over_complex_query_is_rejected() {
+        // 500 users with 10 assumed roles each is far beyond 1000
+        let res = execute(0, 1000, "{ users(first: 500) { edges { node { roles { name } } } } }").await;
+        assert_eq!(res.data, Value::Null);
+        assert_eq!(extension(&res, "code"), Value::from("QUERY_TOO_COMPLEX"));
+        assert_eq!(extension(&res, "limit"), Value::from(1000));
+    }
+
+    #[tokio::test]
+    async fn queries_within_the_limits_run() {
+        let res = execute(2, 5, "{ __typename }").await;
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+    }
+
+    #[tokio::test]
+    async fn zero_turns_a_limit_off() {
+        let deep = "{ child { child { child { child { child { value } } } } } }";
+        let limited = Schema::build(Node, EmptyMutation, EmptySubscription)
+            .extension(QueryLimits { max_depth: 3, max_complexity: 3 })
+            .finish();
+        assert_eq!(extension(&limited.execute(deep).await, "code"), Value::from("QUERY_TOO_DEEP"));
+        let unlimited = Schema::build(Node, EmptyMutation, EmptySubscription)
+            .extension(QueryLimits { max_depth: 0, max_complexity: 0 })
+            .finish();
+        assert!(unlimited.execute(deep).await.errors.is_empty());
+    }
+
+    #[test]
+    fn list_cost_multiplies_and_saturates() {
+        assert_eq!(list_cost(NESTED_LIST_COST, 3), 30);
+        assert_eq!(list_cost(-5, 3), 0);
+        assert_eq!(list_cost(i64::MAX, usize::MAX), u32::MAX as usize);
+    }
+}
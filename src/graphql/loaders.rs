@@ -0,0 +1,173 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_graphql::{dataloader::Loader, Error, ErrorExtensions};
+use sqlx::{PgPool, Row};
+
+use crate::{
+    graphql::queries::{Permissions, Roles, User},
+    utilities::errors::RbacError,
+};
+
+// the roles held by a user
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UserRolesKey(pub i32);
+
+// the permissions granted to a role
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RolePermissionsKey(pub i32);
+
+// the users holding a role
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RoleMembersKey(pub i32);
+
+// the roles granting a permission
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PermissionRolesKey(pub i32);
+
+// batches every nested lookup of one tick into a single `= ANY($1)` query per key type
+pub struct RbacLoader {
+    pub pool: PgPool,
+}
+
+pub fn loader_error(e: Arc<sqlx::Error>) -> Error {
+    println!("Error loader = {:?}", e);
+    RbacError::Internal("Failed to load the related records".to_string()).extend()
+}
+
+// every requested key gets an entry, so parents without children resolve to an empty list
+fn group<K, V>(keys: &[K], rows: Vec<(K, V)>) -> HashMap<K, Vec<V>>
+where
+    K: Copy + Eq + std::hash::Hash,
+{
+    let mut res: HashMap<K, Vec<V>> = keys.iter().map(|k| (*k, Vec::new())).collect();
+    for (k, v) in rows {
+        res.entry(k).or_default().push(v);
+    }
+    res
+}
+
+impl Loader<UserRolesKey> for RbacLoader {
+    type Value = Vec<Roles>;
+    type Error = Arc<sqlx::Error>;
+
+    async fn load(&self, keys: &[UserRolesKey]) -> Result<HashMap<UserRolesKey, Self::Value>, Self::Error> {
+        let ids: Vec<i32> = keys.iter().map(|k| k.0).collect();
+        let data = sqlx::query(
+            "SELECT ur.user_id, r.id, r.name FROM user_roles ur JOIN roles r ON r.id = ur.role_id
+            WHERE ur.user_id = ANY($1) ORDER BY r.name;",
+        )
+        .bind(&ids)
+        .fetch_all(&self.pool)
+        .await?;
+        let rows = data
+            .iter()
+            .map(|row| {
+                (
+                    UserRolesKey(row.get("user_id")),
+                    Roles {
+                        id: row.get("id"),
+                        name: row.get("name"),
+                    },
+                )
+            })
+            .collect();
+        Ok(group(keys, rows))
+    }
+}
+
+impl Loader<RolePermissionsKey> for RbacLoader {
+    type Value = Vec<Permissions>;
+    type Error = Arc<sqlx::Error>;
+
+    async fn load(
+        &self,
+        keys: &[RolePermissionsKey],
+    ) -> Result<HashMap<RolePermissionsKey, Self::Value>, Self::Error> {
+        let ids: Vec<i32> = keys.iter().map(|k| k.0).collect();
+        let data = sqlx::query(
+            "SELECT rp.role_id, p.id, p.action FROM role_permissions rp JOIN permissions p ON p.id = rp.permission_id
+            WHERE rp.role_id = ANY($1) ORDER BY p.action;",
+        )
+        .bind(&ids)
+        .fetch_all(&self.pool)
+        .await?;
+        let rows = data
+            .iter()
+            .map(|row| {
+                (
+                    RolePermissionsKey(row.get("role_id")),
+                    Permissions {
+                        id: row.get("id"),
+                        action: row.get("action"),
+                    },
+                )
+            })
+            .collect();
+        Ok(group(keys, rows))
+    }
+}
+
+impl Loader<RoleMembersKey> for RbacLoader {
+    type Value = Vec<User>;
+    type Error = Arc<sqlx::Error>;
+
+    async fn load(&self, keys: &[RoleMembersKey]) -> Result<HashMap<RoleMembersKey, Self::Value>, Self::Error> {
+        let ids: Vec<i32> = keys.iter().map(|k| k.0).collect();
+        let data = sqlx::query(
+            "SELECT ur.role_id, u.id, u.name, u.email, u.created_at, u.last_login_at, u.email_verified_at FROM user_roles ur JOIN users u ON u.id = ur.user_id
+            WHERE ur.role_id = ANY($1) ORDER BY u.id;",
+        )
+        .bind(&ids)
+        .fetch_all(&self.pool)
+        .await?;
+        let rows = data
+            .iter()
+            .map(|row| {
+                (
+                    RoleMembersKey(row.get("role_id")),
+                    User {
+                        id: row.get("id"),
+                        name: row.get("name"),
+                        email: row.get("email"),
+                        created_at: row.get("created_at"),
+                        last_login_at: row.get("last_login_at"),
+                        email_verified_at: row.get("email_verified_at"),
+                    },
+                )
+            })
+            .collect();
+        Ok(group(keys, rows))
+    }
+}
+
+impl Loader<PermissionRolesKey> for RbacLoader {
+    type Value = Vec<Roles>;
+    type Error = Arc<sqlx::Error>;
+
+    async fn load(
+        &self,
+        keys: &[PermissionRolesKey],
+    ) -> Result<HashMap<PermissionRolesKey, Self::Value>, Self::Error> {
+        let ids: Vec<i32> = keys.iter().map(|k| k.0).collect();
+        let data = sqlx::query(
+            "SELECT rp.permission_id, r.id, r.name FROM role_permissions rp JOIN roles r ON r.id = rp.role_id
+            WHERE rp.permission_id = ANY($1) ORDER BY r.name;",
+        )
+        .bind(&ids)
+        .fetch_all(&self.pool)
+        .await?;
+        let rows = data
+            .iter()
+            .map(|row| {
+                (
+                    PermissionRolesKey(row.get("permission_id")),
+                    Roles {
+                        id: row.get("id"),
+                        name: row.get("name"),
+                    },
+                )
+            })
+            .collect();
+        Ok(group(keys, rows))
+    }
+}
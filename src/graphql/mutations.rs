@@ -1,22 +1,414 @@
-use async_graphql::{Context, Error, ErrorExtensions, Object};
+use async_graphql::{Context, ErrorExtensions, Object};
+use chrono::{DateTime, Utc};
+use serde_json::json;
 use sqlx::{PgPool , Row};
 
 use crate::{
     db::{
+        api_keys::{delete_api_key, fetch_api_key, insert_api_key},
         permissions::{self, insert_permissions},
-        roles::{fetch_role_permission, insert_role_permissions, insert_roles},
-        users::{check_user_info, insert_role_user, insert_users},
+        policy::{record_policy_revision, rollback_policy},
+        roles::{count_role_permissions, fetch_role_permission, insert_role_permissions, insert_roles, remove_role_permission},
+        login_failures::clear_login_failures,
+        mfa::{confirm_totp, delete_mfa_challenge, delete_totp, fetch_totp, insert_pending_totp, use_totp_step},
+        refresh_tokens::{revoke_refresh_token, revoke_user_refresh_tokens, use_refresh_token, RefreshOutcome},
+        account_tokens::use_account_token,
+        users::{
+            check_user_info, count_user_roles, fetch_user_by_id, fetch_user_email, fetch_user_id_by_email, insert_role_user, insert_users,
+            mark_email_verified, remove_role_user, require_email_verification, set_password,
+        },
+        webhooks::{commit_with_event, delete_webhook, insert_webhook, retry_delivery},
     },
+    graphql::queries::{ApiKey, AuthPayload, TotpConfirmation, TotpEnrollment, WebhookSubscription},
     utilities::{
+        account_mail::{check_password_len, send_email_verification, send_password_reset, EMAIL_VERIFICATION, PASSWORD_RESET},
+        api_keys::new_api_key,
+        audit::{AuditEvent, RequestMeta},
         auth::authorize,
-        jwt::{create_jwt, decode_jwt, Claims},
+        errors::{extension, parse_id, RbacError},
+        jwt::{decode_jwt, Claims},
+        login_guard::{account_key, check_login_allowed, login_failed, LoginPolicy},
+        mailer::MailSettings,
+        mfa::{
+            challenge_failed, challenge_user, check_mfa_code, invalid_mfa_token, mfa_missing, start_mfa_challenge,
+            MfaPolicy,
+        },
+        permission_cache::permission_cache,
+        session::{hash_token, invalid_refresh_token, issue_session, open_session, SessionPolicy},
+        totp::{hash_recovery_code, new_recovery_codes, new_secret, otpauth_uri, qr_png_base64, verify_code},
     },
 };
 
+// who is setting up MFA: the user of a pending login when `mfa_token` is given, else the caller
+async fn mfa_subject(ctx: &Context<'_>, action: &str, mfa_token: Option<&str>) -> async_graphql::Result<(i32, AuditEvent)> {
+    let pool = ctx.data::<PgPool>().unwrap();
+    if let Some(mfa_token) = mfa_token {
+        return match challenge_user(pool, mfa_token).await {
+            Ok(v) => Ok((v, AuditEvent::new(&v.to_string(), action))),
+            Err(e) => Err(AuditEvent::new("anonymous", action).denied(ctx, e).await),
+        };
+    }
+    let token = ctx.data::<Option<String>>().unwrap();
+    let role_perm = match authorize(pool, token.clone()).await {
+        Ok(v) => v,
+        Err(e) => {
+            println!("Error {}:- {:?}", action, e);
+            return Err(AuditEvent::new("anonymous", action).denied(ctx, e).await);
+        }
+    };
+    let user_id = parse_id(&role_perm.sub, "sub")?;
+    Ok((user_id, AuditEvent::new(&role_perm.sub, action)))
+}
+
 pub struct Mutation;
 
 #[Object]
 impl Mutation {
+    // a mutation rather than a query: it changes state (lockout counters, last_login_at, a new
+    // refresh token) and must never be served from a cache or replayed by a retrying client
+    pub async fn login(
+        &self,
+        ctx: &Context<'_>,
+        email: String,
+        password: String,
+    ) -> async_graphql::Result<AuthPayload> {
+        let pool = ctx.data::<PgPool>().unwrap();
+        let policy = ctx.data::<LoginPolicy>().unwrap();
+        let session = ctx.data::<SessionPolicy>().unwrap();
+        let mfa = ctx.data::<MfaPolicy>().unwrap();
+        let client_ip = ctx.data_opt::<RequestMeta>().and_then(|m| m.client_ip.clone());
+        let audit = AuditEvent::new(&email, "login").target(&email);
+        if let Err(e) = check_login_allowed(pool, &email, client_ip.as_deref()).await {
+            return Err(audit.denied(ctx, e).await);
+        }
+        let info = match check_user_info(pool, email.to_string(), password.to_string()).await {
+            Ok(v) => v,
+            Err(e) => {
+                // only wrong credentials count towards a lockout, not database errors
+                if extension(&e, "code").as_deref() == Some("UNAUTHENTICATED") {
+                    if let Err(e) = login_failed(ctx, pool, policy, &email, client_ip.as_deref()).await {
+                        return Err(audit.failure(ctx, e).await);
+                    }
+                }
+                return Err(audit.failure(ctx, e).await);
+            }
+        };
+        if info.email_unverified {
+            return Err(audit.denied(ctx, RbacError::EmailNotVerified.extend()).await);
+        }
+        // the failed-login count is only reset once every factor checked out
+        match start_mfa_challenge(pool, mfa, info.id, &info.role).await {
+            Ok(None) => (),
+            Ok(Some(e)) => {
+                audit.after("mfa challenge").success(ctx).await;
+                return Err(e);
+            }
+            Err(e) => return Err(audit.failure(ctx, e).await),
+        }
+        match open_session(pool, session, info.id).await {
+            Ok(v) => {
+                audit.after(info.id.to_string()).success(ctx).await;
+                Ok(v)
+            }
+            Err(e) => Err(audit.failure(ctx, e).await),
+        }
+    }
+
+    // trades a refresh token for a new access token and a new refresh token. Roles are read
+    // again, so the new access token reflects grants made since login. A refresh token that was
+    // already used ends every session of its user
+    pub async fn refresh_session(&self, ctx: &Context<'_>, refresh_token: String) -> async_graphql::Result<AuthPayload> {
+        let pool = ctx.data::<PgPool>().unwrap();
+        let session = ctx.data::<SessionPolicy>().unwrap();
+        let audit = AuditEvent::new("anonymous", "refresh_session");
+        let user_id = match use_refresh_token(pool, &hash_token(&refresh_token)).await {
+            Ok(RefreshOutcome::Rotated(v)) => v,
+            Ok(RefreshOutcome::Reused(v)) => {
+                println!("Refresh token reused for user {}, all of its sessions were revoked", v);
+                let audit = AuditEvent::new(&v.to_string(), "refresh_session").after("all sessions revoked");
+                return Err(audit.denied(ctx, invalid_refresh_token()).await);
+            }
+            Ok(RefreshOutcome::Invalid) => return Err(audit.denied(ctx, invalid_refresh_token()).await),
+            Err(e) => return Err(audit.failure(ctx, e).await),
+        };
+        let audit = AuditEvent::new(&user_id.to_string(), "refresh_session");
+        let user = match fetch_user_by_id(pool, user_id).await {
+            Ok(Some(v)) => v,
+            Ok(None) => return Err(audit.denied(ctx, invalid_refresh_token()).await),
+            Err(e) => return Err(audit.failure(ctx, e).await),
+        };
+        match mfa_missing(pool, ctx.data::<MfaPolicy>().unwrap(), user_id).await {
+            Ok(false) => (),
+            Ok(true) => {
+                let e = RbacError::Forbidden("Your role requires multi-factor authentication, log in again to set it up".to_string()).extend();
+                return Err(audit.denied(ctx, e).await);
+            }
+            Err(e) => return Err(audit.failure(ctx, e).await),
+        }
+        match issue_session(pool, session, user).await {
+            Ok(v) => {
+                audit.success(ctx).await;
+                Ok(v)
+            }
+            Err(e) => Err(audit.failure(ctx, e).await),
+        }
+    }
+
+    // revokes the refresh token; the access token stays valid until it expires
+    pub async fn logout(&self, ctx: &Context<'_>, refresh_token: String) -> async_graphql::Result<String> {
+        let pool = ctx.data::<PgPool>().unwrap();
+        let audit = AuditEvent::new("anonymous", "logout");
+        match revoke_refresh_token(pool, &hash_token(&refresh_token)).await {
+            Ok(true) => {
+                audit.success(ctx).await;
+                Ok("Successfully logged out".to_string())
+            }
+            Ok(false) => Err(audit.denied(ctx, invalid_refresh_token()).await),
+            Err(e) => Err(audit.failure(ctx, e).await),
+        }
+    }
+
+    // second login step: a code from the authenticator, or a recovery code, for the mfaToken
+    // from login's MFA_REQUIRED error
+    pub async fn verify_mfa(&self, ctx: &Context<'_>, mfa_token: String, code: String) -> async_graphql::Result<AuthPayload> {
+        let pool = ctx.data::<PgPool>().unwrap();
+        let policy = ctx.data::<LoginPolicy>().unwrap();
+        let session = ctx.data::<SessionPolicy>().unwrap();
+        let client_ip = ctx.data_opt::<RequestMeta>().and_then(|m| m.client_ip.clone());
+        let user_id = match challenge_user(pool, &mfa_token).await {
+            Ok(v) => v,
+            Err(e) => return Err(AuditEvent::new("anonymous", "verify_mfa").denied(ctx, e).await),
+        };
+        let audit = AuditEvent::new(&user_id.to_string(), "verify_mfa");
+        let email = match fetch_user_email(pool, user_id).await {
+            Ok(Some(v)) => v,
+            Ok(None) => return Err(audit.denied(ctx, invalid_mfa_token()).await),
+            Err(e) => return Err(audit.failure(ctx, e).await),
+        };
+        // wrong codes lock the account like wrong passwords do
+        if let Err(e) = check_login_allowed(pool, &email, client_ip.as_deref()).await {
+            return Err(audit.denied(ctx, e).await);
+        }
+        let secret = match fetch_totp(pool, user_id).await {
+            Ok(Some(v)) if v.confirmed => v.secret,
+            Ok(_) => return Err(audit.denied(ctx, RbacError::Forbidden("Set up an authenticator with enrollTotp and confirmTotp first".to_string()).extend()).await),
+            Err(e) => return Err(audit.failure(ctx, e).await),
+        };
+        match check_mfa_code(pool, user_id, &secret, &code).await {
+            Ok(true) => (),
+            Ok(false) => {
+                if let Err(e) = challenge_failed(pool, &mfa_token).await {
+                    return Err(audit.failure(ctx, e).await);
+                }
+                if let Err(e) = login_failed(ctx, pool, policy, &email, client_ip.as_deref()).await {
+                    return Err(audit.failure(ctx, e).await);
+                }
+                return Err(audit.denied(ctx, RbacError::Unauthenticated("Invalid authentication code".to_string()).extend()).await);
+            }
+            Err(e) => return Err(audit.failure(ctx, e).await),
+        }
+        match delete_mfa_challenge(pool, &hash_token(&mfa_token)).await {
+            Ok(true) => (),
+            Ok(false) => return Err(audit.denied(ctx, invalid_mfa_token()).await),
+            Err(e) => return Err(audit.failure(ctx, e).await),
+        }
+        match open_session(pool, session, user_id).await {
+            Ok(v) => {
+                audit.success(ctx).await;
+                Ok(v)
+            }
+            Err(e) => Err(audit.failure(ctx, e).await),
+        }
+    }
+
+    // starts setting up an authenticator app. Signed-in users call it with their access token;
+    // users whose role demands MFA call it with the mfaToken of their login instead
+    pub async fn enroll_totp(&self, ctx: &Context<'_>, mfa_token: Option<String>) -> async_graphql::Result<TotpEnrollment> {
+        let pool = ctx.data::<PgPool>().unwrap();
+        let mfa = ctx.data::<MfaPolicy>().unwrap();
+        let (user_id, audit) = mfa_subject(ctx, "enroll_totp", mfa_token.as_deref()).await?;
+        let email = match fetch_user_email(pool, user_id).await {
+            Ok(Some(v)) => v,
+            Ok(None) => return Err(audit.failure(ctx, RbacError::NotFound("User Not Found".to_string()).extend()).await),
+            Err(e) => return Err(audit.failure(ctx, e).await),
+        };
+        let secret = new_secret();
+        match insert_pending_totp(pool, user_id, &secret).await {
+            Ok(true) => (),
+            Ok(false) => return Err(audit.failure(ctx, RbacError::Conflict("An authenticator is already set up, an Admin can remove it with resetTotp".to_string()).extend()).await),
+            Err(e) => return Err(audit.failure(ctx, e).await),
+        }
+        let otpauth_uri = otpauth_uri(&mfa.issuer, &email, &secret);
+        let qr_png = match qr_png_base64(&otpauth_uri) {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error qr_png_base64 = {:?}", e);
+                return Err(audit.failure(ctx, RbacError::Internal("Failed to draw the QR code".to_string()).extend()).await);
+            }
+        };
+        audit.success(ctx).await;
+        Ok(TotpEnrollment {
+            secret,
+            otpauth_uri,
+            qr_png,
+        })
+    }
+
+    // turns MFA on with a first code from the new authenticator and returns the recovery codes.
+    // Called with an mfaToken it also finishes that login
+    pub async fn confirm_totp(&self, ctx: &Context<'_>, code: String, mfa_token: Option<String>) -> async_graphql::Result<TotpConfirmation> {
+        let pool = ctx.data::<PgPool>().unwrap();
+        let session = ctx.data::<SessionPolicy>().unwrap();
+        let (user_id, audit) = mfa_subject(ctx, "confirm_totp", mfa_token.as_deref()).await?;
+        let totp = match fetch_totp(pool, user_id).await {
+            Ok(Some(v)) if !v.confirmed => v,
+            Ok(Some(_)) => return Err(audit.failure(ctx, RbacError::Conflict("The authenticator is already confirmed".to_string()).extend()).await),
+            Ok(None) => return Err(audit.failure(ctx, RbacError::NotFound("Call enrollTotp first".to_string()).extend()).await),
+            Err(e) => return Err(audit.failure(ctx, e).await),
+        };
+        let step = verify_code(&totp.secret, &code);
+        let accepted = match step {
+            Some(step) => match use_totp_step(pool, user_id, step).await {
+                Ok(v) => v,
+                Err(e) => return Err(audit.failure(ctx, e).await),
+            },
+            None => false,
+        };
+        if !accepted {
+            if let Some(token) = &mfa_token {
+                if let Err(e) = challenge_failed(pool, token).await {
+                    return Err(audit.failure(ctx, e).await);
+                }
+            }
+            return Err(audit.failure(ctx, RbacError::validation("code", "code doesn't match the authenticator").extend()).await);
+        }
+        let recovery_codes = new_recovery_codes();
+        let hashes: Vec<String> = recovery_codes.iter().map(|c| hash_recovery_code(c)).collect();
+        if let Err(e) = confirm_totp(pool, user_id, &hashes).await {
+            return Err(audit.failure(ctx, e).await);
+        }
+        let mut res = TotpConfirmation {
+            recovery_codes,
+            session: None,
+        };
+        if let Some(token) = &mfa_token {
+            match delete_mfa_challenge(pool, &hash_token(token)).await {
+                Ok(true) => (),
+                Ok(false) => return Err(audit.denied(ctx, invalid_mfa_token()).await),
+                Err(e) => return Err(audit.failure(ctx, e).await),
+            }
+            match open_session(pool, session, user_id).await {
+                Ok(v) => res.session = Some(v),
+                Err(e) => return Err(audit.failure(ctx, e).await),
+            }
+        }
+        audit.success(ctx).await;
+        Ok(res)
+    }
+
+    // mails a password reset link. The answer is the same whether or not the email belongs to an
+    // account, so it can't be used to find out who has one
+    pub async fn request_password_reset(&self, ctx: &Context<'_>, email: String) -> async_graphql::Result<String> {
+        let pool = ctx.data::<PgPool>().unwrap();
+        let mail = ctx.data::<MailSettings>().unwrap();
+        let audit = AuditEvent::new("anonymous", "request_password_reset").target(&email);
+        match fetch_user_id_by_email(pool, &email).await {
+            Ok(Some(id)) => {
+                if let Err(e) = send_password_reset(pool, mail, id, &email).await {
+                    return Err(audit.failure(ctx, e).await);
+                }
+                audit.after(id.to_string()).success(ctx).await;
+            }
+            Ok(None) => audit.success(ctx).await,
+            Err(e) => return Err(audit.failure(ctx, e).await),
+        }
+        Ok("If the email belongs to an account, a password reset link was sent to it".to_string())
+    }
+
+    // sets a new password with the token from requestPasswordReset. Opening the email proves the
+    // address too, so it is marked verified; every session and the lockout of the account end
+    pub async fn reset_password(&self, ctx: &Context<'_>, token: String, password: String) -> async_graphql::Result<String> {
+        let pool = ctx.data::<PgPool>().unwrap();
+        let audit = AuditEvent::new("anonymous", "reset_password");
+        // checked before the token is spent, so a rejected password doesn't use it up
+        if let Err(e) = check_password_len("password", &password) {
+            return Err(audit.denied(ctx, e).await);
+        }
+        let user_id = match use_account_token(pool, PASSWORD_RESET, &hash_token(&token)).await {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                let e = RbacError::validation("token", "Invalid or expired password reset token").extend();
+                return Err(audit.denied(ctx, e).await);
+            }
+            Err(e) => return Err(audit.failure(ctx, e).await),
+        };
+        let audit = AuditEvent::new(&user_id.to_string(), "reset_password").target(user_id.to_string());
+        if let Err(e) = set_password(pool, user_id, &password).await {
+            return Err(audit.failure(ctx, e).await);
+        }
+        if let Err(e) = mark_email_verified(pool, user_id).await {
+            return Err(audit.failure(ctx, e).await);
+        }
+        if let Err(e) = revoke_user_refresh_tokens(pool, user_id).await {
+            return Err(audit.failure(ctx, e).await);
+        }
+        match fetch_user_email(pool, user_id).await {
+            Ok(Some(email)) => {
+                if let Err(e) = clear_login_failures(pool, &account_key(&email)).await {
+                    return Err(audit.failure(ctx, e).await);
+                }
+            }
+            Ok(None) => (),
+            Err(e) => return Err(audit.failure(ctx, e).await),
+        }
+        audit.success(ctx).await;
+        Ok("Password Successfully changed".to_string())
+    }
+
+    // mails a new verification link, e.g. when the first one expired. Like requestPasswordReset
+    // it answers the same for unknown and already verified emails
+    pub async fn send_verification_email(&self, ctx: &Context<'_>, email: String) -> async_graphql::Result<String> {
+        let pool = ctx.data::<PgPool>().unwrap();
+        let mail = ctx.data::<MailSettings>().unwrap();
+        let audit = AuditEvent::new("anonymous", "send_verification_email").target(&email);
+        let user = match fetch_user_id_by_email(pool, &email).await {
+            Ok(Some(id)) => fetch_user_by_id(pool, id).await,
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        };
+        match user {
+            Ok(Some(user)) if user.email_verified_at.is_none() => {
+                if let Err(e) = send_email_verification(pool, mail, user.id, &email).await {
+                    return Err(audit.failure(ctx, e).await);
+                }
+                audit.after(user.id.to_string()).success(ctx).await;
+            }
+            Ok(_) => audit.success(ctx).await,
+            Err(e) => return Err(audit.failure(ctx, e).await),
+        }
+        Ok("If the email belongs to an unverified account, a verification link was sent to it".to_string())
+    }
+
+    // confirms the address with the token from the verification email
+    pub async fn verify_email(&self, ctx: &Context<'_>, token: String) -> async_graphql::Result<String> {
+        let pool = ctx.data::<PgPool>().unwrap();
+        let audit = AuditEvent::new("anonymous", "verify_email");
+        let user_id = match use_account_token(pool, EMAIL_VERIFICATION, &hash_token(&token)).await {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                let e = RbacError::validation("token", "Invalid or expired verification token").extend();
+                return Err(audit.denied(ctx, e).await);
+            }
+            Err(e) => return Err(audit.failure(ctx, e).await),
+        };
+        let audit = AuditEvent::new(&user_id.to_string(), "verify_email").target(user_id.to_string());
+        if let Err(e) = mark_email_verified(pool, user_id).await {
+            return Err(audit.failure(ctx, e).await);
+        }
+        audit.success(ctx).await;
+        Ok("Email Successfully verified".to_string())
+    }
+
     pub async fn add_user(
         &self,
         ctx: &Context<'_>,
@@ -26,35 +418,69 @@ impl Mutation {
     ) -> async_graphql::Result<String> {
         let token = ctx.data::<Option<String>>().unwrap();
         let pool = ctx.data::<PgPool>().unwrap();
+        let mail = ctx.data::<MailSettings>().unwrap();
+        let mut actor = "anonymous".to_string();
         if !token.is_none() {
             let role_perm = match authorize(pool, token.clone()).await {
                 Ok(v) => v,
                 Err(e) => {
                     println!("Error add_user:- {:?}", e);
-                    return Err(e);
+                    return Err(AuditEvent::new(&actor, "add_user").target(&username).denied(ctx, e).await);
                 }
             };
-    
+
             if !role_perm.role.contains(&"Admin".to_string()) {
-                return Err(Error::new("Not Authorized")
-                .extend_with(|_, x| x.set("details", "Not Authorized to add user")));
+                return Err(AuditEvent::new(&role_perm.sub, "add_user").target(&username).denied(ctx, RbacError::Forbidden("Not Authorized to add user".to_string()).extend()).await);
             }
+            actor = role_perm.sub;
         }
+        let audit = AuditEvent::new(&actor, "add_user")
+            .target(&username)
+            .after(&email);
+        let mut tx = match pool.begin().await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error begin transaction = {:?}", e);
+                return Err(audit.failure(ctx, RbacError::Internal("Failed to start the transaction".to_string()).extend()).await);
+            }
+        };
 
-        match insert_users(pool, username.clone(), email, password).await {
-            Ok(v) =>{ 
-                match insert_role_user(pool, username.clone(), "Viewer".to_string()).await {
-                    Ok(_) => Ok(format!("User {:?}, successfully added", username)),
+        match insert_users(&mut tx, username.clone(), email.clone(), password).await {
+            Ok(_) =>{
+                match insert_role_user(&mut tx, username.clone(), "Viewer".to_string()).await {
+                    Ok(_) => {
+                        if mail.require_verification {
+                            if let Err(e) = require_email_verification(&mut tx, &username).await {
+                                return Err(audit.failure(ctx, e).await);
+                            }
+                        }
+                        if let Err(e) = commit_with_event(tx, "user.created", json!({"name": username, "email": email, "roles": ["Viewer"]})).await {
+                            return Err(audit.failure(ctx, e).await);
+                        }
+                        if mail.require_verification {
+                            // the user exists either way; a lost email is fixed with sendVerificationEmail
+                            match fetch_user_id_by_email(pool, &email).await {
+                                Ok(Some(id)) => {
+                                    if let Err(e) = send_email_verification(pool, mail, id, &email).await {
+                                        println!("Error send_email_verification = {:?}", e);
+                                    }
+                                }
+                                Ok(None) => (),
+                                Err(e) => println!("Error fetch_user_id_by_email = {:?}", e),
+                            }
+                        }
+                        audit.success(ctx).await;
+                        Ok(format!("User {:?}, successfully added", username))
+                    }
                     Err(e) => {
-                        return Err(e.into());
+                        return Err(audit.failure(ctx, e).await);
                     }
                 }
-                
+
         },
             Err(e) => {
                 println!("Error add_user = {:?}", e);
-                return Err(Error::new("Unable to Add User")
-                    .extend_with(|_, x| x.set("details", "User with same name present")));
+                return Err(audit.failure(ctx, RbacError::Conflict("User with same name present".to_string()).extend()).await);
             }
         }
     }
@@ -66,18 +492,36 @@ impl Mutation {
             Ok(v) => v,
             Err(e) => {
                 println!("Error add_role:- {:?}", e);
-                return Err(e);
+                return Err(AuditEvent::new("anonymous", "add_role").target(&name).denied(ctx, e).await);
             }
         };
+        let audit = AuditEvent::new(&role_perm.sub, "add_role")
+            .target(&name)
+            .after(&name);
 
         if !role_perm.role.contains(&"Admin".to_string()) {
-            return Err(Error::new("Not Authorized")
-            .extend_with(|_, x| x.set("details", "Not Authorized to add Roles")));
+            return Err(audit.denied(ctx, RbacError::Forbidden("Not Authorized to add Roles".to_string()).extend()).await);
         }
 
-        match insert_roles(pool, name).await {
-            Ok(v) => Ok(format!("Roled added :- {:?}", v)),
-            Err(e) => Err(e),
+        let mut tx = match pool.begin().await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error begin transaction = {:?}", e);
+                return Err(audit.failure(ctx, RbacError::Internal("Failed to start the transaction".to_string()).extend()).await);
+            }
+        };
+        match insert_roles(&mut tx, name).await {
+            Ok(v) => {
+                if let Err(e) = record_policy_revision(&mut tx, &role_perm.sub, &format!("Added role {:?}", v)).await {
+                    return Err(audit.failure(ctx, e).await);
+                }
+                if let Err(e) = commit_with_event(tx, "role.created", json!({"name": v})).await {
+                    return Err(audit.failure(ctx, e).await);
+                }
+                audit.success(ctx).await;
+                Ok(format!("Roled added :- {:?}", v))
+            }
+            Err(e) => Err(audit.failure(ctx, e).await),
         }
     }
 
@@ -94,25 +538,39 @@ impl Mutation {
             Ok(v) => v,
             Err(e) => {
                 println!("Error assign_user_role:- {:?}", e);
-                return Err(e);
+                return Err(AuditEvent::new("anonymous", "assign_user_role").target(&username).denied(ctx, e).await);
             }
         };
+        let audit = AuditEvent::new(&role_perm.sub, "assign_user_role")
+            .target(&username)
+            .after(&roles);
 
         if roles=="Admin".to_string() {
-            return Err(Error::new("Can't Assign Admin role"));
+            return Err(audit.failure(ctx, RbacError::Forbidden("Can't Assign Admin role".to_string()).extend()).await);
         }
 
         if !role_perm.perm.contains(&"Update".to_string()) {
-            return Err(Error::new("Not Authorized")
-                .extend_with(|_, x| x.set("details", "You are not authorized to add role")));
+            return Err(audit.denied(ctx, RbacError::Forbidden("You are not authorized to add role".to_string()).extend()).await);
         }
-            match insert_role_user(pool, username.clone(), roles).await {
+        let mut tx = match pool.begin().await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error begin transaction = {:?}", e);
+                return Err(audit.failure(ctx, RbacError::Internal("Failed to start the transaction".to_string()).extend()).await);
+            }
+        };
+            match insert_role_user(&mut tx, username.clone(), roles.clone()).await {
                 Ok(_) => (),
                 Err(e) => {
                     println!("Error insert_role_user = {:?}", e);
-                    return Err(e);
+                    return Err(audit.failure(ctx, e).await);
                 }
         };
+        if let Err(e) = commit_with_event(tx, "user.role_assigned", json!({"user": username, "role": roles})).await {
+            return Err(audit.failure(ctx, e).await);
+        }
+        permission_cache().invalidate();
+        audit.success(ctx).await;
         Ok(format!(
             "Roles added successfully for user :- {:?}",
             username
@@ -132,21 +590,38 @@ impl Mutation {
             Ok(v) => v,
             Err(e) => {
                 println!("Error assign_user_role:- {:?}", e);
-                return Err(e);
+                return Err(AuditEvent::new("anonymous", "assign_role_permissions").target(&name).denied(ctx, e).await);
             }
         };
+        let audit = AuditEvent::new(&role_perm.sub, "assign_role_permissions")
+            .target(&name)
+            .after(&permissions);
 
         if !role_perm.perm.contains(&"Create".to_string()) {
-            return Err(Error::new("Not Authorized")
-                .extend_with(|_, x| x.set("details", "You are not authorized to add role")));
+            return Err(audit.denied(ctx, RbacError::Forbidden("You are not authorized to add role".to_string()).extend()).await);
         }
-        match insert_role_permissions(pool, name.clone(), permissions).await {
+        let mut tx = match pool.begin().await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error begin transaction = {:?}", e);
+                return Err(audit.failure(ctx, RbacError::Internal("Failed to start the transaction".to_string()).extend()).await);
+            }
+        };
+        match insert_role_permissions(&mut tx, name.clone(), permissions.clone()).await {
                 Ok(_) => (),
                 Err(e) => {
                     println!("Error insert_role_user = {:?}", e);
-                    return Err(e);
+                    return Err(audit.failure(ctx, e).await);
                 }
             }
+        if let Err(e) = record_policy_revision(&mut tx, &role_perm.sub, &format!("Granted {:?} to role {:?}", permissions, name)).await {
+            return Err(audit.failure(ctx, e).await);
+        }
+        if let Err(e) = commit_with_event(tx, "role.permission_granted", json!({"role": name, "permission": permissions})).await {
+            return Err(audit.failure(ctx, e).await);
+        }
+        permission_cache().invalidate();
+        audit.success(ctx).await;
         Ok(format!(
             "Permissions added successfully for role :- {:?}",
             name
@@ -161,42 +636,45 @@ impl Mutation {
             Ok(v) => v,
             Err(e) => {
                 println!("Error delete_user_role:- {:?}", e);
-                return Err(e);
+                return Err(AuditEvent::new("anonymous", "delete_user_role").target(&user_name).denied(ctx, e).await);
             }
         };
+        let audit = AuditEvent::new(&role_perm.sub, "delete_user_role")
+            .target(&user_name)
+            .before(&role_name);
         if role_name == "Admin".to_string() {
-            return Err(Error::new("Admin Role Can't be deleted"));
+            return Err(audit.failure(ctx, RbacError::Forbidden("Admin Role Can't be deleted".to_string()).extend()).await);
         }
-        let data = match sqlx::query("select count(1) from user_roles where user_id in (SELECT ID from USERS where name like $1);")
-        .bind(&user_name).fetch_one(pool).await {
+        let count = match count_user_roles(pool, &user_name).await {
             Ok(v) => v,
-            Err(e) => {
-                println!("Error getting count of userRole = {:?}",e);
-                return Err(Error::new("INternal Server Error"));
-            }
+            Err(e) => return Err(audit.failure(ctx, e).await),
         };
-        let count : i64 = data.get("count");
         if count == 1 {
-            return Err(Error::new("Minimum One role required."));
-        } 
+            return Err(audit.failure(ctx, RbacError::validation("roleName", "Minimum One role required.").extend()).await);
+        }
 
 
 
         if !role_perm.perm.contains(&"Delete".to_string()){
-            return Err(Error::new("Not Authorized")
-            .extend_with(|_, x| x.set("details", "Not Authorized to add Permissions")));
+            return Err(audit.denied(ctx, RbacError::Forbidden("Not Authorized to add Permissions".to_string()).extend()).await);
         }
 
-        match sqlx::query("DELETE FROM user_roles where user_id in (SELECT ID from USERS where name like $1) and role_id in (SELECT id FROM roles WHERE name like $2);")
-        .bind(user_name)
-        .bind(role_name).execute(pool).await {
-            Ok(_) => (),
+        let mut tx = match pool.begin().await {
+            Ok(v) => v,
             Err(e) => {
-                println!("Error delete user role = {:?}",e);
-                return Err(Error::new("Unable to Delete Assigned Role"));
+                println!("Error begin transaction = {:?}", e);
+                return Err(audit.failure(ctx, RbacError::Internal("Failed to start the transaction".to_string()).extend()).await);
             }
         };
-        
+        if let Err(e) = remove_role_user(&mut tx, &user_name, &role_name).await {
+            return Err(audit.failure(ctx, e).await);
+        }
+        if let Err(e) = commit_with_event(tx, "user.role_removed", json!({"user": user_name, "role": role_name})).await {
+            return Err(audit.failure(ctx, e).await);
+        }
+        permission_cache().invalidate();
+
+        audit.success(ctx).await;
 
         Ok(format!("Successfuly deleted Role from User"))
     }
@@ -209,38 +687,44 @@ impl Mutation {
             Ok(v) => v,
             Err(e) => {
                 println!("Error delete_user_role:- {:?}", e);
-                return Err(e);
+                return Err(AuditEvent::new("anonymous", "delete_role_permission").target(&role_name).denied(ctx, e).await);
             }
         };
+        let audit = AuditEvent::new(&role_perm.sub, "delete_role_permission")
+            .target(&role_name)
+            .before(&action);
         if role_name=="Admin".to_string() {
-            return Err(Error::new("Admin Role can't be updated"));
+            return Err(audit.failure(ctx, RbacError::Forbidden("Admin Role can't be updated".to_string()).extend()).await);
         }
-        let data = match sqlx::query("select count(1) from role_permissions where role_id in (SELECT ID from roles where name like $1);")
-        .bind(&role_name).fetch_one(pool).await {
+        let count = match count_role_permissions(pool, &role_name).await {
             Ok(v) => v,
-            Err(e) => {
-                println!("Error getting count of userRole = {:?}",e);
-                return Err(Error::new("INternal Server Error"));
-            }
+            Err(e) => return Err(audit.failure(ctx, e).await),
         };
-        let count : i64 = data.get("count");
         if count == 1 {
-            return Err(Error::new("Minimum One permission required."));
-        } 
+            return Err(audit.failure(ctx, RbacError::validation("action", "Minimum One permission required.").extend()).await);
+        }
         if !role_perm.role.contains(&"Admin".to_string()){
-            return Err(Error::new("Not Authorized")
-            .extend_with(|_, x| x.set("details", "Not Authorized to add Permissions")));
+            return Err(audit.denied(ctx, RbacError::Forbidden("Not Authorized to add Permissions".to_string()).extend()).await);
         }
 
-        match sqlx::query("DELETE FROM role_permissions where role_id in (SELECT ID from roles where name like $1) and permission_id in (SELECT id FROM permissions WHERE action like $2);")
-        .bind(&role_name)
-        .bind(&action).execute(pool).await {
-            Ok(_) => (),
+        let mut tx = match pool.begin().await {
+            Ok(v) => v,
             Err(e) => {
-                println!("Error delete role permission = {:?}",e);
-                return Err(Error::new("Unable to Delete Assigned Perission"));
+                println!("Error begin transaction = {:?}", e);
+                return Err(audit.failure(ctx, RbacError::Internal("Failed to start the transaction".to_string()).extend()).await);
             }
         };
+        if let Err(e) = remove_role_permission(&mut tx, &role_name, &action).await {
+            return Err(audit.failure(ctx, e).await);
+        }
+        if let Err(e) = record_policy_revision(&mut tx, &role_perm.sub, &format!("Revoked {:?} from role {:?}", action, role_name)).await {
+            return Err(audit.failure(ctx, e).await);
+        }
+        if let Err(e) = commit_with_event(tx, "role.permission_revoked", json!({"role": role_name, "permission": action})).await {
+            return Err(audit.failure(ctx, e).await);
+        }
+        permission_cache().invalidate();
+        audit.success(ctx).await;
 
         Ok(format!("Permission successfully removed from role"))
     }
@@ -254,33 +738,50 @@ impl Mutation {
             Ok(v) => v,
             Err(e) => {
                 println!("Error delete_user:- {:?}", e);
-                return Err(e);
+                return Err(AuditEvent::new("anonymous", "delete_user").target(&id).denied(ctx, e).await);
             }
         };
+        let mut audit = AuditEvent::new(&role_perm.sub, "delete_user").target(&id);
 
         if !role_perm.role.contains(&"Admin".to_string()) {
-            return Err(Error::new("Not Authorized")
-                .extend_with(|_, x| x.set("details", "You are not authorized to delete user")));
+            return Err(audit.denied(ctx, RbacError::Forbidden("You are not authorized to delete user".to_string()).extend()).await);
         }
         if role_perm.sub == id {
-            return Err(Error::new("Admin User Can't be deleted"));
+            return Err(audit.failure(ctx, RbacError::Forbidden("Admin User Can't be deleted".to_string()).extend()).await);
+        }
+        let id = parse_id(&id, "id")?;
+        if let Ok(Some(v)) = sqlx::query("SELECT name,email from users where id = $1;").bind(id).fetch_optional(pool).await {
+            let name: String = v.get("name");
+            let email: String = v.get("email");
+            audit = audit.before(format!("{} <{}>", name, email));
         }
-        let id = id.parse::<i32>().unwrap();
-        match sqlx::query("DELETE from USER_ROLES where user_id =$1").bind(id).execute(pool).await {
+        let mut tx = match pool.begin().await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error begin transaction = {:?}", e);
+                return Err(audit.failure(ctx, RbacError::Internal("Failed to start the transaction".to_string()).extend()).await);
+            }
+        };
+        match sqlx::query("DELETE from USER_ROLES where user_id =$1").bind(id).execute(&mut *tx).await {
             Ok(_) => {
-                match sqlx::query("DELETE from USERS where id = $1").bind(id).execute(pool).await {
+                match sqlx::query("DELETE from USERS where id = $1").bind(id).execute(&mut *tx).await {
                     Ok(_) => (),
                     Err(e) => {
                         println!("Error at delete user ={:?}",e);
-                        return Err(Error::new("Internal Server Error"));
-                    } 
+                        return Err(audit.failure(ctx, RbacError::Internal("Failed to delete the user".to_string()).extend()).await);
+                    }
                 }
             },
             Err(e) => {
                 println!("Error at delete user user_role = {:?}",e);
-                return Err(Error::new("Internal Server Error"));
+                return Err(audit.failure(ctx, RbacError::Internal("Failed to delete the roles of the user".to_string()).extend()).await);
             }
         }
+        if let Err(e) = commit_with_event(tx, "user.deleted", json!({"id": id})).await {
+            return Err(audit.failure(ctx, e).await);
+        }
+        permission_cache().invalidate();
+        audit.success(ctx).await;
         Ok("User Successfuly deleted".to_string())
     }
 
@@ -292,30 +793,49 @@ impl Mutation {
             Ok(v) => v,
             Err(e) => {
                 println!("Error delete_role:- {:?}", e);
-                return Err(e);
+                return Err(AuditEvent::new("anonymous", "delete_role").target(&id).denied(ctx, e).await);
             }
         };
+        let mut audit = AuditEvent::new(&role_perm.sub, "delete_role").target(&id);
 
         if !role_perm.role.contains(&"Admin".to_string()) {
-            return Err(Error::new("Not Authorized")
-                .extend_with(|_, x| x.set("details", "You are not authorized to delete role")));
+            return Err(audit.denied(ctx, RbacError::Forbidden("You are not authorized to delete role".to_string()).extend()).await);
+        }
+        let id = parse_id(&id, "id")?;
+        if let Ok(Some(v)) = sqlx::query("SELECT name from roles where id = $1;").bind(id).fetch_optional(pool).await {
+            let name: String = v.get("name");
+            audit = audit.before(name);
         }
-        let id = id.parse::<i32>().unwrap();
-        match sqlx::query("DELETE from role_permissions where role_id =$1").bind(id).execute(pool).await {
+        let mut tx = match pool.begin().await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error begin transaction = {:?}", e);
+                return Err(audit.failure(ctx, RbacError::Internal("Failed to start the transaction".to_string()).extend()).await);
+            }
+        };
+        match sqlx::query("DELETE from role_permissions where role_id =$1").bind(id).execute(&mut *tx).await {
             Ok(_) => {
-                match sqlx::query("DELETE from ROLES where id = $1").bind(id).execute(pool).await {
+                match sqlx::query("DELETE from ROLES where id = $1").bind(id).execute(&mut *tx).await {
                     Ok(_) => (),
                     Err(e) => {
                         println!("Error at delete role ={:?}",e);
-                        return Err(Error::new("Internal Server Error"));
-                    } 
+                        return Err(audit.failure(ctx, RbacError::Internal("Failed to delete the role".to_string()).extend()).await);
+                    }
                 }
             },
             Err(e) => {
                 println!("Error at delete user user_role = {:?}",e);
-                return Err(Error::new("Internal Server Error"));
+                return Err(audit.failure(ctx, RbacError::Internal("Failed to delete the permissions of the role".to_string()).extend()).await);
             }
         }
+        if let Err(e) = record_policy_revision(&mut tx, &role_perm.sub, &format!("Deleted role {}", id)).await {
+            return Err(audit.failure(ctx, e).await);
+        }
+        if let Err(e) = commit_with_event(tx, "role.deleted", json!({"id": id})).await {
+            return Err(audit.failure(ctx, e).await);
+        }
+        permission_cache().invalidate();
+        audit.success(ctx).await;
         Ok("User Successfuly deleted".to_string())
     }
 
@@ -327,34 +847,38 @@ impl Mutation {
             Ok(v) => v,
             Err(e) => {
                 println!("Error update_password:- {:?}", e);
-                return Err(e);
+                return Err(AuditEvent::new("anonymous", "update_password").target(&id).denied(ctx, e).await);
             }
         };
+        let audit = AuditEvent::new(&role_perm.sub, "update_password").target(&id);
         if id != role_perm.sub {
-            return Err(Error::new("Not Authorized"));
+            return Err(audit.denied(ctx, RbacError::Forbidden("Not Authorized".to_string()).extend()).await);
+        }
+        if let Err(e) = check_password_len("passwd", &passwd) {
+            return Err(audit.failure(ctx, e).await);
         }
-        let id = id.parse::<i32>().unwrap();
+        let id = parse_id(&id, "id")?;
         let qry = "SELECT EXISTS (SELECT * FROM USERS WHERE id = $1);";
     let res = match sqlx::query(&qry).bind(&id).fetch_one(pool).await {
         Ok(v) => v,
         Err(e) => {
-            return Err(Error::new("User Does not exists")
-                .extend_with(|_, e| e.set("details", "User Not Found")));
+            println!("Error update password = {:?}",e);
+            return Err(audit.failure(ctx, RbacError::Internal("Failed to check the user".to_string()).extend()).await);
         }
     };
     let user_exists: bool = res.get("exists");
     if !user_exists {
-        return Err(Error::new("User Does not exists")
-            .extend_with(|_, e| e.set("details", "User Not Found")));
+        return Err(audit.failure(ctx, RbacError::NotFound("User Not Found".to_string()).extend()).await);
     }
 
-    match sqlx::query("UPDATE users SET password_hash= $1 where id = $2").bind(&passwd).bind(&id).execute(pool).await {
-        Ok(_) => (),
-        Err(e) => {
-            println!("Error update password = {:?}",e);
-            return Err(Error::new("Unable to change Password"));
-        }
+    if let Err(e) = set_password(pool, id, &passwd).await {
+        return Err(audit.failure(ctx, e).await);
+    }
+    // sessions opened with the old password end; the caller logs in again with the new one
+    if let Err(e) = revoke_user_refresh_tokens(pool, id).await {
+        return Err(audit.failure(ctx, e).await);
     }
+        audit.success(ctx).await;
         Ok("Password Successfully changed".to_string())
     }
 
@@ -366,23 +890,44 @@ impl Mutation {
             Ok(v) => v,
             Err(e) => {
                 println!("Error update_role_name:- {:?}", e);
-                return Err(e);
+                return Err(AuditEvent::new("anonymous", "update_role_name").target(&id).denied(ctx, e).await);
             }
         };
+        let mut audit = AuditEvent::new(&role_perm.sub, "update_role_name")
+            .target(&id)
+            .after(&name);
 
 
         if !role_perm.role.contains(&"Admin".to_string()) {
-            return Err(Error::new("Not Authorized")
-                .extend_with(|_, x| x.set("details", "You are not authorized to update role")));
+            return Err(audit.denied(ctx, RbacError::Forbidden("You are not authorized to update role".to_string()).extend()).await);
+        }
+        let id = parse_id(&id, "id")?;
+        if let Ok(Some(v)) = sqlx::query("SELECT name from roles where id = $1;").bind(id).fetch_optional(pool).await {
+            let old: String = v.get("name");
+            audit = audit.before(old);
         }
-        let id = id.parse::<i32>().unwrap();
-        match sqlx::query("UPDATE roles set name=$1 where id = $2;").bind(&name).bind(&id).execute(pool).await {
+        let mut tx = match pool.begin().await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error begin transaction = {:?}", e);
+                return Err(audit.failure(ctx, RbacError::Internal("Failed to start the transaction".to_string()).extend()).await);
+            }
+        };
+        match sqlx::query("UPDATE roles set name=$1 where id = $2;").bind(&name).bind(id).execute(&mut *tx).await {
             Ok(_)=>(),
             Err(e) => {
                 println!("Error update Role = {:?}",e);
-                return Err(Error::new("Unable to Update role"));
+                return Err(audit.failure(ctx, RbacError::Internal("Unable to Update role".to_string()).extend()).await);
             }
         }
+        if let Err(e) = record_policy_revision(&mut tx, &role_perm.sub, &format!("Renamed role {} to {:?}", id, name)).await {
+            return Err(audit.failure(ctx, e).await);
+        }
+        if let Err(e) = commit_with_event(tx, "role.renamed", json!({"id": id, "name": name})).await {
+            return Err(audit.failure(ctx, e).await);
+        }
+        permission_cache().invalidate();
+        audit.success(ctx).await;
         Ok("Role name Successfully changes".to_string())
     }
 
@@ -395,18 +940,36 @@ impl Mutation {
             Ok(v) => v,
             Err(e) => {
                 println!("Error update_user_name:- {:?}", e);
-                return Err(e);
+                return Err(AuditEvent::new("anonymous", "update_user_name").target(&id).denied(ctx, e).await);
             }
         };
+        let mut audit = AuditEvent::new(&role_perm.sub, "update_user_name")
+            .target(&id)
+            .after(&name);
 
-        let id = id.parse::<i32>().unwrap();
-        match sqlx::query("UPDATE users set name=$1 where id = $2;").bind(&name).bind(&id).execute(pool).await {
+        let id = parse_id(&id, "id")?;
+        if let Ok(Some(v)) = sqlx::query("SELECT name from users where id = $1;").bind(id).fetch_optional(pool).await {
+            let old: String = v.get("name");
+            audit = audit.before(old);
+        }
+        let mut tx = match pool.begin().await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error begin transaction = {:?}", e);
+                return Err(audit.failure(ctx, RbacError::Internal("Failed to start the transaction".to_string()).extend()).await);
+            }
+        };
+        match sqlx::query("UPDATE users set name=$1 where id = $2;").bind(&name).bind(id).execute(&mut *tx).await {
             Ok(_)=>(),
             Err(e) => {
                 println!("Error update Name = {:?}",e);
-                return Err(Error::new("Unable to Update User"));
+                return Err(audit.failure(ctx, RbacError::Internal("Unable to Update User".to_string()).extend()).await);
             }
         }
+        if let Err(e) = commit_with_event(tx, "user.renamed", json!({"id": id, "name": name})).await {
+            return Err(audit.failure(ctx, e).await);
+        }
+        audit.success(ctx).await;
         Ok("User name Successfully changed".to_string())
     }
 
@@ -418,23 +981,330 @@ impl Mutation {
             Ok(v) => v,
             Err(e) => {
                 println!("Error update_user_name:- {:?}", e);
-                return Err(e);
+                return Err(AuditEvent::new("anonymous", "update_user_role").target(&user_id).denied(ctx, e).await);
             }
         };
+        let audit = AuditEvent::new(&role_perm.sub, "update_user_role")
+            .target(&user_id)
+            .before(&current_role)
+            .after(&new_role);
 
         if !role_perm.perm.contains(&"Update".to_string()) {
-            return Err(Error::new("You are not authorized to update user role"));
+            return Err(audit.denied(ctx, RbacError::Forbidden("You are not authorized to update user role".to_string()).extend()).await);
         }
-        let user_id = user_id.parse::<i32>().unwrap();
+        let user_id = parse_id(&user_id, "userId")?;
+        let mut tx = match pool.begin().await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error begin transaction = {:?}", e);
+                return Err(audit.failure(ctx, RbacError::Internal("Failed to start the transaction".to_string()).extend()).await);
+            }
+        };
         match sqlx::query("UPDATE user_roles set role_id =(SELECT id from roles where name=$1 ) where user_id=$2 and role_id in (SELECT id from roles where name = $3);")
-        .bind(&new_role).bind(&user_id).bind(current_role).execute(pool).await {
+        .bind(&new_role).bind(user_id).bind(&current_role).execute(&mut *tx).await {
             Ok(_) => (),
             Err(e) => {
                 print!("Error e = {:?}",e);
-                return Err(Error::new("User Role Not updates"));
+                return Err(audit.failure(ctx, RbacError::Internal("User Role Not updated".to_string()).extend()).await);
             }
         }
+        if let Err(e) = commit_with_event(tx, "user.role_changed", json!({"user_id": user_id, "from": current_role, "to": new_role})).await {
+            return Err(audit.failure(ctx, e).await);
+        }
+        permission_cache().invalidate();
+        audit.success(ctx).await;
 
         Ok("User Role Updated Successfully".to_string())
     }
+
+    pub async fn rollback_policy(&self, ctx: &Context<'_>, revision: i32) -> async_graphql::Result<String> {
+        let token = ctx.data::<Option<String>>().unwrap();
+        let pool = ctx.data::<PgPool>().unwrap();
+
+        let role_perm = match authorize(pool, token.clone()).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error rollback_policy:- {:?}", e);
+                return Err(AuditEvent::new("anonymous", "rollback_policy").target(revision).denied(ctx, e).await);
+            }
+        };
+        let audit = AuditEvent::new(&role_perm.sub, "rollback_policy").target(revision);
+
+        if !role_perm.role.contains(&"Admin".to_string()) {
+            return Err(audit.denied(ctx, RbacError::Forbidden("You are not authorized to roll back the policy".to_string()).extend()).await);
+        }
+
+        match rollback_policy(pool, revision, &role_perm.sub).await {
+            Ok(v) => {
+                permission_cache().invalidate();
+                audit.after(v).success(ctx).await;
+                Ok(format!(
+                    "Policy rolled back to revision {} as revision {}",
+                    revision, v
+                ))
+            }
+            Err(e) => {
+                println!("Error rollback_policy = {:?}", e);
+                Err(audit.failure(ctx, e).await)
+            }
+        }
+    }
+
+    // `events` limits the subscription to those event names (e.g. "user.deleted"); empty means all
+    pub async fn add_webhook(&self, ctx: &Context<'_>, url: String, #[graphql(default)] events: Vec<String>) -> async_graphql::Result<WebhookSubscription> {
+        let token = ctx.data::<Option<String>>().unwrap();
+        let pool = ctx.data::<PgPool>().unwrap();
+
+        let role_perm = match authorize(pool, token.clone()).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error add_webhook:- {:?}", e);
+                return Err(AuditEvent::new("anonymous", "add_webhook").target(&url).denied(ctx, e).await);
+            }
+        };
+        let audit = AuditEvent::new(&role_perm.sub, "add_webhook")
+            .target(&url)
+            .after(events.join(","));
+
+        if !role_perm.role.contains(&"Admin".to_string()) {
+            return Err(audit.denied(ctx, RbacError::Forbidden("You are not authorized to add webhooks".to_string()).extend()).await);
+        }
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(audit.failure(ctx, RbacError::validation("url", "The URL must start with http:// or https://").extend()).await);
+        }
+
+        match insert_webhook(pool, &url, &events).await {
+            Ok((w, secret)) => {
+                audit.success(ctx).await;
+                Ok(WebhookSubscription {
+                    id: w.id,
+                    url: w.url,
+                    events: w.events,
+                    active: w.active,
+                    created_at: w.created_at,
+                    secret: Some(secret),
+                })
+            }
+            Err(e) => Err(audit.failure(ctx, e).await),
+        }
+    }
+
+    pub async fn remove_webhook(&self, ctx: &Context<'_>, id: i32) -> async_graphql::Result<String> {
+        let token = ctx.data::<Option<String>>().unwrap();
+        let pool = ctx.data::<PgPool>().unwrap();
+
+        let role_perm = match authorize(pool, token.clone()).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error remove_webhook:- {:?}", e);
+                return Err(AuditEvent::new("anonymous", "remove_webhook").target(id).denied(ctx, e).await);
+            }
+        };
+        let audit = AuditEvent::new(&role_perm.sub, "remove_webhook").target(id);
+
+        if !role_perm.role.contains(&"Admin".to_string()) {
+            return Err(audit.denied(ctx, RbacError::Forbidden("You are not authorized to remove webhooks".to_string()).extend()).await);
+        }
+
+        match delete_webhook(pool, id).await {
+            Ok(true) => {
+                audit.success(ctx).await;
+                Ok("Webhook successfully removed".to_string())
+            }
+            Ok(false) => Err(audit.failure(ctx, RbacError::NotFound("Webhook Not Found".to_string()).extend()).await),
+            Err(e) => Err(audit.failure(ctx, e).await),
+        }
+    }
+
+    pub async fn retry_webhook_delivery(&self, ctx: &Context<'_>, id: i64) -> async_graphql::Result<String> {
+        let token = ctx.data::<Option<String>>().unwrap();
+        let pool = ctx.data::<PgPool>().unwrap();
+
+        let role_perm = match authorize(pool, token.clone()).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error retry_webhook_delivery:- {:?}", e);
+                return Err(AuditEvent::new("anonymous", "retry_webhook_delivery").target(id).denied(ctx, e).await);
+            }
+        };
+        let audit = AuditEvent::new(&role_perm.sub, "retry_webhook_delivery").target(id);
+
+        if !role_perm.role.contains(&"Admin".to_string()) {
+            return Err(audit.denied(ctx, RbacError::Forbidden("You are not authorized to retry webhook deliveries".to_string()).extend()).await);
+        }
+
+        match retry_delivery(pool, id).await {
+            Ok(true) => {
+                audit.success(ctx).await;
+                Ok("Delivery queued for retry".to_string())
+            }
+            Ok(false) => Err(audit.failure(ctx, RbacError::NotFound("Only dead-lettered deliveries can be retried".to_string()).extend()).await),
+            Err(e) => Err(audit.failure(ctx, e).await),
+        }
+    }
+
+    // lifts a login lockout early and resets the account's failed-login count
+    pub async fn unlock_user(&self, ctx: &Context<'_>, id: String) -> async_graphql::Result<String> {
+        let token = ctx.data::<Option<String>>().unwrap();
+        let pool = ctx.data::<PgPool>().unwrap();
+
+        let role_perm = match authorize(pool, token.clone()).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error unlock_user:- {:?}", e);
+                return Err(AuditEvent::new("anonymous", "unlock_user").target(&id).denied(ctx, e).await);
+            }
+        };
+        let audit = AuditEvent::new(&role_perm.sub, "unlock_user").target(&id);
+
+        if !role_perm.role.contains(&"Admin".to_string()) {
+            return Err(audit.denied(ctx, RbacError::Forbidden("You are not authorized to unlock users".to_string()).extend()).await);
+        }
+        let user_id = parse_id(&id, "id")?;
+        let email = match fetch_user_email(pool, user_id).await {
+            Ok(Some(v)) => v,
+            Ok(None) => return Err(audit.failure(ctx, RbacError::NotFound("User Not Found".to_string()).extend()).await),
+            Err(e) => return Err(audit.failure(ctx, e).await),
+        };
+        match clear_login_failures(pool, &account_key(&email)).await {
+            Ok(true) => {
+                audit.success(ctx).await;
+                Ok("User successfully unlocked".to_string())
+            }
+            Ok(false) => {
+                audit.success(ctx).await;
+                Ok("User had no failed logins".to_string())
+            }
+            Err(e) => Err(audit.failure(ctx, e).await),
+        }
+    }
+
+    // for a lost authenticator: removes it with its recovery codes and ends the user's sessions,
+    // so the next login sets up a new one
+    pub async fn reset_totp(&self, ctx: &Context<'_>, id: String) -> async_graphql::Result<String> {
+        let token = ctx.data::<Option<String>>().unwrap();
+        let pool = ctx.data::<PgPool>().unwrap();
+
+        let role_perm = match authorize(pool, token.clone()).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error reset_totp:- {:?}", e);
+                return Err(AuditEvent::new("anonymous", "reset_totp").target(&id).denied(ctx, e).await);
+            }
+        };
+        let audit = AuditEvent::new(&role_perm.sub, "reset_totp").target(&id);
+
+        if !role_perm.role.contains(&"Admin".to_string()) {
+            return Err(audit.denied(ctx, RbacError::Forbidden("You are not authorized to reset authenticators".to_string()).extend()).await);
+        }
+        let user_id = parse_id(&id, "id")?;
+        match delete_totp(pool, user_id).await {
+            Ok(true) => (),
+            Ok(false) => return Err(audit.failure(ctx, RbacError::NotFound("User has no authenticator".to_string()).extend()).await),
+            Err(e) => return Err(audit.failure(ctx, e).await),
+        }
+        if let Err(e) = revoke_user_refresh_tokens(pool, user_id).await {
+            return Err(audit.failure(ctx, e).await);
+        }
+        audit.success(ctx).await;
+        Ok("Authenticator successfully reset".to_string())
+    }
+
+    // a key for scripts and CI that acts as the caller, limited to `permissions` when given. The
+    // key is only in this answer; the server keeps its hash
+    pub async fn create_api_key(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        permissions: Option<Vec<String>>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> async_graphql::Result<ApiKey> {
+        let token = ctx.data::<Option<String>>().unwrap();
+        let pool = ctx.data::<PgPool>().unwrap();
+
+        let role_perm = match authorize(pool, token.clone()).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error create_api_key:- {:?}", e);
+                return Err(AuditEvent::new("anonymous", "create_api_key").target(&name).denied(ctx, e).await);
+            }
+        };
+        let audit = AuditEvent::new(&role_perm.sub, "create_api_key").target(&name);
+
+        // otherwise a leaked key could mint keys that outlive its revocation
+        if role_perm.api_key.is_some() {
+            return Err(audit.denied(ctx, RbacError::Forbidden("API keys can't create API keys, log in first".to_string()).extend()).await);
+        }
+        let name = name.trim().to_string();
+        if name.is_empty() || name.len() > 255 {
+            return Err(audit.failure(ctx, RbacError::validation("name", "name must be 1 to 255 characters").extend()).await);
+        }
+        let permissions = match permissions {
+            Some(mut v) => {
+                v.sort();
+                v.dedup();
+                if v.is_empty() {
+                    return Err(audit.failure(ctx, RbacError::validation("permissions", "permissions can't be empty, leave it out for all of yours").extend()).await);
+                }
+                if let Some(p) = v.iter().find(|p| !role_perm.perm.contains(p)) {
+                    return Err(audit.failure(ctx, RbacError::validation("permissions", format!("{:?} is not one of your permissions", p)).extend()).await);
+                }
+                Some(v)
+            }
+            None => None,
+        };
+        if expires_at.is_some_and(|v| v <= Utc::now()) {
+            return Err(audit.failure(ctx, RbacError::validation("expiresAt", "expiresAt must be in the future").extend()).await);
+        }
+        let user_id = parse_id(&role_perm.sub, "sub")?;
+        let (key, prefix) = new_api_key();
+        match insert_api_key(pool, user_id, &name, &prefix, &hash_token(&key), permissions.as_deref(), expires_at).await {
+            Ok(row) => {
+                audit.after(&row.prefix).success(ctx).await;
+                Ok(ApiKey {
+                    key: Some(key),
+                    ..ApiKey::from(row)
+                })
+            }
+            Err(e) => Err(audit.failure(ctx, e).await),
+        }
+    }
+
+    // the owner of the key or an Admin can revoke it; it stops working at once
+    pub async fn revoke_api_key(&self, ctx: &Context<'_>, id: String) -> async_graphql::Result<String> {
+        let token = ctx.data::<Option<String>>().unwrap();
+        let pool = ctx.data::<PgPool>().unwrap();
+
+        let role_perm = match authorize(pool, token.clone()).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error revoke_api_key:- {:?}", e);
+                return Err(AuditEvent::new("anonymous", "revoke_api_key").target(&id).denied(ctx, e).await);
+            }
+        };
+        let audit = AuditEvent::new(&role_perm.sub, "revoke_api_key").target(&id);
+
+        if role_perm.api_key.is_some() {
+            return Err(audit.denied(ctx, RbacError::Forbidden("API keys can't revoke API keys, log in first".to_string()).extend()).await);
+        }
+        let key_id = parse_id(&id, "id")?;
+        let row = match fetch_api_key(pool, key_id).await {
+            Ok(Some(v)) => v,
+            Ok(None) => return Err(audit.failure(ctx, RbacError::NotFound("API Key Not Found".to_string()).extend()).await),
+            Err(e) => return Err(audit.failure(ctx, e).await),
+        };
+        // other users' keys look the same as missing ones to non-Admins
+        if row.user_id.to_string() != role_perm.sub && !role_perm.role.contains(&"Admin".to_string()) {
+            return Err(audit.failure(ctx, RbacError::NotFound("API Key Not Found".to_string()).extend()).await);
+        }
+        let audit = audit.after(&row.prefix);
+        match delete_api_key(pool, key_id).await {
+            Ok(true) => {
+                audit.success(ctx).await;
+                Ok("API key successfully revoked".to_string())
+            }
+            Ok(false) => Err(audit.failure(ctx, RbacError::NotFound("API Key Not Found".to_string()).extend()).await),
+            Err(e) => Err(audit.failure(ctx, e).await),
+        }
+    }
 }
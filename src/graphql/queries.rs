@@ -1,37 +1,206 @@
-use std::{collections::HashMap, string};
+
+use std::{collections::HashSet, sync::Mutex};
 
 use actix_web::{http::header::HeaderValue, HttpRequest};
-use async_graphql::{Context, Data, Error, ErrorExtensions, Object};
+use async_graphql::{
+    connection::{Connection, CursorType, Edge, OpaqueCursor},
+    dataloader::DataLoader,
+    ComplexObject, Context, Data, Error, ErrorExtensions, Object,
+};
+use chrono::{DateTime, Utc};
 use sqlx::{PgPool, Row};
+use tokio::sync::OnceCell;
 
 use crate::{
-    db::{roles::fetch_role_permission, users::check_user_info},
-    utilities::{auth::authorize, jwt::create_jwt},
+    db::{
+        api_keys::{fetch_api_keys, ApiKeyRow},
+        audit::{fetch_audit_events, AuditQuery},
+        pagination::{Page, PageCursor, SortKey},
+        permissions::fetch_permissions_page,
+        policy::{diff_policy, fetch_policy_revisions, fetch_policy_snapshot},
+        roles::{fetch_role_permission, fetch_roles_page, fetch_user_role_grants},
+        users::{fetch_users_page, UserQuery},
+        webhooks::{fetch_dead_letters, fetch_webhooks},
+    },
+    graphql::{
+        limits::{list_cost, NESTED_LIST_COST, UNBOUNDED_LIST_COST},
+        loaders::{
+            loader_error, PermissionRolesKey, RbacLoader, RoleMembersKey, RolePermissionsKey, UserRolesKey,
+        },
+    },
+    utilities::{
+        audit::AuditEvent,
+        auth::{authorize, AuthPerm},
+        errors::{parse_id, RbacError},
+        permission_cache::permission_cache,
+    },
 };
 
-#[derive(sqlx::FromRow, async_graphql::SimpleObject)]
+#[derive(sqlx::FromRow, async_graphql::SimpleObject, Clone)]
+#[graphql(complex)]
 pub struct User {
     pub id: i32,
     pub name: String,
     pub email: String,
+    pub created_at: DateTime<Utc>,
+    // None until the user's first login
+    pub last_login_at: Option<DateTime<Utc>>,
+    pub email_verified_at: Option<DateTime<Utc>>,
 }
 
-#[derive(sqlx::FromRow, async_graphql::SimpleObject)]
+#[derive(sqlx::FromRow, async_graphql::SimpleObject, Clone)]
+#[graphql(complex)]
 pub struct Roles {
     pub id: i32,
     pub name: String,
 }
 
-#[derive(sqlx::FromRow, async_graphql::SimpleObject)]
+#[derive(sqlx::FromRow, async_graphql::SimpleObject, Clone)]
+#[graphql(complex)]
 pub struct Permissions {
     pub id: i32,
     pub action: String,
 }
 
-#[derive(sqlx::FromRow, async_graphql::SimpleObject)]
-pub struct TokenData {
-    pub token: String,
-    pub id: String,
+// the caller of one GraphQL request. graphql_handler puts an empty one in each request; the first
+// nested field that needs it authorizes, and every other row and field reuses the answer
+#[derive(Default)]
+pub struct RequestAuth {
+    caller: OnceCell<async_graphql::Result<AuthPerm>>,
+    // nested fields already denied in this request, so each is audited once and not once per row
+    denied: Mutex<HashSet<&'static str>>,
+}
+
+// nested fields can be reached from any parent, so each one checks the caller itself
+async fn authorize_nested(ctx: &Context<'_>, field: &'static str, admin_only: bool) -> async_graphql::Result<()> {
+    let db_pool = ctx.data::<PgPool>().unwrap();
+    let token = ctx.data::<Option<String>>().unwrap();
+    // subscriptions carry no RequestAuth: their caller can lose access while the stream runs
+    let checked;
+    let role_perm = match ctx.data_opt::<RequestAuth>() {
+        Some(auth) => auth.caller.get_or_init(|| authorize(db_pool, token.clone())).await,
+        None => {
+            checked = authorize(db_pool, token.clone()).await;
+            &checked
+        }
+    };
+    let role_perm = match role_perm {
+        Ok(v) => v,
+        Err(e) => return Err(deny_nested(ctx, "anonymous", field, e.clone()).await),
+    };
+    let allowed = if admin_only {
+        role_perm.role.contains(&"Admin".to_string())
+    } else {
+        role_perm.perm.contains(&"Read".to_string())
+    };
+    if !allowed {
+        let e = RbacError::Forbidden("You are not authorized to view this field".to_string()).extend();
+        return Err(deny_nested(ctx, &role_perm.sub, field, e).await);
+    }
+    Ok(())
+}
+
+async fn deny_nested(ctx: &Context<'_>, actor: &str, field: &'static str, e: Error) -> Error {
+    let first = ctx
+        .data_opt::<RequestAuth>()
+        .is_none_or(|auth| auth.denied.lock().unwrap().insert(field));
+    if !first {
+        return e;
+    }
+    AuditEvent::new(actor, field).denied(ctx, e).await
+}
+
+#[ComplexObject]
+impl User {
+    #[graphql(complexity = "list_cost(NESTED_LIST_COST, child_complexity)")]
+    async fn roles(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Roles>> {
+        authorize_nested(ctx, "User.roles", false).await?;
+        let loader = ctx.data::<DataLoader<RbacLoader>>().unwrap();
+        let roles = loader.load_one(UserRolesKey(self.id)).await.map_err(loader_error)?;
+        Ok(roles.unwrap_or_default())
+    }
+
+    // effective permissions: the union of the permissions of all the user's roles
+    #[graphql(complexity = "list_cost(NESTED_LIST_COST, child_complexity)")]
+    async fn permissions(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Permissions>> {
+        authorize_nested(ctx, "User.permissions", false).await?;
+        let loader = ctx.data::<DataLoader<RbacLoader>>().unwrap();
+        let roles = loader
+            .load_one(UserRolesKey(self.id))
+            .await
+            .map_err(loader_error)?
+            .unwrap_or_default();
+        let grants = loader
+            .load_many(roles.iter().map(|r| RolePermissionsKey(r.id)))
+            .await
+            .map_err(loader_error)?;
+        let mut res: Vec<Permissions> = grants.into_values().flatten().collect();
+        res.sort_by(|a, b| a.action.cmp(&b.action).then(a.id.cmp(&b.id)));
+        res.dedup_by_key(|p| p.id);
+        Ok(res)
+    }
+}
+
+#[ComplexObject]
+impl Roles {
+    #[graphql(complexity = "list_cost(NESTED_LIST_COST, child_complexity)")]
+    async fn permissions(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Permissions>> {
+        authorize_nested(ctx, "Roles.permissions", false).await?;
+        let loader = ctx.data::<DataLoader<RbacLoader>>().unwrap();
+        let res = loader.load_one(RolePermissionsKey(self.id)).await.map_err(loader_error)?;
+        Ok(res.unwrap_or_default())
+    }
+
+    #[graphql(complexity = "list_cost(NESTED_LIST_COST, child_complexity)")]
+    async fn members(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<User>> {
+        authorize_nested(ctx, "Roles.members", true).await?;
+        let loader = ctx.data::<DataLoader<RbacLoader>>().unwrap();
+        let res = loader.load_one(RoleMembersKey(self.id)).await.map_err(loader_error)?;
+        Ok(res.unwrap_or_default())
+    }
+}
+
+#[ComplexObject]
+impl Permissions {
+    #[graphql(complexity = "list_cost(NESTED_LIST_COST, child_complexity)")]
+    async fn roles(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Roles>> {
+        authorize_nested(ctx, "Permissions.roles", false).await?;
+        let loader = ctx.data::<DataLoader<RbacLoader>>().unwrap();
+        let res = loader.load_one(PermissionRolesKey(self.id)).await.map_err(loader_error)?;
+        Ok(res.unwrap_or_default())
+    }
+}
+
+#[derive(async_graphql::SimpleObject)]
+pub struct AuthPayload {
+    // send as `Authorization: Bearer <accessToken>` until `expiresAt`
+    pub access_token: String,
+    // exchanged once for a new payload with `refreshSession`, until `refreshExpiresAt`
+    pub refresh_token: String,
+    pub token_type: String,
+    pub expires_at: DateTime<Utc>,
+    pub refresh_expires_at: DateTime<Utc>,
+    pub user: User,
+    pub roles: Vec<String>,
+    // effective permissions: the union of the permissions of `roles`
+    pub permissions: Vec<String>,
+}
+
+#[derive(async_graphql::SimpleObject)]
+pub struct TotpEnrollment {
+    // base32, for typing into an authenticator by hand
+    pub secret: String,
+    pub otpauth_uri: String,
+    // the otpauth URI as a QR code: a base64 PNG, e.g. for <img src="data:image/png;base64,...">
+    pub qr_png: String,
+}
+
+#[derive(async_graphql::SimpleObject)]
+pub struct TotpConfirmation {
+    // shown only this once; each one can stand in for a code from the authenticator one time
+    pub recovery_codes: Vec<String>,
+    // set when the enrollment finished a login, i.e. confirmTotp was called with an mfaToken
+    pub session: Option<AuthPayload>,
 }
 
 #[derive(sqlx::FromRow, async_graphql::SimpleObject)]
@@ -46,6 +215,259 @@ pub struct RoleUsers {
     pub user_name: String,
     pub user_email: String
 }
+
+#[derive(async_graphql::SimpleObject)]
+pub struct PolicyRevision {
+    pub id: i32,
+    pub actor: String,
+    pub summary: String,
+    pub created_at: DateTime<Utc>,
+    pub roles: Vec<RolePermi>,
+}
+
+#[derive(async_graphql::SimpleObject)]
+pub struct PolicyGrant {
+    pub role: String,
+    pub permission: String,
+}
+
+#[derive(async_graphql::SimpleObject)]
+pub struct RoleRename {
+    pub role_id: i32,
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(async_graphql::SimpleObject)]
+pub struct PolicyDiff {
+    pub from: i32,
+    pub to: i32,
+    pub added_roles: Vec<String>,
+    pub removed_roles: Vec<String>,
+    pub renamed_roles: Vec<RoleRename>,
+    pub granted: Vec<PolicyGrant>,
+    pub revoked: Vec<PolicyGrant>,
+}
+
+#[derive(async_graphql::SimpleObject)]
+pub struct AuditEventEntry {
+    pub id: i64,
+    pub created_at: DateTime<Utc>,
+    pub actor: String,
+    pub action: String,
+    pub target: Option<String>,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub outcome: String,
+    pub reason: Option<String>,
+    pub client_ip: Option<String>,
+    pub request_id: Option<String>,
+}
+
+#[derive(async_graphql::SimpleObject)]
+pub struct AuditEventPage {
+    pub total_count: i64,
+    pub events: Vec<AuditEventEntry>,
+}
+
+#[derive(async_graphql::InputObject, Default)]
+pub struct AuditEventFilter {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub target: Option<String>,
+    pub outcome: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+#[derive(async_graphql::SimpleObject)]
+pub struct WebhookSubscription {
+    pub id: i32,
+    pub url: String,
+    pub events: Vec<String>,
+    pub active: bool,
+    pub created_at: DateTime<Utc>,
+    // only returned once, when the subscription is created
+    pub secret: Option<String>,
+}
+
+#[derive(async_graphql::SimpleObject)]
+pub struct ApiKey {
+    pub id: i32,
+    pub name: String,
+    // the start of the key, enough to recognise it
+    pub prefix: String,
+    // null when the key carries every permission of its user
+    pub permissions: Option<Vec<String>>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    // only returned once, when the key is created
+    pub key: Option<String>,
+}
+
+impl From<ApiKeyRow> for ApiKey {
+    fn from(row: ApiKeyRow) -> Self {
+        ApiKey {
+            id: row.id,
+            name: row.name,
+            prefix: row.prefix,
+            permissions: row.permissions,
+            created_at: row.created_at,
+            expires_at: row.expires_at,
+            last_used_at: row.last_used_at,
+            key: None,
+        }
+    }
+}
+
+#[derive(async_graphql::SimpleObject)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub subscription_id: i32,
+    pub url: String,
+    pub event: String,
+    pub payload: serde_json::Value,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+#[derive(async_graphql::Enum, Copy, Clone, Eq, PartialEq, Default)]
+pub enum SortDirection {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(async_graphql::Enum, Copy, Clone, Eq, PartialEq, Default)]
+pub enum UserSortField {
+    #[default]
+    Id,
+    Name,
+    Email,
+    CreatedAt,
+}
+
+#[derive(async_graphql::InputObject, Default)]
+pub struct UserSort {
+    #[graphql(default)]
+    pub field: UserSortField,
+    #[graphql(default)]
+    pub direction: SortDirection,
+}
+
+#[derive(async_graphql::InputObject, Default)]
+pub struct UserFilter {
+    // case-insensitive substring matches
+    pub name_contains: Option<String>,
+    pub email_contains: Option<String>,
+    // exact role name
+    pub has_role: Option<String>,
+    pub created_after: Option<DateTime<Utc>>,
+}
+
+#[derive(async_graphql::Enum, Copy, Clone, Eq, PartialEq, Default)]
+pub enum RoleSortField {
+    #[default]
+    Id,
+    Name,
+}
+
+#[derive(async_graphql::InputObject, Default)]
+pub struct RoleSort {
+    #[graphql(default)]
+    pub field: RoleSortField,
+    #[graphql(default)]
+    pub direction: SortDirection,
+}
+
+#[derive(async_graphql::InputObject, Default)]
+pub struct RoleFilter {
+    pub name_contains: Option<String>,
+}
+
+#[derive(async_graphql::Enum, Copy, Clone, Eq, PartialEq, Default)]
+pub enum PermissionSortField {
+    #[default]
+    Id,
+    Action,
+}
+
+#[derive(async_graphql::InputObject, Default)]
+pub struct PermissionSort {
+    #[graphql(default)]
+    pub field: PermissionSortField,
+    #[graphql(default)]
+    pub direction: SortDirection,
+}
+
+#[derive(async_graphql::InputObject, Default)]
+pub struct PermissionFilter {
+    pub action_contains: Option<String>,
+}
+
+#[derive(async_graphql::SimpleObject)]
+pub struct ConnectionTotal {
+    // number of rows matching the filter, across all pages
+    pub total_count: i64,
+}
+
+pub type ListCursor = OpaqueCursor<PageCursor>;
+pub type UserConnection = Connection<ListCursor, User, ConnectionTotal>;
+pub type RoleConnection = Connection<ListCursor, Roles, ConnectionTotal>;
+pub type PermissionConnection = Connection<ListCursor, Permissions, ConnectionTotal>;
+
+fn sort_key(name: &'static str, column: &'static str, sql_type: &'static str, direction: SortDirection) -> SortKey {
+    SortKey {
+        name,
+        column,
+        sql_type,
+        descending: direction == SortDirection::Desc,
+    }
+}
+
+pub fn decode_after(after: Option<String>) -> async_graphql::Result<Option<PageCursor>> {
+    match after {
+        None => Ok(None),
+        Some(v) => match ListCursor::decode_cursor(&v) {
+            Ok(c) => Ok(Some(c.0)),
+            Err(_) => Err(RbacError::validation("after", "after is not a cursor returned by this list").extend()),
+        },
+    }
+}
+
+fn into_connection<T, F>(page: Page, paged: bool, node: F) -> Connection<ListCursor, T, ConnectionTotal>
+where
+    T: async_graphql::OutputType,
+    F: Fn(&sqlx::postgres::PgRow) -> T,
+{
+    let mut conn = Connection::with_additional_fields(
+        paged,
+        page.has_next,
+        ConnectionTotal {
+            total_count: page.total,
+        },
+    );
+    conn.edges = page
+        .rows
+        .into_iter()
+        .map(|(cursor, row)| Edge::new(OpaqueCursor(cursor), node(&row)))
+        .collect();
+    conn
+}
+
+#[derive(async_graphql::SimpleObject)]
+pub struct PermissionCacheMetrics {
+    pub hits: u64,
+    pub misses: u64,
+    // hits / (hits + misses), 0 before the first lookup
+    pub hit_ratio: f64,
+    pub invalidations: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub max_entries: usize,
+    pub ttl_seconds: u64,
+}
 pub struct Query;
 #[Object]
 impl Query {
@@ -53,27 +475,7 @@ impl Query {
         "Hello".to_string()
     }
 
-    pub async fn login(
-        &self,
-        ctx: &Context<'_>,
-        email: String,
-        password: String,
-    ) -> async_graphql::Result<TokenData> {
-        let pool = ctx.data::<PgPool>().unwrap();
-        match check_user_info(pool, email.to_string(), password.to_string()).await {
-            Ok(v) => {
-                let uid = format!("{}", v.id);
-                let role = v.role;
-                // call the jwt token function
-                match create_jwt(&uid, role).await {
-                    Ok(v) => Ok(TokenData { token: v , id: uid.clone()}),
-                    Err(e) => Err(e.into()),
-                }
-            }
-            Err(e) => Err(e),
-        }
-    }
-
+    #[graphql(deprecation = "Use `users`, which is paginated", complexity = "list_cost(UNBOUNDED_LIST_COST, child_complexity)")]
     pub async fn fetch_all_user(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<User>> {
         // let token = ctx.data::<String>().unwrap();
         let db_pool = ctx.data::<PgPool>().unwrap();
@@ -82,22 +484,20 @@ impl Query {
             Ok(v) => v,
             Err(e) => {
                 println!("Error assign_user_role:- {:?}", e);
-                return Err(e);
+                return Err(AuditEvent::new("anonymous", "fetch_all_user").denied(ctx, e).await);
             }
         };
 
         if !role_perm.perm.contains(&"Read".to_string()) {
-            return Err(Error::new("Not Authorized")
-                .extend_with(|_, x| x.set("details", "You are not authorized to view users")));
+            return Err(AuditEvent::new(&role_perm.sub, "fetch_all_user").denied(ctx, RbacError::Forbidden("You are not authorized to view users".to_string()).extend()).await);
         }
-        let data = match sqlx::query("SELECT id,name,email from users order by id;")
+        let data = match sqlx::query("SELECT id,name,email,created_at,last_login_at,email_verified_at from users order by id;")
             .fetch_all(db_pool)
             .await
         {
             Ok(v) => v,
             Err(e) => {
-                return Err(Error::new("Intenal Server Error")
-                    .extend_with(|_, x| x.set("details", " Failed to fetch the users")));
+                return Err(RbacError::Internal("Failed to fetch the users".to_string()).extend());
             }
         };
         let mut res: Vec<User> = vec![];
@@ -105,7 +505,10 @@ impl Query {
             let id: i32 = i.get("id");
             let name: String = i.get("name");
             let email: String = i.get("email");
-            res.push(User { id, name, email });
+            let created_at: DateTime<Utc> = i.get("created_at");
+            let last_login_at: Option<DateTime<Utc>> = i.get("last_login_at");
+            let email_verified_at: Option<DateTime<Utc>> = i.get("email_verified_at");
+            res.push(User { id, name, email, created_at, last_login_at, email_verified_at });
         }
         Ok(res)
     }
@@ -118,24 +521,24 @@ impl Query {
             Ok(v) => v,
             Err(e) => {
                 println!("Error fetch_user:- {:?}", e);
-                return Err(e);
+                return Err(AuditEvent::new("anonymous", "fetch_user").denied(ctx, e).await);
             }
         };
-        let id = id.parse::<i32>().unwrap();
+        let id = parse_id(&id, "id")?;
         if !role_perm.role.contains(&"Admin".to_string()) {
             let check_exist = match sqlx::query("select  Exists (select * from user_roles a , roles b where a.role_id = b.id and a.user_id=$1 and b.name LIKE 'Admin');").bind(&id).fetch_one(db_pool).await {
                 Ok(v) => v,
                 Err(e) => {
                     println!("Error fetch user = {:?}",e);
-                    return Err(Error::new("Internal Server Error"));
+                    return Err(RbacError::Internal("Failed to check the roles of the user".to_string()).extend());
                 }
             };
             let exist : bool= check_exist.get("exists");
             if exist {
-                return Err(Error::new("Not Authorized"));
+                return Err(AuditEvent::new(&role_perm.sub, "fetch_user").denied(ctx, RbacError::Forbidden("Not Authorized".to_string()).extend()).await);
             }
             else {
-                match sqlx::query("SELECT id,name,email from users where id = $1;")
+                match sqlx::query("SELECT id,name,email,created_at,last_login_at,email_verified_at from users where id = $1;")
             .bind(id)
             .fetch_one(db_pool)
             .await
@@ -144,14 +547,16 @@ impl Query {
                 return Ok(User {
                 id: v.get("id"),
                 name: v.get("name"),
-                email: v.get("email"),});
+                email: v.get("email"),
+                created_at: v.get("created_at"),
+                last_login_at: v.get("last_login_at"),
+                email_verified_at: v.get("email_verified_at"),});
                 },
-            Err(e) => {return Err(Error::new("User Not Found")
-                .extend_with(|_, x| x.set("details", "User with the following id not found")));}
+            Err(e) => {return Err(RbacError::NotFound("User with the following id not found".to_string()).extend());}
         }
             }
         }
-        else {match sqlx::query("SELECT id,name,email from users where id = $1;")
+        else {match sqlx::query("SELECT id,name,email,created_at,last_login_at,email_verified_at from users where id = $1;")
             .bind(id)
             .fetch_one(db_pool)
             .await
@@ -160,13 +565,16 @@ impl Query {
                 return Ok(User {
                 id: v.get("id"),
                 name: v.get("name"),
-                email: v.get("email"),});
+                email: v.get("email"),
+                created_at: v.get("created_at"),
+                last_login_at: v.get("last_login_at"),
+                email_verified_at: v.get("email_verified_at"),});
                 },
-            Err(e) => {return Err(Error::new("User Not Found")
-                .extend_with(|_, x| x.set("details", "User with the following id not found")));}
+            Err(e) => {return Err(RbacError::NotFound("User with the following id not found".to_string()).extend());}
         }}
     }
 
+    #[graphql(deprecation = "Use `roles`, which is paginated", complexity = "list_cost(UNBOUNDED_LIST_COST, child_complexity)")]
     async fn fetch_all_roles(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Roles>> {
         let db_pool = ctx.data::<PgPool>().unwrap();
         let token = ctx.data::<Option<String>>().unwrap();
@@ -175,13 +583,12 @@ impl Query {
             Ok(v) => v,
             Err(e) => {
                 println!("Error assign_user_role:- {:?}", e);
-                return Err(e);
+                return Err(AuditEvent::new("anonymous", "fetch_all_roles").denied(ctx, e).await);
             }
         };
 
         if !role_perm.perm.contains(&"Read".to_string()) {
-            return Err(Error::new("Not Authorized")
-                .extend_with(|_, x| x.set("details", "You are not authorized to View Role")));
+            return Err(AuditEvent::new(&role_perm.sub, "fetch_all_roles").denied(ctx, RbacError::Forbidden("You are not authorized to View Role".to_string()).extend()).await);
         }
         let data = match sqlx::query("SELECT id,name from roles order by id;")
             .fetch_all(db_pool)
@@ -189,8 +596,7 @@ impl Query {
         {
             Ok(v) => v,
             Err(e) => {
-                return Err(Error::new("Intenal Server Error")
-                    .extend_with(|_, x| x.set("details", " Failed to fetch the Roles")));
+                return Err(RbacError::Internal("Failed to fetch the Roles".to_string()).extend());
             }
         };
 
@@ -204,6 +610,7 @@ impl Query {
         Ok(res)
     }
 
+    #[graphql(deprecation = "Use `permissions`, which is paginated", complexity = "list_cost(UNBOUNDED_LIST_COST, child_complexity)")]
     async fn fetch_all_permissions(
         &self,
         ctx: &Context<'_>,
@@ -215,13 +622,12 @@ impl Query {
             Ok(v) => v,
             Err(e) => {
                 println!("Error assign_user_role:- {:?}", e);
-                return Err(e);
+                return Err(AuditEvent::new("anonymous", "fetch_all_permissions").denied(ctx, e).await);
             }
         };
 
         if !role_perm.perm.contains(&"Read".to_string()) {
-            return Err(Error::new("Not Authorized")
-                .extend_with(|_, x| x.set("details", "You are not authorized to view permissions")));
+            return Err(AuditEvent::new(&role_perm.sub, "fetch_all_permissions").denied(ctx, RbacError::Forbidden("You are not authorized to view permissions".to_string()).extend()).await);
         }
         let data = match sqlx::query("SELECT id,action from permissions order by id;")
             .fetch_all(db_pool)
@@ -229,8 +635,7 @@ impl Query {
         {
             Ok(v) => v,
             Err(e) => {
-                return Err(Error::new("Intenal Server Error")
-                    .extend_with(|_, x| x.set("details", " Failed to fetch the permissions")));
+                return Err(RbacError::Internal("Failed to fetch the permissions".to_string()).extend());
             }
         };
 
@@ -245,54 +650,161 @@ impl Query {
     }
 
 
-    async fn fetch_user_role_permission(&self,ctx: &Context<'_>,id: String) -> async_graphql::Result<Vec<RolePermi>>{
+    // Relay connection over users; pass pageInfo.endCursor as `after` for the next page
+    #[graphql(complexity = "list_cost(first, child_complexity)")]
+    async fn users(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 50)] first: i64,
+        after: Option<String>,
+        filter: Option<UserFilter>,
+        sort: Option<UserSort>,
+    ) -> async_graphql::Result<UserConnection> {
         let db_pool = ctx.data::<PgPool>().unwrap();
         let token = ctx.data::<Option<String>>().unwrap();
 
         let role_perm = match authorize(db_pool, token.clone()).await {
             Ok(v) => v,
             Err(e) => {
-                println!("Error authorize_user_role_permission:- {:?}", e);
-                return Err(e);
+                println!("Error users:- {:?}", e);
+                return Err(AuditEvent::new("anonymous", "users").denied(ctx, e).await);
             }
         };
-
         if !role_perm.perm.contains(&"Read".to_string()) {
-            return Err(Error::new("Not Authorized")
-                .extend_with(|_, x| x.set("details", "You are not authorized to view permissions")));
+            return Err(AuditEvent::new(&role_perm.sub, "users").denied(ctx, RbacError::Forbidden("You are not authorized to view users".to_string()).extend()).await);
         }
-        let id = id.parse::<i32>().unwrap();
-        let data = match sqlx::query("select a.user_id, c.name,d.action from user_roles a, role_permissions b, roles c, permissions d where a.role_id = b.role_id and b.permission_id = d.id and a.role_id = c.id and a.user_id = $1;")
-        .bind(id)
-        .fetch_all(db_pool).await {
+
+        let filter = filter.unwrap_or_default();
+        let sort = sort.unwrap_or_default();
+        let key = match sort.field {
+            UserSortField::Id => sort_key("id", "id", "integer", sort.direction),
+            UserSortField::Name => sort_key("name", "name", "varchar", sort.direction),
+            UserSortField::Email => sort_key("email", "email", "varchar", sort.direction),
+            UserSortField::CreatedAt => sort_key("created_at", "created_at", "timestamptz", sort.direction),
+        };
+        let qry = UserQuery {
+            name_contains: filter.name_contains,
+            email_contains: filter.email_contains,
+            has_role: filter.has_role,
+            created_after: filter.created_after,
+        };
+        let after = decode_after(after)?;
+        let paged = after.is_some();
+        let page = fetch_users_page(db_pool, &qry, &key, after, first).await?;
+        Ok(into_connection(page, paged, |row| User {
+            id: row.get("id"),
+            name: row.get("name"),
+            email: row.get("email"),
+            created_at: row.get("created_at"),
+            last_login_at: row.get("last_login_at"),
+            email_verified_at: row.get("email_verified_at"),
+        }))
+    }
+
+    #[graphql(complexity = "list_cost(first, child_complexity)")]
+    async fn roles(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 50)] first: i64,
+        after: Option<String>,
+        filter: Option<RoleFilter>,
+        sort: Option<RoleSort>,
+    ) -> async_graphql::Result<RoleConnection> {
+        let db_pool = ctx.data::<PgPool>().unwrap();
+        let token = ctx.data::<Option<String>>().unwrap();
+
+        let role_perm = match authorize(db_pool, token.clone()).await {
             Ok(v) => v,
             Err(e) => {
-                println!("Error fetch_user_role_permission = {:?}",e);
-                return Err(Error::new("Intenal Server Error")
-                    .extend_with(|_, x| x.set("details", " Failed to fetch the permissions")));
+                println!("Error roles:- {:?}", e);
+                return Err(AuditEvent::new("anonymous", "roles").denied(ctx, e).await);
             }
         };
-        let mut dum:HashMap<String, Vec<String>> = HashMap::new();
-        for i in data {
-            let name: String = i.get("name");
-            let action : String = i.get("action");
-            if !dum.contains_key(&name) {
-                dum.insert(name.clone(), vec![]);
-            }
+        if !role_perm.perm.contains(&"Read".to_string()) {
+            return Err(AuditEvent::new(&role_perm.sub, "roles").denied(ctx, RbacError::Forbidden("You are not authorized to View Role".to_string()).extend()).await);
+        }
 
-            dum.get_mut(&name).unwrap().push(action.clone());
+        let filter = filter.unwrap_or_default();
+        let sort = sort.unwrap_or_default();
+        let key = match sort.field {
+            RoleSortField::Id => sort_key("id", "id", "integer", sort.direction),
+            RoleSortField::Name => sort_key("name", "name", "varchar", sort.direction),
+        };
+        let after = decode_after(after)?;
+        let paged = after.is_some();
+        let page = fetch_roles_page(db_pool, filter.name_contains.as_deref(), &key, after, first).await?;
+        Ok(into_connection(page, paged, |row| Roles {
+            id: row.get("id"),
+            name: row.get("name"),
+        }))
+    }
+
+    #[graphql(complexity = "list_cost(first, child_complexity)")]
+    async fn permissions(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 50)] first: i64,
+        after: Option<String>,
+        filter: Option<PermissionFilter>,
+        sort: Option<PermissionSort>,
+    ) -> async_graphql::Result<PermissionConnection> {
+        let db_pool = ctx.data::<PgPool>().unwrap();
+        let token = ctx.data::<Option<String>>().unwrap();
+
+        let role_perm = match authorize(db_pool, token.clone()).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error permissions:- {:?}", e);
+                return Err(AuditEvent::new("anonymous", "permissions").denied(ctx, e).await);
+            }
+        };
+        if !role_perm.perm.contains(&"Read".to_string()) {
+            return Err(AuditEvent::new(&role_perm.sub, "permissions").denied(ctx, RbacError::Forbidden("You are not authorized to view permissions".to_string()).extend()).await);
         }
 
-        let mut res: Vec<RolePermi> = Vec::new();
-        for (i,j) in dum.iter() {
-            res.push( RolePermi {
-                role: i.clone(),
-                perm: j.clone()
-            });
+        let filter = filter.unwrap_or_default();
+        let sort = sort.unwrap_or_default();
+        let key = match sort.field {
+            PermissionSortField::Id => sort_key("id", "id", "integer", sort.direction),
+            PermissionSortField::Action => sort_key("action", "action", "varchar", sort.direction),
+        };
+        let after = decode_after(after)?;
+        let paged = after.is_some();
+        let page = fetch_permissions_page(db_pool, filter.action_contains.as_deref(), &key, after, first).await?;
+        Ok(into_connection(page, paged, |row| Permissions {
+            id: row.get("id"),
+            action: row.get("action"),
+        }))
+    }
+
+    async fn fetch_user_role_permission(&self,ctx: &Context<'_>,id: String) -> async_graphql::Result<Vec<RolePermi>>{
+        let db_pool = ctx.data::<PgPool>().unwrap();
+        let token = ctx.data::<Option<String>>().unwrap();
+
+        let role_perm = match authorize(db_pool, token.clone()).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error authorize_user_role_permission:- {:?}", e);
+                return Err(AuditEvent::new("anonymous", "fetch_user_role_permission").denied(ctx, e).await);
+            }
+        };
+
+        if !role_perm.perm.contains(&"Read".to_string()) {
+            return Err(AuditEvent::new(&role_perm.sub, "fetch_user_role_permission").denied(ctx, RbacError::Forbidden("You are not authorized to view permissions".to_string()).extend()).await);
         }
+        let id = parse_id(&id, "id")?;
+        let grants = fetch_user_role_grants(db_pool, id).await?;
+        let res: Vec<RolePermi> = grants
+            .into_iter()
+            .map(|g| RolePermi {
+                role: g.name,
+                perm: g.actions,
+            })
+            .collect();
         Ok(res)
     }
 
+    #[graphql(complexity = "list_cost(UNBOUNDED_LIST_COST, child_complexity)")]
     async fn fetch_role_users(&self, ctx: &Context<'_>, role_name:String) -> async_graphql::Result<Vec<RoleUsers>> {
         let db_pool = ctx.data::<PgPool>().unwrap();
         let token = ctx.data::<Option<String>>().unwrap();
@@ -301,19 +813,18 @@ impl Query {
             Ok(v) => v,
             Err(e) => {
                 println!("Error fetch_role:- {:?}", e);
-                return Err(e);
+                return Err(AuditEvent::new("anonymous", "fetch_role_users").denied(ctx, e).await);
             }
         };
         if !role_perm.role.contains(&"Admin".to_string()) {
-            return Err(Error::new("Not Authorized")
-                .extend_with(|_, x| x.set("details", "You are not authorized to view roles")));
+            return Err(AuditEvent::new(&role_perm.sub, "fetch_role_users").denied(ctx, RbacError::Forbidden("You are not authorized to view roles".to_string()).extend()).await);
         }
         // let role_id = role_id.parse::<i32>().unwrap();
         let data = match sqlx::query("select b.id,b.name,b.email from user_roles a, users b where a.user_id = b.id and a.role_id in (SELECT id from roles where name=$1);").bind(&role_name).fetch_all(db_pool).await {
             Ok(v) => v,
             Err(e) => {
-                println!("Error fetch role");
-                return Err(Error::new("Internal Server Error"));
+                println!("Error fetch role = {:?}",e);
+                return Err(RbacError::Internal("Failed to fetch the users of the role".to_string()).extend());
             }
         };
         let mut res : Vec<RoleUsers> = Vec::new();
@@ -337,12 +848,11 @@ impl Query {
             Ok(v) => v,
             Err(e) => {
                 println!("Error fetch_role:- {:?}", e);
-                return Err(e);
+                return Err(AuditEvent::new("anonymous", "fetch_role_all_permissions").denied(ctx, e).await);
             }
         };
         if !role_perm.role.contains(&"Admin".to_string()) {
-            return Err(Error::new("Not Authorized")
-                .extend_with(|_, x| x.set("details", "You are not authorized to view roles")));
+            return Err(AuditEvent::new(&role_perm.sub, "fetch_role_all_permissions").denied(ctx, RbacError::Forbidden("You are not authorized to view roles".to_string()).extend()).await);
         }
 
         match fetch_role_permission(db_pool,vec![role_name.clone()]).await {
@@ -352,6 +862,245 @@ impl Query {
             }
         }
     }
-}
 
+    #[graphql(complexity = "list_cost(UNBOUNDED_LIST_COST, child_complexity)")]
+    async fn policy_revisions(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<PolicyRevision>> {
+        let db_pool = ctx.data::<PgPool>().unwrap();
+        let token = ctx.data::<Option<String>>().unwrap();
+
+        let role_perm = match authorize(db_pool, token.clone()).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error policy_revisions:- {:?}", e);
+                return Err(AuditEvent::new("anonymous", "policy_revisions").denied(ctx, e).await);
+            }
+        };
+        if !role_perm.role.contains(&"Admin".to_string()) {
+            return Err(AuditEvent::new(&role_perm.sub, "policy_revisions").denied(ctx, RbacError::Forbidden("You are not authorized to view the policy history".to_string()).extend()).await);
+        }
+
+        let revisions = fetch_policy_revisions(db_pool).await?;
+        let mut res: Vec<PolicyRevision> = Vec::new();
+        for rev in revisions {
+            res.push(PolicyRevision {
+                id: rev.id,
+                actor: rev.actor,
+                summary: rev.summary,
+                created_at: rev.created_at,
+                roles: rev
+                    .snapshot
+                    .roles
+                    .into_iter()
+                    .map(|r| RolePermi {
+                        role: r.name,
+                        perm: r.permissions,
+                    })
+                    .collect(),
+            });
+        }
+        Ok(res)
+    }
+
+    async fn policy_diff(&self, ctx: &Context<'_>, from: i32, to: i32) -> async_graphql::Result<PolicyDiff> {
+        let db_pool = ctx.data::<PgPool>().unwrap();
+        let token = ctx.data::<Option<String>>().unwrap();
+
+        let role_perm = match authorize(db_pool, token.clone()).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error policy_diff:- {:?}", e);
+                return Err(AuditEvent::new("anonymous", "policy_diff").denied(ctx, e).await);
+            }
+        };
+        if !role_perm.role.contains(&"Admin".to_string()) {
+            return Err(AuditEvent::new(&role_perm.sub, "policy_diff").denied(ctx, RbacError::Forbidden("You are not authorized to view the policy history".to_string()).extend()).await);
+        }
+
+        let old = fetch_policy_snapshot(db_pool, from).await?;
+        let new = fetch_policy_snapshot(db_pool, to).await?;
+        let changes = diff_policy(&old, &new);
+        let grants = |v: Vec<(String, String)>| {
+            v.into_iter()
+                .map(|(role, permission)| PolicyGrant { role, permission })
+                .collect()
+        };
+        Ok(PolicyDiff {
+            from,
+            to,
+            added_roles: changes.added_roles,
+            removed_roles: changes.removed_roles,
+            renamed_roles: changes
+                .renamed_roles
+                .into_iter()
+                .map(|(role_id, from, to)| RoleRename { role_id, from, to })
+                .collect(),
+            granted: grants(changes.granted),
+            revoked: grants(changes.revoked),
+        })
+    }
+
+    #[graphql(complexity = "list_cost(limit, child_complexity)")]
+    async fn audit_events(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<AuditEventFilter>,
+        #[graphql(default = 50)] limit: i64,
+        #[graphql(default = 0)] offset: i64,
+    ) -> async_graphql::Result<AuditEventPage> {
+        let db_pool = ctx.data::<PgPool>().unwrap();
+        let token = ctx.data::<Option<String>>().unwrap();
+
+        let role_perm = match authorize(db_pool, token.clone()).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error audit_events:- {:?}", e);
+                return Err(AuditEvent::new("anonymous", "audit_events").denied(ctx, e).await);
+            }
+        };
+        if !role_perm.role.contains(&"Admin".to_string()) {
+            return Err(AuditEvent::new(&role_perm.sub, "audit_events").denied(ctx, RbacError::Forbidden("You are not authorized to view the audit log".to_string()).extend()).await);
+        }
+        if !(1..=500).contains(&limit) || offset < 0 {
+            let field = if offset < 0 { "offset" } else { "limit" };
+            return Err(RbacError::validation(field, "limit must be between 1 and 500 and offset must not be negative").extend());
+        }
+
+        let filter = filter.unwrap_or_default();
+        let qry = AuditQuery {
+            actor: filter.actor,
+            action: filter.action,
+            target: filter.target,
+            outcome: filter.outcome,
+            since: filter.since,
+            until: filter.until,
+        };
+        let (total_count, rows) = fetch_audit_events(db_pool, &qry, limit, offset).await?;
+        let events = rows
+            .into_iter()
+            .map(|row| AuditEventEntry {
+                id: row.id,
+                created_at: row.created_at,
+                actor: row.record.actor,
+                action: row.record.action,
+                target: row.record.target,
+                before: row.record.before,
+                after: row.record.after,
+                outcome: row.record.outcome,
+                reason: row.record.reason,
+                client_ip: row.record.client_ip,
+                request_id: row.record.request_id,
+            })
+            .collect();
+        Ok(AuditEventPage { total_count, events })
+    }
+
+    // the caller's API keys; an Admin can pass `userId` to see another user's
+    async fn list_api_keys(&self, ctx: &Context<'_>, user_id: Option<String>) -> async_graphql::Result<Vec<ApiKey>> {
+        let db_pool = ctx.data::<PgPool>().unwrap();
+        let token = ctx.data::<Option<String>>().unwrap();
+
+        let role_perm = match authorize(db_pool, token.clone()).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error list_api_keys:- {:?}", e);
+                return Err(AuditEvent::new("anonymous", "list_api_keys").denied(ctx, e).await);
+            }
+        };
+        let user_id = user_id.unwrap_or_else(|| role_perm.sub.clone());
+        if user_id != role_perm.sub && !role_perm.role.contains(&"Admin".to_string()) {
+            return Err(AuditEvent::new(&role_perm.sub, "list_api_keys").target(&user_id).denied(ctx, RbacError::Forbidden("You are not authorized to view the API keys of other users".to_string()).extend()).await);
+        }
+        let user_id = parse_id(&user_id, "userId")?;
+        let res = fetch_api_keys(db_pool, user_id).await?;
+        Ok(res.into_iter().map(ApiKey::from).collect())
+    }
+
+    async fn webhooks(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<WebhookSubscription>> {
+        let db_pool = ctx.data::<PgPool>().unwrap();
+        let token = ctx.data::<Option<String>>().unwrap();
 
+        let role_perm = match authorize(db_pool, token.clone()).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error webhooks:- {:?}", e);
+                return Err(AuditEvent::new("anonymous", "webhooks").denied(ctx, e).await);
+            }
+        };
+        if !role_perm.role.contains(&"Admin".to_string()) {
+            return Err(AuditEvent::new(&role_perm.sub, "webhooks").denied(ctx, RbacError::Forbidden("You are not authorized to view webhooks".to_string()).extend()).await);
+        }
+
+        let res = fetch_webhooks(db_pool).await?;
+        Ok(res
+            .into_iter()
+            .map(|w| WebhookSubscription {
+                id: w.id,
+                url: w.url,
+                events: w.events,
+                active: w.active,
+                created_at: w.created_at,
+                secret: None,
+            })
+            .collect())
+    }
+
+    async fn webhook_dead_letters(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<WebhookDelivery>> {
+        let db_pool = ctx.data::<PgPool>().unwrap();
+        let token = ctx.data::<Option<String>>().unwrap();
+
+        let role_perm = match authorize(db_pool, token.clone()).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error webhook_dead_letters:- {:?}", e);
+                return Err(AuditEvent::new("anonymous", "webhook_dead_letters").denied(ctx, e).await);
+            }
+        };
+        if !role_perm.role.contains(&"Admin".to_string()) {
+            return Err(AuditEvent::new(&role_perm.sub, "webhook_dead_letters").denied(ctx, RbacError::Forbidden("You are not authorized to view webhooks".to_string()).extend()).await);
+        }
+
+        let res = fetch_dead_letters(db_pool).await?;
+        Ok(res
+            .into_iter()
+            .map(|d| WebhookDelivery {
+                id: d.id,
+                subscription_id: d.subscription_id,
+                url: d.url,
+                event: d.event,
+                payload: d.payload,
+                attempts: d.attempts,
+                last_error: d.last_error,
+                created_at: d.created_at,
+            })
+            .collect())
+    }
+
+    async fn permission_cache_stats(&self, ctx: &Context<'_>) -> async_graphql::Result<PermissionCacheMetrics> {
+        let db_pool = ctx.data::<PgPool>().unwrap();
+        let token = ctx.data::<Option<String>>().unwrap();
+
+        let role_perm = match authorize(db_pool, token.clone()).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error permission_cache_stats:- {:?}", e);
+                return Err(AuditEvent::new("anonymous", "permission_cache_stats").denied(ctx, e).await);
+            }
+        };
+        if !role_perm.role.contains(&"Admin".to_string()) {
+            return Err(AuditEvent::new(&role_perm.sub, "permission_cache_stats").denied(ctx, RbacError::Forbidden("You are not authorized to view the cache metrics".to_string()).extend()).await);
+        }
+
+        let stats = permission_cache().stats();
+        let lookups = stats.hits + stats.misses;
+        Ok(PermissionCacheMetrics {
+            hits: stats.hits,
+            misses: stats.misses,
+            hit_ratio: if lookups == 0 { 0.0 } else { stats.hits as f64 / lookups as f64 },
+            invalidations: stats.invalidations,
+            evictions: stats.evictions,
+            entries: stats.entries,
+            max_entries: stats.max_entries,
+            ttl_seconds: stats.ttl_seconds,
+        })
+    }
+}
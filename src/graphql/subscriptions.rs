@@ -0,0 +1,348 @@
+use std::time::Duration;
+
+use async_graphql::{Context, Enum, ErrorExtensions, SimpleObject, Subscription as GraphQLSubscription};
+use futures_util::Stream;
+use sqlx::{PgPool, Row};
+use tokio::{
+    sync::broadcast::{error::RecvError, Receiver},
+    time::{interval_at, Instant, Interval},
+};
+
+use crate::{
+    db::{roles::fetch_role_permission, users::fetch_user_roles},
+    utilities::{
+        audit::AuditEvent,
+        auth::{authorize, AuthPerm},
+        change_feed::{ChangeEvent, ChangeFeed},
+        errors::{parse_id, RbacError},
+    },
+};
+
+// how often a running subscription checks that its caller may still watch, besides on changes
+// to the caller's own user and to roles
+const RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+pub enum ChangeOperation {
+    Insert,
+    Update,
+    Delete,
+}
+
+impl ChangeOperation {
+    fn from_event(event: &ChangeEvent) -> Self {
+        match event.op.as_str() {
+            "INSERT" => ChangeOperation::Insert,
+            "DELETE" => ChangeOperation::Delete,
+            _ => ChangeOperation::Update,
+        }
+    }
+}
+
+#[derive(SimpleObject, Clone)]
+pub struct PermissionChange {
+    pub user_id: i32,
+    pub roles: Vec<String>,
+    pub permissions: Vec<String>,
+}
+
+#[derive(SimpleObject, Clone)]
+pub struct RoleChange {
+    pub role_id: i32,
+    // roles, role_permissions or user_roles; "resync" after the subscriber fell behind and
+    // missed events, with the current state of the role
+    pub source: String,
+    pub operation: ChangeOperation,
+    pub name: Option<String>,
+    pub permissions: Vec<String>,
+}
+
+#[derive(SimpleObject, Clone)]
+pub struct UserChange {
+    pub user_id: i32,
+    // users or user_roles
+    pub source: String,
+    pub operation: ChangeOperation,
+}
+
+async fn effective_permissions(pool: &PgPool, user_id: i32) -> async_graphql::Result<PermissionChange> {
+    let roles = fetch_user_roles(pool, user_id).await?;
+    let mut permissions = fetch_role_permission(pool, roles.clone()).await?;
+    permissions.sort();
+    permissions.dedup();
+    Ok(PermissionChange {
+        user_id,
+        roles,
+        permissions,
+    })
+}
+
+// the role's name and permissions, None once it was deleted
+async fn role_state(pool: &PgPool, role_id: i32) -> async_graphql::Result<(Option<String>, Vec<String>)> {
+    let name: Option<String> = match sqlx::query("SELECT name FROM roles WHERE id = $1;")
+        .bind(role_id)
+        .fetch_optional(pool)
+        .await
+    {
+        Ok(v) => v.map(|row| row.get("name")),
+        Err(e) => {
+            println!("Error role_changed = {:?}", e);
+            return Err(RbacError::Internal("Failed to fetch the role".to_string()).extend());
+        }
+    };
+    let permissions = match &name {
+        Some(name) => fetch_role_permission(pool, vec![name.clone()]).await?,
+        None => vec![],
+    };
+    Ok((name, permissions))
+}
+
+enum FeedItem {
+    Event(ChangeEvent),
+    // the subscriber fell behind and the oldest events were dropped, or the feed reconnected
+    Missed,
+    Recheck,
+    Closed,
+}
+
+async fn next_item(rx: &mut Receiver<ChangeEvent>, recheck: &mut Interval) -> FeedItem {
+    tokio::select! {
+        res = rx.recv() => match res {
+            Ok(v) if v.is_resync() => FeedItem::Missed,
+            Ok(v) => FeedItem::Event(v),
+            Err(RecvError::Lagged(_)) => FeedItem::Missed,
+            Err(RecvError::Closed) => FeedItem::Closed,
+        },
+        _ = recheck.tick() => FeedItem::Recheck,
+    }
+}
+
+fn recheck_interval() -> Interval {
+    interval_at(Instant::now() + RECHECK_INTERVAL, RECHECK_INTERVAL)
+}
+
+// the caller as they are now. authorize looks an API key up again, so a revoked key fails; a
+// JWT carries the roles of its login until it expires, so those are read again
+async fn current_caller(pool: &PgPool, token: Option<String>) -> async_graphql::Result<AuthPerm> {
+    let mut caller = authorize(pool, token).await?;
+    if caller.api_key.is_none() {
+        caller.role = fetch_user_roles(pool, parse_id(&caller.sub, "sub")?).await?;
+    }
+    Ok(caller)
+}
+
+// the stream ends with this error once the caller may no longer watch
+async fn still_allowed(
+    pool: &PgPool,
+    token: &Option<String>,
+    allowed: impl Fn(&AuthPerm) -> bool,
+) -> async_graphql::Result<()> {
+    let caller = current_caller(pool, token.clone()).await?;
+    if !allowed(&caller) {
+        return Err(RbacError::Forbidden("You are no longer allowed to watch this".to_string()).extend());
+    }
+    Ok(())
+}
+
+// changes that can take away the caller's access: to their own user or roles, or to any role
+fn touches_caller(event: &ChangeEvent, sub: &str) -> bool {
+    event.table == "roles" || event.user_id.is_some_and(|id| id.to_string() == sub)
+}
+
+fn is_admin(caller: &AuthPerm) -> bool {
+    caller.role.contains(&"Admin".to_string())
+}
+
+pub struct Subscription;
+
+#[GraphQLSubscription]
+impl Subscription {
+    // emits the user's effective roles and permissions whenever they change
+    async fn permissions_changed(
+        &self,
+        ctx: &Context<'_>,
+        user_id: String,
+    ) -> async_graphql::Result<impl Stream<Item = async_graphql::Result<PermissionChange>>> {
+        let pool = ctx.data::<PgPool>().unwrap().clone();
+        let token = ctx.data::<Option<String>>().unwrap().clone();
+        let feed = ctx.data::<ChangeFeed>().unwrap();
+
+        let role_perm = match authorize(&pool, token.clone()).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error permissions_changed:- {:?}", e);
+                return Err(AuditEvent::new("anonymous", "permissions_changed").target(&user_id).denied(ctx, e).await);
+            }
+        };
+        if role_perm.sub != user_id && !is_admin(&role_perm) {
+            return Err(AuditEvent::new(&role_perm.sub, "permissions_changed").target(&user_id).denied(ctx, RbacError::Forbidden("You can only watch your own permissions".to_string()).extend()).await);
+        }
+        let watched = user_id.clone();
+        let allowed = move |caller: &AuthPerm| caller.sub == watched || is_admin(caller);
+        let user_id = parse_id(&user_id, "userId")?;
+
+        let mut rx = feed.subscribe();
+        let mut last = effective_permissions(&pool, user_id).await?;
+        Ok(async_stream::stream! {
+            let mut recheck = recheck_interval();
+            loop {
+                let event = match next_item(&mut rx, &mut recheck).await {
+                    FeedItem::Event(v) => Some(v),
+                    // missed events may have changed anything, so the state is read again below
+                    FeedItem::Missed => None,
+                    FeedItem::Recheck => {
+                        if let Err(e) = still_allowed(&pool, &token, &allowed).await {
+                            yield Err(e);
+                            break;
+                        }
+                        continue;
+                    }
+                    FeedItem::Closed => break,
+                };
+                if let Some(event) = &event {
+                    if touches_caller(event, &role_perm.sub) {
+                        if let Err(e) = still_allowed(&pool, &token, &allowed).await {
+                            yield Err(e);
+                            break;
+                        }
+                    }
+                    let relevant = match event.table.as_str() {
+                        "user_roles" | "users" => event.user_id == Some(user_id),
+                        "roles" | "role_permissions" => true,
+                        _ => false,
+                    };
+                    if !relevant {
+                        continue;
+                    }
+                }
+                match effective_permissions(&pool, user_id).await {
+                    Ok(current) => {
+                        if current.roles != last.roles || current.permissions != last.permissions {
+                            last = current.clone();
+                            yield Ok(current);
+                        }
+                    }
+                    Err(e) => yield Err(e),
+                }
+            }
+        })
+    }
+
+    // emits when the role is renamed or deleted, its permissions change, or members join or leave
+    async fn role_changed(
+        &self,
+        ctx: &Context<'_>,
+        role_id: String,
+    ) -> async_graphql::Result<impl Stream<Item = async_graphql::Result<RoleChange>>> {
+        let pool = ctx.data::<PgPool>().unwrap().clone();
+        let token = ctx.data::<Option<String>>().unwrap().clone();
+        let feed = ctx.data::<ChangeFeed>().unwrap();
+
+        let role_perm = match authorize(&pool, token.clone()).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error role_changed:- {:?}", e);
+                return Err(AuditEvent::new("anonymous", "role_changed").target(&role_id).denied(ctx, e).await);
+            }
+        };
+        if !is_admin(&role_perm) {
+            return Err(AuditEvent::new(&role_perm.sub, "role_changed").target(&role_id).denied(ctx, RbacError::Forbidden("You are not authorized to watch roles".to_string()).extend()).await);
+        }
+        let role_id = parse_id(&role_id, "roleId")?;
+
+        let mut rx = feed.subscribe();
+        Ok(async_stream::stream! {
+            let mut recheck = recheck_interval();
+            loop {
+                let (source, operation) = match next_item(&mut rx, &mut recheck).await {
+                    FeedItem::Event(event) => {
+                        if touches_caller(&event, &role_perm.sub) {
+                            if let Err(e) = still_allowed(&pool, &token, is_admin).await {
+                                yield Err(e);
+                                break;
+                            }
+                        }
+                        if !event.touches_role(role_id) {
+                            continue;
+                        }
+                        (event.table.clone(), ChangeOperation::from_event(&event))
+                    }
+                    FeedItem::Missed => ("resync".to_string(), ChangeOperation::Update),
+                    FeedItem::Recheck => {
+                        if let Err(e) = still_allowed(&pool, &token, is_admin).await {
+                            yield Err(e);
+                            break;
+                        }
+                        continue;
+                    }
+                    FeedItem::Closed => break,
+                };
+                match role_state(&pool, role_id).await {
+                    Ok((name, permissions)) => yield Ok(RoleChange {
+                        role_id,
+                        source,
+                        operation,
+                        name,
+                        permissions,
+                    }),
+                    Err(e) => yield Err(e),
+                }
+            }
+        })
+    }
+
+    // emits for every user created, renamed or deleted and every role membership change
+    async fn user_changed(&self, ctx: &Context<'_>) -> async_graphql::Result<impl Stream<Item = async_graphql::Result<UserChange>>> {
+        let pool = ctx.data::<PgPool>().unwrap().clone();
+        let token = ctx.data::<Option<String>>().unwrap().clone();
+        let feed = ctx.data::<ChangeFeed>().unwrap();
+
+        let role_perm = match authorize(&pool, token.clone()).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error user_changed:- {:?}", e);
+                return Err(AuditEvent::new("anonymous", "user_changed").denied(ctx, e).await);
+            }
+        };
+        if !is_admin(&role_perm) {
+            return Err(AuditEvent::new(&role_perm.sub, "user_changed").denied(ctx, RbacError::Forbidden("You are not authorized to watch users".to_string()).extend()).await);
+        }
+
+        let mut rx = feed.subscribe();
+        Ok(async_stream::stream! {
+            let mut recheck = recheck_interval();
+            loop {
+                let event = match next_item(&mut rx, &mut recheck).await {
+                    FeedItem::Event(v) => v,
+                    // there is no single state to send instead of the missed events, so the
+                    // client subscribes again and reloads what it shows
+                    FeedItem::Missed => {
+                        yield Err(RbacError::Internal("Missed user changes, subscribe again".to_string()).extend());
+                        break;
+                    }
+                    FeedItem::Recheck => {
+                        if let Err(e) = still_allowed(&pool, &token, is_admin).await {
+                            yield Err(e);
+                            break;
+                        }
+                        continue;
+                    }
+                    FeedItem::Closed => break,
+                };
+                if touches_caller(&event, &role_perm.sub) {
+                    if let Err(e) = still_allowed(&pool, &token, is_admin).await {
+                        yield Err(e);
+                        break;
+                    }
+                }
+                if let ("users" | "user_roles", Some(user_id)) = (event.table.as_str(), event.user_id) {
+                    yield Ok(UserChange {
+                        user_id,
+                        source: event.table.clone(),
+                        operation: ChangeOperation::from_event(&event),
+                    });
+                }
+            }
+        })
+    }
+}
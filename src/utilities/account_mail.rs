@@ -0,0 +1,89 @@
+use async_graphql::ErrorExtensions;
+use sqlx::PgPool;
+
+use crate::{
+    db::account_tokens::insert_account_token,
+    utilities::{
+        errors::RbacError,
+        mailer::{Email, MailSettings},
+        session::{hash_token, new_token},
+    },
+};
+
+pub const PASSWORD_RESET: &str = "password_reset";
+pub const EMAIL_VERIFICATION: &str = "email_verification";
+
+const PASSWORD_RESET_SECS: i64 = 60 * 60;
+const EMAIL_VERIFICATION_SECS: i64 = 24 * 60 * 60;
+
+// new passwords set through resetPassword or updatePassword
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+// `field` is the argument the password came in
+pub fn check_password_len(field: &str, password: &str) -> async_graphql::Result<()> {
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        let e = RbacError::validation(field, format!("password must be at least {} characters", MIN_PASSWORD_LEN));
+        return Err(e.extend());
+    }
+    Ok(())
+}
+
+// issues a single-use token and mails it, as a link and on its own for clients without the page
+async fn send_token(
+    pool: &PgPool,
+    mail: &MailSettings,
+    user_id: i32,
+    email: &str,
+    purpose: &str,
+    ttl_secs: i64,
+) -> async_graphql::Result<()> {
+    let token = new_token();
+    insert_account_token(pool, user_id, purpose, &hash_token(&token), ttl_secs).await?;
+    let (subject, page, action) = match purpose {
+        PASSWORD_RESET => ("Reset your password", "reset-password", "choose a new password"),
+        _ => ("Verify your email address", "verify-email", "verify your email address"),
+    };
+    let expires = match ttl_secs / 3600 {
+        1 => "1 hour".to_string(),
+        hours => format!("{} hours", hours),
+    };
+    let body = format!(
+        "Open the link below to {}:\n\n{}/{}?token={}\n\nor enter this code: {}\n\nIt expires in {}. If you didn't ask for this email, ignore it.",
+        action,
+        mail.link_base.trim_end_matches('/'),
+        page,
+        token,
+        token,
+        expires
+    );
+    mail.send_later(Email {
+        to: email.to_string(),
+        subject: subject.to_string(),
+        body,
+    });
+    Ok(())
+}
+
+pub async fn send_password_reset(pool: &PgPool, mail: &MailSettings, user_id: i32, email: &str) -> async_graphql::Result<()> {
+    send_token(pool, mail, user_id, email, PASSWORD_RESET, PASSWORD_RESET_SECS).await
+}
+
+pub async fn send_email_verification(pool: &PgPool, mail: &MailSettings, user_id: i32, email: &str) -> async_graphql::Result<()> {
+    send_token(pool, mail, user_id, email, EMAIL_VERIFICATION, EMAIL_VERIFICATION_SECS).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utilities::errors::extension;
+
+    #[test]
+    fn short_passwords_are_rejected_on_their_field() {
+        let e = check_password_len("passwd", "1234567").unwrap_err();
+        assert_eq!(extension(&e, "code").as_deref(), Some("VALIDATION_FAILED"));
+        assert_eq!(extension(&e, "field").as_deref(), Some("passwd"));
+        // counted in characters, not bytes
+        assert!(check_password_len("passwd", "ééééééé").is_err());
+        assert!(check_password_len("passwd", "12345678").is_ok());
+    }
+}
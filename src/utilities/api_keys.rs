@@ -0,0 +1,173 @@
+use async_graphql::ErrorExtensions;
+use rand::RngCore;
+use sqlx::PgPool;
+
+use crate::{
+    db::{api_keys::use_api_key, users::fetch_user_roles},
+    utilities::{
+        auth::AuthPerm,
+        errors::RbacError,
+        permission_cache::permission_cache,
+        session::{hash_token, new_token},
+    },
+};
+
+// every key starts with this, so it is told apart from a JWT without a lookup and secret
+// scanners can match it
+pub const API_KEY_PREFIX: &str = "rbac_";
+
+// a new key and its prefix, e.g. "rbac_1a2b3c4d". The prefix stays readable in listings; the
+// key is only shown once
+pub fn new_api_key() -> (String, String) {
+    let mut id = [0u8; 4];
+    rand::thread_rng().fill_bytes(&mut id);
+    let prefix = format!("{}{}", API_KEY_PREFIX, hex::encode(id));
+    let key = format!("{}_{}", prefix, new_token());
+    (key, prefix)
+}
+
+pub fn is_api_key(token: &str) -> bool {
+    token.starts_with(API_KEY_PREFIX)
+}
+
+// the prefix of a well formed key, e.g. "rbac_1a2b3c4d" of "rbac_1a2b3c4d_<64 hex digits>"
+pub fn api_key_prefix(key: &str) -> Option<&str> {
+    let (id, secret) = key.strip_prefix(API_KEY_PREFIX)?.split_once('_')?;
+    let hex = |v: &str, len: usize| v.len() == len && v.bytes().all(|b| b.is_ascii_hexdigit());
+    if !hex(id, 8) || !hex(secret, 64) {
+        return None;
+    }
+    Some(&key[..API_KEY_PREFIX.len() + id.len()])
+}
+
+// what an API key may do: the current roles and permissions of its user, or with a permission
+// subset only those permissions and no roles, so a scoped key never passes an Admin check
+pub async fn authorize_api_key(pool: &PgPool, key: &str) -> async_graphql::Result<AuthPerm> {
+    let invalid = || RbacError::Unauthenticated("Invalid or expired API key".to_string()).extend();
+    // no key was ever issued in another shape, so those don't cost a lookup
+    if api_key_prefix(key).is_none() {
+        return Err(invalid());
+    }
+    let row = match use_api_key(pool, &hash_token(key)).await? {
+        Some(v) => v,
+        None => return Err(invalid()),
+    };
+    let roles = fetch_user_roles(pool, row.user_id).await?;
+    let perm = permission_cache().permissions(pool, &roles).await?;
+    let (role, perm) = match &row.permissions {
+        Some(scope) => (Vec::new(), perm.into_iter().filter(|p| scope.contains(p)).collect()),
+        None => (roles, perm),
+    };
+    Ok(AuthPerm {
+        sub: row.user_id.to_string(),
+        role,
+        perm,
+        api_key: Some(row.id),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use async_graphql::{Request, Schema};
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::*;
+    use crate::{
+        db::{
+            api_keys::{delete_api_key, fetch_api_key, insert_api_key},
+            db_config::{test_pool, test_user},
+        },
+        graphql::{mutations::Mutation, queries::Query, subscriptions::Subscription},
+        utilities::errors::extension,
+    };
+
+    // a key of `user_id` limited to `scope`, or with all of the user's permissions
+    async fn key(pool: &PgPool, user_id: i32, scope: Option<&[&str]>) -> (i32, String) {
+        let (key, prefix) = new_api_key();
+        let scope: Option<Vec<String>> = scope.map(|v| v.iter().map(|p| p.to_string()).collect());
+        let row = insert_api_key(pool, user_id, "test", &prefix, &hash_token(&key), scope.as_deref(), None)
+            .await
+            .unwrap();
+        (row.id, key)
+    }
+
+    async fn editor(pool: &PgPool) -> i32 {
+        let user_id = test_user(pool).await;
+        sqlx::query("INSERT INTO user_roles (user_id, role_id) SELECT $1, id FROM roles WHERE name = 'Editor';")
+            .bind(user_id)
+            .execute(pool)
+            .await
+            .unwrap();
+        user_id
+    }
+
+    #[test]
+    fn prefix_of_issued_keys() {
+        let (key, prefix) = new_api_key();
+        assert_eq!(api_key_prefix(&key), Some(prefix.as_str()));
+        for bad in ["rbac_", "rbac_1a2b3c4d", "rbac_1a2b3c4d_", "rbac_zzzzzzzz_00", &key[..key.len() - 1], &format!("{}0", key)] {
+            assert_eq!(api_key_prefix(bad), None, "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_keys_are_rejected_before_any_lookup() {
+        // a lookup would fail to connect and answer INTERNAL_SERVER_ERROR instead
+        let pool = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(100))
+            .connect_lazy("postgres://nobody@127.0.0.1:1/none")
+            .unwrap();
+        let e = authorize_api_key(&pool, "rbac_not_a_key").await.unwrap_err();
+        assert_eq!(extension(&e, "code").as_deref(), Some("UNAUTHENTICATED"));
+    }
+
+    #[tokio::test]
+    async fn scoped_key_gets_the_intersection_with_its_owner() {
+        let Some(pool) = test_pool().await else { return };
+        let user_id = editor(&pool).await;
+
+        let (_, full) = key(&pool, user_id, None).await;
+        let auth = authorize_api_key(&pool, &full).await.unwrap();
+        assert_eq!(auth.role, vec!["Editor".to_string()]);
+        assert_eq!(auth.perm, vec!["Read".to_string(), "Update".to_string()]);
+
+        // Delete is not the owner's, so the key doesn't get it either; and no roles
+        let (id, scoped) = key(&pool, user_id, Some(&["Delete", "Read"])).await;
+        let auth = authorize_api_key(&pool, &scoped).await.unwrap();
+        assert!(auth.role.is_empty());
+        assert_eq!(auth.perm, vec!["Read".to_string()]);
+        assert_eq!(auth.sub, user_id.to_string());
+        assert_eq!(auth.api_key, Some(id));
+    }
+
+    #[tokio::test]
+    async fn revoked_key_fails() {
+        let Some(pool) = test_pool().await else { return };
+        let user_id = editor(&pool).await;
+        let (id, key) = key(&pool, user_id, None).await;
+        assert!(authorize_api_key(&pool, &key).await.is_ok());
+        assert!(delete_api_key(&pool, id).await.unwrap());
+        let e = authorize_api_key(&pool, &key).await.unwrap_err();
+        assert_eq!(extension(&e, "code").as_deref(), Some("UNAUTHENTICATED"));
+    }
+
+    #[tokio::test]
+    async fn key_cannot_mint_or_revoke_keys() {
+        let Some(pool) = test_pool().await else { return };
+        let user_id = editor(&pool).await;
+        let (id, key) = key(&pool, user_id, None).await;
+        let schema = Schema::build(Query, Mutation, Subscription).data(pool.clone()).finish();
+        for query in [
+            r#"mutation { createApiKey(name: "minted") { prefix } }"#.to_string(),
+            format!(r#"mutation {{ revokeApiKey(id: "{}") }}"#, id),
+        ] {
+            let res = schema.execute(Request::new(query).data(Some(key.clone()))).await;
+            assert_eq!(res.errors.len(), 1, "{:?}", res.errors);
+            let code = res.errors[0].extensions.as_ref().unwrap().get("code").cloned();
+            assert_eq!(code, Some(async_graphql::Value::from("FORBIDDEN")));
+        }
+        assert!(fetch_api_key(&pool, id).await.unwrap().is_some());
+    }
+}
@@ -0,0 +1,385 @@
+use std::net::{IpAddr, SocketAddr};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderMap, HeaderName, HeaderValue},
+    middleware::Next,
+    web, HttpMessage, HttpRequest,
+};
+use async_graphql::{Context, Error};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    db::audit::{insert_audit_event, AuditKey, AuditRecord},
+    utilities::audit_sink::AuditDispatcher,
+};
+
+// per-request data added to the GraphQL context by the handler
+#[derive(Debug, Clone)]
+pub struct RequestMeta {
+    pub client_ip: Option<String>,
+    pub request_id: String,
+}
+
+impl RequestMeta {
+    // what request_meta_middleware worked out for this request
+    pub fn from_request(req: &HttpRequest) -> Self {
+        if let Some(meta) = req.extensions().get::<RequestMeta>() {
+            return meta.clone();
+        }
+        Self::read(req)
+    }
+
+    fn read(req: &HttpRequest) -> Self {
+        // the client's id is kept when it is short, printable ASCII, so it can't bloat or break
+        // the audit log and the response header
+        let request_id = req
+            .headers()
+            .get("X-Request-Id")
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.is_empty() && v.len() <= 128 && v.bytes().all(|b| b.is_ascii_graphic()))
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        let trusted = req.app_data::<web::Data<TrustedProxies>>();
+        RequestMeta {
+            client_ip: match trusted {
+                Some(trusted) => trusted.client_ip(req.peer_addr().map(|a| a.ip()), req.headers()),
+                None => req.peer_addr().map(|a| a.ip().to_string()),
+            },
+            request_id,
+        }
+    }
+}
+
+// gives every request its RequestMeta, kept in the request extensions for the handlers, and
+// echoes the request id on the response so clients can find the audit rows of a call
+pub async fn request_meta_middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let meta = RequestMeta::read(req.request());
+    let request_id = HeaderValue::from_str(&meta.request_id).ok();
+    req.extensions_mut().insert(meta);
+    let mut res = next.call(req).await?;
+    if let Some(request_id) = request_id {
+        res.headers_mut().insert(HeaderName::from_static("x-request-id"), request_id);
+    }
+    Ok(res)
+}
+
+// an address, or a CIDR range such as 10.0.0.0/8
+#[derive(Debug, Clone, PartialEq)]
+struct IpRange {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl IpRange {
+    fn parse(value: &str) -> Result<Self, String> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| format!("invalid address {:?}", value))?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(v) => v.trim().parse().ok().filter(|p| *p <= bits).ok_or_else(|| format!("invalid prefix in {:?}", value))?,
+            None => bits,
+        };
+        Ok(IpRange { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        let (net, ip, bits) = match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (u32::from(net) as u128, u32::from(ip) as u128, 32),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(ip), 128),
+            _ => return false,
+        };
+        if self.prefix == 0 {
+            return true;
+        }
+        let shift = bits - self.prefix;
+        net >> shift == ip >> shift
+    }
+}
+
+// reverse proxies whose X-Forwarded-For / Forwarded headers are believed. Anyone else could
+// put any address there, and the client IP keys the audit log, the login lockout and the rate
+// limit, so by default only the peer address of the connection counts
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Vec<IpRange>);
+
+impl TrustedProxies {
+    // comma-separated addresses and CIDR ranges, empty for none
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let ranges = value
+            .split(',')
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+            .map(IpRange::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(TrustedProxies(ranges))
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|r| r.contains(ip))
+    }
+
+    // the peer, unless it is a trusted proxy: then the hops it forwarded are walked from the
+    // nearest one, and the first address that isn't a trusted proxy is the client
+    pub fn client_ip(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Option<String> {
+        let peer = peer?;
+        if !self.trusts(peer) {
+            return Some(peer.to_string());
+        }
+        let hops = forwarded_hops(headers);
+        for hop in hops.iter().rev() {
+            match parse_hop(hop) {
+                Some(ip) if self.trusts(ip) => continue,
+                Some(ip) => return Some(ip.to_string()),
+                // obfuscated or "unknown"; nothing further left can be believed
+                None => return Some(peer.to_string()),
+            }
+        }
+        Some(hops.first().and_then(|h| parse_hop(h)).unwrap_or(peer).to_string())
+    }
+}
+
+// the client addresses proxies recorded, nearest last. The standard Forwarded header wins over
+// X-Forwarded-For when a proxy sets both
+fn forwarded_hops(headers: &HeaderMap) -> Vec<String> {
+    let forwarded: Vec<String> = headers
+        .get_all("Forwarded")
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                key.trim().eq_ignore_ascii_case("for").then(|| value.trim().trim_matches('"').to_string())
+            })
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+    headers
+        .get_all("X-Forwarded-For")
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+// "1.2.3.4", "1.2.3.4:80", "2001:db8::1" or "[2001:db8::1]:443"
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    hop.parse::<IpAddr>()
+        .ok()
+        .or_else(|| hop.parse::<SocketAddr>().ok().map(|a| a.ip()))
+        .or_else(|| hop.trim_start_matches('[').trim_end_matches(']').parse().ok())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+    Denied,
+}
+
+impl AuditOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditOutcome::Success => "success",
+            AuditOutcome::Failure => "failure",
+            AuditOutcome::Denied => "denied",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditEvent {
+    pub actor: String,
+    pub action: String,
+    pub target: Option<String>,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(actor: &str, action: &str) -> Self {
+        AuditEvent {
+            actor: actor.to_string(),
+            action: action.to_string(),
+            target: None,
+            before: None,
+            after: None,
+        }
+    }
+
+    pub fn target(mut self, target: impl ToString) -> Self {
+        self.target = Some(target.to_string());
+        self
+    }
+
+    pub fn before(mut self, before: impl ToString) -> Self {
+        self.before = Some(before.to_string());
+        self
+    }
+
+    pub fn after(mut self, after: impl ToString) -> Self {
+        self.after = Some(after.to_string());
+        self
+    }
+
+    pub async fn success(&self, ctx: &Context<'_>) {
+        self.record(ctx, AuditOutcome::Success, None).await;
+    }
+
+    // records the failed attempt and hands the error back to the resolver
+    pub async fn failure(&self, ctx: &Context<'_>, err: Error) -> Error {
+        self.record(ctx, AuditOutcome::Failure, Some(&err)).await;
+        err
+    }
+
+    pub async fn denied(&self, ctx: &Context<'_>, err: Error) -> Error {
+        self.record(ctx, AuditOutcome::Denied, Some(&err)).await;
+        err
+    }
+
+    async fn record(&self, ctx: &Context<'_>, outcome: AuditOutcome, err: Option<&Error>) {
+        let pool = ctx.data::<PgPool>().unwrap();
+        self.write(
+            pool,
+            ctx.data_opt::<RequestMeta>(),
+            ctx.data_opt::<AuditKey>(),
+            ctx.data_opt::<AuditDispatcher>(),
+            outcome,
+            err,
+        )
+        .await;
+    }
+
+    async fn write(
+        &self,
+        pool: &PgPool,
+        meta: Option<&RequestMeta>,
+        key: Option<&AuditKey>,
+        dispatcher: Option<&AuditDispatcher>,
+        outcome: AuditOutcome,
+        err: Option<&Error>,
+    ) {
+        let record = AuditRecord {
+            actor: self.actor.clone(),
+            action: self.action.clone(),
+            target: self.target.clone(),
+            before: self.before.clone(),
+            after: self.after.clone(),
+            outcome: outcome.as_str().to_string(),
+            reason: err.map(|e| e.message.clone()),
+            client_ip: meta.and_then(|m| m.client_ip.clone()),
+            request_id: meta.map(|m| m.request_id.clone()),
+        };
+        match insert_audit_event(pool, record, key).await {
+            Ok(row) => {
+                if let Some(dispatcher) = dispatcher {
+                    dispatcher.publish(row);
+                }
+            }
+            Err(e) => println!("Error insert_audit_event = {:?}", e),
+        }
+    }
+}
+
+// records audit events for handlers that run outside of a GraphQL resolver, with the same
+// pool, chain key and sinks the schema was built with
+#[derive(Clone)]
+pub struct AuditWriter {
+    pub pool: PgPool,
+    pub key: Option<AuditKey>,
+    pub dispatcher: Option<AuditDispatcher>,
+}
+
+impl AuditWriter {
+    pub async fn success(&self, meta: &RequestMeta, event: &AuditEvent) {
+        self.record(meta, event, AuditOutcome::Success, None).await;
+    }
+
+    pub async fn failure(&self, meta: &RequestMeta, event: &AuditEvent, err: Error) -> Error {
+        self.record(meta, event, AuditOutcome::Failure, Some(&err)).await;
+        err
+    }
+
+    pub async fn denied(&self, meta: &RequestMeta, event: &AuditEvent, err: Error) -> Error {
+        self.record(meta, event, AuditOutcome::Denied, Some(&err)).await;
+        err
+    }
+
+    async fn record(&self, meta: &RequestMeta, event: &AuditEvent, outcome: AuditOutcome, err: Option<&Error>) {
+        event
+            .write(&self.pool, Some(meta), self.key.as_ref(), self.dispatcher.as_ref(), outcome, err)
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(HeaderName::from_static(name), HeaderValue::from_static(value));
+        }
+        map
+    }
+
+    fn ip(v: &str) -> Option<IpAddr> {
+        Some(v.parse().unwrap())
+    }
+
+    #[test]
+    fn untrusted_peer_ignores_forwarding_headers() {
+        let trusted = TrustedProxies::default();
+        let h = headers(&[("x-forwarded-for", "6.6.6.6"), ("forwarded", "for=7.7.7.7")]);
+        assert_eq!(trusted.client_ip(ip("203.0.113.9"), &h).as_deref(), Some("203.0.113.9"));
+    }
+
+    #[test]
+    fn trusted_proxy_yields_nearest_untrusted_hop() {
+        let trusted = TrustedProxies::parse("10.0.0.0/8, 192.168.1.1").unwrap();
+        // the client forged 6.6.6.6; the proxies appended the real address and each other
+        let h = headers(&[("x-forwarded-for", "6.6.6.6, 198.51.100.7, 10.1.2.3")]);
+        assert_eq!(trusted.client_ip(ip("192.168.1.1"), &h).as_deref(), Some("198.51.100.7"));
+    }
+
+    #[test]
+    fn forwarded_header_wins_and_ports_are_dropped() {
+        let trusted = TrustedProxies::parse("::1").unwrap();
+        let h = headers(&[
+            ("x-forwarded-for", "6.6.6.6"),
+            ("forwarded", "for=\"[2001:db8::7]:4711\";proto=https"),
+        ]);
+        assert_eq!(trusted.client_ip(ip("::1"), &h).as_deref(), Some("2001:db8::7"));
+    }
+
+    #[test]
+    fn trusted_proxy_without_headers_is_the_client() {
+        let trusted = TrustedProxies::parse("127.0.0.1").unwrap();
+        assert_eq!(trusted.client_ip(ip("127.0.0.1"), &HeaderMap::new()).as_deref(), Some("127.0.0.1"));
+        let h = headers(&[("x-forwarded-for", "unknown")]);
+        assert_eq!(trusted.client_ip(ip("127.0.0.1"), &h).as_deref(), Some("127.0.0.1"));
+    }
+
+    #[test]
+    fn ranges_parse_and_match() {
+        assert!(TrustedProxies::parse("10.0.0.0/33").is_err());
+        assert!(TrustedProxies::parse("proxy.local").is_err());
+        let trusted = TrustedProxies::parse("172.16.0.0/12,fd00::/8").unwrap();
+        assert!(trusted.trusts("172.31.255.1".parse().unwrap()));
+        assert!(!trusted.trusts("172.32.0.1".parse().unwrap()));
+        assert!(trusted.trusts("fd12::1".parse().unwrap()));
+        assert!(!trusted.trusts("::ffff:172.16.0.1".parse().unwrap()));
+    }
+}
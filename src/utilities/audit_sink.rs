@@ -0,0 +1,454 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    net::{TcpStream, UdpSocket},
+    path::PathBuf,
+    sync::mpsc::{channel, Sender},
+    thread,
+};
+
+use chrono::SecondsFormat;
+
+use crate::db::audit::AuditRow;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SinkFormat {
+    Json,
+    Cef,
+}
+
+impl SinkFormat {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "json" => Ok(SinkFormat::Json),
+            "cef" => Ok(SinkFormat::Cef),
+            _ => Err(format!("unknown audit format {:?}, expected json or cef", value)),
+        }
+    }
+
+    pub fn format(&self, event: &AuditRow) -> String {
+        match self {
+            SinkFormat::Json => format_json(event),
+            SinkFormat::Cef => format_cef(event),
+        }
+    }
+}
+
+pub trait AuditSink: Send {
+    fn name(&self) -> String;
+    fn emit(&mut self, event: &AuditRow) -> std::io::Result<()>;
+}
+
+pub fn format_json(event: &AuditRow) -> String {
+    serde_json::json!({
+        "id": event.id,
+        "created_at": event.created_at.to_rfc3339_opts(SecondsFormat::Micros, true),
+        "actor": event.record.actor,
+        "action": event.record.action,
+        "target": event.record.target,
+        "before": event.record.before,
+        "after": event.record.after,
+        "outcome": event.record.outcome,
+        "reason": event.record.reason,
+        "client_ip": event.record.client_ip,
+        "request_id": event.record.request_id,
+        "hash": event.hash,
+    })
+    .to_string()
+}
+
+fn cef_header(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+fn cef_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('=', "\\=")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n")
+}
+
+// ArcSight Common Event Format, one event per line
+pub fn format_cef(event: &AuditRow) -> String {
+    let severity = match event.record.outcome.as_str() {
+        "denied" => 7,
+        "failure" => 5,
+        _ => 3,
+    };
+    let mut ext = vec![
+        format!("rt={}", event.created_at.timestamp_millis()),
+        format!("externalId={}", event.id),
+        format!("suser={}", cef_value(&event.record.actor)),
+        format!("act={}", cef_value(&event.record.action)),
+        format!("outcome={}", cef_value(&event.record.outcome)),
+    ];
+    let optional = [
+        ("src", &event.record.client_ip),
+        ("msg", &event.record.reason),
+        ("cs1Label=target cs1", &event.record.target),
+        ("cs2Label=before cs2", &event.record.before),
+        ("cs3Label=after cs3", &event.record.after),
+        ("cs4Label=requestId cs4", &event.record.request_id),
+        ("cs5Label=hash cs5", &event.hash),
+    ];
+    for (key, value) in optional {
+        if let Some(value) = value {
+            ext.push(format!("{}={}", key, cef_value(value)));
+        }
+    }
+    format!(
+        "CEF:0|RBAC|rbac_server|{}|{}|{}|{}|{}",
+        env!("CARGO_PKG_VERSION"),
+        cef_header(&event.record.action),
+        cef_header(&format!("{} {}", event.record.action, event.record.outcome)),
+        severity,
+        ext.join(" ")
+    )
+}
+
+// JSON lines written to `path`, shifted to path.1 .. path.N once it grows past `max_bytes`
+pub struct JsonlFileSink {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: Option<File>,
+    written: u64,
+}
+
+impl JsonlFileSink {
+    pub fn new(path: PathBuf, max_bytes: u64, max_files: usize) -> Self {
+        JsonlFileSink {
+            path,
+            max_bytes,
+            max_files,
+            file: None,
+            written: 0,
+        }
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file = None;
+        for n in (1..self.max_files).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(&from, self.rotated(n + 1))?;
+            }
+        }
+        if self.max_files > 0 {
+            fs::rename(&self.path, self.rotated(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+}
+
+impl AuditSink for JsonlFileSink {
+    fn name(&self) -> String {
+        format!("jsonl:{}", self.path.display())
+    }
+
+    fn emit(&mut self, event: &AuditRow) -> std::io::Result<()> {
+        if self.file.is_some() && self.written >= self.max_bytes {
+            self.rotate()?;
+        }
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            self.written = file.metadata()?.len();
+            self.file = Some(file);
+        }
+        let line = format!("{}\n", format_json(event));
+        self.file.as_mut().unwrap().write_all(line.as_bytes())?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+}
+
+enum SyslogTransport {
+    Udp(UdpSocket),
+    Tcp(Option<TcpStream>),
+}
+
+// RFC 5424 messages over UDP (one datagram each) or TCP (octet-counted framing)
+pub struct SyslogSink {
+    addr: String,
+    transport: SyslogTransport,
+    format: SinkFormat,
+    hostname: String,
+}
+
+impl SyslogSink {
+    // url is udp://host:port or tcp://host:port
+    pub fn connect(url: &str, format: SinkFormat) -> Result<Self, String> {
+        let (scheme, addr) = url
+            .split_once("://")
+            .ok_or_else(|| format!("invalid syslog address {:?}", url))?;
+        let transport = match scheme {
+            "udp" => {
+                let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| e.to_string())?;
+                socket.connect(addr).map_err(|e| e.to_string())?;
+                SyslogTransport::Udp(socket)
+            }
+            "tcp" => SyslogTransport::Tcp(None),
+            _ => return Err(format!("unknown syslog transport {:?}, expected udp or tcp", scheme)),
+        };
+        Ok(SyslogSink {
+            addr: addr.to_string(),
+            transport,
+            format,
+            hostname: std::env::var("HOSTNAME").unwrap_or_else(|_| "-".to_string()),
+        })
+    }
+
+    fn message(&self, event: &AuditRow) -> String {
+        // facility 10 (security/authorization)
+        let severity = match event.record.outcome.as_str() {
+            "denied" => 4,
+            "failure" => 5,
+            _ => 6,
+        };
+        format!(
+            "<{}>1 {} {} rbac_server {} {} - {}",
+            10 * 8 + severity,
+            event.created_at.to_rfc3339_opts(SecondsFormat::Micros, true),
+            self.hostname,
+            std::process::id(),
+            "audit",
+            self.format.format(event)
+        )
+    }
+}
+
+impl AuditSink for SyslogSink {
+    fn name(&self) -> String {
+        match self.transport {
+            SyslogTransport::Udp(_) => format!("syslog:udp://{}", self.addr),
+            SyslogTransport::Tcp(_) => format!("syslog:tcp://{}", self.addr),
+        }
+    }
+
+    fn emit(&mut self, event: &AuditRow) -> std::io::Result<()> {
+        let msg = self.message(event);
+        match &mut self.transport {
+            SyslogTransport::Udp(socket) => {
+                socket.send(msg.as_bytes())?;
+            }
+            SyslogTransport::Tcp(stream) => {
+                if stream.is_none() {
+                    *stream = Some(TcpStream::connect(&self.addr)?);
+                }
+                let frame = format!("{} {}", msg.len(), msg);
+                if let Err(e) = stream.as_mut().unwrap().write_all(frame.as_bytes()) {
+                    // reconnect on the next event
+                    *stream = None;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// fans every stored audit event out to the configured sinks on a background thread
+#[derive(Clone)]
+pub struct AuditDispatcher {
+    tx: Sender<AuditRow>,
+}
+
+impl AuditDispatcher {
+    pub fn start(mut sinks: Vec<Box<dyn AuditSink>>) -> Self {
+        let (tx, rx) = channel::<AuditRow>();
+        thread::spawn(move || {
+            for event in rx {
+                for sink in sinks.iter_mut() {
+                    if let Err(e) = sink.emit(&event) {
+                        println!("Error audit sink {} = {:?}", sink.name(), e);
+                    }
+                }
+            }
+        });
+        AuditDispatcher { tx }
+    }
+
+    pub fn publish(&self, event: AuditRow) {
+        if let Err(e) = self.tx.send(event) {
+            println!("Error audit dispatcher = {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read},
+        net::TcpListener,
+        time::Duration,
+    };
+
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::db::audit::AuditRecord;
+
+    fn event(id: i64, outcome: &str) -> AuditRow {
+        AuditRow {
+            id,
+            created_at: Utc.with_ymd_and_hms(2024, 5, 1, 12, 30, 0).unwrap(),
+            record: AuditRecord {
+                actor: "1".to_string(),
+                action: "add_user_role".to_string(),
+                target: Some("bob".to_string()),
+                before: None,
+                after: Some("Editor".to_string()),
+                outcome: outcome.to_string(),
+                reason: None,
+                client_ip: Some("203.0.113.9".to_string()),
+                request_id: Some("req-1".to_string()),
+            },
+            prev_hash: None,
+            hash: Some("ab".repeat(32)),
+            signature: None,
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rbac_audit_sink_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("audit.jsonl")
+    }
+
+    fn syslog(url: &str, format: SinkFormat) -> SyslogSink {
+        let mut sink = SyslogSink::connect(url, format).unwrap();
+        sink.hostname = "host1".to_string();
+        sink
+    }
+
+    #[test]
+    fn syslog_message_follows_rfc5424() {
+        let sink = syslog("udp://127.0.0.1:9", SinkFormat::Json);
+        let msg = sink.message(&event(7, "success"));
+        let header = format!("<86>1 2024-05-01T12:30:00.000000Z host1 rbac_server {} audit - ", std::process::id());
+        let json = msg.strip_prefix(&header).unwrap();
+        let body: serde_json::Value = serde_json::from_str(json).unwrap();
+        assert_eq!(body["id"], 7);
+        assert_eq!(body["created_at"], "2024-05-01T12:30:00.000000Z");
+
+        // facility 10: warning for denied, notice for failure
+        assert!(sink.message(&event(7, "denied")).starts_with("<84>1 "));
+        assert!(sink.message(&event(7, "failure")).starts_with("<85>1 "));
+    }
+
+    #[test]
+    fn cef_escapes_header_and_extension_values() {
+        let mut row = event(3, "denied");
+        row.record.action = r"a|b\c".to_string();
+        row.record.actor = "x=y\\z|w".to_string();
+        row.record.reason = Some("line1\nline2\r".to_string());
+        let cef = format_cef(&row);
+        let expected = format!(
+            r"CEF:0|RBAC|rbac_server|{}|a\|b\\c|a\|b\\c denied|7|",
+            env!("CARGO_PKG_VERSION")
+        );
+        assert!(cef.starts_with(&expected), "{}", cef);
+        // pipes need no escaping in the extension
+        assert!(cef.contains(r"suser=x\=y\\z|w "), "{}", cef);
+        assert!(cef.contains(r"act=a|b\\c "), "{}", cef);
+        assert!(cef.contains(r"msg=line1\nline2\r "), "{}", cef);
+        assert!(cef.contains("src=203.0.113.9 "));
+        assert!(cef.contains("cs1Label=target cs1=bob "));
+        assert!(!cef.contains("cs2Label"));
+        assert!(!cef.contains('\n'));
+    }
+
+    #[test]
+    fn jsonl_rotates_and_keeps_max_files() {
+        let path = temp_path("rotation");
+        let line_len = format_json(&event(1, "success")).len() as u64 + 1;
+        // two events per file
+        let mut sink = JsonlFileSink::new(path.clone(), line_len * 2, 2);
+        for id in 1..=7 {
+            sink.emit(&event(id, "success")).unwrap();
+        }
+        let ids = |p: &PathBuf| -> Vec<i64> {
+            BufReader::new(File::open(p).unwrap())
+                .lines()
+                .map(|l| serde_json::from_str::<serde_json::Value>(&l.unwrap()).unwrap()["id"].as_i64().unwrap())
+                .collect()
+        };
+        assert_eq!(ids(&path), vec![7]);
+        assert_eq!(ids(&sink.rotated(1)), vec![5, 6]);
+        assert_eq!(ids(&sink.rotated(2)), vec![3, 4]);
+        assert!(!sink.rotated(3).exists());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn jsonl_without_backups_starts_over() {
+        let path = temp_path("no_backups");
+        let mut sink = JsonlFileSink::new(path.clone(), 1, 0);
+        sink.emit(&event(1, "success")).unwrap();
+        sink.emit(&event(2, "success")).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(content.lines().count(), 1);
+        assert!(!sink.rotated(1).exists());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn syslog_udp_sends_one_datagram_per_event() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut sink = syslog(&format!("udp://{}", server.local_addr().unwrap()), SinkFormat::Cef);
+        sink.emit(&event(1, "success")).unwrap();
+        sink.emit(&event(2, "denied")).unwrap();
+        let mut buf = [0u8; 4096];
+        for (id, outcome, pri) in [(1, "success", "<86>1 "), (2, "denied", "<84>1 ")] {
+            let n = server.recv(&mut buf).unwrap();
+            let msg = std::str::from_utf8(&buf[..n]).unwrap();
+            assert_eq!(msg, sink.message(&event(id, outcome)));
+            assert!(msg.starts_with(pri));
+        }
+    }
+
+    #[test]
+    fn syslog_tcp_uses_octet_counting() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sink = syslog(&format!("tcp://{}", server.local_addr().unwrap()), SinkFormat::Json);
+        sink.emit(&event(1, "success")).unwrap();
+        sink.emit(&event(2, "failure")).unwrap();
+        let expected = [sink.message(&event(1, "success")), sink.message(&event(2, "failure"))];
+        drop(sink);
+
+        let (stream, _) = server.accept().unwrap();
+        let mut data = String::new();
+        BufReader::new(stream).read_to_string(&mut data).unwrap();
+        let mut rest = data.as_str();
+        for msg in expected {
+            let (len, tail) = rest.split_once(' ').unwrap();
+            let len: usize = len.parse().unwrap();
+            assert_eq!(&tail[..len], msg);
+            rest = &tail[len..];
+        }
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn parses_formats_and_rejects_bad_urls() {
+        assert_eq!(SinkFormat::parse("CEF").unwrap(), SinkFormat::Cef);
+        assert_eq!(SinkFormat::parse("json").unwrap(), SinkFormat::Json);
+        assert!(SinkFormat::parse("xml").is_err());
+        assert!(SyslogSink::connect("127.0.0.1:514", SinkFormat::Json).is_err());
+        assert!(SyslogSink::connect("http://127.0.0.1:514", SinkFormat::Json).is_err());
+    }
+}
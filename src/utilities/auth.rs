@@ -2,8 +2,12 @@ use async_graphql::ErrorExtensions;
 use sqlx::{Pool, Postgres};
 
 use crate::{
-    db::roles::fetch_role_permission,
-    utilities::jwt::{decode_jwt, Claims},
+    utilities::{
+        api_keys::{authorize_api_key, is_api_key},
+        errors::RbacError,
+        jwt::{decode_jwt, Claims},
+        permission_cache::permission_cache,
+    },
 };
 
 #[derive(Debug)]
@@ -11,23 +15,27 @@ pub struct AuthPerm {
     pub sub: String,
     pub role: Vec<String>,
     pub perm: Vec<String>,
+    // set when the caller used an API key instead of a login token
+    pub api_key: Option<i32>,
 }
 pub async fn authorize(
     pool: &Pool<Postgres>,
     token: Option<String>,
 ) -> async_graphql::Result<AuthPerm> {
     if token.is_none() {
-        return Err(async_graphql::Error::new("Token Not Found")
-            .extend_with(|_, e| e.set("details", "Token is required.")));
+        return Err(RbacError::Unauthenticated("Token is required.".to_string()).extend());
     }
     let token = token.unwrap();
+    if is_api_key(&token) {
+        return authorize_api_key(pool, &token).await;
+    }
     let claim: Claims = match decode_jwt(token) {
         Ok(v) => v,
         Err(e) => {
             return Err(e);
         }
     };
-    let vec_perm = match fetch_role_permission(pool, claim.role.clone()).await {
+    let vec_perm = match permission_cache().permissions(pool, &claim.role).await {
         Ok(v) => v,
         Err(e) => {
             println!("Error add_role:- {:?}", e);
@@ -39,5 +47,6 @@ pub async fn authorize(
         sub: claim.sub,
         role: claim.role,
         perm: vec_perm,
+        api_key: None,
     })
 }
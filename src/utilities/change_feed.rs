@@ -0,0 +1,91 @@
+use serde::Deserialize;
+use sqlx::{postgres::PgListener, Pool, Postgres};
+use tokio::sync::broadcast;
+
+// channel the database triggers in db_config publish on
+pub const CHANGE_CHANNEL: &str = "rbac_changes";
+
+// one row-level change to users, roles, user_roles or role_permissions
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChangeEvent {
+    pub table: String,
+    pub op: String,
+    pub user_id: Option<i32>,
+    pub role_id: Option<i32>,
+    // set when an UPDATE on user_roles moved a user from one role to another
+    pub old_role_id: Option<i32>,
+}
+
+impl ChangeEvent {
+    // sent after the listener lost its connection; notifications may have been missed meanwhile
+    pub fn resync() -> Self {
+        ChangeEvent {
+            table: String::new(),
+            op: "RESYNC".to_string(),
+            user_id: None,
+            role_id: None,
+            old_role_id: None,
+        }
+    }
+
+    pub fn is_resync(&self) -> bool {
+        self.op == "RESYNC"
+    }
+
+    pub fn touches_role(&self, role_id: i32) -> bool {
+        self.role_id == Some(role_id) || self.old_role_id == Some(role_id)
+    }
+}
+
+// fan-out of database change notifications to every subscriber in this process
+#[derive(Clone)]
+pub struct ChangeFeed {
+    tx: broadcast::Sender<ChangeEvent>,
+}
+
+impl ChangeFeed {
+    pub fn subscribe(&self) -> broadcast::Receiver<ChangeEvent> {
+        self.tx.subscribe()
+    }
+}
+
+pub async fn start_change_feed(pool: &Pool<Postgres>) -> Result<ChangeFeed, sqlx::Error> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(CHANGE_CHANNEL).await?;
+    let (tx, _) = broadcast::channel(1024);
+    let feed = ChangeFeed { tx: tx.clone() };
+    tokio::spawn(async move {
+        loop {
+            // try_recv returns None when the connection dropped, where recv would reconnect and
+            // carry on as if nothing was missed
+            match listener.try_recv().await {
+                Ok(Some(notification)) => match serde_json::from_str::<ChangeEvent>(notification.payload()) {
+                    Ok(event) => {
+                        // an error only means nobody is subscribed right now
+                        let _ = tx.send(event);
+                    }
+                    Err(e) => println!("Error change feed payload = {:?}", e),
+                },
+                Ok(None) => {
+                    relisten(&mut listener).await;
+                    let _ = tx.send(ChangeEvent::resync());
+                }
+                Err(e) => {
+                    println!("Error change feed = {:?}", e);
+                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                    relisten(&mut listener).await;
+                    let _ = tx.send(ChangeEvent::resync());
+                }
+            }
+        }
+    });
+    Ok(feed)
+}
+
+// reconnects and listens again, so the resync sent afterwards covers everything missed meanwhile
+async fn relisten(listener: &mut PgListener) {
+    while let Err(e) = listener.listen(CHANGE_CHANNEL).await {
+        println!("Error change feed = {:?}", e);
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+}
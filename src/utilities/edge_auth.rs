@@ -0,0 +1,239 @@
+use async_graphql::ErrorExtensions;
+use serde::Deserialize;
+use sqlx::{Pool, Postgres};
+
+use crate::utilities::{
+    audit::{AuditEvent, AuditWriter, RequestMeta},
+    auth::authorize,
+    errors::{extension, RbacError},
+};
+
+// maps a request seen by the edge proxy to the permission it needs, e.g.
+// {"method": "DELETE", "path": "/api/users/*", "permission": "Delete"}
+#[derive(Debug, Clone, Deserialize)]
+pub struct RouteRule {
+    // an HTTP method, or "*" for any
+    #[serde(default = "any_method")]
+    pub method: String,
+    // `*` matches one path segment, a trailing `**` matches the rest of the path
+    pub path: String,
+    // None only requires a valid token
+    pub permission: Option<String>,
+}
+
+fn any_method() -> String {
+    "*".to_string()
+}
+
+impl RouteRule {
+    fn matches(&self, method: &str, path: &str) -> bool {
+        if self.method != "*" && !self.method.eq_ignore_ascii_case(method) {
+            return false;
+        }
+        let mut pattern = self.path.trim_matches('/').split('/');
+        let mut segments = path.trim_matches('/').split('/');
+        loop {
+            match (pattern.next(), segments.next()) {
+                (Some("**"), _) => return true,
+                (Some(p), Some(s)) if p == "*" || p == s => (),
+                (None, None) => return true,
+                _ => return false,
+            }
+        }
+    }
+}
+
+// first matching rule wins; a request no rule matches is denied
+#[derive(Debug, Clone, Default)]
+pub struct RouteRules {
+    pub rules: Vec<RouteRule>,
+}
+
+impl RouteRules {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let rules: Vec<RouteRule> = serde_json::from_str(&content).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(RouteRules { rules })
+    }
+
+    pub fn find(&self, method: &str, path: &str) -> Option<&RouteRule> {
+        // the query string never takes part in matching
+        let path = path.split('?').next().unwrap_or("");
+        self.rules.iter().find(|r| r.matches(method, path))
+    }
+}
+
+pub enum EdgeDecision {
+    Allow { sub: String, roles: Vec<String> },
+    Unauthenticated(String),
+    Forbidden(String),
+    // the decision could not be made, e.g. the database is down
+    Error(String),
+}
+
+// shared by the nginx auth_request endpoint and the Envoy ext_authz service
+pub async fn decide(
+    pool: &Pool<Postgres>,
+    audit: &AuditWriter,
+    meta: &RequestMeta,
+    rules: &RouteRules,
+    token: Option<String>,
+    method: &str,
+    path: &str,
+) -> EdgeDecision {
+    let target = format!("{} {}", method, path);
+    let role_perm = match authorize(pool, token).await {
+        Ok(v) => v,
+        Err(e) => {
+            let e = audit
+                .denied(meta, &AuditEvent::new("anonymous", "edge.authorize").target(&target), e)
+                .await;
+            return match extension(&e, "code").as_deref() {
+                Some("UNAUTHENTICATED") => EdgeDecision::Unauthenticated(e.message),
+                _ => EdgeDecision::Error(e.message),
+            };
+        }
+    };
+    let allowed = match rules.find(method, path) {
+        Some(rule) => match &rule.permission {
+            Some(permission) => role_perm.perm.contains(permission),
+            None => true,
+        },
+        None => false,
+    };
+    if !allowed {
+        let e = RbacError::Forbidden(format!("You are not authorized to {}", target)).extend();
+        let e = audit
+            .denied(meta, &AuditEvent::new(&role_perm.sub, "edge.authorize").target(&target), e)
+            .await;
+        return EdgeDecision::Forbidden(e.message);
+    }
+    EdgeDecision::Allow {
+        sub: role_perm.sub,
+        roles: role_perm.role,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::db_config::{test_pool, test_user},
+        utilities::jwt::create_jwt,
+    };
+
+    fn rules(json: &str) -> RouteRules {
+        RouteRules {
+            rules: serde_json::from_str(json).unwrap(),
+        }
+    }
+
+    fn permission<'a>(rules: &'a RouteRules, method: &str, path: &str) -> Option<Option<&'a str>> {
+        rules.find(method, path).map(|r| r.permission.as_deref())
+    }
+
+    #[test]
+    fn star_matches_one_segment() {
+        let rules = rules(r#"[{"path": "/api/users/*/roles", "permission": "Read"}]"#);
+        assert_eq!(permission(&rules, "GET", "/api/users/7/roles"), Some(Some("Read")));
+        assert_eq!(permission(&rules, "GET", "/api/users/7/roles/"), Some(Some("Read")));
+        assert_eq!(permission(&rules, "GET", "/api/users/roles"), None);
+        assert_eq!(permission(&rules, "GET", "/api/users/7/8/roles"), None);
+        assert_eq!(permission(&rules, "GET", "/api/users/7/roles/1"), None);
+    }
+
+    #[test]
+    fn trailing_double_star_matches_the_rest() {
+        let rules = rules(r#"[{"path": "/static/**"}]"#);
+        assert_eq!(permission(&rules, "GET", "/static/app.js"), Some(None));
+        assert_eq!(permission(&rules, "GET", "/static/img/a/b.png"), Some(None));
+        assert_eq!(permission(&rules, "GET", "/static"), Some(None));
+        assert_eq!(permission(&rules, "GET", "/staticfiles/app.js"), None);
+        assert_eq!(permission(&rules, "GET", "/api/static/app.js"), None);
+    }
+
+    #[test]
+    fn method_is_exact_unless_wildcarded() {
+        let rules = rules(
+            r#"[
+                {"method": "DELETE", "path": "/api/users/*", "permission": "Delete"},
+                {"method": "*", "path": "/api/users/*", "permission": "Read"}
+            ]"#,
+        );
+        assert_eq!(permission(&rules, "DELETE", "/api/users/7"), Some(Some("Delete")));
+        assert_eq!(permission(&rules, "delete", "/api/users/7"), Some(Some("Delete")));
+        assert_eq!(permission(&rules, "GET", "/api/users/7"), Some(Some("Read")));
+        assert_eq!(permission(&rules, "PATCH", "/api/users/7"), Some(Some("Read")));
+    }
+
+    #[test]
+    fn method_defaults_to_any() {
+        let rules = rules(r#"[{"path": "/api/roles", "permission": "Read"}]"#);
+        assert_eq!(rules.rules[0].method, "*");
+        assert_eq!(permission(&rules, "POST", "/api/roles"), Some(Some("Read")));
+    }
+
+    #[test]
+    fn query_string_is_ignored() {
+        let rules = rules(r#"[{"method": "GET", "path": "/api/users", "permission": "Read"}]"#);
+        assert_eq!(permission(&rules, "GET", "/api/users?first=10&after=abc"), Some(Some("Read")));
+        assert_eq!(permission(&rules, "GET", "/api/users?/admin"), Some(Some("Read")));
+        assert_eq!(permission(&rules, "GET", "/api/users/x?y"), None);
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = rules(
+            r#"[
+                {"path": "/api/users/me"},
+                {"path": "/api/users/*", "permission": "Read"},
+                {"path": "/api/**", "permission": "Update"}
+            ]"#,
+        );
+        assert_eq!(permission(&rules, "GET", "/api/users/me"), Some(None));
+        assert_eq!(permission(&rules, "GET", "/api/users/7"), Some(Some("Read")));
+        assert_eq!(permission(&rules, "GET", "/api/roles/7"), Some(Some("Update")));
+    }
+
+    #[test]
+    fn unmatched_requests_have_no_rule() {
+        assert_eq!(permission(&RouteRules::default(), "GET", "/"), None);
+        let rules = rules(r#"[{"method": "GET", "path": "/api/users"}]"#);
+        assert_eq!(permission(&rules, "POST", "/api/users"), None);
+        assert_eq!(permission(&rules, "GET", "/api"), None);
+        assert_eq!(permission(&rules, "GET", "/api/users/7"), None);
+    }
+
+    #[tokio::test]
+    async fn decide_denies_what_no_rule_allows() {
+        let Some(pool) = test_pool().await else { return };
+        let id = test_user(&pool).await;
+        let token = create_jwt(&id.to_string(), vec!["Viewer".to_string()]).await.unwrap().0;
+        let audit = AuditWriter {
+            pool: pool.clone(),
+            key: None,
+            dispatcher: None,
+        };
+        let meta = RequestMeta {
+            client_ip: None,
+            request_id: "edge-test".to_string(),
+        };
+        let rules = rules(
+            r#"[
+                {"method": "GET", "path": "/api/users/**", "permission": "Read"},
+                {"method": "DELETE", "path": "/api/users/*", "permission": "Delete"}
+            ]"#,
+        );
+        let decide = |token: Option<&str>, method: &'static str, path: &'static str| {
+            decide(&pool, &audit, &meta, &rules, token.map(str::to_string), method, path)
+        };
+        match decide(Some(&token), "GET", "/api/users/7").await {
+            EdgeDecision::Allow { sub, roles } => assert_eq!((sub, roles), (id.to_string(), vec!["Viewer".to_string()])),
+            _ => panic!("a matching rule with a held permission should allow"),
+        }
+        // a matching rule whose permission is missing, then no matching rule at all
+        assert!(matches!(decide(Some(&token), "DELETE", "/api/users/7").await, EdgeDecision::Forbidden(_)));
+        assert!(matches!(decide(Some(&token), "GET", "/api/roles").await, EdgeDecision::Forbidden(_)));
+        assert!(matches!(decide(None, "GET", "/api/users/7").await, EdgeDecision::Unauthenticated(_)));
+    }
+}
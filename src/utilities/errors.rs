@@ -0,0 +1,124 @@
+use async_graphql::{Error, ErrorExtensions, ServerError, Value};
+use thiserror::Error;
+
+// Every error returned to clients goes through this type, so `extensions.code` is one of a fixed
+// set that the frontend can match on instead of the English message.
+//
+// async-graphql converts anything that implements Display with `?`, which would drop the code,
+// so build the response error explicitly with `.extend()`.
+#[derive(Debug, Error)]
+pub enum RbacError {
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    Unauthenticated(String),
+    #[error("{0}")]
+    Forbidden(String),
+    // `field` is the path of the offending argument, e.g. "id" or "filter.createdAfter"
+    #[error("{message}")]
+    Validation { field: String, message: String },
+    // the message stays generic; the string says what failed and goes into `details`
+    #[error("Internal Server Error")]
+    Internal(String),
+    #[error("Query is nested too deep: depth {depth} exceeds the limit of {limit}")]
+    QueryTooDeep { depth: usize, limit: usize },
+    #[error("Query is too complex: complexity {complexity} exceeds the limit of {limit}")]
+    QueryTooComplex { complexity: usize, limit: usize },
+    #[error("Too many requests, retry after {retry_after}s")]
+    RateLimited { retry_after: u64 },
+    #[error("Too many failed logins, try again in {retry_after}s")]
+    LoginLocked { retry_after: u64 },
+    // the password was right; finish with verifyMfa, or enroll first when `enrolled` is false
+    #[error("A second factor is required to finish the login")]
+    MfaRequired { mfa_token: String, enrolled: bool },
+    // the password was right, but the account waits for verifyEmail
+    #[error("Verify your email address before logging in")]
+    EmailNotVerified,
+}
+
+impl RbacError {
+    pub fn validation(field: &str, message: impl Into<String>) -> Self {
+        RbacError::Validation {
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            RbacError::NotFound(_) => "NOT_FOUND",
+            RbacError::Conflict(_) => "CONFLICT",
+            RbacError::Unauthenticated(_) => "UNAUTHENTICATED",
+            RbacError::Forbidden(_) => "FORBIDDEN",
+            RbacError::Validation { .. } => "VALIDATION_FAILED",
+            RbacError::Internal(_) => "INTERNAL_SERVER_ERROR",
+            RbacError::QueryTooDeep { .. } => "QUERY_TOO_DEEP",
+            RbacError::QueryTooComplex { .. } => "QUERY_TOO_COMPLEX",
+            RbacError::RateLimited { .. } => "RATE_LIMITED",
+            RbacError::LoginLocked { .. } => "LOGIN_LOCKED",
+            RbacError::MfaRequired { .. } => "MFA_REQUIRED",
+            RbacError::EmailNotVerified => "EMAIL_NOT_VERIFIED",
+        }
+    }
+}
+
+impl ErrorExtensions for RbacError {
+    fn extend(&self) -> Error {
+        Error::new(self.to_string()).extend_with(|_, e| {
+            e.set("code", self.code());
+            match self {
+                RbacError::Validation { field, .. } => e.set("field", field.as_str()),
+                RbacError::Internal(details) => e.set("details", details.as_str()),
+                RbacError::QueryTooDeep { depth, limit } => {
+                    e.set("depth", *depth as u64);
+                    e.set("limit", *limit as u64);
+                }
+                RbacError::QueryTooComplex { complexity, limit } => {
+                    e.set("complexity", *complexity as u64);
+                    e.set("limit", *limit as u64);
+                }
+                RbacError::RateLimited { retry_after } | RbacError::LoginLocked { retry_after } => {
+                    e.set("retryAfter", *retry_after)
+                }
+                RbacError::MfaRequired { mfa_token, enrolled } => {
+                    e.set("mfaToken", mfa_token.as_str());
+                    e.set("mfaEnrolled", *enrolled);
+                }
+                _ => (),
+            }
+        })
+    }
+}
+
+// parses a numeric id argument instead of panicking on bad input
+pub fn parse_id(value: &str, field: &str) -> async_graphql::Result<i32> {
+    match value.parse::<i32>() {
+        Ok(v) => Ok(v),
+        Err(_) => Err(RbacError::validation(field, format!("{} must be a numeric id", field)).extend()),
+    }
+}
+
+// reads a string extension such as `code` back out of an error, for APIs other than GraphQL
+pub fn extension(e: &Error, name: &str) -> Option<String> {
+    match e.extensions.as_ref().and_then(|x| x.get(name)) {
+        Some(Value::String(v)) => Some(v.clone()),
+        _ => None,
+    }
+}
+
+// the `retryAfter` seconds of a RATE_LIMITED or LOGIN_LOCKED error
+pub fn retry_after(e: &Error) -> Option<u64> {
+    match e.extensions.as_ref().and_then(|x| x.get("retryAfter")) {
+        Some(Value::Number(v)) => v.as_u64(),
+        _ => None,
+    }
+}
+
+// for rejections that happen before any resolver runs, so there is no field position
+pub fn request_error(e: RbacError) -> ServerError {
+    let mut err = ServerError::new(e.to_string(), None);
+    err.extensions = e.extend().extensions;
+    err
+}
@@ -1,60 +1,57 @@
-use std::sync::Mutex;
-
 use actix_web::{HttpRequest, HttpResponse};
-use async_graphql::{Error, ErrorExtensions};
-use chrono::Utc;
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
-use serde::{Deserialize, Serialize};
+use async_graphql::ErrorExtensions;
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{encode, EncodingKey, Header};
+// parsing and verification live in the rbac_auth crate so downstream services share them
+pub use rbac_auth::jwt::{parse_bearer, Claims};
+use rbac_auth::jwt::decode_shared;
 
-const JWT_SECRET: &[u8] = b"rbac_secret";
+use crate::utilities::errors::RbacError;
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Claims {
-    pub sub: String,
-    pub role: Vec<String>,
-    pub exp: usize,
-}
+const JWT_SECRET: &[u8] = b"rbac_secret";
+// access tokens are short-lived; clients renew them with the refresh token from login
+const ACCESS_TOKEN_MINUTES: i64 = 15;
 
+// returns the token and the moment it expires
 pub async fn create_jwt(
     uid: &str,
     role: Vec<String>,
-) -> Result<String, jsonwebtoken::errors::Error> {
-    println!("Hello from the create_jwt ");
-
+) -> Result<(String, DateTime<Utc>), jsonwebtoken::errors::Error> {
     let expiration = Utc::now()
-        .checked_add_signed(chrono::Duration::minutes(15))
-        .expect("Valid Timestamp")
-        .timestamp();
-    println!("Expiration = {:?}", expiration);
+        .checked_add_signed(chrono::Duration::minutes(ACCESS_TOKEN_MINUTES))
+        .expect("Valid Timestamp");
     let claims = Claims {
         sub: uid.to_string(),
         role: role,
-        exp: expiration as usize,
+        exp: expiration.timestamp() as usize,
     };
 
     let header = Header::new(jsonwebtoken::Algorithm::HS256);
-    encode(&header, &claims, &EncodingKey::from_secret(JWT_SECRET))
+    let token = encode(&header, &claims, &EncodingKey::from_secret(JWT_SECRET))?;
+    Ok((token, expiration))
 }
 
-pub fn extract_jwt(req: Mutex<HttpRequest>) -> Option<String> {
-    if let Some(auth_header) = req.lock().unwrap().headers().get("Authorization") {
+// a JWT or an API key from `Authorization: Bearer`, else an API key from `X-API-Key`
+pub fn extract_jwt(req: &HttpRequest) -> Option<String> {
+    if let Some(auth_header) = req.headers().get("Authorization") {
         if let Ok(auth_str) = auth_header.to_str() {
-            if auth_str.starts_with("Bearer") {
-                let token = auth_str.trim_start_matches("Bearer ");
-                return Some(token.to_string());
-            }
+            return parse_bearer(auth_str);
         }
     }
+    if let Some(key) = req.headers().get("X-API-Key") {
+        return key.to_str().ok().map(|v| v.trim().to_string());
+    }
     None
     // Err(Error::new("Invalid Authorization").extend_with(|_,x| x.set("details","Missing Authorization")))
     // Err(HttpResponse::Unauthorized().body("Missing or Invalid Authorization"))
 }
 
 pub fn decode_jwt(token: String) -> async_graphql::Result<Claims> {
-    let validation = Validation::new(jsonwebtoken::Algorithm::HS256);
-    match decode::<Claims>(&token, &DecodingKey::from_secret(JWT_SECRET), &validation) {
-        Ok(token_data) => Ok(token_data.claims),
-        Err(e) => Err(Error::new("Invalid Authorization")
-            .extend_with(|_, x| x.set("details", "Invalid Authorization"))),
+    match decode_shared(&token, JWT_SECRET) {
+        Ok(v) => Ok(v),
+        Err(e) => {
+            println!("Error decode_jwt = {:?}", e);
+            Err(RbacError::Unauthenticated("Invalid Authorization".to_string()).extend())
+        }
     }
 }
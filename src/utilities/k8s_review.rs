@@ -0,0 +1,429 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+
+use crate::{
+    db::{
+        roles::fetch_role_grants,
+        users::{fetch_user_email, fetch_user_id_by_email, fetch_user_roles},
+    },
+    utilities::jwt::decode_jwt,
+};
+
+// authentication.k8s.io/v1 TokenReview, as sent by the kube-apiserver token webhook
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenReview {
+    pub api_version: String,
+    pub kind: String,
+    #[serde(default)]
+    pub spec: TokenReviewSpec,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct TokenReviewSpec {
+    #[serde(default)]
+    pub token: String,
+}
+
+// the answer leaves out the spec, so the token is never echoed back
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenReviewResponse {
+    pub api_version: String,
+    pub kind: String,
+    pub status: TokenReviewStatus,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct TokenReviewStatus {
+    pub authenticated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<K8sUser>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// usernames and groups this server hands out start with this, so they can't pass for cluster
+// users or for groups such as system:masters, and the authorizer knows which users are ours
+pub const K8S_PREFIX: &str = "rbac:";
+
+// the email becomes the Kubernetes username and the roles become its groups
+#[derive(Debug, Serialize)]
+pub struct K8sUser {
+    pub username: String,
+    pub uid: String,
+    pub groups: Vec<String>,
+}
+
+// authorization.k8s.io/v1 SubjectAccessReview, as sent by the kube-apiserver authorization webhook
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubjectAccessReview {
+    pub api_version: String,
+    pub kind: String,
+    #[serde(default)]
+    pub spec: SubjectAccessReviewSpec,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubjectAccessReviewSpec {
+    pub resource_attributes: Option<ResourceAttributes>,
+    #[serde(default)]
+    pub user: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ResourceAttributes {
+    // empty for cluster scoped resources
+    #[serde(default)]
+    pub namespace: String,
+    #[serde(default)]
+    pub verb: String,
+    #[serde(default)]
+    pub resource: String,
+    #[serde(default)]
+    pub subresource: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubjectAccessReviewResponse {
+    pub api_version: String,
+    pub kind: String,
+    pub status: SubjectAccessReviewStatus,
+}
+
+// `allowed: false` without `denied` is "no opinion", so other authorizers such as the
+// built-in RBAC still get a say
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubjectAccessReviewStatus {
+    pub allowed: bool,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub reason: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub evaluation_error: String,
+}
+
+// which of our roles apply in which namespaces and to which resources, e.g.
+// {"roles": ["Editor"], "namespaces": ["team-a"], "resources": ["deployments", "pods/*"]}
+#[derive(Debug, Clone, Deserialize)]
+pub struct K8sRule {
+    pub roles: Vec<String>,
+    // "*" is every namespace including cluster scope, "" is cluster scope only
+    #[serde(default = "everything")]
+    pub namespaces: Vec<String>,
+    // "pods" leaves out subresources, "pods/*" or "pods/log" name them, "*" is everything
+    #[serde(default = "everything")]
+    pub resources: Vec<String>,
+}
+
+fn everything() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+impl K8sRule {
+    fn matches(&self, attrs: &ResourceAttributes) -> bool {
+        let namespace = self
+            .namespaces
+            .iter()
+            .any(|n| n == "*" || *n == attrs.namespace);
+        let resource = self.resources.iter().any(|r| {
+            if r == "*" {
+                return true;
+            }
+            match r.split_once('/') {
+                Some((res, sub)) => {
+                    res == attrs.resource && !attrs.subresource.is_empty() && (sub == "*" || sub == attrs.subresource)
+                }
+                None => *r == attrs.resource && attrs.subresource.is_empty(),
+            }
+        });
+        namespace && resource
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct K8sRules {
+    pub rules: Vec<K8sRule>,
+}
+
+impl K8sRules {
+    // the roles of the user that some rule applies to these attributes
+    fn applicable_roles(&self, attrs: &ResourceAttributes, user_roles: &[String]) -> Vec<String> {
+        let mut roles: Vec<String> = self
+            .rules
+            .iter()
+            .filter(|rule| rule.matches(attrs))
+            .flat_map(|rule| rule.roles.iter())
+            .filter(|role| user_roles.contains(role))
+            .cloned()
+            .collect();
+        roles.sort();
+        roles.dedup();
+        roles
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let rules: Vec<K8sRule> = serde_json::from_str(&content).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(K8sRules { rules })
+    }
+}
+
+// the permission a Kubernetes verb needs; verbs like impersonate or escalate have none
+pub fn verb_action(verb: &str) -> Option<&'static str> {
+    match verb {
+        "get" | "list" | "watch" => Some("Read"),
+        "create" => Some("Create"),
+        "update" | "patch" => Some("Update"),
+        "delete" | "deletecollection" => Some("Delete"),
+        _ => None,
+    }
+}
+
+pub async fn review_token(pool: &Pool<Postgres>, review: TokenReview) -> TokenReviewResponse {
+    let status = match authenticate(pool, review.spec.token).await {
+        Ok(user) => TokenReviewStatus {
+            authenticated: true,
+            user: Some(user),
+            error: None,
+        },
+        Err(e) => TokenReviewStatus {
+            authenticated: false,
+            user: None,
+            error: Some(e),
+        },
+    };
+    TokenReviewResponse {
+        api_version: review.api_version,
+        kind: review.kind,
+        status,
+    }
+}
+
+async fn authenticate(pool: &Pool<Postgres>, token: String) -> Result<K8sUser, String> {
+    let claims = decode_jwt(token).map_err(|e| e.message)?;
+    let id: i32 = claims.sub.parse().map_err(|_| "Invalid Authorization".to_string())?;
+    // the token outlives role changes and deletions, so identity and groups come from the database
+    let email = match fetch_user_email(pool, id).await {
+        Ok(Some(v)) => v,
+        Ok(None) => return Err("User Not Found".to_string()),
+        Err(e) => return Err(e.message),
+    };
+    let groups = fetch_user_roles(pool, id).await.map_err(|e| e.message)?;
+    Ok(K8sUser {
+        username: format!("{}{}", K8S_PREFIX, email),
+        uid: claims.sub,
+        groups: groups.into_iter().map(|g| format!("{}{}", K8S_PREFIX, g)).collect(),
+    })
+}
+
+pub async fn review_access(
+    pool: &Pool<Postgres>,
+    rules: &K8sRules,
+    review: SubjectAccessReview,
+) -> SubjectAccessReviewResponse {
+    let status = match evaluate(pool, rules, &review.spec).await {
+        Ok(v) => v,
+        Err(e) => SubjectAccessReviewStatus {
+            allowed: false,
+            reason: String::new(),
+            evaluation_error: e,
+        },
+    };
+    SubjectAccessReviewResponse {
+        api_version: review.api_version,
+        kind: review.kind,
+        status,
+    }
+}
+
+fn no_opinion(reason: String) -> SubjectAccessReviewStatus {
+    SubjectAccessReviewStatus {
+        allowed: false,
+        reason,
+        evaluation_error: String::new(),
+    }
+}
+
+async fn evaluate(
+    pool: &Pool<Postgres>,
+    rules: &K8sRules,
+    spec: &SubjectAccessReviewSpec,
+) -> Result<SubjectAccessReviewStatus, String> {
+    // anyone else was authenticated by another webhook, a certificate or a service account token
+    let email = match spec.user.strip_prefix(K8S_PREFIX) {
+        Some(v) => v,
+        None => return Ok(no_opinion(format!("{} was not authenticated by the RBAC server", spec.user))),
+    };
+    let attrs = match &spec.resource_attributes {
+        Some(v) => v,
+        None => return Ok(no_opinion("Non-resource requests are not governed by the RBAC server".to_string())),
+    };
+    let action = match verb_action(&attrs.verb) {
+        Some(v) => v,
+        None => return Ok(no_opinion(format!("The verb {} has no matching permission", attrs.verb))),
+    };
+    let user_id = match fetch_user_id_by_email(pool, email).await.map_err(|e| e.message)? {
+        Some(v) => v,
+        None => return Ok(no_opinion(format!("{} is not an RBAC server user", spec.user))),
+    };
+    let user_roles = fetch_user_roles(pool, user_id).await.map_err(|e| e.message)?;
+    let roles = rules.applicable_roles(attrs, &user_roles);
+    if roles.is_empty() {
+        return Ok(no_opinion(format!(
+            "No role of {} applies to {} in namespace {:?}",
+            spec.user, attrs.resource, attrs.namespace
+        )));
+    }
+    let grants = fetch_role_grants(pool, &roles).await.map_err(|e| e.message)?;
+    match grants.iter().find(|g| g.actions.iter().any(|a| a == action)) {
+        Some(grant) => Ok(SubjectAccessReviewStatus {
+            allowed: true,
+            reason: format!("Role {} grants {}", grant.name, action),
+            evaluation_error: String::new(),
+        }),
+        None => Ok(no_opinion(format!("Roles {} do not grant {}", roles.join(", "), action))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = include_str!("../../k8s/rules.example.json");
+    const ACCESS_REVIEW: &str = include_str!("../../k8s/subject_access_review.json");
+
+    fn rules() -> K8sRules {
+        K8sRules {
+            rules: serde_json::from_str(RULES).unwrap(),
+        }
+    }
+
+    fn attrs(namespace: &str, verb: &str, resource: &str, subresource: &str) -> ResourceAttributes {
+        ResourceAttributes {
+            namespace: namespace.to_string(),
+            verb: verb.to_string(),
+            resource: resource.to_string(),
+            subresource: subresource.to_string(),
+        }
+    }
+
+    fn roles(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    // nothing is reached in the database on the paths that answer before looking the user up
+    fn pool() -> Pool<Postgres> {
+        sqlx::postgres::PgPoolOptions::new()
+            .connect_lazy("postgres://nobody@127.0.0.1:1/none")
+            .unwrap()
+    }
+
+    #[test]
+    fn rule_defaults_cover_every_namespace_and_resource() {
+        let admin = &rules().rules[0];
+        assert!(admin.matches(&attrs("", "get", "nodes", "")));
+        assert!(admin.matches(&attrs("kube-system", "get", "pods", "exec")));
+    }
+
+    #[test]
+    fn rule_matches_namespaces_and_subresources() {
+        let editor = &rules().rules[1];
+        assert!(editor.matches(&attrs("team-a", "get", "deployments", "")));
+        assert!(editor.matches(&attrs("team-b", "get", "pods", "log")));
+        // pods without a subresource name doesn't reach pods/exec
+        assert!(!editor.matches(&attrs("team-a", "create", "pods", "exec")));
+        assert!(!editor.matches(&attrs("team-c", "get", "deployments", "")));
+        assert!(!editor.matches(&attrs("", "get", "deployments", "")));
+    }
+
+    #[test]
+    fn rule_wildcard_subresource() {
+        let rule: K8sRule = serde_json::from_str(r#"{"roles": ["Ops"], "namespaces": [""], "resources": ["nodes/*"]}"#).unwrap();
+        assert!(rule.matches(&attrs("", "get", "nodes", "proxy")));
+        assert!(!rule.matches(&attrs("", "get", "nodes", "")));
+        assert!(!rule.matches(&attrs("default", "get", "nodes", "proxy")));
+    }
+
+    #[test]
+    fn applicable_roles_are_those_of_the_user() {
+        let rules = rules();
+        let got = rules.applicable_roles(&attrs("team-a", "get", "secrets", ""), &roles(&["Editor", "Viewer"]));
+        assert_eq!(got, roles(&["Viewer"]));
+        let got = rules.applicable_roles(&attrs("team-a", "get", "pods", ""), &roles(&["Editor", "Viewer", "Other"]));
+        assert_eq!(got, roles(&["Editor", "Viewer"]));
+        assert!(rules.applicable_roles(&attrs("team-a", "get", "pods", ""), &roles(&["Other"])).is_empty());
+    }
+
+    #[test]
+    fn verbs_map_to_permissions() {
+        for (verb, action) in [
+            ("get", Some("Read")),
+            ("list", Some("Read")),
+            ("watch", Some("Read")),
+            ("create", Some("Create")),
+            ("update", Some("Update")),
+            ("patch", Some("Update")),
+            ("delete", Some("Delete")),
+            ("deletecollection", Some("Delete")),
+            ("impersonate", None),
+            ("escalate", None),
+            ("bind", None),
+        ] {
+            assert_eq!(verb_action(verb), action, "{}", verb);
+        }
+    }
+
+    #[test]
+    fn fixture_review_parses() {
+        let review: SubjectAccessReview = serde_json::from_str(ACCESS_REVIEW).unwrap();
+        assert_eq!(review.spec.user, "rbac:admin@test.com");
+        let attrs = review.spec.resource_attributes.unwrap();
+        assert_eq!((attrs.namespace.as_str(), attrs.verb.as_str(), attrs.resource.as_str()), ("team-a", "get", "deployments"));
+    }
+
+    #[tokio::test]
+    async fn users_of_other_authenticators_get_no_opinion() {
+        let mut review: serde_json::Value = serde_json::from_str(ACCESS_REVIEW).unwrap();
+        review["spec"]["user"] = "admin@test.com".into();
+        let review: SubjectAccessReview = serde_json::from_value(review).unwrap();
+        let res = review_access(&pool(), &rules(), review).await;
+        let body = serde_json::to_value(&res).unwrap();
+        assert_eq!(body["apiVersion"], "authorization.k8s.io/v1");
+        assert_eq!(body["kind"], "SubjectAccessReview");
+        assert_eq!(body["status"]["allowed"], false);
+        assert!(body["status"].get("denied").is_none());
+        assert!(body["status"].get("evaluationError").is_none());
+        assert_eq!(body["status"]["reason"], "admin@test.com was not authenticated by the RBAC server");
+    }
+
+    #[tokio::test]
+    async fn unmapped_verbs_and_non_resource_requests_get_no_opinion() {
+        let mut review: serde_json::Value = serde_json::from_str(ACCESS_REVIEW).unwrap();
+        review["spec"]["resourceAttributes"]["verb"] = "impersonate".into();
+        let res = review_access(&pool(), &rules(), serde_json::from_value(review.clone()).unwrap()).await;
+        assert!(!res.status.allowed);
+        assert_eq!(res.status.reason, "The verb impersonate has no matching permission");
+
+        review["spec"].as_object_mut().unwrap().remove("resourceAttributes");
+        review["spec"]["nonResourceAttributes"] = serde_json::json!({"path": "/healthz", "verb": "get"});
+        let res = review_access(&pool(), &rules(), serde_json::from_value(review).unwrap()).await;
+        assert!(!res.status.allowed);
+        assert!(res.status.evaluation_error.is_empty());
+    }
+
+    #[tokio::test]
+    async fn token_review_never_echoes_the_token() {
+        let review: TokenReview = serde_json::from_str(include_str!("../../k8s/token_review.json")).unwrap();
+        let res = review_token(&pool(), review).await;
+        let body = serde_json::to_value(&res).unwrap();
+        assert_eq!(body["apiVersion"], "authentication.k8s.io/v1");
+        assert_eq!(body["kind"], "TokenReview");
+        assert_eq!(body["status"]["authenticated"], false);
+        assert!(body.get("spec").is_none());
+        assert!(body["status"].get("user").is_none());
+    }
+}
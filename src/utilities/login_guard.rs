@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+use async_graphql::{Context, ErrorExtensions};
+use sqlx::PgPool;
+
+use crate::{
+    db::login_failures::{clear_login_failures, lock_login, login_lock_remaining, record_login_failure},
+    utilities::{audit::AuditEvent, errors::RbacError},
+};
+
+// failures are forgotten after a day without one, so backoff keeps growing across lockouts
+const FAILURE_WINDOW_SECS: i64 = 24 * 60 * 60;
+
+// how many wrong passwords an account or a client address gets before it is locked out. Each
+// failure past the threshold doubles the lockout, starting at `base_lockout`, up to `max_lockout`
+#[derive(Debug, Clone)]
+pub struct LoginPolicy {
+    pub account_threshold: i32,
+    pub ip_threshold: i32,
+    pub base_lockout: Duration,
+    pub max_lockout: Duration,
+}
+
+impl LoginPolicy {
+    fn lockout_secs(&self, failures: i32, threshold: i32) -> Option<i64> {
+        if threshold <= 0 || failures < threshold {
+            return None;
+        }
+        let doublings = (failures - threshold).min(30) as u32;
+        let secs = self.base_lockout.as_secs().saturating_mul(1 << doublings);
+        Some(secs.min(self.max_lockout.as_secs()) as i64)
+    }
+}
+
+// unknown emails are counted like real ones, so a lockout doesn't reveal which accounts exist
+pub fn account_key(email: &str) -> String {
+    format!("email:{}", email.trim().to_lowercase())
+}
+
+fn keys(email: &str, client_ip: Option<&str>) -> Vec<String> {
+    let mut keys = vec![account_key(email)];
+    if let Some(ip) = client_ip {
+        keys.push(format!("ip:{}", ip));
+    }
+    keys
+}
+
+// fails with LOGIN_LOCKED while the account or the client address is locked out
+pub async fn check_login_allowed(pool: &PgPool, email: &str, client_ip: Option<&str>) -> async_graphql::Result<()> {
+    match login_lock_remaining(pool, &keys(email, client_ip)).await? {
+        Some(retry_after) => Err(RbacError::LoginLocked { retry_after }.extend()),
+        None => Ok(()),
+    }
+}
+
+// counts the failure against the account and the address; lockouts it starts go to the audit log
+pub async fn login_failed(
+    ctx: &Context<'_>,
+    pool: &PgPool,
+    policy: &LoginPolicy,
+    email: &str,
+    client_ip: Option<&str>,
+) -> async_graphql::Result<()> {
+    for (i, key) in keys(email, client_ip).iter().enumerate() {
+        let threshold = if i == 0 { policy.account_threshold } else { policy.ip_threshold };
+        let failures = record_login_failure(pool, key, FAILURE_WINDOW_SECS).await?;
+        if let Some(secs) = policy.lockout_secs(failures, threshold) {
+            lock_login(pool, key, secs).await?;
+            AuditEvent::new(email, "login_locked")
+                .target(key)
+                .after(format!("{}s after {} failures", secs, failures))
+                .success(ctx)
+                .await;
+        }
+    }
+    Ok(())
+}
+
+// the address keeps its count, otherwise one valid account would reset a stuffing run
+pub async fn login_succeeded(pool: &PgPool, email: &str) -> async_graphql::Result<()> {
+    clear_login_failures(pool, &account_key(email)).await.map(|_| ())
+}
@@ -0,0 +1,88 @@
+use async_graphql::ErrorExtensions;
+use sqlx::PgPool;
+
+use crate::{
+    db::{
+        mfa::{fetch_mfa_challenge, fetch_totp, insert_mfa_challenge, record_mfa_failure, use_recovery_code, use_totp_step},
+        users::fetch_user_roles,
+    },
+    utilities::{
+        errors::RbacError,
+        session::{hash_token, new_token},
+        totp::{hash_recovery_code, verify_code},
+    },
+};
+
+// how long the second login step stays open, and how many wrong codes it takes
+const CHALLENGE_SECS: i64 = 5 * 60;
+const MAX_CHALLENGE_ATTEMPTS: i32 = 5;
+
+#[derive(Debug, Clone)]
+pub struct MfaPolicy {
+    // shown as the account's name in authenticator apps
+    pub issuer: String,
+    // members of these roles can't finish a login without a second factor
+    pub required_roles: Vec<String>,
+}
+
+impl MfaPolicy {
+    pub fn required_for(&self, roles: &[String]) -> bool {
+        roles.iter().any(|r| self.required_roles.contains(r))
+    }
+}
+
+pub fn invalid_mfa_token() -> async_graphql::Error {
+    RbacError::Unauthenticated("Invalid or expired MFA token".to_string()).extend()
+}
+
+async fn totp_enabled(pool: &PgPool, user_id: i32) -> async_graphql::Result<bool> {
+    Ok(fetch_totp(pool, user_id).await?.is_some_and(|t| t.confirmed))
+}
+
+// after the password checked out: None when the login can finish, else the MFA_REQUIRED error
+// carrying a new challenge token for verifyMfa (or for enrollTotp, when a role demands MFA the
+// user hasn't set up yet)
+pub async fn start_mfa_challenge(
+    pool: &PgPool,
+    policy: &MfaPolicy,
+    user_id: i32,
+    roles: &[String],
+) -> async_graphql::Result<Option<async_graphql::Error>> {
+    let enrolled = totp_enabled(pool, user_id).await?;
+    if !enrolled && !policy.required_for(roles) {
+        return Ok(None);
+    }
+    let mfa_token = new_token();
+    insert_mfa_challenge(pool, user_id, &hash_token(&mfa_token), CHALLENGE_SECS).await?;
+    Ok(Some(RbacError::MfaRequired { mfa_token, enrolled }.extend()))
+}
+
+// true when a role of the user demands MFA but it isn't set up, e.g. a user made Admin after
+// their last login. Their sessions can't be refreshed until they enroll
+pub async fn mfa_missing(pool: &PgPool, policy: &MfaPolicy, user_id: i32) -> async_graphql::Result<bool> {
+    if !policy.required_for(&fetch_user_roles(pool, user_id).await?) {
+        return Ok(false);
+    }
+    Ok(!totp_enabled(pool, user_id).await?)
+}
+
+// the user whose password was checked for `mfa_token`
+pub async fn challenge_user(pool: &PgPool, mfa_token: &str) -> async_graphql::Result<i32> {
+    match fetch_mfa_challenge(pool, &hash_token(mfa_token)).await? {
+        Some(v) => Ok(v),
+        None => Err(invalid_mfa_token()),
+    }
+}
+
+// counts a wrong code against the challenge, which ends after a few of them
+pub async fn challenge_failed(pool: &PgPool, mfa_token: &str) -> async_graphql::Result<()> {
+    record_mfa_failure(pool, &hash_token(mfa_token), MAX_CHALLENGE_ATTEMPTS).await
+}
+
+// a code from the authenticator, or one of the recovery codes. Either works only once
+pub async fn check_mfa_code(pool: &PgPool, user_id: i32, secret: &str, code: &str) -> async_graphql::Result<bool> {
+    if let Some(step) = verify_code(secret, code) {
+        return use_totp_step(pool, user_id, step).await;
+    }
+    use_recovery_code(pool, user_id, &hash_recovery_code(code)).await
+}
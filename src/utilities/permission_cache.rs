@@ -0,0 +1,289 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use sqlx::{Pool, Postgres};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{db::roles::fetch_role_permission, utilities::change_feed::ChangeFeed};
+
+pub const DEFAULT_TTL: Duration = Duration::from_secs(60);
+pub const DEFAULT_MAX_ENTRIES: usize = 1024;
+
+static PERMISSION_CACHE: OnceLock<PermissionCache> = OnceLock::new();
+
+struct CacheEntry {
+    permissions: Vec<String>,
+    loaded_at: Instant,
+}
+
+struct CacheState {
+    entries: HashMap<Vec<String>, CacheEntry>,
+    // bumped on every invalidation so a load that raced with it is not stored
+    generation: u64,
+}
+
+// effective permissions of a role set, keyed by the sorted role names from the token
+pub struct PermissionCache {
+    ttl: Duration,
+    max_entries: usize,
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    invalidations: AtomicU64,
+    evictions: AtomicU64,
+}
+
+pub struct PermissionCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub invalidations: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub max_entries: usize,
+    pub ttl_seconds: u64,
+}
+
+impl PermissionCache {
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        PermissionCache {
+            ttl,
+            max_entries,
+            state: Mutex::new(CacheState {
+                entries: HashMap::new(),
+                generation: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            invalidations: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    pub async fn permissions(
+        &self,
+        pool: &Pool<Postgres>,
+        roles: &[String],
+    ) -> async_graphql::Result<Vec<String>> {
+        self.cached(roles, |key| fetch_role_permission(pool, key)).await
+    }
+
+    // `load` only runs on a miss, with the normalized key
+    async fn cached<F, Fut>(&self, roles: &[String], load: F) -> async_graphql::Result<Vec<String>>
+    where
+        F: FnOnce(Vec<String>) -> Fut,
+        Fut: Future<Output = async_graphql::Result<Vec<String>>>,
+    {
+        let mut key = roles.to_vec();
+        key.sort();
+        key.dedup();
+
+        let generation = {
+            let state = self.state.lock().unwrap();
+            if let Some(entry) = state.entries.get(&key) {
+                if entry.loaded_at.elapsed() < self.ttl {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return Ok(entry.permissions.clone());
+                }
+            }
+            state.generation
+        };
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let permissions = load(key.clone()).await?;
+        if self.max_entries == 0 {
+            return Ok(permissions);
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.generation == generation {
+            if !state.entries.contains_key(&key) && state.entries.len() >= self.max_entries {
+                self.evict(&mut state);
+            }
+            state.entries.insert(
+                key,
+                CacheEntry {
+                    permissions: permissions.clone(),
+                    loaded_at: Instant::now(),
+                },
+            );
+        }
+        Ok(permissions)
+    }
+
+    // drops expired entries, or the oldest one when none has expired yet
+    fn evict(&self, state: &mut CacheState) {
+        let before = state.entries.len();
+        let ttl = self.ttl;
+        state.entries.retain(|_, e| e.loaded_at.elapsed() < ttl);
+        if state.entries.len() == before {
+            let oldest = state
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.loaded_at)
+                .map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                state.entries.remove(&oldest);
+            }
+        }
+        self.evictions
+            .fetch_add((before - state.entries.len()) as u64, Ordering::Relaxed);
+    }
+
+    pub fn invalidate(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.clear();
+        state.generation += 1;
+        self.invalidations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> PermissionCacheStats {
+        PermissionCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            invalidations: self.invalidations.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            entries: self.state.lock().unwrap().entries.len(),
+            max_entries: self.max_entries,
+            ttl_seconds: self.ttl.as_secs(),
+        }
+    }
+}
+
+// sets the TTL and size of the process wide cache; only the first call has an effect
+pub fn init_permission_cache(ttl: Duration, max_entries: usize) {
+    let _ = PERMISSION_CACHE.set(PermissionCache::new(ttl, max_entries));
+}
+
+// shared by every caller of `authorize`, whether it comes from GraphQL or elsewhere
+pub fn permission_cache() -> &'static PermissionCache {
+    PERMISSION_CACHE.get_or_init(|| PermissionCache::new(DEFAULT_TTL, DEFAULT_MAX_ENTRIES))
+}
+
+// clears the cache whenever any instance changes roles, grants or memberships
+pub fn watch_permission_changes(feed: &ChangeFeed) {
+    let mut rx = feed.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match rx.recv().await {
+                // the listener reconnected and may have missed notifications
+                Ok(event) if event.is_resync() => permission_cache().invalidate(),
+                Ok(event) if event.table == "users" => (),
+                Ok(_) => permission_cache().invalidate(),
+                // some notifications were missed, so any entry may be stale
+                Err(RecvError::Lagged(_)) => permission_cache().invalidate(),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roles(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    // a loader answering with the role names themselves, counting how often it ran
+    async fn get(cache: &PermissionCache, names: &[&str], loads: &AtomicU64) -> Vec<String> {
+        cache
+            .cached(&roles(names), |key| async move {
+                loads.fetch_add(1, Ordering::Relaxed);
+                Ok(key)
+            })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn role_order_and_duplicates_share_an_entry() {
+        let (cache, loads) = (PermissionCache::new(DEFAULT_TTL, 8), AtomicU64::new(0));
+        assert_eq!(get(&cache, &["b", "a", "b"], &loads).await, roles(&["a", "b"]));
+        assert_eq!(get(&cache, &["a", "b"], &loads).await, roles(&["a", "b"]));
+        assert_eq!(loads.load(Ordering::Relaxed), 1);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+    }
+
+    #[tokio::test]
+    async fn entries_expire_after_the_ttl() {
+        let (cache, loads) = (PermissionCache::new(Duration::from_millis(50), 8), AtomicU64::new(0));
+        get(&cache, &["a"], &loads).await;
+        get(&cache, &["a"], &loads).await;
+        assert_eq!(loads.load(Ordering::Relaxed), 1);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        get(&cache, &["a"], &loads).await;
+        assert_eq!(loads.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn the_oldest_entry_is_evicted_at_the_bound() {
+        let (cache, loads) = (PermissionCache::new(DEFAULT_TTL, 2), AtomicU64::new(0));
+        for names in [["a"], ["b"], ["c"]] {
+            get(&cache, &names, &loads).await;
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.evictions), (2, 1));
+        // "a" went, "b" and "c" are still cached
+        get(&cache, &["b"], &loads).await;
+        get(&cache, &["c"], &loads).await;
+        assert_eq!(loads.load(Ordering::Relaxed), 3);
+        get(&cache, &["a"], &loads).await;
+        assert_eq!(loads.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn expired_entries_are_evicted_before_live_ones() {
+        let (cache, loads) = (PermissionCache::new(Duration::from_millis(50), 2), AtomicU64::new(0));
+        get(&cache, &["a"], &loads).await;
+        get(&cache, &["b"], &loads).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        get(&cache, &["c"], &loads).await;
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.evictions), (1, 2));
+    }
+
+    #[tokio::test]
+    async fn a_zero_bound_caches_nothing() {
+        let (cache, loads) = (PermissionCache::new(DEFAULT_TTL, 0), AtomicU64::new(0));
+        get(&cache, &["a"], &loads).await;
+        get(&cache, &["a"], &loads).await;
+        assert_eq!((loads.load(Ordering::Relaxed), cache.stats().entries), (2, 0));
+    }
+
+    #[tokio::test]
+    async fn a_load_racing_an_invalidation_is_not_stored() {
+        let (cache, loads) = (PermissionCache::new(DEFAULT_TTL, 8), AtomicU64::new(0));
+        // the grants change while the old ones are being read
+        let stale = cache
+            .cached(&roles(&["a"]), |_| async {
+                cache.invalidate();
+                Ok(roles(&["stale"]))
+            })
+            .await
+            .unwrap();
+        assert_eq!(stale, roles(&["stale"]));
+        assert_eq!(cache.stats().entries, 0);
+        assert_eq!(get(&cache, &["a"], &loads).await, roles(&["a"]));
+        assert_eq!(loads.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn invalidate_drops_every_entry() {
+        let (cache, loads) = (PermissionCache::new(DEFAULT_TTL, 8), AtomicU64::new(0));
+        get(&cache, &["a"], &loads).await;
+        get(&cache, &["b"], &loads).await;
+        cache.invalidate();
+        assert_eq!((cache.stats().entries, cache.stats().invalidations), (0, 1));
+        get(&cache, &["a"], &loads).await;
+        assert_eq!(loads.load(Ordering::Relaxed), 3);
+    }
+}
@@ -0,0 +1,200 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header,
+    middleware::Next,
+    web, HttpResponse,
+};
+use async_graphql::Response;
+
+use crate::utilities::{
+    api_keys::{api_key_prefix, is_api_key},
+    audit::RequestMeta,
+    errors::{request_error, RbacError},
+    jwt::{decode_jwt, extract_jwt},
+};
+
+// keeps memory bounded when many distinct clients show up, e.g. a scan from rotating IPs
+const MAX_BUCKETS: usize = 100_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// token bucket per client: `burst` requests at once, refilled at `rate` requests per second
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        RateLimiter {
+            rate,
+            burst: burst.max(1) as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // takes a token for `key`, or says how long until the next one is available
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(key) {
+            // a full bucket is the same as no bucket, so those can go first
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, b| b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < burst);
+            if buckets.len() >= MAX_BUCKETS {
+                buckets.clear();
+            }
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    // a valid token is throttled per user and an API key per key, wherever they come from;
+    // anything else per address. Err is the number of seconds for Retry-After
+    pub fn check_client(&self, token: Option<&str>, client_ip: Option<&str>) -> Result<(), u64> {
+        let key = match token {
+            // keys are only looked up later, so any well formed one is counted on its own
+            Some(t) if is_api_key(t) => api_key_prefix(t).map(|prefix| format!("key:{}", prefix)),
+            Some(t) => decode_jwt(t.to_string()).ok().map(|claims| format!("sub:{}", claims.sub)),
+            None => None,
+        };
+        let key = key.unwrap_or_else(|| format!("ip:{}", client_ip.unwrap_or("unknown")));
+        self.check(&key).map_err(|wait| wait.as_secs_f64().ceil() as u64)
+    }
+}
+
+// the limit for every HTTP surface: GraphQL and its websocket, /v1, /authz and /k8s. The
+// limiter is app data only when RATE_LIMIT is on
+pub async fn rate_limit_middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let limited = req.app_data::<web::Data<RateLimiter>>().and_then(|limiter| {
+        let meta = RequestMeta::from_request(req.request());
+        limiter
+            .check_client(extract_jwt(req.request()).as_deref(), meta.client_ip.as_deref())
+            .err()
+    });
+    let Some(retry_after) = limited else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+    let e = RbacError::RateLimited { retry_after };
+    let mut res = HttpResponse::TooManyRequests();
+    res.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+    // GraphQL clients read `errors`, the rest gets the body of REST errors
+    let res = if req.path() == "/" {
+        res.json(Response::from_errors(vec![request_error(e)]))
+    } else {
+        res.json(serde_json::json!({ "code": "RATE_LIMITED", "message": e.to_string() }))
+    };
+    Ok(req.into_response(res).map_into_right_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::StatusCode,
+        middleware::from_fn,
+        test::{call_service, init_service, read_body_json, TestRequest},
+        App,
+    };
+
+    use super::*;
+    use crate::utilities::api_keys::new_api_key;
+
+    #[test]
+    fn bucket_allows_burst_then_refuses() {
+        let limiter = RateLimiter::new(1.0, 2);
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.check("a").is_ok());
+        let wait = limiter.check("a").unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_secs(1));
+        // every client has its own bucket
+        assert!(limiter.check("b").is_ok());
+    }
+
+    #[test]
+    fn api_keys_get_a_bucket_each() {
+        let limiter = RateLimiter::new(0.5, 1);
+        let (key, _) = new_api_key();
+        let (other, _) = new_api_key();
+        assert!(limiter.check_client(Some(&key), Some("203.0.113.9")).is_ok());
+        // the key is throttled from any address, and doesn't use up the address for others
+        assert_eq!(limiter.check_client(Some(&key), Some("203.0.113.10")), Err(2));
+        assert!(limiter.check_client(Some(&other), Some("203.0.113.9")).is_ok());
+        assert!(limiter.check_client(None, Some("203.0.113.9")).is_ok());
+        // a malformed key counts against the address like any other bad token
+        assert_eq!(limiter.check_client(Some("rbac_garbage"), Some("203.0.113.9")), Err(2));
+    }
+
+    #[test]
+    fn invalid_tokens_count_against_the_address() {
+        let limiter = RateLimiter::new(0.5, 1);
+        assert!(limiter.check_client(Some("garbage"), Some("203.0.113.9")).is_ok());
+        // a made up token doesn't buy a fresh bucket
+        assert_eq!(limiter.check_client(Some("other garbage"), Some("203.0.113.9")), Err(2));
+        assert!(limiter.check_client(None, Some("203.0.113.10")).is_ok());
+    }
+
+    async fn call(limiter: Option<RateLimiter>, path: &str) -> Vec<ServiceResponse<impl MessageBody>> {
+        let mut app = App::new().wrap(from_fn(rate_limit_middleware));
+        if let Some(limiter) = limiter {
+            app = app.app_data(web::Data::new(limiter));
+        }
+        let app = init_service(app.default_service(web::to(HttpResponse::Ok))).await;
+        let mut res = Vec::new();
+        for _ in 0..2 {
+            res.push(call_service(&app, TestRequest::post().uri(path).to_request()).await);
+        }
+        res
+    }
+
+    #[actix_web::test]
+    async fn middleware_answers_graphql_errors_on_the_graphql_endpoint() {
+        let mut res = call(Some(RateLimiter::new(1.0, 1)), "/").await;
+        assert_eq!(res[0].status(), StatusCode::OK);
+        let limited = res.remove(1);
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(limited.headers().get(header::RETRY_AFTER).unwrap(), "1");
+        let body: serde_json::Value = read_body_json(limited).await;
+        assert_eq!(body["errors"][0]["extensions"]["code"], "RATE_LIMITED");
+        assert_eq!(body["errors"][0]["extensions"]["retryAfter"], 1);
+    }
+
+    #[actix_web::test]
+    async fn middleware_answers_rest_errors_elsewhere() {
+        let mut res = call(Some(RateLimiter::new(1.0, 1)), "/v1/users").await;
+        let limited = res.remove(1);
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+        let body: serde_json::Value = read_body_json(limited).await;
+        assert_eq!(body["code"], "RATE_LIMITED");
+    }
+
+    #[actix_web::test]
+    async fn middleware_passes_everything_when_disabled() {
+        let res = call(None, "/k8s/authorize").await;
+        assert!(res.iter().all(|r| r.status() == StatusCode::OK));
+    }
+}
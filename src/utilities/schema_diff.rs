@@ -0,0 +1,441 @@
+use std::collections::BTreeMap;
+
+use async_graphql::parser::{
+    parse_schema,
+    types::{BaseType, FieldDefinition, InputValueDefinition, Type, TypeDefinition, TypeKind, TypeSystemDefinition},
+    Positioned,
+};
+
+// breaking changes fail existing clients, dangerous ones can change what they see at runtime
+// (a new enum value hitting an exhaustive match, a different argument default), safe ones can't
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChangeLevel {
+    Breaking,
+    Dangerous,
+    Safe,
+}
+
+impl ChangeLevel {
+    pub fn label(&self) -> &'static str {
+        match self {
+            ChangeLevel::Breaking => "BREAKING",
+            ChangeLevel::Dangerous => "DANGEROUS",
+            ChangeLevel::Safe => "SAFE",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SchemaChange {
+    pub level: ChangeLevel,
+    // the type or `Type.field` / `Type.field(arg)` that changed
+    pub path: String,
+    pub message: String,
+}
+
+struct Parsed {
+    types: BTreeMap<String, TypeDefinition>,
+    roots: [Option<String>; 3],
+}
+
+fn parse(sdl: &str) -> Result<Parsed, String> {
+    let document = parse_schema(sdl).map_err(|e| e.to_string())?;
+    let mut parsed = Parsed {
+        types: BTreeMap::new(),
+        roots: [None, None, None],
+    };
+    let mut schema_definition = false;
+    for definition in document.definitions {
+        match definition {
+            TypeSystemDefinition::Type(ty) => {
+                parsed.types.insert(ty.node.name.node.to_string(), ty.node);
+            }
+            TypeSystemDefinition::Schema(def) => {
+                schema_definition = true;
+                parsed.roots = [
+                    def.node.query.map(|v| v.node.to_string()),
+                    def.node.mutation.map(|v| v.node.to_string()),
+                    def.node.subscription.map(|v| v.node.to_string()),
+                ];
+            }
+            TypeSystemDefinition::Directive(_) => (),
+        }
+    }
+    // without a schema definition the types named Query, Mutation and Subscription are the roots
+    if !schema_definition {
+        for (root, name) in parsed.roots.iter_mut().zip(["Query", "Mutation", "Subscription"]) {
+            if parsed.types.contains_key(name) {
+                *root = Some(name.to_string());
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+fn kind_name(kind: &TypeKind) -> &'static str {
+    match kind {
+        TypeKind::Scalar => "scalar",
+        TypeKind::Object(_) => "object",
+        TypeKind::Interface(_) => "interface",
+        TypeKind::Union(_) => "union",
+        TypeKind::Enum(_) => "enum",
+        TypeKind::InputObject(_) => "input",
+    }
+}
+
+// a field may become stricter (nullable -> non-null), never looser or of another type
+fn safe_output_change(old: &Type, new: &Type) -> bool {
+    if !old.nullable && new.nullable {
+        return false;
+    }
+    match (&old.base, &new.base) {
+        (BaseType::Named(a), BaseType::Named(b)) => a == b,
+        (BaseType::List(a), BaseType::List(b)) => safe_output_change(a, b),
+        _ => false,
+    }
+}
+
+// an argument or input field may become looser (non-null -> nullable), never stricter
+fn safe_input_change(old: &Type, new: &Type) -> bool {
+    if old.nullable && !new.nullable {
+        return false;
+    }
+    match (&old.base, &new.base) {
+        (BaseType::Named(a), BaseType::Named(b)) => a == b,
+        (BaseType::List(a), BaseType::List(b)) => safe_input_change(a, b),
+        _ => false,
+    }
+}
+
+fn is_deprecated(field: &FieldDefinition) -> bool {
+    field.directives.iter().any(|d| d.node.name.node.as_str() == "deprecated")
+}
+
+fn required(input: &InputValueDefinition) -> bool {
+    !input.ty.node.nullable && input.default_value.is_none()
+}
+
+struct Diff {
+    changes: Vec<SchemaChange>,
+}
+
+impl Diff {
+    fn push(&mut self, level: ChangeLevel, path: String, message: String) {
+        self.changes.push(SchemaChange { level, path, message });
+    }
+
+    // arguments of a field, or fields of an input object; `what` names them in messages
+    fn inputs(
+        &mut self,
+        path: &str,
+        what: &str,
+        old: &[Positioned<InputValueDefinition>],
+        new: &[Positioned<InputValueDefinition>],
+    ) {
+        for o in old {
+            let name = o.node.name.node.as_str();
+            let at = format!("{}.{}", path, name);
+            match new.iter().find(|n| n.node.name.node.as_str() == name) {
+                None => self.push(ChangeLevel::Breaking, at, format!("{} removed", what)),
+                Some(n) => {
+                    let (old_ty, new_ty) = (&o.node.ty.node, &n.node.ty.node);
+                    if old_ty != new_ty {
+                        let level = if safe_input_change(old_ty, new_ty) {
+                            ChangeLevel::Safe
+                        } else if required(&n.node) || !safe_input_change(&strip(old_ty), &strip(new_ty)) {
+                            ChangeLevel::Breaking
+                        } else {
+                            // the argument became non-null but kept a default
+                            ChangeLevel::Dangerous
+                        };
+                        self.push(level, at.clone(), format!("{} type changed from {} to {}", what, old_ty, new_ty));
+                    }
+                    let old_default = o.node.default_value.as_ref().map(|v| v.node.to_string());
+                    let new_default = n.node.default_value.as_ref().map(|v| v.node.to_string());
+                    if old_default != new_default {
+                        self.push(
+                            ChangeLevel::Dangerous,
+                            at,
+                            format!(
+                                "{} default changed from {} to {}",
+                                what,
+                                old_default.as_deref().unwrap_or("none"),
+                                new_default.as_deref().unwrap_or("none")
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+        for n in new {
+            let name = n.node.name.node.as_str();
+            if old.iter().any(|o| o.node.name.node.as_str() == name) {
+                continue;
+            }
+            let at = format!("{}.{}", path, name);
+            if required(&n.node) {
+                self.push(ChangeLevel::Breaking, at, format!("required {} added", what));
+            } else {
+                self.push(ChangeLevel::Dangerous, at, format!("optional {} added", what));
+            }
+        }
+    }
+
+    fn fields(&mut self, ty: &str, old: &[Positioned<FieldDefinition>], new: &[Positioned<FieldDefinition>]) {
+        for o in old {
+            let name = o.node.name.node.as_str();
+            let at = format!("{}.{}", ty, name);
+            let Some(n) = new.iter().find(|n| n.node.name.node.as_str() == name) else {
+                self.push(ChangeLevel::Breaking, at, "field removed".to_string());
+                continue;
+            };
+            let (old_ty, new_ty) = (&o.node.ty.node, &n.node.ty.node);
+            if old_ty != new_ty {
+                let level = if safe_output_change(old_ty, new_ty) {
+                    ChangeLevel::Safe
+                } else {
+                    ChangeLevel::Breaking
+                };
+                self.push(level, at.clone(), format!("type changed from {} to {}", old_ty, new_ty));
+            }
+            match (is_deprecated(&o.node), is_deprecated(&n.node)) {
+                (false, true) => self.push(ChangeLevel::Safe, at.clone(), "field deprecated".to_string()),
+                (true, false) => self.push(ChangeLevel::Safe, at.clone(), "deprecation removed".to_string()),
+                _ => (),
+            }
+            self.inputs(&at, "argument", &o.node.arguments, &n.node.arguments);
+        }
+        for n in new {
+            let name = n.node.name.node.as_str();
+            if !old.iter().any(|o| o.node.name.node.as_str() == name) {
+                self.push(ChangeLevel::Safe, format!("{}.{}", ty, name), "field added".to_string());
+            }
+        }
+    }
+
+    fn names(&mut self, path: &str, what: &str, old: Vec<&str>, new: Vec<&str>, added: ChangeLevel) {
+        for o in &old {
+            if !new.contains(o) {
+                self.push(ChangeLevel::Breaking, path.to_string(), format!("{} {} removed", what, o));
+            }
+        }
+        for n in &new {
+            if !old.contains(n) {
+                self.push(added, path.to_string(), format!("{} {} added", what, n));
+            }
+        }
+    }
+}
+
+fn strip(ty: &Type) -> Type {
+    Type {
+        base: ty.base.clone(),
+        nullable: true,
+    }
+}
+
+// everything that changed between the `baseline` SDL and the `current` one, breaking first
+pub fn diff_schemas(baseline: &str, current: &str) -> Result<Vec<SchemaChange>, String> {
+    let old = parse(baseline).map_err(|e| format!("baseline: {}", e))?;
+    let new = parse(current).map_err(|e| format!("current schema: {}", e))?;
+    let mut diff = Diff { changes: Vec::new() };
+
+    for (i, operation) in ["query", "mutation", "subscription"].iter().enumerate() {
+        if old.roots[i] != new.roots[i] {
+            let level = if old.roots[i].is_none() {
+                ChangeLevel::Safe
+            } else {
+                ChangeLevel::Breaking
+            };
+            diff.push(
+                level,
+                "schema".to_string(),
+                format!(
+                    "{} root changed from {} to {}",
+                    operation,
+                    old.roots[i].as_deref().unwrap_or("none"),
+                    new.roots[i].as_deref().unwrap_or("none")
+                ),
+            );
+        }
+    }
+
+    for (name, o) in &old.types {
+        let Some(n) = new.types.get(name) else {
+            diff.push(ChangeLevel::Breaking, name.clone(), format!("{} removed", kind_name(&o.kind)));
+            continue;
+        };
+        match (&o.kind, &n.kind) {
+            (TypeKind::Scalar, TypeKind::Scalar) => (),
+            (TypeKind::Object(a), TypeKind::Object(b)) => {
+                diff.fields(name, &a.fields, &b.fields);
+                diff.names(
+                    name,
+                    "interface",
+                    a.implements.iter().map(|v| v.node.as_str()).collect(),
+                    b.implements.iter().map(|v| v.node.as_str()).collect(),
+                    ChangeLevel::Dangerous,
+                );
+            }
+            (TypeKind::Interface(a), TypeKind::Interface(b)) => diff.fields(name, &a.fields, &b.fields),
+            (TypeKind::Union(a), TypeKind::Union(b)) => diff.names(
+                name,
+                "member",
+                a.members.iter().map(|v| v.node.as_str()).collect(),
+                b.members.iter().map(|v| v.node.as_str()).collect(),
+                ChangeLevel::Dangerous,
+            ),
+            (TypeKind::Enum(a), TypeKind::Enum(b)) => diff.names(
+                name,
+                "value",
+                a.values.iter().map(|v| v.node.value.node.as_str()).collect(),
+                b.values.iter().map(|v| v.node.value.node.as_str()).collect(),
+                ChangeLevel::Dangerous,
+            ),
+            (TypeKind::InputObject(a), TypeKind::InputObject(b)) => {
+                diff.inputs(name, "input field", &a.fields, &b.fields)
+            }
+            (a, b) => diff.push(
+                ChangeLevel::Breaking,
+                name.clone(),
+                format!("changed from {} to {}", kind_name(a), kind_name(b)),
+            ),
+        }
+    }
+    for (name, n) in &new.types {
+        if !old.types.contains_key(name) {
+            diff.push(ChangeLevel::Safe, name.clone(), format!("{} added", kind_name(&n.kind)));
+        }
+    }
+
+    diff.changes.sort_by_key(|c| c.level);
+    Ok(diff.changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = r#"
+        enum Outcome { SUCCESS FAILURE }
+        input UserFilter { name: String, limit: Int! = 10 }
+        type User { id: ID! name: String email: String! }
+        type Query {
+            user(id: ID!): User
+            users(filter: UserFilter, first: Int = 50): [User!]!
+        }
+        type Mutation { addUser(name: String!): User! }
+    "#;
+
+    // the changes of `current` against BASE, as (level, path, message)
+    fn diff(current: &str) -> Vec<(ChangeLevel, String, String)> {
+        diff_schemas(BASE, current)
+            .unwrap()
+            .into_iter()
+            .map(|c| (c.level, c.path, c.message))
+            .collect()
+    }
+
+    fn change(level: ChangeLevel, path: &str, message: &str) -> (ChangeLevel, String, String) {
+        (level, path.to_string(), message.to_string())
+    }
+
+    #[test]
+    fn identical_schemas_have_no_changes() {
+        assert!(diff(BASE).is_empty());
+    }
+
+    #[test]
+    fn removals_are_breaking() {
+        let current = BASE
+            .replace(" email: String!", "")
+            .replace("enum Outcome { SUCCESS FAILURE }", "enum Outcome { SUCCESS }")
+            .replace("type Mutation { addUser(name: String!): User! }", "");
+        assert_eq!(
+            diff(&current),
+            vec![
+                change(ChangeLevel::Breaking, "schema", "mutation root changed from Mutation to none"),
+                change(ChangeLevel::Breaking, "Mutation", "object removed"),
+                change(ChangeLevel::Breaking, "Outcome", "value FAILURE removed"),
+                change(ChangeLevel::Breaking, "User.email", "field removed"),
+            ]
+        );
+    }
+
+    #[test]
+    fn output_types_may_only_get_stricter() {
+        let current = BASE.replace("id: ID! name: String ", "id: ID name: String! ");
+        assert_eq!(
+            diff(&current),
+            vec![
+                change(ChangeLevel::Breaking, "User.id", "type changed from ID! to ID"),
+                change(ChangeLevel::Safe, "User.name", "type changed from String to String!"),
+            ]
+        );
+    }
+
+    #[test]
+    fn arguments_may_only_get_looser() {
+        let current = BASE
+            .replace("user(id: ID!)", "user(id: ID)")
+            .replace("addUser(name: String!)", "addUser(name: String!, email: String!)");
+        assert_eq!(
+            diff(&current),
+            vec![
+                change(ChangeLevel::Breaking, "Mutation.addUser.email", "required argument added"),
+                change(ChangeLevel::Safe, "Query.user.id", "argument type changed from ID! to ID"),
+            ]
+        );
+        let stricter = BASE.replace("user(id: ID!)", "user(id: Int!)");
+        assert_eq!(diff(&stricter)[0].0, ChangeLevel::Breaking);
+    }
+
+    #[test]
+    fn defaults_and_optional_inputs_are_dangerous() {
+        let current = BASE
+            .replace("first: Int = 50", "first: Int = 20")
+            .replace("limit: Int! = 10 }", "limit: Int! = 10, sort: String }")
+            .replace("filter: UserFilter,", "filter: UserFilter!,");
+        assert_eq!(
+            diff(&current),
+            vec![
+                change(ChangeLevel::Breaking, "Query.users.filter", "argument type changed from UserFilter to UserFilter!"),
+                change(ChangeLevel::Dangerous, "Query.users.first", "argument default changed from 50 to 20"),
+                change(ChangeLevel::Dangerous, "UserFilter.sort", "optional input field added"),
+            ]
+        );
+    }
+
+    #[test]
+    fn additions_are_safe_and_enum_values_dangerous() {
+        let current = BASE
+            .replace("email: String! }", "email: String! createdAt: String }")
+            .replace("enum Outcome { SUCCESS FAILURE }", "enum Outcome { SUCCESS FAILURE DENIED }\nscalar DateTime");
+        assert_eq!(
+            diff(&current),
+            vec![
+                change(ChangeLevel::Dangerous, "Outcome", "value DENIED added"),
+                change(ChangeLevel::Safe, "User.createdAt", "field added"),
+                change(ChangeLevel::Safe, "DateTime", "scalar added"),
+            ]
+        );
+    }
+
+    #[test]
+    fn deprecation_is_safe() {
+        let current = BASE.replace("name: String email", "name: String @deprecated(reason: \"use email\") email");
+        assert_eq!(diff(&current), vec![change(ChangeLevel::Safe, "User.name", "field deprecated")]);
+    }
+
+    #[test]
+    fn kind_changes_are_breaking() {
+        let current = BASE.replace("enum Outcome { SUCCESS FAILURE }", "scalar Outcome");
+        assert_eq!(diff(&current), vec![change(ChangeLevel::Breaking, "Outcome", "changed from enum to scalar")]);
+    }
+
+    #[test]
+    fn reports_which_side_failed_to_parse() {
+        assert!(diff_schemas("type {", BASE).unwrap_err().starts_with("baseline: "));
+        assert!(diff_schemas(BASE, "type {").unwrap_err().starts_with("current schema: "));
+    }
+}
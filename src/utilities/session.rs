@@ -0,0 +1,105 @@
+use std::time::Duration;
+
+use async_graphql::ErrorExtensions;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+use crate::{
+    db::{
+        refresh_tokens::insert_refresh_token,
+        users::{fetch_user_roles, record_login},
+    },
+    graphql::queries::{AuthPayload, User},
+    utilities::{errors::RbacError, jwt::create_jwt, login_guard::login_succeeded, permission_cache::permission_cache},
+};
+
+// how long a refresh token can be exchanged for a new access token
+#[derive(Debug, Clone)]
+pub struct SessionPolicy {
+    pub refresh_ttl: Duration,
+}
+
+// refresh tokens and MFA challenges are stored only as this hash, so a leaked table doesn't
+// hand out sessions
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+pub fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+// signs an access token with the user's current roles and opens a refresh token next to it
+pub async fn issue_session(pool: &PgPool, policy: &SessionPolicy, user: User) -> async_graphql::Result<AuthPayload> {
+    let roles = fetch_user_roles(pool, user.id).await?;
+    let mut permissions = permission_cache().permissions(pool, &roles).await?;
+    permissions.sort();
+    permissions.dedup();
+    let (access_token, expires_at) = match create_jwt(&user.id.to_string(), roles.clone()).await {
+        Ok(v) => v,
+        Err(e) => {
+            println!("Error create_jwt = {:?}", e);
+            return Err(RbacError::Internal("Failed to sign the access token".to_string()).extend());
+        }
+    };
+    let refresh_token = new_token();
+    let refresh_expires_at = insert_refresh_token(
+        pool,
+        user.id,
+        &hash_token(&refresh_token),
+        policy.refresh_ttl.as_secs() as i64,
+    )
+    .await?;
+    Ok(AuthPayload {
+        access_token,
+        refresh_token,
+        token_type: "Bearer".to_string(),
+        expires_at,
+        refresh_expires_at,
+        user,
+        roles,
+        permissions,
+    })
+}
+
+// the end of a login, once every factor checked out: resets the failed-login count, stamps
+// last_login_at and issues the tokens
+pub async fn open_session(pool: &PgPool, policy: &SessionPolicy, user_id: i32) -> async_graphql::Result<AuthPayload> {
+    let user = record_login(pool, user_id).await?;
+    login_succeeded(pool, &user.email).await?;
+    issue_session(pool, policy, user).await
+}
+
+pub fn invalid_refresh_token() -> async_graphql::Error {
+    RbacError::Unauthenticated("Invalid or expired refresh token".to_string()).extend()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{
+        db_config::{test_pool, test_user},
+        refresh_tokens::{use_refresh_token, RefreshOutcome},
+        users::fetch_user_by_id,
+    };
+
+    #[tokio::test]
+    async fn issued_refresh_token_rotates_once() {
+        let Some(pool) = test_pool().await else { return };
+        let user_id = test_user(&pool).await;
+        let user = fetch_user_by_id(&pool, user_id).await.unwrap().unwrap();
+        let policy = SessionPolicy {
+            refresh_ttl: Duration::from_secs(24 * 60 * 60),
+        };
+        let session = issue_session(&pool, &policy, user).await.unwrap();
+        assert!(session.refresh_expires_at > session.expires_at);
+        // only the hash is stored
+        assert!(matches!(use_refresh_token(&pool, &session.refresh_token).await.unwrap(), RefreshOutcome::Invalid));
+        let hash = hash_token(&session.refresh_token);
+        assert!(matches!(use_refresh_token(&pool, &hash).await.unwrap(), RefreshOutcome::Rotated(v) if v == user_id));
+        assert!(matches!(use_refresh_token(&pool, &hash).await.unwrap(), RefreshOutcome::Reused(v) if v == user_id));
+    }
+}